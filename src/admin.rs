@@ -0,0 +1,138 @@
+//! Reports of operational problems (failing fetches, sends and logins) to
+//! `config.admin_room`. Reports of the same kind are throttled, so a flapping subscription
+//! doesn't flood the room: within `admin_report_interval_minutes` only the first one is
+//! sent, and the next one afterwards tells how many were held back. If there is no next
+//! one, `run` reports the held back count once the interval is over.
+use super::{
+    formatting::Message,
+    i18n::{self, Language},
+    room_settings::{self, NotificationType},
+    send_to_room, SharedState,
+};
+use matrix_sdk::Client;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Lower bound of how often `run` looks for held back reports
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Throttling state of one kind of report
+#[derive(Debug, Clone)]
+pub struct ReportThrottle {
+    last_sent: Instant,
+    suppressed: usize,
+}
+
+/// Sends the message built by `message` in the language of the admin room, unless a report
+/// with the same `key` was sent recently. It always ends up on stderr, in English.
+pub async fn report(
+    client: &Client,
+    ctx: &SharedState,
+    key: &str,
+    message: impl Fn(Language) -> Message,
+) {
+    eprintln!("{}", message(Language::En).plain());
+    let Some(admin_room) = &ctx.cfg.admin_room else {
+        return;
+    };
+    let suppressed = {
+        let mut throttles = ctx.admin_reports.lock().unwrap();
+        match throttles.get_mut(key) {
+            Some(throttle) if throttle.last_sent.elapsed() < ctx.cfg.admin_report_interval => {
+                throttle.suppressed += 1;
+                return;
+            }
+            _ => throttles
+                .insert(
+                    key.to_string(),
+                    ReportThrottle {
+                        last_sent: Instant::now(),
+                        suppressed: 0,
+                    },
+                )
+                .map(|x| x.suppressed)
+                .unwrap_or(0),
+        }
+    };
+    let lang = language(client, ctx).await;
+    let mut message = message(lang);
+    if suppressed > 0 {
+        message = message.line_break().append(Message::fill(
+            i18n::translate(lang, "({count} similar reports were held back)"),
+            &[("count", Message::new().text(&suppressed.to_string()))],
+        ));
+    }
+    // Failing to report is only logged, there is nowhere else to report it to
+    if let Err(e) = send_to_room(
+        client,
+        admin_room,
+        NotificationType::Notice,
+        message.plain(),
+        message.html(),
+    )
+    .await
+    {
+        eprintln!("Failed to report to the admin room: {e:?}");
+    }
+}
+
+/// Reports the counts of held back reports whose interval is over, and forgets the kinds
+/// that were quiet for a whole interval
+pub async fn run(client: Client, ctx: SharedState) {
+    let Some(admin_room) = ctx.cfg.admin_room.clone() else {
+        return;
+    };
+    let interval = ctx.cfg.admin_report_interval.max(MIN_FLUSH_INTERVAL);
+    loop {
+        sleep(interval).await;
+        let held_back: Vec<_> = {
+            let mut throttles = ctx.admin_reports.lock().unwrap();
+            let mut held_back = Vec::new();
+            throttles.retain(|key, throttle| {
+                if throttle.last_sent.elapsed() < ctx.cfg.admin_report_interval {
+                    return true;
+                }
+                if throttle.suppressed > 0 {
+                    held_back.push((key.clone(), throttle.suppressed));
+                }
+                false
+            });
+            held_back
+        };
+        if held_back.is_empty() {
+            continue;
+        }
+        let lang = language(&client, &ctx).await;
+        for (key, count) in held_back {
+            let message = Message::fill(
+                i18n::translate(lang, "{count} more reports of kind {kind} were held back"),
+                &[
+                    ("count", Message::new().text(&count.to_string())),
+                    ("kind", Message::new().code(&key)),
+                ],
+            );
+            eprintln!("{}", message.plain());
+            if let Err(e) = send_to_room(
+                &client,
+                &admin_room,
+                NotificationType::Notice,
+                message.plain(),
+                message.html(),
+            )
+            .await
+            {
+                eprintln!("Failed to report to the admin room: {e:?}");
+            }
+        }
+    }
+}
+
+async fn language(client: &Client, ctx: &SharedState) -> Language {
+    match &ctx.cfg.admin_room {
+        Some(admin_room) => room_settings::get(client, admin_room)
+            .await
+            .language
+            .unwrap_or(ctx.cfg.language),
+        None => ctx.cfg.language,
+    }
+}
@@ -0,0 +1,151 @@
+//! Keyword alerts: users register patterns per room with !alertme, and announcements in
+//! that room with a matching entry mention them. Persisted like the watch list.
+use super::{watch_list, SharedState};
+use matrix_sdk::{
+    ruma::{events::macros::EventContent, OwnedRoomId, OwnedUserId, RoomId, UserId},
+    Client,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+const ALERTS_DOCUMENT: &str = "alerts";
+/// Patterns a user may register per room
+pub const MAX_ALERTS_PER_USER: usize = 10;
+
+/// Patterns of each user in a room
+pub type RoomAlerts = BTreeMap<OwnedUserId, Vec<String>>;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.alerts", kind = GlobalAccountData)]
+pub struct AlertsEventContent {
+    pub rooms: BTreeMap<OwnedRoomId, RoomAlerts>,
+}
+
+pub async fn store(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content = AlertsEventContent {
+        rooms: ctx.alerts.lock().unwrap().clone(),
+    };
+    watch_list::store_document(client, ctx, ALERTS_DOCUMENT, content).await
+}
+
+/// Loads the persisted alert patterns
+pub async fn restore(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content: AlertsEventContent =
+        watch_list::restore_document(client, ctx, ALERTS_DOCUMENT).await?;
+    *ctx.alerts.lock().unwrap() = content.rooms;
+    Ok(())
+}
+
+/// Adds a pattern of `user` in `room_id`
+pub async fn add(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &RoomId,
+    user: &UserId,
+    pattern: &str,
+) -> anyhow::Result<()> {
+    Regex::new(pattern)?;
+    {
+        let mut alerts = ctx.alerts.lock().unwrap();
+        let patterns = alerts
+            .entry(room_id.to_owned())
+            .or_default()
+            .entry(user.to_owned())
+            .or_default();
+        if patterns.iter().any(|x| x == pattern) {
+            return Ok(());
+        }
+        if patterns.len() >= MAX_ALERTS_PER_USER {
+            anyhow::bail!("You already have {MAX_ALERTS_PER_USER} patterns in this room");
+        }
+        patterns.push(pattern.to_string());
+    }
+    store(client, ctx).await
+}
+
+/// Removes all patterns of `user` in `room_id`. Returns how many there were.
+pub async fn clear(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &RoomId,
+    user: &UserId,
+) -> anyhow::Result<usize> {
+    let removed = {
+        let mut alerts = ctx.alerts.lock().unwrap();
+        let removed = alerts
+            .get_mut(room_id)
+            .and_then(|x| x.remove(user))
+            .map(|x| x.len())
+            .unwrap_or(0);
+        if alerts.get(room_id).is_some_and(|x| x.is_empty()) {
+            alerts.remove(room_id);
+        }
+        removed
+    };
+    if removed > 0 {
+        store(client, ctx).await?;
+    }
+    Ok(removed)
+}
+
+/// The patterns of `user` in `room_id`
+pub fn patterns(ctx: &SharedState, room_id: &RoomId, user: &UserId) -> Vec<String> {
+    ctx.alerts
+        .lock()
+        .unwrap()
+        .get(room_id)
+        .and_then(|x| x.get(user))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Users in `room_id` with a pattern matching any of the entries
+pub fn matching_users(
+    ctx: &SharedState,
+    room_id: &RoomId,
+    entries: &HashSet<String>,
+) -> Vec<OwnedUserId> {
+    let Some(room_alerts) = ctx.alerts.lock().unwrap().get(room_id).cloned() else {
+        return Vec::new();
+    };
+    room_alerts
+        .into_iter()
+        .filter(|(_, patterns)| {
+            patterns
+                .iter()
+                .filter_map(|x| Regex::new(x).ok())
+                .any(|x| entries.iter().any(|entry| x.is_match(entry)))
+        })
+        .map(|(user, _)| user)
+        .collect()
+}
+
+/// Moves all patterns of `from` to `to`, e.g. after a room upgrade
+pub async fn move_room(
+    client: &Client,
+    ctx: &SharedState,
+    from: &RoomId,
+    to: &RoomId,
+) -> anyhow::Result<()> {
+    {
+        let mut alerts = ctx.alerts.lock().unwrap();
+        let Some(patterns) = alerts.remove(from) else {
+            return Ok(());
+        };
+        alerts.insert(to.to_owned(), patterns);
+    }
+    store(client, ctx).await
+}
+
+/// Drops all patterns of a room we left
+pub async fn remove_room(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &RoomId,
+) -> anyhow::Result<()> {
+    if ctx.alerts.lock().unwrap().remove(room_id).is_none() {
+        return Ok(());
+    }
+    store(client, ctx).await
+}
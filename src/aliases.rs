@@ -0,0 +1,124 @@
+//! Room aliases are accepted wherever a room can be given, and resolved to room IDs via
+//! the room directory of the homeserver. Resolutions are cached, and kept in the state DB,
+//! so a homeserver that is down while we start doesn't stop the bot from using the aliases
+//! it resolved before. Each instance has its own cache, as it has its own state DB.
+use super::SessionStore;
+use matrix_sdk::ruma::{OwnedRoomAliasId, OwnedRoomId, RoomOrAliasId};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::Duration,
+};
+
+const ALIASES_DOCUMENT: &str = "room_aliases";
+/// A homeserver that doesn't answer in time counts as down, and the cache is used
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Cache = BTreeMap<OwnedRoomAliasId, OwnedRoomId>;
+
+/// The caches of all instances, by the db_path of the instance. Instances without a
+/// session DB share one per homeserver, which isn't persisted anyway.
+fn caches() -> MutexGuard<'static, BTreeMap<String, Cache>> {
+    static CACHES: OnceLock<Mutex<BTreeMap<String, Cache>>> = OnceLock::new();
+    CACHES
+        .get_or_init(|| Mutex::new(BTreeMap::new()))
+        .lock()
+        .unwrap()
+}
+
+fn cache_key(homeserver_url: &str, storage: &dyn SessionStore) -> String {
+    match storage.get_session_db() {
+        Some(db) => db.db_path.display().to_string(),
+        None => homeserver_url.to_string(),
+    }
+}
+
+#[derive(Deserialize)]
+struct DirectoryResponse {
+    room_id: OwnedRoomId,
+}
+
+/// Reads the aliases resolved by earlier runs
+pub async fn restore(storage: &dyn SessionStore) -> anyhow::Result<()> {
+    let Some(db) = storage.get_session_db() else {
+        return Ok(());
+    };
+    if let Some(stored) = db.state.document::<Cache>(ALIASES_DOCUMENT).await? {
+        let key = db.db_path.display().to_string();
+        caches().entry(key).or_default().extend(stored);
+    }
+    Ok(())
+}
+
+async fn store(storage: &dyn SessionStore) -> anyhow::Result<()> {
+    if let Some(db) = storage.get_session_db() {
+        let key = db.db_path.display().to_string();
+        let cache = caches().get(&key).cloned().unwrap_or_default();
+        db.state.set_document(ALIASES_DOCUMENT, &cache).await?;
+    }
+    Ok(())
+}
+
+/// Asks the room directory, which doesn't need us to be logged in
+async fn lookup(homeserver_url: &str, alias: &OwnedRoomAliasId) -> anyhow::Result<OwnedRoomId> {
+    let mut url = reqwest::Url::parse(homeserver_url)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid homeserver URL {homeserver_url}"))?
+        .pop_if_empty()
+        .extend([
+            "_matrix",
+            "client",
+            "v3",
+            "directory",
+            "room",
+            alias.as_str(),
+        ]);
+    let response: DirectoryResponse = reqwest::Client::builder()
+        .timeout(LOOKUP_TIMEOUT)
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response.room_id)
+}
+
+/// The ID of a room given by ID or alias. Falls back to the cached ID of an alias if the
+/// homeserver can't tell right now.
+pub async fn resolve(
+    homeserver_url: &str,
+    storage: &dyn SessionStore,
+    room: &str,
+) -> anyhow::Result<OwnedRoomId> {
+    let room = RoomOrAliasId::parse(room)?;
+    let alias = match OwnedRoomId::try_from(room.clone()) {
+        Ok(room_id) => return Ok(room_id),
+        Err(alias) => alias,
+    };
+    let key = cache_key(homeserver_url, storage);
+    let cached = caches().get(&key).and_then(|x| x.get(&alias)).cloned();
+    match lookup(homeserver_url, &alias).await {
+        Ok(room_id) => {
+            if cached.as_ref() != Some(&room_id) {
+                caches()
+                    .entry(key)
+                    .or_default()
+                    .insert(alias, room_id.clone());
+                if let Err(e) = store(storage).await {
+                    eprintln!("Failed to store the resolved room aliases: {e:?}");
+                }
+            }
+            Ok(room_id)
+        }
+        Err(e) => match cached {
+            Some(room_id) => {
+                eprintln!("Failed to resolve {alias} ({e}), using {room_id} from the cache");
+                Ok(room_id)
+            }
+            None => Err(e.context(format!("Failed to resolve {alias}"))),
+        },
+    }
+}
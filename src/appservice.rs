@@ -0,0 +1,107 @@
+//! Runs the bot as application service: the homeserver pushes events to us via the
+//! transaction API instead of us syncing, and we send with the AS token, which isn't
+//! rate-limited. Note that application services can't decrypt encrypted rooms.
+use super::{
+    knocking, matrix::register_event_handlers, private_files, spaces, watch_list, AppServiceConfig,
+    SharedState,
+};
+use anyhow::bail;
+use matrix_sdk::Client;
+use matrix_sdk_appservice::{AppService, AppServiceRegistration};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::fs;
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+async fn write_registration(cfg: &AppServiceConfig) -> anyhow::Result<()> {
+    let registration = format!(
+        "id: matrix_mozilla_bot
+url: {url}
+as_token: {as_token}
+hs_token: {hs_token}
+sender_localpart: {localpart}
+rate_limited: false
+namespaces:
+  users:
+    - exclusive: true
+      regex: '@{localpart_regex}:{server_name_regex}'
+  aliases: []
+  rooms: []
+",
+        url = cfg.url,
+        as_token = generate_token(),
+        hs_token = generate_token(),
+        localpart = cfg.sender_localpart,
+        localpart_regex = regex::escape(&cfg.sender_localpart),
+        server_name_regex = regex::escape(&cfg.server_name),
+    );
+    if let Some(parent) = cfg.registration.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    // Holds the tokens
+    private_files::write(&cfg.registration, registration)?;
+    Ok(())
+}
+
+/// Starts the transaction API server and returns the client of our bot user
+pub async fn start(aio: SharedState, cfg: &AppServiceConfig) -> anyhow::Result<Client> {
+    if !cfg.registration.exists() {
+        write_registration(cfg).await?;
+        bail!(
+            "Generated the registration {}. Add it to the app_service_config_files of your homeserver and restart the bot.",
+            cfg.registration.display()
+        );
+    }
+    let registration = AppServiceRegistration::try_from_yaml_file(&cfg.registration)?;
+
+    let mut client_builder = Client::builder();
+    // Only the state store is of use here, as encryption isn't available to us
+    if let Some(db) = &aio.cfg.session_storage.get_session_db() {
+        client_builder = client_builder.sqlite_store(&db.db_path, Some(&db.db_pw));
+    }
+    let appservice = AppService::builder(
+        aio.cfg.homeserver_url.as_str().try_into()?,
+        cfg.server_name.as_str().try_into()?,
+        registration,
+    )
+    .client_builder(client_builder)
+    .build()
+    .await?;
+
+    // `None` gives us the sender_localpart user of the registration
+    let client = appservice.user(None).await?;
+    if let Err(e) = watch_list::restore_from_account_data(&client, &aio).await {
+        eprintln!("Failed to restore the watched rooms from the account data: {e:?}");
+    }
+    if let Err(e) = watch_list::restore_routed_mutes(&client, &aio).await {
+        eprintln!("Failed to restore the mutes of routed rooms: {e:?}");
+    }
+    if let Err(e) = knocking::restore(&client, &aio).await {
+        eprintln!("Failed to restore the knocked rooms: {e:?}");
+    }
+    let mut problems = watch_list::join_configured(&client, &aio).await;
+    problems.extend(spaces::join_configured(&client, &aio).await);
+    for problem in problems {
+        eprintln!("{problem}");
+    }
+    register_event_handlers(&client, &aio);
+
+    let host = cfg.listen_host.clone();
+    let port = cfg.listen_port;
+    println!(
+        "Running as application service {}, listening on {host}:{port}",
+        client.user_id().map(|x| x.to_string()).unwrap_or_default()
+    );
+    tokio::spawn(async move {
+        if let Err(e) = appservice.run(host, port).await {
+            eprintln!("Application service stopped: {e:?}");
+        }
+    });
+    Ok(client)
+}
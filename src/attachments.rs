@@ -0,0 +1,123 @@
+//! Small files among the new entries of a subscription (checksums, logs), posted as
+//! m.file right after the notification, so they can be read without following the link
+//! upstream. Which files is up to the `attach` regex of the subscription.
+//!
+//! They stay with their notification when it is held back for quiet hours or digests, or
+//! waits in the outbox, and are posted through the send queue like everything else.
+//! Like the notification, they are redacted once the retention of the room is over.
+use super::{
+    mozilla::{HttpCache, MozData},
+    resources::ResourceTracker,
+    retention, send_queue, SharedState,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use matrix_sdk::{
+    ruma::{
+        events::room::message::{
+            FileInfo, FileMessageEventContent, MessageType, RoomMessageEventContent,
+        },
+        RoomId, UInt,
+    },
+    Client,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashSet, sync::Arc};
+
+/// Larger files are only linked, as usual
+const MAX_ATTACHMENT_SIZE: usize = 1024 * 1024;
+/// A new release can bring lots of matching files, only the first ones are attached
+const MAX_ATTACHMENTS_PER_POLL: usize = 5;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Attachment {
+    pub name: String,
+    /// Base64 in the outbox
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub data: Vec<u8>,
+}
+
+fn to_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+}
+
+/// Downloads the new entries matching `attach`. Files that can't be downloaded (or are too
+/// large) are left out, the notification links them anyway.
+pub async fn fetch(
+    http: &Arc<HttpCache>,
+    resources: &ResourceTracker,
+    source: &MozData,
+    entries: &HashSet<String>,
+) -> Vec<Attachment> {
+    let Some(pattern) = &source.attach else {
+        return Vec::new();
+    };
+    let mut matching: Vec<_> = entries
+        .iter()
+        .filter(|x| !x.ends_with('/'))
+        .filter(|x| pattern.is_match(file_name(x)))
+        .collect();
+    matching.sort();
+    let mut attachments = Vec::new();
+    for entry in matching.into_iter().take(MAX_ATTACHMENTS_PER_POLL) {
+        let url = format!("{}/{}/{entry}", source.base_url, source.url_part);
+        match http.download(&url, MAX_ATTACHMENT_SIZE, resources).await {
+            Ok(data) => attachments.push(Attachment {
+                name: file_name(entry).to_string(),
+                data,
+            }),
+            Err(e) => eprintln!("Not attaching {url}: {e:?}"),
+        }
+    }
+    attachments
+}
+
+fn file_name(entry: &str) -> &str {
+    entry.rsplit('/').next().unwrap_or(entry)
+}
+
+/// Uploads the files to the media repository and posts them. In encrypted rooms, they
+/// get encrypted before the upload.
+pub async fn send(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &RoomId,
+    attachments: &[Attachment],
+) -> anyhow::Result<()> {
+    let Some(room) = client.get_room(room_id) else {
+        return Ok(());
+    };
+    let encrypted = !attachments.is_empty() && room.is_encrypted().await?;
+    for attachment in attachments {
+        // Checksums and logs are text, anything else is offered for download as is
+        let content_type = if std::str::from_utf8(&attachment.data).is_ok() {
+            mime::TEXT_PLAIN_UTF_8
+        } else {
+            mime::APPLICATION_OCTET_STREAM
+        };
+        let mut content = if encrypted {
+            let file = client
+                .upload_encrypted_file(&content_type, &mut attachment.data.as_slice())
+                .await?;
+            FileMessageEventContent::encrypted(attachment.name.clone(), file)
+        } else {
+            let response = client
+                .media()
+                .upload(&content_type, attachment.data.clone())
+                .await?;
+            FileMessageEventContent::plain(attachment.name.clone(), response.content_uri)
+        };
+        let mut info = FileInfo::new();
+        info.mimetype = Some(content_type.to_string());
+        info.size = UInt::new(attachment.data.len() as u64);
+        content.info = Some(Box::new(info));
+        let content = RoomMessageEventContent::new(MessageType::File(content));
+        let event_id = send_queue::send(&room, content).await?;
+        retention::track(ctx, room_id, &[event_id]).await;
+    }
+    Ok(())
+}
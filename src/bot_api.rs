@@ -0,0 +1,159 @@
+//! Machine-readable control interface for other bots and clients.
+//!
+//! Trusted senders can either send a `org.mozillabot.command` to-device event to our
+//! device, or post the same JSON as message body in a DM with the bot, e.g.
+//! `{"command": "check", "source": "ff_rel"}` or
+//! `{"command": "subscribe", "name": "nss", "url_part": "security/nss/releases"}`.
+//!
+//! Answers go back the same way: as DM reply, or as `org.mozillabot.response` to-device
+//! event to all devices of the sender, with the `request_id` of the command, if it had one.
+use super::{subscriptions, RuntimeSubscription, SharedState};
+use matrix_sdk::{
+    event_handler::Ctx,
+    ruma::{
+        api::client::to_device::send_event_to_device,
+        events::{macros::EventContent, AnyToDeviceEventContent, ToDeviceEventContent},
+        serde::Raw,
+        to_device::DeviceIdOrAllDevices,
+        TransactionId, UserId,
+    },
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum BotApiCommand {
+    Subscribe {
+        name: String,
+        url_part: String,
+        filter: Option<String>,
+        #[serde(default)]
+        query_subdirs: bool,
+    },
+    Check {
+        source: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.command", kind = ToDevice)]
+pub struct BotCommandEventContent {
+    /// Echoed in the response, so callers can match the two
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub command: BotApiCommand,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.response", kind = ToDevice)]
+pub struct BotResponseEventContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Always an object, as returned by `execute`
+    #[serde(flatten)]
+    pub response: serde_json::Value,
+}
+
+/// Executes a command and returns the JSON response for the caller
+pub async fn execute(
+    client: &Client,
+    ctx: &SharedState,
+    command: BotApiCommand,
+) -> serde_json::Value {
+    match command {
+        BotApiCommand::Subscribe {
+            name,
+            url_part,
+            filter,
+            query_subdirs,
+        } => {
+            let subscription = RuntimeSubscription {
+                name,
+                url_part,
+                filter,
+                query_subdirs,
+                room: None,
+            };
+            match subscriptions::add(client, ctx, subscription).await {
+                Ok(()) => json!({"ok": true}),
+                Err(e) => json!({"ok": false, "error": e.to_string()}),
+            }
+        }
+        BotApiCommand::Check { source } => {
+            let source = match source {
+                Some(source) => match ctx.find_source_name(&source) {
+                    Some(name) => Some(name),
+                    None => {
+                        return json!({"ok": false, "error": format!("unknown source {source}")})
+                    }
+                },
+                None => None,
+            };
+            match ctx.check_now(source).await {
+                Ok(results) => {
+                    let results: serde_json::Map<_, _> = results
+                        .into_iter()
+                        .map(|(url_part, outcome)| (url_part, json!(outcome.to_string())))
+                        .collect();
+                    json!({"ok": true, "results": results})
+                }
+                Err(e) => json!({"ok": false, "error": e.to_string()}),
+            }
+        }
+    }
+}
+
+pub async fn on_to_device_command(
+    event: ToDeviceBotCommandEvent,
+    client: Client,
+    ctx: Ctx<SharedState>,
+) {
+    if ctx.is_ignored(&event.sender) {
+        return;
+    }
+    if !ctx.accepts_bot_api_from(&event.sender) {
+        println!(
+            "Ignoring bot API command from untrusted user {}",
+            event.sender
+        );
+        return;
+    }
+    println!(
+        "Bot API command from {}: {:?}",
+        event.sender, event.content.command
+    );
+    let response = execute(&client, &ctx, event.content.command).await;
+    println!("Bot API response for {}: {}", event.sender, response);
+    let content = BotResponseEventContent {
+        request_id: event.content.request_id,
+        response,
+    };
+    if let Err(e) = send_response(&client, &event.sender, content).await {
+        eprintln!(
+            "Failed to answer the bot API command of {}: {e:?}",
+            event.sender
+        );
+    }
+}
+
+/// Sends a response to all devices of `user`, as we don't know which one asked
+async fn send_response(
+    client: &Client,
+    user: &UserId,
+    content: BotResponseEventContent,
+) -> anyhow::Result<()> {
+    let event_type = content.event_type();
+    let raw = Raw::new(&content)?.cast::<AnyToDeviceEventContent>();
+    let messages = BTreeMap::from([(
+        user.to_owned(),
+        BTreeMap::from([(DeviceIdOrAllDevices::AllDevices, raw)]),
+    )]);
+    let request =
+        send_event_to_device::v3::Request::new_raw(event_type, TransactionId::new(), messages);
+    client.send(request, None).await?;
+    Ok(())
+}
@@ -0,0 +1,309 @@
+//! The `check-config` subcommand: validates the whole config up front and lists every
+//! problem it finds, instead of the bot stopping at the first one while starting. It
+//! parses with the same functions as the bot, only without resolving room aliases.
+use super::{
+    appservice_login_data, check_routed_sources,
+    config_file::{
+        optional_section, section, AppServiceSection, ConfigSection, LimitsSection, LoginSection,
+        RoomSection, SubscriptionSection,
+    },
+    i18n::Language,
+    lifecycle::LifecycleAnnouncements,
+    mozilla::BASE_URL,
+    parse_room_config, parse_schedule, parse_subscription, user_patterns,
+    watch_list::WatchListStorage,
+};
+use config::{Config, Value};
+use matrix_sdk::ruma::RoomOrAliasId;
+use std::collections::BTreeMap;
+use tokio::time::Duration;
+
+#[derive(Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn check<T>(&mut self, what: impl AsRef<str>, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.0.push(format!("{}: {e:#}", what.as_ref()));
+                None
+            }
+        }
+    }
+}
+
+/// One row of the summary table
+struct SubscriptionRow {
+    name: String,
+    url: String,
+    filter: String,
+    schedule: String,
+    status: String,
+}
+
+/// Checks every instance (or account) of the config, prints a summary of the
+/// subscriptions, and fails if anything is wrong
+pub async fn run(
+    settings: &Config,
+    names: &[(Option<String>, Option<String>)],
+) -> anyhow::Result<()> {
+    let mut problems = Problems::default();
+    let http = reqwest::Client::new();
+    let mut top_level_checked = false;
+    for (instance, account) in names {
+        let prefix = instance
+            .as_ref()
+            .map(|x| format!("instance.{x}."))
+            .unwrap_or_default();
+        let login_prefix = account
+            .as_ref()
+            .map(|x| format!("account.{x}."))
+            .unwrap_or_else(|| prefix.clone());
+        let name = instance.as_deref().or(account.as_deref());
+        check_login(settings, &login_prefix, name, &mut problems);
+        // Accounts share the top-level settings, which only need checking once
+        if instance.is_none() && std::mem::replace(&mut top_level_checked, true) {
+            continue;
+        }
+        let routed = check_settings(settings, &prefix, &mut problems);
+        let (names, rows) =
+            check_subscriptions(settings, &prefix, instance.is_some(), &http, &mut problems).await;
+        let names: Vec<_> = names.iter().map(String::as_str).collect();
+        for (what, sources) in routed {
+            problems.check(what, check_routed_sources(Some(&sources), &names));
+        }
+        if let Some(instance) = instance {
+            println!("Instance {instance}:");
+        }
+        print_table(&rows);
+    }
+    if problems.0.is_empty() {
+        println!("The config is fine");
+        return Ok(());
+    }
+    eprintln!();
+    for problem in &problems.0 {
+        eprintln!("- {problem}");
+    }
+    anyhow::bail!("Found {} problems in the config", problems.0.len())
+}
+
+fn check_login(settings: &Config, prefix: &str, name: Option<&str>, problems: &mut Problems) {
+    let key = format!("{prefix}login");
+    let Some(login) = problems.check(&key, settings.get::<LoginSection>(&key).map_err(Into::into))
+    else {
+        return;
+    };
+    problems.check(
+        format!("{key}.homeserver_url"),
+        reqwest::Url::parse(&login.homeserver_url).map_err(Into::into),
+    );
+    let appservice_key = format!("{prefix}appservice");
+    let appservice = problems
+        .check(
+            &appservice_key,
+            optional_section::<AppServiceSection>(settings, &appservice_key).map_err(Into::into),
+        )
+        .flatten();
+    if let Some(appservice) = appservice.clone() {
+        problems.check(&appservice_key, appservice_login_data(appservice));
+    }
+    problems.check(format!("{key}.oidc"), login.check_oidc());
+    problems.check(format!("{key}.password"), login.load_password(name));
+    problems.check(format!("{key}.db_pw"), login.load_db_pw(name));
+    if appservice.is_none() && !login.oidc && !cfg!(feature = "sso-login") {
+        problems.check(
+            format!("{key}.username"),
+            login.username.ok_or_else(|| anyhow::anyhow!("missing")),
+        );
+    }
+}
+
+/// Returns the subscriptions each `[[room]]` routes, to check them against the
+/// subscriptions
+fn check_settings(
+    settings: &Config,
+    prefix: &str,
+    problems: &mut Problems,
+) -> Vec<(String, Vec<String>)> {
+    let key = format!("{prefix}limits");
+    problems.check(
+        &key,
+        section::<LimitsSection>(settings, &key).map_err(Into::into),
+    );
+    let key = format!("{prefix}room");
+    let rooms = problems
+        .check(
+            &key,
+            optional_section::<Vec<RoomSection>>(settings, &key).map_err(Into::into),
+        )
+        .flatten()
+        .unwrap_or_default();
+    let mut routed = Vec::new();
+    for room in rooms {
+        let what = format!("{key} {}", room.id);
+        problems.check(&what, RoomOrAliasId::parse(&room.id).map_err(Into::into));
+        if let Some(sources) = room.sources.clone() {
+            routed.push((what.clone(), sources));
+        }
+        problems.check(&what, parse_room_config(room));
+    }
+    let key = format!("{prefix}config");
+    let Some(config) = problems.check(
+        &key,
+        section::<ConfigSection>(settings, &key).map_err(Into::into),
+    ) else {
+        return routed;
+    };
+    for (list, patterns) in [
+        ("accept_commands_from", &config.accept_commands_from),
+        ("ignore_users", &config.ignore_users),
+        ("bot_users", &config.bot_users),
+        ("admins", &config.admins),
+    ] {
+        problems.check(format!("{key}.{list}"), user_patterns(patterns));
+    }
+    for (list, rooms) in [("rooms", &config.rooms), ("spaces", &config.spaces)] {
+        for room in rooms {
+            problems.check(
+                format!("{key}.{list}"),
+                RoomOrAliasId::parse(room.as_str()).map_err(Into::into),
+            );
+        }
+    }
+    if let Some(room) = &config.admin_room {
+        problems.check(
+            format!("{key}.admin_room"),
+            RoomOrAliasId::parse(room.as_str()).map_err(Into::into),
+        );
+    }
+    if let Some(value) = &config.announce_lifecycle {
+        problems.check(
+            format!("{key}.announce_lifecycle"),
+            LifecycleAnnouncements::parse(value),
+        );
+    }
+    if let Some(value) = &config.language {
+        problems.check(format!("{key}.language"), Language::parse(value));
+    }
+    if let Some(value) = &config.watch_list_storage {
+        problems.check(
+            format!("{key}.watch_list_storage"),
+            WatchListStorage::parse(value),
+        );
+    }
+    routed
+}
+
+/// Returns the names of all subscriptions, including broken ones, and the table rows of
+/// the ones that parse
+async fn check_subscriptions(
+    settings: &Config,
+    prefix: &str,
+    in_instance: bool,
+    http: &reqwest::Client,
+    problems: &mut Problems,
+) -> (Vec<String>, Vec<SubscriptionRow>) {
+    let key = format!("{prefix}subscription");
+    // Checked one by one, so one broken subscription doesn't hide the problems of the others
+    let subscriptions = match optional_section::<BTreeMap<String, Value>>(settings, &key) {
+        Ok(Some(subscriptions)) => subscriptions,
+        Ok(None) if in_instance => optional_section(settings, "subscription")
+            .ok()
+            .flatten()
+            .unwrap_or_default(),
+        Ok(None) => {
+            problems.check::<()>(&key, Err(anyhow::anyhow!("missing")));
+            return (Vec::new(), Vec::new());
+        }
+        Err(e) => {
+            problems.check::<()>(&key, Err(e.into()));
+            return (Vec::new(), Vec::new());
+        }
+    };
+    let names = subscriptions.keys().cloned().collect();
+    let mut rows = Vec::new();
+    for (name, value) in subscriptions {
+        let what = format!("subscription {name}");
+        let Some(sub) = problems.check(
+            &what,
+            value
+                .try_deserialize::<SubscriptionSection>()
+                .map_err(Into::into),
+        ) else {
+            continue;
+        };
+        problems.check(&what, parse_subscription(&name, &sub));
+        // Only whether it parses matters here
+        problems.check(&what, parse_schedule(&sub, Duration::ZERO));
+        for room in sub.rooms.iter().flatten() {
+            problems.check(
+                format!("{what}.rooms"),
+                RoomOrAliasId::parse(room.as_str()).map_err(Into::into),
+            );
+        }
+        let url = format!("{BASE_URL}/{}/", sub.url_part);
+        let status = match http
+            .get(&url)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+        {
+            Ok(_) => String::from("OK"),
+            Err(e) => {
+                problems
+                    .0
+                    .push(format!("{what}: {url} is not reachable: {e}"));
+                String::from("unreachable")
+            }
+        };
+        rows.push(SubscriptionRow {
+            name,
+            url,
+            filter: sub.filter.unwrap_or_else(|| String::from("-")),
+            schedule: sub
+                .schedule
+                .unwrap_or_else(|| String::from("default interval")),
+            status,
+        });
+    }
+    (names, rows)
+}
+
+fn print_table(rows: &[SubscriptionRow]) {
+    let header = SubscriptionRow {
+        name: String::from("SUBSCRIPTION"),
+        url: String::from("URL"),
+        filter: String::from("FILTER"),
+        schedule: String::from("SCHEDULE"),
+        status: String::from("STATUS"),
+    };
+    let width = |column: fn(&SubscriptionRow) -> &str| {
+        rows.iter()
+            .chain([&header])
+            .map(|x| column(x).chars().count())
+            .max()
+            .unwrap_or(0)
+    };
+    let widths = [
+        width(|x| &x.name),
+        width(|x| &x.url),
+        width(|x| &x.filter),
+        width(|x| &x.schedule),
+    ];
+    for row in [&header].into_iter().chain(rows) {
+        println!(
+            "{:w0$}  {:w1$}  {:w2$}  {:w3$}  {}",
+            row.name,
+            row.url,
+            row.filter,
+            row.schedule,
+            row.status,
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Command line interface. Without a subcommand, the bot runs as usual.
+use super::{config_file::SessionBackend, devices::DEFAULT_STALE_DAYS};
+use clap::Parser;
+use std::path::{Path, PathBuf};
+
+/// Like example_config.toml, with every option documented, but the credentials left to
+/// fill in
+const CONFIG_TEMPLATE: &str = include_str!("../config_template.toml");
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Announces new uploads to archive.mozilla.org in Matrix rooms"
+)]
+pub struct Cli {
+    /// Config file. Defaults to the first botconfig.toml (or .json, .yaml, ...) in the
+    /// working directory, $XDG_CONFIG_HOME/matrix_mozilla_bot/ or /etc/matrix_mozilla_bot/.
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Also log what the Matrix SDK does
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+    /// Name of a separate bot on this machine. Its config is botconfig.<profile>.toml (or
+    /// .json, ...), and its session is kept apart from the ones of other profiles.
+    #[arg(short, long, global = true)]
+    pub profile: Option<String>,
+    /// Fail instead of asking for passwords that aren't configured
+    #[arg(long, global = true)]
+    pub non_interactive: bool,
+    /// Start even if another copy of the bot seems to use the same db_path. For init and
+    /// generate-config: replace the config file, if it exists.
+    #[arg(long, global = true)]
+    pub force: bool,
+    #[command(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::Subcommand)]
+pub enum Subcommand {
+    /// Run the bot
+    Run,
+    /// Fetch all subscriptions once and print what is upstream, without logging in
+    Fetch,
+    /// Log in (asking for the password or going through SSO, if needed), store the session
+    /// and exit, so the service started later can restore it without a TTY
+    Login,
+    /// Validate the config and list all problems in it
+    CheckConfig,
+    /// Ask for the essential settings, write a config with them and log in
+    Init,
+    /// Write a config template, with all options explained
+    GenerateConfig {
+        /// Where to write it, instead of stdout
+        output: Option<PathBuf>,
+    },
+    /// List the devices of the bot account, or delete some of them
+    Devices {
+        #[command(subcommand)]
+        action: Option<DevicesAction>,
+    },
+    /// Leave every room and clear the watch list. Asks first, unless --yes is given.
+    LeaveAll {
+        /// Don't ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// List the rooms of the bot account, or join or leave one
+    Rooms {
+        #[command(subcommand)]
+        action: Option<RoomsAction>,
+    },
+    /// Send one message, formatted as Markdown, with the stored session of the first bot
+    /// account in the room
+    Send {
+        /// Room ID or alias
+        #[arg(long)]
+        room: String,
+        /// Read from stdin, if not given
+        message: Option<String>,
+    },
+    /// Move the stored session to another storage and switch the config to it, keeping the
+    /// device (and with it the keys of encrypted rooms)
+    MigrateSession {
+        /// plain, secret_service or keyring
+        to: SessionBackend,
+    },
+    /// Export or import the persisted state (watched rooms, subscriptions, alerts, ...)
+    State {
+        #[command(subcommand)]
+        action: StateAction,
+    },
+    /// List the backups of the state DB, or put one back. Stop the bot first.
+    RestoreBackup {
+        /// File name of the backup, as listed without it
+        backup: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::Subcommand)]
+pub enum StateAction {
+    /// Write the state of all accounts of the config to a JSON file
+    Export { output: PathBuf },
+    /// Replace the state with the one of an exported file. Stop the bot first.
+    Import { input: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::Subcommand)]
+pub enum RoomsAction {
+    /// Joined and invited rooms, with their IDs, names and whether they are watched, as of
+    /// the last sync of the bot
+    List,
+    Join {
+        /// Room ID or alias
+        room: String,
+        /// Also add the room to the watch list
+        #[arg(long)]
+        watch: bool,
+    },
+    Leave {
+        /// Room ID or alias
+        room: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::Subcommand)]
+pub enum DevicesAction {
+    List,
+    Delete {
+        #[arg(required = true)]
+        device_ids: Vec<String>,
+    },
+    /// The devices except the current one that weren't used for a while
+    DeleteStale {
+        /// Unused for at least this many days
+        #[arg(long, default_value_t = DEFAULT_STALE_DAYS)]
+        days: u32,
+    },
+}
+
+/// The `generate-config` subcommand
+pub fn generate_config(output: Option<&Path>, force: bool) -> anyhow::Result<()> {
+    let Some(output) = output else {
+        print!("{CONFIG_TEMPLATE}");
+        return Ok(());
+    };
+    if output.exists() && !force {
+        anyhow::bail!(
+            "{} already exists, pass --force to replace it",
+            output.display()
+        );
+    }
+    std::fs::write(output, CONFIG_TEMPLATE)?;
+    println!("Wrote the config template to {}", output.display());
+    Ok(())
+}
@@ -0,0 +1,1942 @@
+//! Chat commands. Every command is declared once in the registry below, including its
+//! arguments and who may use it, so parsing, permission checks and usage strings are
+//! handled in one place.
+use super::{
+    alerts, aliases, devices, empty_rooms,
+    formatting::{self, escape, Message},
+    i18n::{self, tr, Language},
+    ignore_list, knocking, personal,
+    resources::CommandAllowance,
+    retention,
+    room_settings::{self, Acknowledgement},
+    scheduler::{parse_duration, Schedule},
+    send_queue, send_to_room_with_fields, subscriptions, verification, watch_list, PausedUntil,
+    RuntimeSubscription, SharedState,
+};
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        events::{
+            reaction::ReactionEventContent,
+            relation::{Annotation, Thread},
+            room::message::{FormattedBody, Relation, RoomMessageEventContent},
+            Mentions,
+        },
+        OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomOrAliasId, UserId,
+    },
+    Client,
+};
+use std::{future::Future, sync::OnceLock};
+use tokio::time::{sleep, Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Anyone,
+    /// Users in accept_commands_from
+    Trusted,
+    /// Trusted users, and anyone in a DM with the bot if personal subscriptions are enabled
+    TrustedOrDm,
+    /// Users in config.admins, in the admin room or in a DM with the bot. Not listed in !help for anyone else.
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Arg {
+    Required(&'static str),
+    Optional(&'static str),
+    /// Takes all remaining words, possibly none
+    Rest(&'static str),
+}
+
+/// Everything a command handler gets to work with
+pub struct Invocation {
+    pub room: Room,
+    pub client: Client,
+    pub ctx: SharedState,
+    pub sender: OwnedUserId,
+    /// The message the command came in
+    pub event_id: OwnedEventId,
+    /// Root of the thread the command was sent in, replies go there as well
+    pub thread: Option<OwnedEventId>,
+    pub args: Vec<String>,
+    /// Everything after the command name as it was sent, with its line breaks
+    pub raw_args: String,
+    /// Sent in a DM with personal subscriptions enabled
+    pub in_dm: bool,
+    /// The sender may use trusted commands in this room
+    pub trusted: bool,
+    /// The sender may use admin commands in this room
+    pub admin: bool,
+    /// Language of the room, replies should use it
+    pub lang: Language,
+}
+
+impl Invocation {
+    pub async fn reply(&self, plain: impl Into<String>) -> anyhow::Result<()> {
+        let plain = plain.into();
+        if plain.len() * 2 <= formatting::MAX_BODY_LEN {
+            let content = RoomMessageEventContent::text_plain(plain);
+            let content = as_reply(content, self.thread.as_ref(), &self.event_id);
+            send_queue::send(&self.room, content).await?;
+            return Ok(());
+        }
+        self.send_split(&plain, &formatting::escape(&plain).replace('\n', "<br>"))
+            .await
+    }
+
+    pub async fn reply_html(
+        &self,
+        plain: impl Into<String>,
+        html: impl Into<String>,
+    ) -> anyhow::Result<()> {
+        self.send_split(&plain.into(), &html.into()).await
+    }
+
+    /// Sends replies too long for one event as several messages
+    async fn send_split(&self, plain: &str, html: &str) -> anyhow::Result<()> {
+        for (plain, html) in formatting::split(plain, html, 0) {
+            let content = RoomMessageEventContent::text_html(plain, html);
+            let content = as_reply(content, self.thread.as_ref(), &self.event_id);
+            send_queue::send(&self.room, content).await?;
+        }
+        Ok(())
+    }
+
+    /// Confirms a command that changes something, either with the message or, if the
+    /// room prefers that, with a ✅ or ❌ reaction on the command. Errors still get their
+    /// message as a reply, as the reaction alone doesn't tell what went wrong.
+    pub async fn acknowledge(&self, result: Result<String, String>) -> anyhow::Result<()> {
+        let settings = room_settings::get(&self.client, self.room.room_id()).await;
+        if settings.acknowledge == Acknowledgement::Message {
+            return match result {
+                Ok(text) | Err(text) => self.reply(text).await,
+            };
+        }
+        let key = if result.is_ok() { "✅" } else { "❌" };
+        let content =
+            ReactionEventContent::new(Annotation::new(self.event_id.clone(), key.to_string()));
+        send_queue::send(&self.room, content).await?;
+        if let Err(text) = result {
+            self.reply(text).await?;
+        }
+        Ok(())
+    }
+
+    /// The optional argument at `index`
+    pub fn arg(&self, index: usize) -> Option<&str> {
+        self.args.get(index).map(String::as_str)
+    }
+
+    /// The text following the first `skip` arguments, as it was sent
+    pub fn raw_args_from(&self, skip: usize) -> &str {
+        let mut text = self.raw_args.trim_start();
+        for _ in 0..skip {
+            let end = text.find(char::is_whitespace).unwrap_or(text.len());
+            text = text[end..].trim_start();
+        }
+        text.trim_end()
+    }
+}
+
+/// Entries per reply of the list command
+const LIST_PAGE_SIZE: usize = 50;
+/// Matches per reply of the search command
+const MAX_SEARCH_RESULTS: usize = 20;
+/// Typing notices expire after a few seconds unless renewed
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+
+/// Puts replies in the thread of the command, if it came in one. Replies mention nobody,
+/// unless they say otherwise.
+fn as_reply(
+    mut content: RoomMessageEventContent,
+    thread: Option<&OwnedEventId>,
+    event_id: &OwnedEventId,
+) -> RoomMessageEventContent {
+    content.mentions.get_or_insert_with(Mentions::new);
+    if let Some(root) = thread {
+        content.relates_to = Some(Relation::Thread(Thread::plain(
+            root.clone(),
+            event_id.clone(),
+        )));
+    }
+    content
+}
+
+/// Shows the bot as typing in `room` until `work` is done, for commands that take a while
+async fn while_typing<T>(room: Room, work: impl Future<Output = T>) -> T {
+    tokio::pin!(work);
+    let mut typing = true;
+    loop {
+        if typing {
+            if let Err(e) = room.typing_notice(true).await {
+                eprintln!(
+                    "Failed to send a typing notice to {}: {e:?}",
+                    room.room_id()
+                );
+                typing = false;
+            }
+        }
+        tokio::select! {
+            result = &mut work => {
+                if typing {
+                    let _ = room.typing_notice(false).await;
+                }
+                return result;
+            }
+            _ = sleep(TYPING_REFRESH) => {}
+        }
+    }
+}
+
+type Handler = fn(Invocation) -> BoxFuture<'static, anyhow::Result<()>>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub args: &'static [Arg],
+    pub permission: Permission,
+    pub description: &'static str,
+    handler: Handler,
+}
+
+impl Command {
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{prefix}{}", self.name);
+        for arg in self.args {
+            match arg {
+                Arg::Required(name) => usage += &format!(" <{name}>"),
+                Arg::Optional(name) => usage += &format!(" [{name}]"),
+                Arg::Rest(name) => usage += &format!(" [{name}...]"),
+            }
+        }
+        usage
+    }
+
+    pub fn allowed_for(&self, trusted: bool, in_dm: bool, admin: bool) -> bool {
+        match self.permission {
+            Permission::Anyone => true,
+            Permission::Trusted => trusted,
+            Permission::TrustedOrDm => in_dm || trusted,
+            Permission::Admin => admin,
+        }
+    }
+
+    fn accepts_arg_count(&self, count: usize) -> bool {
+        let required = self
+            .args
+            .iter()
+            .filter(|x| matches!(x, Arg::Required(_)))
+            .count();
+        let unlimited = self.args.iter().any(|x| matches!(x, Arg::Rest(_)));
+        count >= required && (unlimited || count <= self.args.len())
+    }
+}
+
+pub struct CommandRegistry {
+    commands: Vec<Command>,
+}
+
+impl CommandRegistry {
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|x| x.name == name)
+    }
+
+    /// Splits a message into command and the text of its arguments, if it starts with our
+    /// prefix
+    pub fn parse<'a>(&self, prefix: &str, body: &'a str) -> Option<(&Command, &'a str)> {
+        self.parse_words(body.strip_prefix(prefix)?)
+    }
+
+    fn parse_words<'a>(&self, text: &'a str) -> Option<(&Command, &'a str)> {
+        let text = text.trim_start();
+        let end = text.find(char::is_whitespace).unwrap_or(text.len());
+        let command = self.find(&text[..end])?;
+        Some((command, &text[end..]))
+    }
+
+    /// Runs the command in `body`, if there is one and the sender may use it
+    #[allow(clippy::too_many_arguments)]
+    pub async fn dispatch(
+        &self,
+        body: &str,
+        room: Room,
+        client: Client,
+        ctx: SharedState,
+        sender: OwnedUserId,
+        event_id: OwnedEventId,
+        thread: Option<OwnedEventId>,
+    ) -> anyhow::Result<()> {
+        let prefix = ctx.cfg.command_prefix.clone();
+        let mut names = Vec::new();
+        if let Some(own_id) = client.user_id() {
+            names.push(own_id.to_string());
+            names.push(own_id.localpart().to_string());
+            if let Ok(Some(member)) = room.get_member_no_sync(own_id).await {
+                names.extend(member.display_name().map(String::from));
+            }
+        }
+        // "@mozbot: status" works as well as "!status", with or without the prefix
+        let parsed = match strip_mention(body, &names) {
+            Some(rest) => self.parse_words(rest.strip_prefix(prefix.as_str()).unwrap_or(rest)),
+            None => self.parse(&prefix, body),
+        };
+        let Some((command, raw_args)) = parsed else {
+            return Ok(());
+        };
+        let is_dm = room.is_direct().await.unwrap_or(false);
+        let in_dm = ctx.cfg.personal_subscriptions && is_dm;
+        let settings = room_settings::get(&client, room.room_id()).await;
+        let lang = settings.language.unwrap_or(ctx.cfg.language);
+        let trusted = ctx.accepts_commands_in(&room, &sender).await;
+        let admin = ctx.accepts_admin_commands_in(room.room_id(), &sender, is_dm);
+        // Admins get everything in their console DM, whoever else is trusted
+        let trusted = trusted || admin;
+        if !command.allowed_for(trusted, in_dm, admin) {
+            println!(
+                "Ignoring {}{} from untrusted user {sender}",
+                prefix, command.name
+            );
+            return Ok(());
+        }
+        match ctx.resources.check_command(&sender, room.room_id()) {
+            CommandAllowance::Allowed => {}
+            CommandAllowance::SlowDown => {
+                let message = Message::fill(
+                    i18n::translate(
+                        lang,
+                        "{sender}, slow down please. Ignoring your commands for a bit.",
+                    ),
+                    &[("sender", Message::new().mention(&sender))],
+                );
+                let mut content =
+                    RoomMessageEventContent::text_html(message.plain(), message.html());
+                content.mentions = Some(message.mentions());
+                send_queue::send(&room, as_reply(content, thread.as_ref(), &event_id)).await?;
+                return Ok(());
+            }
+            CommandAllowance::Dropped => return Ok(()),
+        }
+        let invocation = Invocation {
+            room,
+            client,
+            ctx,
+            sender,
+            event_id,
+            thread,
+            args: raw_args.split_whitespace().map(String::from).collect(),
+            raw_args: raw_args.to_string(),
+            in_dm,
+            trusted,
+            admin,
+            lang,
+        };
+        if !command.accepts_arg_count(invocation.args.len()) {
+            return invocation
+                .reply(tr!(lang, "Usage: {usage}", usage = command.usage(&prefix)))
+                .await;
+        }
+        (command.handler)(invocation).await
+    }
+}
+
+/// The rest of `body`, if it starts by addressing one of `names`, like "@mozbot: status".
+/// The plain body of mention pills is the display name, sometimes with a leading @.
+fn strip_mention<'a>(body: &'a str, names: &[String]) -> Option<&'a str> {
+    let text = body.trim_start().trim_start_matches('@');
+    names
+        .iter()
+        .map(|x| x.trim_start_matches('@'))
+        .filter(|x| !x.is_empty())
+        .find_map(|name| {
+            let rest = text
+                .get(..name.len())
+                .filter(|x| x.eq_ignore_ascii_case(name))
+                .map(|_| &text[name.len()..])?;
+            if !rest.starts_with([':', ',']) && !rest.starts_with(char::is_whitespace) {
+                return None;
+            }
+            Some(rest.trim_start_matches([':', ',']).trim_start())
+        })
+}
+
+/// All commands the bot understands
+pub fn registry() -> &'static CommandRegistry {
+    static REGISTRY: OnceLock<CommandRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| CommandRegistry {
+        commands: vec![
+            Command {
+                name: "help",
+                args: &[Arg::Optional("command")],
+                permission: Permission::Anyone,
+                description: "List the available commands, or show the usage of one",
+                handler: |i| Box::pin(help(i)),
+            },
+            Command {
+                name: "ping",
+                args: &[],
+                permission: Permission::Trusted,
+                description: "Check whether the bot is alive",
+                handler: |i| Box::pin(ping(i)),
+            },
+            Command {
+                name: "watch",
+                args: &[],
+                permission: Permission::Trusted,
+                description: "Post notifications to this room",
+                handler: |i| Box::pin(watch(i)),
+            },
+            Command {
+                name: "leave",
+                args: &[],
+                permission: Permission::Trusted,
+                description: "Stop notifications and leave this room",
+                handler: |i| Box::pin(leave(i)),
+            },
+            Command {
+                name: "knock",
+                args: &[Arg::Required("room")],
+                permission: Permission::Trusted,
+                description: "Knock on an invite-only room (ID or alias), and join and watch it once let in",
+                handler: |i| Box::pin(knock(i)),
+            },
+            Command {
+                name: "devices",
+                args: &[Arg::Optional("action"), Arg::Rest("device_ids")],
+                permission: Permission::Admin,
+                description: "List the devices of the bot account, or delete old ones (delete <device_id>... or delete-stale [days])",
+                handler: |i| Box::pin(devices(i)),
+            },
+            Command {
+                name: "leave-all",
+                args: &[Arg::Optional("confirm")],
+                permission: Permission::Admin,
+                description: "Leave every room and clear the watch list, e.g. before decommissioning the bot (pass \"confirm\")",
+                handler: |i| Box::pin(leave_all(i)),
+            },
+            Command {
+                name: "shutdown",
+                args: &[Arg::Optional("confirm")],
+                permission: Permission::Admin,
+                description: "Shut the bot down (pass \"confirm\")",
+                handler: |i| Box::pin(shutdown(i)),
+            },
+            Command {
+                name: "restart",
+                args: &[Arg::Optional("confirm")],
+                permission: Permission::Admin,
+                description: "Restart the bot, e.g. to apply a changed config (pass \"confirm\")",
+                handler: |i| Box::pin(restart(i)),
+            },
+            Command {
+                name: "announce",
+                args: &[Arg::Optional("rooms"), Arg::Rest("text")],
+                permission: Permission::Admin,
+                description: "Post a message (Markdown) to all watched rooms, or only to the given comma-separated rooms",
+                handler: |i| Box::pin(announce(i)),
+            },
+            Command {
+                name: "settings",
+                args: &[Arg::Optional("key"), Arg::Rest("value")],
+                permission: Permission::Trusted,
+                description: "Show or change the settings of this room (sources, format, mute, trusted, ack, threads, msgtype, language, retention, digest)",
+                handler: |i| Box::pin(settings(i)),
+            },
+            Command {
+                name: "config",
+                args: &[Arg::Optional("key"), Arg::Rest("value")],
+                permission: Permission::Trusted,
+                description: "Same as !settings, e.g. !config set digest=6h",
+                handler: |i| Box::pin(settings(i)),
+            },
+            Command {
+                name: "status",
+                args: &[],
+                permission: Permission::Trusted,
+                description: "Show uptime and the health of all subscriptions",
+                handler: |i| Box::pin(status(i)),
+            },
+            Command {
+                name: "rooms",
+                args: &[],
+                permission: Permission::Admin,
+                description: "List the rooms the bot announces to, with their member counts and subscriptions",
+                handler: |i| Box::pin(rooms(i)),
+            },
+            Command {
+                name: "verify",
+                args: &[Arg::Required("device_id"), Arg::Optional("cancel")],
+                permission: Permission::Admin,
+                description: "Confirm (or cancel) a verification of the bot, after comparing the emoji reported here",
+                handler: |i| Box::pin(verify(i)),
+            },
+            Command {
+                name: "errors",
+                args: &[Arg::Optional("subscription")],
+                permission: Permission::Admin,
+                description: "Show the recent errors of a subscription (or all of them)",
+                handler: |i| Box::pin(errors(i)),
+            },
+            Command {
+                name: "check",
+                args: &[Arg::Optional("subscription")],
+                permission: Permission::Trusted,
+                description: "Poll a subscription (or all of them) right now",
+                handler: |i| Box::pin(while_typing(i.room.clone(), check(i))),
+            },
+            Command {
+                name: "sources",
+                args: &[],
+                permission: Permission::Anyone,
+                description: "List the subscriptions announced in this room",
+                handler: |i| Box::pin(sources(i)),
+            },
+            Command {
+                name: "latest",
+                args: &[Arg::Optional("subscription")],
+                permission: Permission::Anyone,
+                description: "Show the newest known entry of each subscription",
+                handler: |i| Box::pin(latest(i)),
+            },
+            Command {
+                name: "list",
+                args: &[
+                    Arg::Required("subscription"),
+                    Arg::Optional("pattern"),
+                    Arg::Optional("page=<n>"),
+                ],
+                permission: Permission::Anyone,
+                description: "List the currently known entries of a subscription",
+                handler: |i| Box::pin(while_typing(i.room.clone(), list(i))),
+            },
+            Command {
+                name: "search",
+                args: &[Arg::Rest("term")],
+                permission: Permission::Anyone,
+                description: "Search the known entries of all subscriptions",
+                handler: |i| Box::pin(while_typing(i.room.clone(), search(i))),
+            },
+            Command {
+                name: "alertme",
+                args: &[Arg::Rest("regex|--clear")],
+                permission: Permission::Anyone,
+                description: "Get mentioned when an announcement in this room has an entry matching regex. Without arguments, lists your patterns",
+                handler: |i| Box::pin(alertme(i)),
+            },
+            Command {
+                name: "subscribe",
+                args: &[Arg::Required("url_part"), Arg::Rest("option")],
+                permission: Permission::TrustedOrDm,
+                description: "Announce new uploads below url_part in this room. Options are name=<name>, filter=<regex> and subdirs=true. In a DM, follow an existing subscription personally instead, optionally with filter=<regex>",
+                handler: |i| Box::pin(subscribe(i)),
+            },
+            Command {
+                name: "unsubscribe",
+                args: &[Arg::Required("subscription")],
+                permission: Permission::TrustedOrDm,
+                description: "Remove a subscription added with subscribe, or stop following it in a DM",
+                handler: |i| Box::pin(unsubscribe(i)),
+            },
+            Command {
+                name: "filter",
+                args: &[Arg::Required("subscription"), Arg::Required("regex|--clear")],
+                permission: Permission::Trusted,
+                description: "Change or remove the filter of a subscription",
+                handler: |i| Box::pin(filter(i)),
+            },
+            Command {
+                name: "interval",
+                args: &[Arg::Optional("subscription"), Arg::Required("interval")],
+                permission: Permission::Trusted,
+                description: "Change how often a subscription (or every one without its own schedule) is polled, e.g. 15m",
+                handler: |i| Box::pin(interval(i)),
+            },
+            Command {
+                name: "mute",
+                args: &[Arg::Required("subscription")],
+                permission: Permission::Trusted,
+                description: "Stop announcing a subscription in this room",
+                handler: |i| Box::pin(mute(i)),
+            },
+            Command {
+                name: "unmute",
+                args: &[Arg::Required("subscription")],
+                permission: Permission::Trusted,
+                description: "Announce a muted subscription in this room again",
+                handler: |i| Box::pin(unmute(i)),
+            },
+            Command {
+                name: "pause",
+                args: &[Arg::Optional("subscription"), Arg::Optional("duration")],
+                permission: Permission::Trusted,
+                description: "Hold back announcements (of one subscription) until resumed or for a duration like 4h",
+                handler: |i| Box::pin(pause(i)),
+            },
+            Command {
+                name: "resume",
+                args: &[Arg::Optional("subscription")],
+                permission: Permission::Trusted,
+                description: "Resume announcements paused with pause",
+                handler: |i| Box::pin(resume(i)),
+            },
+            Command {
+                name: "enable",
+                args: &[Arg::Required("subscription")],
+                permission: Permission::Trusted,
+                description: "Re-enable a subscription that was disabled after failing",
+                handler: |i| Box::pin(enable(i)),
+            },
+            Command {
+                name: "ignore",
+                args: &[Arg::Optional("user")],
+                permission: Permission::Trusted,
+                description: "Ignore all messages and invites of a user, or list the ignored users",
+                handler: |i| Box::pin(ignore(i)),
+            },
+            Command {
+                name: "unignore",
+                args: &[Arg::Required("user")],
+                permission: Permission::Trusted,
+                description: "Stop ignoring a user ignored with ignore",
+                handler: |i| Box::pin(unignore(i)),
+            },
+            Command {
+                name: "pubkey",
+                args: &[],
+                permission: Permission::Trusted,
+                description: "Show the key announcements are signed with",
+                handler: |i| Box::pin(pubkey(i)),
+            },
+            Command {
+                name: "resources",
+                args: &[],
+                permission: Permission::Admin,
+                description: "Show the resource usage of the bot",
+                handler: |i| Box::pin(resources(i)),
+            },
+        ],
+    })
+}
+
+async fn help(i: Invocation) -> anyhow::Result<()> {
+    let prefix = &i.ctx.cfg.command_prefix;
+    let commands: Vec<&Command> = match i.arg(0) {
+        Some(name) => match registry().find(name.trim_start_matches(prefix.as_str())) {
+            Some(command) => vec![command],
+            None => return i.reply(tr!(i.lang, "Unknown command {name}", name)).await,
+        },
+        None => registry().commands().iter().collect(),
+    };
+    // Not Markdown, the usages and descriptions are full of <placeholders> and [options]
+    let mut plain = Vec::new();
+    let mut html = Vec::new();
+    for command in commands {
+        if command.permission == Permission::Admin && !i.admin {
+            continue;
+        }
+        let usage = command.usage(prefix);
+        let description = i18n::translate(i.lang, command.description);
+        let (restricted, restricted_html) = if command.allowed_for(i.trusted, i.in_dm, i.admin) {
+            (String::new(), String::new())
+        } else {
+            let restricted = i18n::translate(i.lang, "(not available to you)");
+            (
+                format!(" {restricted}"),
+                format!(" <em>{}</em>", escape(restricted)),
+            )
+        };
+        plain.push(format!("{usage}: {description}{restricted}"));
+        html.push(format!(
+            "<li><code>{}</code>: {}{restricted_html}</li>",
+            escape(&usage),
+            escape(description)
+        ));
+    }
+    i.reply_html(plain.join("\n"), format!("<ul>{}</ul>", html.concat()))
+        .await
+}
+
+async fn ping(i: Invocation) -> anyhow::Result<()> {
+    i.reply("pong").await
+}
+
+async fn watch(i: Invocation) -> anyhow::Result<()> {
+    i.acknowledge(Ok(tr!(i.lang, "Watching..."))).await?;
+    i.ctx
+        .rooms
+        .lock()
+        .unwrap()
+        .entry(i.room.room_id().to_owned())
+        .or_default();
+    watch_list::store(&i.client, &i.ctx).await
+}
+
+async fn leave(i: Invocation) -> anyhow::Result<()> {
+    i.reply(tr!(i.lang, "Bye")).await?;
+    i.room.leave().await?;
+    i.ctx.rooms.lock().unwrap().remove(i.room.room_id());
+    watch_list::store(&i.client, &i.ctx).await
+}
+
+async fn knock(i: Invocation) -> anyhow::Result<()> {
+    let room = i.arg(0).unwrap_or_default();
+    let result = match RoomOrAliasId::parse(room) {
+        Ok(room) => knocking::knock(&i.client, &i.ctx, &room)
+            .await
+            .map(|room_id| tr!(i.lang, "Knocked on {room_id}", room_id))
+            .map_err(|e| tr!(i.lang, "Knocking failed: {e}", e)),
+        Err(e) => Err(tr!(i.lang, "Invalid room {room}: {e}", room, e)),
+    };
+    i.acknowledge(result).await
+}
+
+async fn devices(i: Invocation) -> anyhow::Result<()> {
+    let to_delete = match i.arg(0) {
+        None => {
+            let reply = match devices::list(&i.client).await {
+                Ok(lines) => lines.join("\n"),
+                Err(e) => tr!(i.lang, "Failed to list the devices: {e}", e),
+            };
+            return i.reply(reply).await;
+        }
+        Some("delete") if i.args.len() > 1 => Ok(i.args[1..]
+            .iter()
+            .map(|x| OwnedDeviceId::from(x.as_str()))
+            .collect::<Vec<_>>()),
+        Some("delete-stale") if i.args.len() <= 2 => match i.arg(1).map(str::parse).transpose() {
+            Ok(days) => {
+                devices::stale(&i.client, days.unwrap_or(devices::DEFAULT_STALE_DAYS)).await
+            }
+            Err(_) => {
+                return i
+                    .acknowledge(Err(tr!(
+                        i.lang,
+                        "Invalid number of days {days}",
+                        days = i.args[1]
+                    )))
+                    .await
+            }
+        },
+        Some(action) => {
+            return i
+                .acknowledge(Err(tr!(
+                i.lang,
+                "Unknown action {action}, expected delete <device_id>... or delete-stale [days]",
+                action
+            )))
+                .await
+        }
+    };
+    let result = match to_delete {
+        Ok(to_delete) => devices::delete(&i.client, &i.ctx, &to_delete)
+            .await
+            .map(|()| tr!(i.lang, "Deleted {count} devices", count = to_delete.len())),
+        Err(e) => Err(e),
+    };
+    i.acknowledge(result.map_err(|e| tr!(i.lang, "Failed to delete the devices: {e}", e)))
+        .await
+}
+
+async fn leave_all(i: Invocation) -> anyhow::Result<()> {
+    if i.arg(0) != Some("confirm") {
+        return i
+            .acknowledge(Err(tr!(
+                i.lang,
+                "This leaves every room, including this one. Send {command} to go ahead.",
+                command = format!("{}leave-all confirm", i.ctx.cfg.command_prefix)
+            )))
+            .await;
+    }
+    // Say goodbye while we are still in here
+    i.acknowledge(Ok(tr!(i.lang, "Leaving all rooms...")))
+        .await?;
+    let count = empty_rooms::leave_all(&i.client, &i.ctx).await?;
+    println!("Left {count} rooms on request of {}", i.sender);
+    Ok(())
+}
+
+async fn shutdown(i: Invocation) -> anyhow::Result<()> {
+    if i.arg(0) != Some("confirm") {
+        return i
+            .acknowledge(Err(tr!(
+                i.lang,
+                "This stops the bot, it can only be started again on the host. Send {command} to go ahead.",
+                command = format!("{}shutdown confirm", i.ctx.cfg.command_prefix)
+            )))
+            .await;
+    }
+    println!("Shutting down on request of {}", i.sender);
+    i.acknowledge(Ok(tr!(i.lang, "Shutting down..."))).await?;
+    i.ctx.shutdown(false)
+}
+
+async fn restart(i: Invocation) -> anyhow::Result<()> {
+    if i.arg(0) != Some("confirm") {
+        return i
+            .acknowledge(Err(tr!(
+                i.lang,
+                "This restarts the bot, commands are ignored until it is back. Send {command} to go ahead.",
+                command = format!("{}restart confirm", i.ctx.cfg.command_prefix)
+            )))
+            .await;
+    }
+    println!("Restarting on request of {}", i.sender);
+    i.acknowledge(Ok(tr!(i.lang, "Restarting..."))).await?;
+    i.ctx.shutdown(true)
+}
+
+async fn announce(i: Invocation) -> anyhow::Result<()> {
+    // The first word names the rooms, if it looks like room IDs or aliases. The text keeps
+    // its line breaks, for Markdown lists and paragraphs.
+    let (rooms, text) = match i.arg(0) {
+        Some(rooms) if rooms.starts_with(['#', '!']) && rooms.contains(':') => {
+            (Some(rooms), i.raw_args_from(1))
+        }
+        _ => (None, i.raw_args_from(0)),
+    };
+    if text.is_empty() {
+        return i.acknowledge(Err(tr!(i.lang, "Nothing to announce"))).await;
+    }
+    let watched: Vec<_> = i.ctx.rooms.lock().unwrap().keys().cloned().collect();
+    let targets = match rooms {
+        None => watched,
+        Some(rooms) => {
+            let mut targets = Vec::new();
+            for room in rooms.split(',').filter(|x| !x.is_empty()) {
+                let resolved =
+                    aliases::resolve(&i.ctx.cfg.homeserver_url, &i.ctx.cfg.session_storage, room)
+                        .await;
+                match resolved {
+                    Ok(room_id) if watched.contains(&room_id) => targets.push(room_id),
+                    Ok(_) => {
+                        return i
+                            .acknowledge(Err(tr!(i.lang, "{room} is not watched", room)))
+                            .await
+                    }
+                    Err(e) => {
+                        return i
+                            .acknowledge(Err(tr!(i.lang, "Invalid room {room}: {e}", room, e)))
+                            .await
+                    }
+                }
+            }
+            targets
+        }
+    };
+    let plain = text.to_string();
+    let html = FormattedBody::markdown(&plain)
+        .map(|x| x.body)
+        .unwrap_or_else(|| escape(&plain));
+    let mut failed = Vec::new();
+    for room_id in &targets {
+        let msgtype = room_settings::get(&i.client, room_id)
+            .await
+            .msgtype
+            .unwrap_or_default();
+        let sent = send_to_room_with_fields(
+            &i.client,
+            room_id,
+            msgtype,
+            &plain,
+            &html,
+            &serde_json::Map::new(),
+        )
+        .await;
+        match sent {
+            Ok(event_ids) => retention::track(&i.ctx, room_id, &event_ids).await,
+            Err(e) => {
+                eprintln!("Failed to announce to {room_id}: {e:?}");
+                failed.push(room_id.to_string());
+            }
+        }
+    }
+    let result = if failed.is_empty() {
+        Ok(tr!(
+            i.lang,
+            "Announced in {count} rooms",
+            count = targets.len()
+        ))
+    } else {
+        Err(tr!(
+            i.lang,
+            "Failed to announce in {rooms}",
+            rooms = failed.join(", ")
+        ))
+    };
+    i.acknowledge(result).await
+}
+
+async fn settings(i: Invocation) -> anyhow::Result<()> {
+    let may_set = |user: &UserId| i.ctx.trusted_by_config(i.room.room_id(), user);
+    let reply = room_settings::settings_command(i.lang, &i.room, &i.args, &may_set)
+        .await
+        .unwrap_or_else(|e| tr!(i.lang, "Failed to update the settings: {e}", e));
+    i.reply(reply).await
+}
+
+/// Like "3d 4h 12m", leaving out leading zero units
+fn format_duration(duration: chrono::Duration) -> String {
+    let minutes = duration.num_minutes();
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+async fn status(i: Invocation) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let mut lines = vec![
+        tr!(
+            i.lang,
+            "Up since {time} ({duration})",
+            time = i.ctx.started.format("%Y-%m-%d %H:%M UTC"),
+            duration = format_duration(now - i.ctx.started)
+        ),
+        tr!(
+            i.lang,
+            "Watching {count} rooms",
+            count = i.ctx.rooms.lock().unwrap().len()
+        ),
+    ];
+    let (paused_all, paused_sources) = {
+        let mut paused = i.ctx.paused.lock().unwrap();
+        paused.drop_expired();
+        (paused.all, paused.sources.clone())
+    };
+    let describe_pause = |until: &PausedUntil| match until {
+        Some(until) => tr!(
+            i.lang,
+            "paused until {time}",
+            time = until.format("%Y-%m-%d %H:%M UTC")
+        ),
+        None => tr!(i.lang, "paused until resumed"),
+    };
+    if let Some(until) = &paused_all {
+        lines.push(tr!(
+            i.lang,
+            "All announcements {pause}",
+            pause = describe_pause(until)
+        ));
+    }
+    let mut sources: Vec<_> = i
+        .ctx
+        .sources
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, status)| (name.clone(), status.clone()))
+        .collect();
+    sources.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, status) in sources {
+        let last_poll = status
+            .last_success
+            .map(|x| {
+                tr!(
+                    i.lang,
+                    "last poll {duration} ago",
+                    duration = format_duration(now - x)
+                )
+            })
+            .unwrap_or_else(|| tr!(i.lang, "not polled yet"));
+        let mut line = tr!(
+            i.lang,
+            "{name} ({url_part}): {count} entries, {last_poll}",
+            name,
+            url_part = status.url_part,
+            count = status.entries,
+            last_poll
+        );
+        if let Some((time, error)) = &status.last_error {
+            line += &tr!(
+                i.lang,
+                ", last error {duration} ago: {error}",
+                duration = format_duration(now - *time),
+                error
+            );
+        }
+        if let Some(until) = paused_sources.get(&name) {
+            line += &format!(" [{}]", describe_pause(until));
+        }
+        if status.disabled {
+            line += i18n::translate(i.lang, " [disabled]");
+        }
+        lines.push(line);
+    }
+    i.reply(lines.join("\n")).await
+}
+
+async fn rooms(i: Invocation) -> anyhow::Result<()> {
+    let mut room_ids: Vec<_> = i.ctx.rooms.lock().unwrap().keys().cloned().collect();
+    room_ids.extend(i.ctx.cfg.room_configs.keys().cloned());
+    room_ids.sort();
+    room_ids.dedup();
+    if room_ids.is_empty() {
+        return i.reply(tr!(i.lang, "Not watching any rooms")).await;
+    }
+    let sources: Vec<_> = i
+        .ctx
+        .sources
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, status)| (name.clone(), status.rooms.clone()))
+        .collect();
+    let mut lines = Vec::new();
+    for room_id in room_ids {
+        let settings = room_settings::get(&i.client, &room_id).await;
+        let mut routed: Vec<_> = sources
+            .iter()
+            .filter(|(name, rooms)| i.ctx.announces_to(name, rooms.as_deref(), &room_id))
+            .filter(|(name, _)| settings.wants(name) && !i.ctx.is_muted(&room_id, name))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        routed.sort();
+        let routed = if routed.is_empty() {
+            tr!(i.lang, "no subscriptions")
+        } else {
+            routed.join(", ")
+        };
+        let line = match i.client.get_room(&room_id) {
+            Some(room) => {
+                let name = room
+                    .display_name()
+                    .await
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|_| room_id.to_string());
+                tr!(
+                    i.lang,
+                    "{name} ({room_id}), {count} members: {routed}",
+                    name,
+                    room_id,
+                    count = room.joined_members_count(),
+                    routed
+                )
+            }
+            None => tr!(i.lang, "{room_id} (not joined): {routed}", room_id, routed),
+        };
+        lines.push(line);
+    }
+    i.reply(lines.join("\n")).await
+}
+
+async fn verify(i: Invocation) -> anyhow::Result<()> {
+    let device_id = OwnedDeviceId::from(i.args[0].as_str());
+    let cancel = match i.arg(1) {
+        None => false,
+        Some("cancel") => true,
+        Some(_) => {
+            let usage = registry()
+                .find("verify")
+                .map(|x| x.usage(&i.ctx.cfg.command_prefix))
+                .unwrap_or_default();
+            return i.reply(tr!(i.lang, "Usage: {usage}", usage)).await;
+        }
+    };
+    let Some(sas) = verification::take_unconfirmed(&i.ctx, &device_id) else {
+        return i
+            .reply(tr!(
+                i.lang,
+                "No verification with {device} waits for a confirmation",
+                device
+            ))
+            .await;
+    };
+    let result = if cancel {
+        sas.mismatch().await
+    } else {
+        sas.confirm().await
+    };
+    match result {
+        Ok(()) if cancel => i.reply(tr!(i.lang, "Cancelled the verification")).await,
+        Ok(()) => i.reply(tr!(i.lang, "Confirmed the verification")).await,
+        Err(e) => {
+            i.reply(tr!(i.lang, "Failed to answer the verification: {e}", e))
+                .await
+        }
+    }
+}
+
+async fn errors(i: Invocation) -> anyhow::Result<()> {
+    let name = match i.arg(0) {
+        Some(name) => match i.ctx.find_source_name(name) {
+            Some(name) => Some(name),
+            None => {
+                return i
+                    .reply(tr!(i.lang, "Unknown subscription {name}", name))
+                    .await
+            }
+        },
+        None => None,
+    };
+    let mut sources: Vec<_> = i
+        .ctx
+        .sources
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(x, _)| name.is_none() || name.as_ref() == Some(*x))
+        .map(|(name, status)| (name.clone(), status.clone()))
+        .collect();
+    sources.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut lines = Vec::new();
+    for (name, status) in sources {
+        // The recent errors are cleared by the next successful poll, the last one is kept
+        let errors = if status.recent_errors.is_empty() {
+            status.last_error.into_iter().collect()
+        } else {
+            status.recent_errors
+        };
+        if errors.is_empty() {
+            continue;
+        }
+        lines.push(tr!(
+            i.lang,
+            "{name} ({url_part}), {count} consecutive failures:",
+            name,
+            url_part = status.url_part,
+            count = status.consecutive_failures
+        ));
+        for (time, error) in errors {
+            lines.push(format!("  {}: {error}", time.format("%Y-%m-%d %H:%M UTC")));
+        }
+    }
+    if lines.is_empty() {
+        return i.reply(tr!(i.lang, "No errors")).await;
+    }
+    i.reply(lines.join("\n")).await
+}
+
+async fn check(i: Invocation) -> anyhow::Result<()> {
+    let source = match i.arg(0) {
+        Some(name) => match i.ctx.find_source_name(name) {
+            Some(name) => Some(name),
+            None => {
+                return i
+                    .reply(tr!(i.lang, "Unknown subscription {name}", name))
+                    .await
+            }
+        },
+        None => None,
+    };
+    let reply = match i.ctx.check_now(source).await {
+        Ok(results) if results.is_empty() => tr!(i.lang, "Nothing to check"),
+        Ok(results) => results
+            .into_iter()
+            .map(|(url_part, outcome)| format!("{url_part}: {outcome}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(e) => tr!(i.lang, "Check failed: {e}", e),
+    };
+    i.reply(reply).await
+}
+
+async fn sources(i: Invocation) -> anyhow::Result<()> {
+    if i.in_dm {
+        return personal_sources(i).await;
+    }
+    let settings = room_settings::get(&i.client, i.room.room_id()).await;
+    let mut sources: Vec<_> = i
+        .ctx
+        .sources
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, status)| {
+            i.ctx
+                .announces_to(name, status.rooms.as_deref(), i.room.room_id())
+                && !i.ctx.is_muted(i.room.room_id(), name)
+                && settings.wants(name)
+        })
+        .map(|(name, status)| (name.clone(), status.clone()))
+        .collect();
+    if sources.is_empty() {
+        return i
+            .reply(tr!(i.lang, "No subscriptions are announced in this room"))
+            .await;
+    }
+    sources.sort_by(|a, b| a.0.cmp(&b.0));
+    let now = Utc::now();
+    let lines: Vec<_> = sources
+        .into_iter()
+        .map(|(name, status)| {
+            let filter = status
+                .filter
+                .map(|x| tr!(i.lang, ", filter '{filter}'", filter = x))
+                .unwrap_or_default();
+            let last_change = status
+                .last_change
+                .map(|x| {
+                    tr!(
+                        i.lang,
+                        "last change {duration} ago",
+                        duration = format_duration(now - x)
+                    )
+                })
+                .unwrap_or_else(|| tr!(i.lang, "no changes seen yet"));
+            format!(
+                "{name} ({}): {}{filter}, {last_change}",
+                status.url_part, status.schedule
+            )
+        })
+        .collect();
+    i.reply(lines.join("\n")).await
+}
+
+async fn latest(i: Invocation) -> anyhow::Result<()> {
+    let filter = match i.arg(0) {
+        Some(name) => match i.ctx.find_source_name(name) {
+            Some(name) => Some(name),
+            None => {
+                return i
+                    .reply(tr!(i.lang, "Unknown subscription {name}", name))
+                    .await
+            }
+        },
+        None => None,
+    };
+    let mut lines: Vec<_> = i
+        .ctx
+        .sources
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| filter.is_none() || filter.as_ref() == Some(*name))
+        .map(|(_, status)| {
+            format!(
+                "{}: {}",
+                status.url_part,
+                status
+                    .latest
+                    .as_deref()
+                    .unwrap_or(i18n::translate(i.lang, "nothing known yet"))
+            )
+        })
+        .collect();
+    lines.sort();
+    i.reply(lines.join("\n")).await
+}
+
+async fn list(i: Invocation) -> anyhow::Result<()> {
+    let name = i.arg(0).unwrap_or_default();
+    let Some(name) = i.ctx.find_source_name(name) else {
+        return i
+            .reply(tr!(i.lang, "Unknown subscription {name}", name))
+            .await;
+    };
+    let mut pattern = None;
+    let mut page = 1;
+    for arg in &i.args[1..] {
+        match arg.strip_prefix("page=").map(str::parse::<usize>) {
+            Some(Ok(n)) if n > 0 => page = n,
+            Some(_) => return i.reply(tr!(i.lang, "Invalid page {arg}", arg)).await,
+            None => pattern = Some(arg.to_lowercase()),
+        }
+    }
+    let entries = match i.ctx.entries(Some(name.clone())).await {
+        Ok(mut results) if !results.is_empty() => results.remove(0).entries,
+        Ok(_) => Vec::new(),
+        Err(e) => return i.reply(tr!(i.lang, "Listing failed: {e}", e)).await,
+    };
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|x| {
+            pattern
+                .as_ref()
+                .map(|p| x.to_lowercase().contains(p))
+                .unwrap_or(true)
+        })
+        .collect();
+    if entries.is_empty() {
+        return i
+            .reply(tr!(i.lang, "No matching entries known for {name}", name))
+            .await;
+    }
+    let pages = entries.len().div_ceil(LIST_PAGE_SIZE);
+    let page = page.min(pages);
+    let shown = &entries[(page - 1) * LIST_PAGE_SIZE..(page * LIST_PAGE_SIZE).min(entries.len())];
+    let mut reply = tr!(
+        i.lang,
+        "{name}, page {page}/{pages} ({count} entries):",
+        name,
+        page,
+        pages,
+        count = entries.len()
+    );
+    reply += &format!("\n{}", shown.join("\n"));
+    if page < pages {
+        let prefix = &i.ctx.cfg.command_prefix;
+        let pattern = pattern.map(|x| format!(" {x}")).unwrap_or_default();
+        reply += &tr!(
+            i.lang,
+            "\nNext page: {command}",
+            command = format!("{prefix}list {name}{pattern} page={}", page + 1)
+        );
+    }
+    i.reply(reply).await
+}
+
+async fn search(i: Invocation) -> anyhow::Result<()> {
+    // Terms may contain spaces
+    let term = i.raw_args_from(0).to_lowercase();
+    if term.is_empty() {
+        let usage = registry()
+            .find("search")
+            .map(|x| x.usage(&i.ctx.cfg.command_prefix))
+            .unwrap_or_default();
+        return i.reply(tr!(i.lang, "Usage: {usage}", usage)).await;
+    }
+    let results = match i.ctx.entries(None).await {
+        Ok(results) => results,
+        Err(e) => return i.reply(tr!(i.lang, "Search failed: {e}", e)).await,
+    };
+    let matches: Vec<_> = results
+        .iter()
+        .flat_map(|source| {
+            source
+                .entries
+                .iter()
+                .filter(|x| x.to_lowercase().contains(&term))
+                .map(move |x| (source, x))
+        })
+        .collect();
+    if matches.is_empty() {
+        return i.reply(tr!(i.lang, "Nothing found for {term}", term)).await;
+    }
+    let items = matches
+        .iter()
+        .take(MAX_SEARCH_RESULTS)
+        .map(|(source, entry)| {
+            Message::new()
+                .text(&format!("{}: ", source.name))
+                .link(&format!("{}/{entry}", source.url), entry)
+        });
+    let mut message = Message::fill(
+        i18n::translate(i.lang, "Found {count} matches for {term}:"),
+        &[
+            ("count", Message::new().text(&matches.len().to_string())),
+            ("term", Message::new().code(&term)),
+        ],
+    )
+    .list(items);
+    if matches.len() > MAX_SEARCH_RESULTS {
+        let more = tr!(
+            i.lang,
+            "... and {count} more, please narrow down the search",
+            count = matches.len() - MAX_SEARCH_RESULTS
+        );
+        message = message.text(&format!("\n{more}"));
+    }
+    let (plain, html) = message.into_parts();
+    i.reply_html(plain, html).await
+}
+
+async fn subscribe(i: Invocation) -> anyhow::Result<()> {
+    if i.in_dm {
+        return follow(i).await;
+    }
+    let url_part = i.args[0].trim_matches('/').to_string();
+    let mut subscription = RuntimeSubscription {
+        name: url_part.clone(),
+        url_part,
+        filter: None,
+        query_subdirs: false,
+        room: Some(i.room.room_id().to_owned()),
+    };
+    for option in &i.args[1..] {
+        match option.split_once('=') {
+            Some(("name", name)) => subscription.name = name.to_string(),
+            Some(("filter", filter)) => subscription.filter = Some(filter.to_string()),
+            Some(("subdirs", subdirs)) => match subdirs.parse() {
+                Ok(subdirs) => subscription.query_subdirs = subdirs,
+                Err(_) => {
+                    return i
+                        .reply(tr!(
+                            i.lang,
+                            "subdirs must be true or false, not {subdirs}",
+                            subdirs
+                        ))
+                        .await
+                }
+            },
+            _ => {
+                return i
+                    .reply(tr!(i.lang, "Unknown option {option}", option))
+                    .await
+            }
+        }
+    }
+    let is_configured = i.ctx.find_source_name(&subscription.name).is_some()
+        && !i
+            .ctx
+            .runtime_subscriptions
+            .lock()
+            .unwrap()
+            .contains_key(&subscription.name);
+    if is_configured {
+        return i
+            .reply(tr!(
+                i.lang,
+                "{name} is already defined in the config file",
+                name = subscription.name
+            ))
+            .await;
+    }
+    let existing = i
+        .ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .get(&subscription.name)
+        .cloned();
+    // Replacing one means changing what another room gets
+    if let Some(existing) = existing {
+        let may_replace = match &existing.room {
+            Some(room_id) if room_id == i.room.room_id() => true,
+            Some(room_id) => match i.client.get_room(room_id) {
+                Some(room) => i.ctx.accepts_commands_in(&room, &i.sender).await,
+                None => i.admin,
+            },
+            None => i.admin,
+        };
+        if !may_replace {
+            return i
+                .reply(tr!(
+                    i.lang,
+                    "{name} belongs to a different room",
+                    name = subscription.name
+                ))
+                .await;
+        }
+    }
+    let name = subscription.name.clone();
+    let mut reply = match subscriptions::add(&i.client, &i.ctx, subscription).await {
+        Ok(()) => tr!(
+            i.lang,
+            "Subscribed to {name}, announcing new uploads in this room",
+            name
+        ),
+        Err(e) => return i.reply(tr!(i.lang, "Failed to subscribe: {e}", e)).await,
+    };
+    if !subscriptions::persisted(&i.ctx) {
+        reply += "\n";
+        reply += i18n::translate(
+            i.lang,
+            "Without a session DB, the subscription is gone after a restart.",
+        );
+    }
+    i.reply(reply).await
+}
+
+async fn unsubscribe(i: Invocation) -> anyhow::Result<()> {
+    if i.in_dm {
+        return unfollow(i).await;
+    }
+    let name = i.arg(0).unwrap_or_default();
+    let subscription = i
+        .ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .values()
+        .find(|x| x.name == name || x.url_part == name)
+        .cloned();
+    let reply = match subscription {
+        Some(subscription)
+            if subscription.room.is_some()
+                && subscription.room.as_deref() != Some(i.room.room_id()) =>
+        {
+            tr!(i.lang, "{name} belongs to a different room", name)
+        }
+        Some(subscription) => {
+            match subscriptions::remove(&i.client, &i.ctx, &subscription.name).await {
+                Ok(_) => tr!(i.lang, "Unsubscribed from {name}", name = subscription.name),
+                Err(e) => tr!(i.lang, "Failed to unsubscribe: {e}", e),
+            }
+        }
+        None if i.ctx.find_source_name(name).is_some() => {
+            tr!(
+                i.lang,
+                "{name} is defined in the config file and can only be removed there",
+                name
+            )
+        }
+        None => tr!(i.lang, "Unknown subscription {name}", name),
+    };
+    i.reply(reply).await
+}
+
+async fn alertme(i: Invocation) -> anyhow::Result<()> {
+    let room_id = i.room.room_id();
+    let reply = match i.args.join(" ").as_str() {
+        "" => {
+            let patterns = alerts::patterns(&i.ctx, room_id, &i.sender);
+            if patterns.is_empty() {
+                tr!(i.lang, "You have no alerts in this room")
+            } else {
+                tr!(
+                    i.lang,
+                    "Your alerts in this room: {patterns}",
+                    patterns = format!("'{}'", patterns.join("', '"))
+                )
+            }
+        }
+        "--clear" => match alerts::clear(&i.client, &i.ctx, room_id, &i.sender).await {
+            Ok(removed) => tr!(i.lang, "Removed {removed} alerts", removed),
+            Err(e) => tr!(i.lang, "Failed to remove the alerts: {e}", e),
+        },
+        pattern => match alerts::add(&i.client, &i.ctx, room_id, &i.sender, pattern).await {
+            Ok(()) => tr!(
+                i.lang,
+                "Mentioning you for entries matching '{pattern}'",
+                pattern
+            ),
+            Err(e) => tr!(i.lang, "Failed to add the alert: {e}", e),
+        },
+    };
+    i.reply(reply).await
+}
+
+/// `subscribe` in a DM
+async fn follow(i: Invocation) -> anyhow::Result<()> {
+    let name = i.arg(0).unwrap_or_default();
+    let Some(name) = i.ctx.find_source_name(name) else {
+        return i
+            .reply(tr!(i.lang, "Unknown subscription {name}", name))
+            .await;
+    };
+    let mut filter = None;
+    for option in &i.args[1..] {
+        match option.split_once('=') {
+            Some(("filter", regex)) => filter = Some(regex.to_string()),
+            _ => {
+                return i
+                    .reply(tr!(i.lang, "Unknown option {option}", option))
+                    .await
+            }
+        }
+    }
+    let room_id = i.room.room_id().to_owned();
+    let reply = match personal::follow(&i.client, &i.ctx, &i.sender, room_id, &name, filter.clone())
+        .await
+    {
+        Ok(()) => match filter {
+            Some(filter) => tr!(
+                i.lang,
+                "Following {name}, matching '{filter}'",
+                name,
+                filter
+            ),
+            None => tr!(i.lang, "Following {name}", name),
+        },
+        Err(e) => tr!(i.lang, "Failed to subscribe: {e}", e),
+    };
+    i.reply(reply).await
+}
+
+/// `unsubscribe` in a DM
+async fn unfollow(i: Invocation) -> anyhow::Result<()> {
+    let name = i.arg(0).unwrap_or_default();
+    let name = i
+        .ctx
+        .find_source_name(name)
+        .unwrap_or_else(|| name.to_string());
+    let reply = match personal::unfollow(&i.client, &i.ctx, &i.sender, &name).await {
+        Ok(true) => tr!(i.lang, "No longer following {name}", name),
+        Ok(false) => tr!(i.lang, "You don't follow {name}", name),
+        Err(e) => tr!(i.lang, "Failed to unsubscribe: {e}", e),
+    };
+    i.reply(reply).await
+}
+
+/// `sources` in a DM
+async fn personal_sources(i: Invocation) -> anyhow::Result<()> {
+    let followed = personal::followed_by(&i.ctx, &i.sender);
+    if followed.is_empty() {
+        let prefix = &i.ctx.cfg.command_prefix;
+        return i
+            .reply(tr!(
+                i.lang,
+                "You don't follow any subscriptions yet, use {command}",
+                command = format!("{prefix}subscribe <subscription>")
+            ))
+            .await;
+    }
+    let lines: Vec<_> = followed
+        .into_iter()
+        .map(|(name, filter)| match filter {
+            Some(filter) => tr!(i.lang, "{name}, matching '{filter}'", name, filter),
+            None => name,
+        })
+        .collect();
+    i.reply(lines.join("\n")).await
+}
+
+async fn filter(i: Invocation) -> anyhow::Result<()> {
+    let name = i.arg(0).unwrap_or_default();
+    let Some(name) = i.ctx.find_source_name(name) else {
+        return i
+            .reply(tr!(i.lang, "Unknown subscription {name}", name))
+            .await;
+    };
+    let filter = match i.arg(1).unwrap_or_default() {
+        "--clear" => None,
+        filter => Some(filter.to_string()),
+    };
+    let reply = match subscriptions::set_filter(&i.client, &i.ctx, &name, filter.clone()).await {
+        Ok(()) => match filter {
+            Some(filter) => tr!(i.lang, "Filtering {name} by '{filter}'", name, filter),
+            None => tr!(i.lang, "Removed the filter of {name}", name),
+        },
+        Err(e) => tr!(i.lang, "Failed to change the filter: {e}", e),
+    };
+    i.reply(reply).await
+}
+
+async fn interval(i: Invocation) -> anyhow::Result<()> {
+    let (name, interval) = match i.args.as_slice() {
+        [interval] => (None, interval),
+        [name, interval] => match i.ctx.find_source_name(name) {
+            Some(name) => (Some(name), interval),
+            None => {
+                return i
+                    .reply(tr!(i.lang, "Unknown subscription {name}", name))
+                    .await
+            }
+        },
+        _ => unreachable!("Argument count is checked by the registry"),
+    };
+    let interval = match parse_duration(interval) {
+        Ok(interval) if interval.as_secs() >= 60 => interval,
+        Ok(_) => {
+            return i
+                .reply(tr!(i.lang, "The interval has to be at least 1m"))
+                .await
+        }
+        Err(e) => return i.reply(e.to_string()).await,
+    };
+    let what = name
+        .clone()
+        .unwrap_or_else(|| tr!(i.lang, "all subscriptions without their own schedule"));
+    let reply =
+        match subscriptions::set_interval(&i.client, &i.ctx, name.as_deref(), interval).await {
+            Ok(()) => tr!(
+                i.lang,
+                "Polling {what} {schedule}",
+                what,
+                schedule = Schedule::Interval(interval)
+            ),
+            Err(e) => tr!(i.lang, "Failed to change the interval: {e}", e),
+        };
+    i.reply(reply).await
+}
+
+/// Adds or removes a subscription from the muted ones of the room and returns the reply
+fn set_muted(i: &Invocation, name: &str, muted: bool) -> Result<String, String> {
+    let Some(name) = i.ctx.find_source_name(name) else {
+        return Err(tr!(i.lang, "Unknown subscription {name}", name));
+    };
+    let room_id = i.room.room_id();
+    // Before locking the watch list, which this reads as well
+    let announced = i
+        .ctx
+        .announces_to(&name, source_rooms(i, &name).as_deref(), room_id);
+    let mut rooms = i.ctx.rooms.lock().unwrap();
+    let mut routed_mutes = i.ctx.routed_mutes.lock().unwrap();
+    let room_muted = match rooms.get_mut(room_id) {
+        Some(room) => &mut room.muted,
+        // Rooms the subscription gets routed to, without being watched
+        None if announced => routed_mutes.entry(room_id.to_owned()).or_default(),
+        None => {
+            return Err(tr!(
+                i.lang,
+                "This room isn't watched. Use unsubscribe to stop subscriptions of this room."
+            ))
+        }
+    };
+    let result = match (muted, room_muted.contains(&name)) {
+        (true, false) => {
+            room_muted.insert(name.clone());
+            Ok(tr!(i.lang, "Muted {name} in this room", name))
+        }
+        (false, true) => {
+            room_muted.remove(&name);
+            Ok(tr!(i.lang, "Unmuted {name} in this room", name))
+        }
+        (true, true) => Err(tr!(i.lang, "{name} is already muted", name)),
+        (false, false) => Err(tr!(i.lang, "{name} isn't muted", name)),
+    };
+    routed_mutes.retain(|_, x| !x.is_empty());
+    result
+}
+
+/// The rooms a subscription lists itself
+fn source_rooms(i: &Invocation, name: &str) -> Option<Vec<OwnedRoomId>> {
+    i.ctx
+        .sources
+        .lock()
+        .unwrap()
+        .get(name)
+        .and_then(|x| x.rooms.clone())
+}
+
+async fn mute(i: Invocation) -> anyhow::Result<()> {
+    let result = set_muted(&i, i.arg(0).unwrap_or_default(), true);
+    if result.is_ok() {
+        watch_list::store(&i.client, &i.ctx).await?;
+        watch_list::store_routed_mutes(&i.client, &i.ctx).await?;
+    }
+    i.acknowledge(result).await
+}
+
+async fn unmute(i: Invocation) -> anyhow::Result<()> {
+    let result = set_muted(&i, i.arg(0).unwrap_or_default(), false);
+    if result.is_ok() {
+        watch_list::store(&i.client, &i.ctx).await?;
+        watch_list::store_routed_mutes(&i.client, &i.ctx).await?;
+    }
+    i.acknowledge(result).await
+}
+
+async fn pause(i: Invocation) -> anyhow::Result<()> {
+    let mut args: Vec<&str> = i.args.iter().map(String::as_str).collect();
+    // The last argument is a duration, if it parses as one
+    let duration = match args.last().map(|x| parse_duration(x)) {
+        Some(Ok(duration)) => {
+            args.pop();
+            Some(duration)
+        }
+        _ if args.len() == 2 => {
+            return i
+                .acknowledge(Err(tr!(
+                    i.lang,
+                    "Invalid duration {duration}",
+                    duration = args[1]
+                )))
+                .await
+        }
+        _ => None,
+    };
+    let source = match args.first() {
+        Some(name) => match i.ctx.find_source_name(name) {
+            Some(name) => Some(name),
+            None => {
+                return i
+                    .acknowledge(Err(tr!(i.lang, "Unknown subscription {name}", name)))
+                    .await
+            }
+        },
+        None => None,
+    };
+    let until = match duration {
+        Some(duration) => {
+            let until = chrono::Duration::from_std(duration)
+                .ok()
+                .and_then(|x| Utc::now().checked_add_signed(x));
+            match until {
+                Some(until) => Some(until),
+                None => {
+                    return i
+                        .acknowledge(Err(tr!(i.lang, "That duration is too long")))
+                        .await
+                }
+            }
+        }
+        None => None,
+    };
+    i.ctx.pause(source.as_deref(), until);
+    let what = source.unwrap_or_else(|| tr!(i.lang, "all announcements"));
+    let reply = match until {
+        Some(until) => tr!(
+            i.lang,
+            "Paused {what} until {time}",
+            what,
+            time = until.format("%Y-%m-%d %H:%M UTC")
+        ),
+        None => tr!(i.lang, "Paused {what} until resumed", what),
+    };
+    i.acknowledge(Ok(reply)).await
+}
+
+async fn resume(i: Invocation) -> anyhow::Result<()> {
+    let source = match i.arg(0) {
+        Some(name) => match i.ctx.find_source_name(name) {
+            Some(name) => Some(name),
+            None => {
+                return i
+                    .acknowledge(Err(tr!(i.lang, "Unknown subscription {name}", name)))
+                    .await
+            }
+        },
+        None => None,
+    };
+    let what = source
+        .clone()
+        .unwrap_or_else(|| tr!(i.lang, "all announcements"));
+    let result = if i.ctx.resume(source.as_deref()) {
+        Ok(tr!(i.lang, "Resumed {what}", what))
+    } else {
+        Err(tr!(i.lang, "{what} were not paused", what))
+    };
+    i.acknowledge(result).await
+}
+
+async fn enable(i: Invocation) -> anyhow::Result<()> {
+    let name = i.arg(0).unwrap_or_default();
+    let result = match i.ctx.find_source_name(name) {
+        Some(name) if i.ctx.enable_source(&name) => Ok(tr!(i.lang, "Re-enabled {name}", name)),
+        _ => Err(tr!(i.lang, "Unknown subscription {name}", name)),
+    };
+    i.acknowledge(result).await
+}
+
+async fn ignore(i: Invocation) -> anyhow::Result<()> {
+    let Some(user) = i.arg(0) else {
+        let configured = i.ctx.cfg.reloadable.lock().unwrap().ignore_users.clone();
+        let mut ignored: Vec<_> = configured
+            .iter()
+            .map(|x| tr!(i.lang, "{user} (config)", user = x))
+            .collect();
+        ignored.extend(i.ctx.ignored.lock().unwrap().iter().map(|x| x.to_string()));
+        if ignored.is_empty() {
+            return i.reply(tr!(i.lang, "Nobody is ignored")).await;
+        }
+        return i.reply(ignored.join("\n")).await;
+    };
+    let user = match UserId::parse(user) {
+        Ok(user) => user,
+        Err(e) => {
+            return i
+                .reply(tr!(i.lang, "Invalid user {user}: {e}", user, e))
+                .await
+        }
+    };
+    if user == i.sender {
+        return i.reply(tr!(i.lang, "You can't ignore yourself")).await;
+    }
+    let result = match ignore_list::ignore(&i.client, &i.ctx, &user).await {
+        Ok(true) => Ok(tr!(i.lang, "Ignoring {user}", user)),
+        Ok(false) => Err(tr!(i.lang, "{user} is already ignored", user)),
+        Err(e) => Err(tr!(i.lang, "Failed to ignore {user}: {e}", user, e)),
+    };
+    i.acknowledge(result).await
+}
+
+async fn unignore(i: Invocation) -> anyhow::Result<()> {
+    let user = i.arg(0).unwrap_or_default();
+    let user = match UserId::parse(user) {
+        Ok(user) => user,
+        Err(e) => {
+            return i
+                .reply(tr!(i.lang, "Invalid user {user}: {e}", user, e))
+                .await
+        }
+    };
+    let result = match ignore_list::unignore(&i.client, &i.ctx, &user).await {
+        Ok(true) => Ok(tr!(i.lang, "No longer ignoring {user}", user)),
+        Ok(false) if i.ctx.is_ignored(&user) => Err(tr!(
+            i.lang,
+            "{user} is ignored in the config file and can only be removed there",
+            user
+        )),
+        Ok(false) => Err(tr!(i.lang, "{user} isn't ignored", user)),
+        Err(e) => Err(tr!(i.lang, "Failed to unignore {user}: {e}", user, e)),
+    };
+    i.acknowledge(result).await
+}
+
+async fn pubkey(i: Invocation) -> anyhow::Result<()> {
+    let reply = match &i.ctx.signer {
+        Some(signer) => tr!(
+            i.lang,
+            "Announcements are signed with Ed25519 key {key}",
+            key = signer.public_key()
+        ),
+        None => tr!(i.lang, "Announcements are not signed"),
+    };
+    i.reply(reply).await
+}
+
+async fn resources(i: Invocation) -> anyhow::Result<()> {
+    let report = i.ctx.resources.report();
+    i.reply(report).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_with_the_prefix() {
+        let (command, args) = registry().parse("!", "!status").unwrap();
+        assert_eq!(command.name, "status");
+        assert_eq!(args, "");
+        let (command, args) = registry().parse("!", "!knock #room:example.org").unwrap();
+        assert_eq!(command.name, "knock");
+        assert_eq!(args.trim(), "#room:example.org");
+        assert!(registry().parse("!", "status").is_none());
+        assert!(registry().parse("!", "!no-such-command").is_none());
+        assert!(registry().parse("!", "!statusx").is_none());
+    }
+
+    #[test]
+    fn generates_usage() {
+        let find = |name| registry().find(name).unwrap();
+        assert_eq!(find("ping").usage("!"), "!ping");
+        assert_eq!(find("knock").usage("!"), "!knock <room>");
+        assert_eq!(find("help").usage("!"), "!help [command]");
+        assert_eq!(
+            find("devices").usage("!"),
+            "!devices [action] [device_ids...]"
+        );
+    }
+
+    #[test]
+    fn checks_argument_counts() {
+        let knock = registry().find("knock").unwrap();
+        assert!(!knock.accepts_arg_count(0));
+        assert!(knock.accepts_arg_count(1));
+        assert!(!knock.accepts_arg_count(2));
+        let devices = registry().find("devices").unwrap();
+        assert!(devices.accepts_arg_count(0));
+        assert!(devices.accepts_arg_count(1));
+        assert!(devices.accepts_arg_count(5));
+    }
+
+    #[test]
+    fn checks_permissions() {
+        let help = registry().find("help").unwrap();
+        assert!(help.allowed_for(false, false, false));
+        let ping = registry().find("ping").unwrap();
+        assert!(!ping.allowed_for(false, true, false));
+        assert!(ping.allowed_for(true, false, false));
+        let resources = registry().find("resources").unwrap();
+        assert!(!resources.allowed_for(true, true, false));
+        assert!(resources.allowed_for(false, false, true));
+    }
+
+    #[test]
+    fn strips_mentions() {
+        let names = [
+            String::from("@mozbot:example.org"),
+            String::from("mozbot"),
+            String::from("Moz Bot"),
+        ];
+        assert_eq!(
+            strip_mention("@mozbot:example.org: status", &names),
+            Some("status")
+        );
+        assert_eq!(strip_mention("@mozbot: status", &names), Some("status"));
+        assert_eq!(strip_mention("mozbot, status", &names), Some("status"));
+        assert_eq!(strip_mention("moz bot status", &names), Some("status"));
+        assert_eq!(strip_mention("mozbotty status", &names), None);
+        assert_eq!(strip_mention("hello", &names), None);
+    }
+}
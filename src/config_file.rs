@@ -0,0 +1,389 @@
+//! The sections of the config file as typed structs. Deserializing a whole section at once
+//! lets the config crate name the key and the type it expected when a value is off, which
+//! reading key by key with `get_string` and friends didn't.
+use super::templates::NotificationTemplate;
+use config::{Config, ConfigError};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// None, if there is no such section
+pub fn optional_section<T: DeserializeOwned>(
+    settings: &Config,
+    key: &str,
+) -> Result<Option<T>, ConfigError> {
+    match settings.get::<T>(key) {
+        Ok(section) => Ok(Some(section)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A missing section reads like an empty one, so all its defaults apply
+pub fn section<T: DeserializeOwned>(settings: &Config, key: &str) -> Result<T, ConfigError> {
+    match optional_section(settings, key)? {
+        Some(section) => Ok(section),
+        None => Config::default().try_deserialize(),
+    }
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// `[login]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginSection {
+    pub homeserver_url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+    #[serde(default = "yes")]
+    pub persist_session: bool,
+    pub db_path: Option<PathBuf>,
+    /// How many backups of the state DB to keep
+    #[serde(default = "default_backups")]
+    pub backups: usize,
+    pub db_pw: Option<String>,
+    pub db_pw_file: Option<PathBuf>,
+    pub db_pw_command: Option<String>,
+    #[serde(default = "yes")]
+    pub use_secret_service: bool,
+    /// Wins over `use_secret_service`
+    pub storage: Option<SessionBackend>,
+    pub session_path: Option<PathBuf>,
+    #[serde(default = "yes")]
+    pub bootstrap_cross_signing: bool,
+    #[serde(default = "yes")]
+    pub key_backup: bool,
+    #[serde(default = "default_device_name")]
+    pub device_name: String,
+    #[serde(default)]
+    pub oidc: bool,
+    pub oidc_client_id: Option<String>,
+}
+
+/// Where the session is kept, besides the session DB
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum SessionBackend {
+    Plain,
+    SecretService,
+    Keyring,
+}
+
+impl SessionBackend {
+    /// As written in the config
+    pub fn name(self) -> &'static str {
+        match self {
+            SessionBackend::Plain => "plain",
+            SessionBackend::SecretService => "secret_service",
+            SessionBackend::Keyring => "keyring",
+        }
+    }
+}
+
+fn default_backups() -> usize {
+    5
+}
+
+fn default_device_name() -> String {
+    String::from("Mozilla FTP watcher")
+}
+
+impl LoginSection {
+    /// OIDC refresh tokens are single-use, so each refresh has to be stored. Without a
+    /// persisted session the restarted bot would keep refreshing with a used one.
+    pub fn check_oidc(&self) -> anyhow::Result<()> {
+        if self.oidc && !self.persist_session {
+            anyhow::bail!("oidc needs persist_session, the refreshed tokens have to be stored");
+        }
+        Ok(())
+    }
+
+    /// `storage`, or else what `use_secret_service` stands for
+    pub fn backend(&self) -> SessionBackend {
+        self.storage.unwrap_or(if self.use_secret_service {
+            SessionBackend::SecretService
+        } else {
+            SessionBackend::Plain
+        })
+    }
+
+    /// `password`, or else the content of `password_file`, or else the output of
+    /// `password_command`, or else the systemd credential `password` (`<instance>.password`
+    /// for named instances and accounts)
+    pub fn load_password(&self, instance: Option<&str>) -> anyhow::Result<Option<String>> {
+        configured_secret(
+            &self.password,
+            &self.password_file,
+            &self.password_command,
+            instance,
+            "password",
+        )
+    }
+
+    /// Like `load_password`, for `db_pw`
+    pub fn load_db_pw(&self, instance: Option<&str>) -> anyhow::Result<Option<String>> {
+        configured_secret(
+            &self.db_pw,
+            &self.db_pw_file,
+            &self.db_pw_command,
+            instance,
+            "db_pw",
+        )
+    }
+}
+
+fn configured_secret(
+    value: &Option<String>,
+    file: &Option<PathBuf>,
+    command: &Option<String>,
+    instance: Option<&str>,
+    credential: &str,
+) -> anyhow::Result<Option<String>> {
+    if let Some(value) = value {
+        return Ok(Some(value.clone()));
+    }
+    if let Some(file) = file {
+        return read_secret_file(file).map(Some);
+    }
+    if let Some(command) = command {
+        return secret_command(command).map(Some);
+    }
+    match instance {
+        Some(instance) => systemd_credential(&format!("{instance}.{credential}")),
+        None => systemd_credential(credential),
+    }
+}
+
+/// Runs `command` with the shell and takes the first line it prints, like `pass show` has
+/// the password in it. Its stderr goes to ours, so a pinentry or an error can be seen.
+fn secret_command(command: &str) -> anyhow::Result<String> {
+    #[cfg(not(windows))]
+    let mut shell = std::process::Command::new("sh");
+    #[cfg(not(windows))]
+    shell.arg("-c");
+    #[cfg(windows)]
+    let mut shell = std::process::Command::new("cmd");
+    #[cfg(windows)]
+    shell.arg("/C");
+    let output = shell
+        .arg(command)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run `{command}`: {e}"))?;
+    if !output.status.success() {
+        anyhow::bail!("`{command}` failed with {}", output.status);
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| anyhow::anyhow!("`{command}` printed something that isn't UTF-8"))?;
+    match stdout.lines().next() {
+        Some(secret) if !secret.is_empty() => Ok(secret.to_string()),
+        _ => anyhow::bail!("`{command}` printed nothing"),
+    }
+}
+
+/// A credential passed with LoadCredential= or SetCredential=, if running as systemd
+/// service that has it
+fn systemd_credential(name: &str) -> anyhow::Result<Option<String>> {
+    let Some(dir) = std::env::var_os("CREDENTIALS_DIRECTORY") else {
+        return Ok(None);
+    };
+    let path = Path::new(&dir).join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    read_secret_file(&path).map(Some)
+}
+
+/// Secret mounts of Docker and Kubernetes, and files written with echo, end with a newline
+/// that isn't part of the secret
+pub fn read_secret_file(path: &Path) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", path.display()))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// `[appservice]`. Without the appservice feature, it is only read to refuse it.
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "appservice"), allow(dead_code))]
+pub struct AppServiceSection {
+    pub registration: PathBuf,
+    pub server_name: String,
+    #[serde(default = "default_sender_localpart")]
+    pub sender_localpart: String,
+    /// Defaults to http://localhost:<listen_port>
+    pub url: Option<String>,
+    #[serde(default = "default_listen_host")]
+    pub listen_host: String,
+    #[serde(default = "default_listen_port")]
+    pub listen_port: u16,
+}
+
+fn default_sender_localpart() -> String {
+    String::from("mozillabot")
+}
+
+fn default_listen_host() -> String {
+    String::from("127.0.0.1")
+}
+
+fn default_listen_port() -> u16 {
+    9000
+}
+
+/// `[config]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigSection {
+    #[serde(default = "yes")]
+    pub ignore_own_messages: bool,
+    #[serde(default = "yes")]
+    pub autojoin: bool,
+    #[serde(default = "default_sleep_time_in_minutes")]
+    pub sleep_time_in_minutes: u64,
+    #[serde(default)]
+    pub accept_commands_from: Vec<String>,
+    #[serde(default)]
+    pub ignore_users: Vec<String>,
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    #[serde(default)]
+    pub spaces: Vec<String>,
+    pub admin_room: Option<String>,
+    #[serde(default = "default_admin_report_interval_minutes")]
+    pub admin_report_interval_minutes: u64,
+    pub announce_lifecycle: Option<String>,
+    #[serde(default = "default_outbox_ttl_hours")]
+    pub outbox_ttl_hours: u64,
+    #[serde(default = "default_max_consecutive_failures")]
+    pub max_consecutive_failures: usize,
+    #[serde(default)]
+    pub poll_before_sync: bool,
+    #[serde(default)]
+    pub startup_quiet_minutes: u64,
+    #[serde(default)]
+    pub sign_announcements: bool,
+    pub watch_list_storage: Option<String>,
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+    #[serde(default)]
+    pub personal_subscriptions: bool,
+    pub language: Option<String>,
+    #[serde(default)]
+    pub leave_empty_rooms: bool,
+    #[serde(default)]
+    pub bot_users: Vec<String>,
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+fn default_sleep_time_in_minutes() -> u64 {
+    60
+}
+
+fn default_admin_report_interval_minutes() -> u64 {
+    30
+}
+
+fn default_outbox_ttl_hours() -> u64 {
+    24
+}
+
+fn default_max_consecutive_failures() -> usize {
+    5
+}
+
+fn default_command_prefix() -> String {
+    String::from("!")
+}
+
+/// `[limits]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitsSection {
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    #[serde(default = "default_max_seen_entries")]
+    pub max_seen_entries: usize,
+    #[serde(default = "default_max_sends_per_minute")]
+    pub max_sends_per_minute: usize,
+    #[serde(default = "default_max_commands_per_minute")]
+    pub max_commands_per_minute: usize,
+}
+
+fn default_max_concurrent_requests() -> usize {
+    8
+}
+
+fn default_max_seen_entries() -> usize {
+    100_000
+}
+
+fn default_max_sends_per_minute() -> usize {
+    30
+}
+
+fn default_max_commands_per_minute() -> usize {
+    10
+}
+
+/// One `[[room]]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoomSection {
+    pub id: String,
+    pub quiet_hours: Option<String>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    pub sources: Option<Vec<String>>,
+    pub accept_commands_from: Option<Vec<String>>,
+}
+
+fn default_timezone() -> String {
+    String::from("UTC")
+}
+
+/// The wording of notifications, of a subscription or one of its translations
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TemplateSection {
+    pub template: Option<String>,
+    pub template_html: Option<String>,
+    #[serde(default)]
+    pub template_markdown: bool,
+}
+
+impl TemplateSection {
+    pub fn build(&self) -> anyhow::Result<Option<NotificationTemplate>> {
+        self.template
+            .clone()
+            .map(|x| {
+                NotificationTemplate::new(x, self.template_html.clone(), self.template_markdown)
+            })
+            .transpose()
+    }
+}
+
+/// One `[subscription.<name>]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubscriptionSection {
+    pub url_part: String,
+    pub query_subdirs: bool,
+    pub filter: Option<String>,
+    pub schedule: Option<String>,
+    pub rooms: Option<Vec<String>>,
+    #[serde(default)]
+    pub update_in_place: bool,
+    #[serde(flatten)]
+    pub template: TemplateSection,
+    /// By language code
+    #[serde(default)]
+    pub translations: BTreeMap<String, TemplateSection>,
+    pub msgtype: Option<String>,
+    #[serde(default)]
+    pub pin: bool,
+    pub attach: Option<String>,
+}
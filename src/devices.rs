@@ -0,0 +1,125 @@
+//! The devices of the bot account. Every fresh login (e.g. after the restored session was
+//! rejected) leaves the previous device behind, so they can be listed and deleted with
+//! `!devices` or the `devices` subcommand.
+use super::{cli::DevicesAction, matrix::restore_client, LoginData, SharedState};
+use chrono::{TimeZone, Utc};
+use matrix_sdk::{
+    ruma::{
+        api::client::uiaa::{AuthData, Password, UserIdentifier},
+        OwnedDeviceId,
+    },
+    Client,
+};
+
+/// One line per device, the current one marked with a *
+pub async fn list(client: &Client) -> anyhow::Result<Vec<String>> {
+    let mut devices = client.devices().await?.devices;
+    devices.sort_by_key(|x| std::cmp::Reverse(x.last_seen_ts));
+    Ok(devices
+        .into_iter()
+        .map(|device| {
+            let current = if Some(device.device_id.as_ref()) == client.device_id() {
+                "* "
+            } else {
+                ""
+            };
+            let last_seen = device
+                .last_seen_ts
+                .and_then(|x| Utc.timestamp_millis_opt(x.get().into()).single())
+                .map(|x| x.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_else(|| String::from("never"));
+            format!(
+                "{current}{} ({}), last seen {last_seen}",
+                device.device_id,
+                device.display_name.as_deref().unwrap_or("unnamed")
+            )
+        })
+        .collect())
+}
+
+/// Default of how many days devices have to be unused to count as stale
+pub const DEFAULT_STALE_DAYS: u32 = 30;
+
+/// The devices except the one we are running as, which weren't seen for `days` days.
+/// Devices the homeserver doesn't know the last activity of are kept.
+pub async fn stale(client: &Client, days: u32) -> anyhow::Result<Vec<OwnedDeviceId>> {
+    let Some(cutoff) = Utc::now().checked_sub_signed(chrono::Duration::days(days.into())) else {
+        return Ok(Vec::new());
+    };
+    Ok(client
+        .devices()
+        .await?
+        .devices
+        .into_iter()
+        .filter(|x| Some(x.device_id.as_ref()) != client.device_id())
+        .filter(|x| {
+            x.last_seen_ts
+                .and_then(|x| Utc.timestamp_millis_opt(x.get().into()).single())
+                .is_some_and(|x| x < cutoff)
+        })
+        .map(|x| x.device_id)
+        .collect())
+}
+
+/// Deletes devices. The homeserver wants the password for that, so this only works for
+/// password logins with the password in the config.
+pub async fn delete(
+    client: &Client,
+    aio: &SharedState,
+    devices: &[OwnedDeviceId],
+) -> anyhow::Result<()> {
+    if devices
+        .iter()
+        .any(|x| Some(x.as_ref()) == client.device_id())
+    {
+        anyhow::bail!("Refusing to delete the device we are running as");
+    }
+    let error = match client.delete_devices(devices, None).await {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    let Some(uiaa) = error.as_uiaa_response() else {
+        return Err(error.into());
+    };
+    let password = match &aio.cfg.login_data {
+        LoginData::UsernamePassword(username, password) if !password.is_empty() => {
+            let mut auth = Password::new(
+                UserIdentifier::UserIdOrLocalpart(username.clone()),
+                password.clone(),
+            );
+            auth.session = uiaa.session.clone();
+            auth
+        }
+        _ => anyhow::bail!("Deleting devices needs login.password in the config"),
+    };
+    client
+        .delete_devices(devices, Some(AuthData::Password(password)))
+        .await?;
+    Ok(())
+}
+
+/// The `devices` subcommand
+pub async fn run_subcommand(aio: &SharedState, action: &DevicesAction) -> anyhow::Result<()> {
+    let client = restore_client(aio).await?;
+    match action {
+        DevicesAction::List => {
+            for line in list(&client).await? {
+                println!("{line}");
+            }
+        }
+        DevicesAction::Delete { device_ids } => {
+            let devices: Vec<_> = device_ids
+                .iter()
+                .map(|x| OwnedDeviceId::from(x.as_str()))
+                .collect();
+            delete(&client, aio, &devices).await?;
+            println!("Deleted {} devices", devices.len());
+        }
+        DevicesAction::DeleteStale { days } => {
+            let devices = stale(&client, *days).await?;
+            delete(&client, aio, &devices).await?;
+            println!("Deleted {} stale devices", devices.len());
+        }
+    }
+    Ok(())
+}
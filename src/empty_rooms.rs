@@ -0,0 +1,166 @@
+//! With `config.leave_empty_rooms`, the bot leaves rooms once no people are left in them,
+//! so abandoned rooms don't keep getting notifications forever. Other bots listed in
+//! `config.bot_users` don't count as people. Rooms the config asks for (`config.rooms`,
+//! `config.spaces`, `[[room]]` and the admin room) are never left as empty.
+//!
+//! `!leave-all` and the `leave-all` subcommand leave all rooms at once, e.g. before
+//! decommissioning an instance or after autojoin went on a spree.
+use super::{alerts, outbox, pins, store_queued, subscriptions, watch_list, SharedState};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::events::room::member::{MembershipState, OriginalSyncRoomMemberEvent},
+    Client, RoomMemberships, RoomState,
+};
+
+/// Whether anyone but us and other bots is in the room, or invited to it
+async fn has_people(client: &Client, ctx: &SharedState, room: &Room) -> anyhow::Result<bool> {
+    let members = room
+        .members(RoomMemberships::JOIN | RoomMemberships::INVITE)
+        .await?;
+    Ok(members.iter().any(|member| {
+        let user = member.user_id();
+        Some(user) != client.user_id() && !ctx.is_bot_user(user)
+    }))
+}
+
+/// Leaves the room if nobody is left in it. Returns whether we left.
+pub async fn leave_if_empty(
+    client: &Client,
+    ctx: &SharedState,
+    room: &Room,
+) -> anyhow::Result<bool> {
+    if !ctx.cfg.leave_empty_rooms || room.state() != RoomState::Joined {
+        return Ok(false);
+    }
+    // Reports should still have somewhere to go when the admins left for a moment, and
+    // configured rooms would only get joined again at the next start
+    if ctx.cfg.admin_room.as_deref() == Some(room.room_id())
+        || ctx.is_configured_room(room.room_id())
+    {
+        return Ok(false);
+    }
+    if has_people(client, ctx, room).await? {
+        return Ok(false);
+    }
+    println!(
+        "Leaving {}, as nobody else is in there anymore",
+        room.room_id()
+    );
+    room.leave().await?;
+    forget(client, ctx, room).await?;
+    Ok(true)
+}
+
+/// Drops everything we keep about a room we left. Failing steps don't stop the others,
+/// the room is gone either way.
+pub async fn forget(client: &Client, ctx: &SharedState, room: &Room) -> anyhow::Result<()> {
+    let room_id = room.room_id();
+    let mut failures = Vec::new();
+    if ctx.rooms.lock().unwrap().remove(room_id).is_some() {
+        if let Err(e) = watch_list::store(client, ctx).await {
+            failures.push(format!("watch list: {e:#}"));
+        }
+    }
+    if ctx.routed_mutes.lock().unwrap().remove(room_id).is_some() {
+        if let Err(e) = watch_list::store_routed_mutes(client, ctx).await {
+            failures.push(format!("mutes: {e:#}"));
+        }
+    }
+    if let Err(e) = alerts::remove_room(client, ctx, room_id).await {
+        failures.push(format!("alerts: {e:#}"));
+    }
+    let names: Vec<_> = ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|x| x.room.as_deref() == Some(room_id))
+        .map(|x| x.name.clone())
+        .collect();
+    for name in names {
+        if let Err(e) = subscriptions::remove(client, ctx, &name).await {
+            failures.push(format!("subscription {name}: {e:#}"));
+        }
+    }
+    if ctx.queued.lock().unwrap().remove(room_id).is_some() {
+        store_queued(ctx).await;
+    }
+    let undelivered = {
+        let mut outbox = ctx.outbox.lock().unwrap();
+        let before = outbox.len();
+        outbox.retain(|x| x.room_id != room_id);
+        outbox.len() != before
+    };
+    if undelivered {
+        if let Err(e) = outbox::store(ctx).await {
+            failures.push(format!("outbox: {e:#}"));
+        }
+    }
+    ctx.thread_roots
+        .lock()
+        .unwrap()
+        .retain(|(x, _), _| x != room_id);
+    ctx.editable
+        .lock()
+        .unwrap()
+        .retain(|(x, _), _| x != room_id);
+    ctx.pinned.lock().unwrap().retain(|(x, _), _| x != room_id);
+    if let Err(e) = pins::store(ctx).await {
+        failures.push(format!("pins: {e:#}"));
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("{}", failures.join("; "));
+    }
+    Ok(())
+}
+
+pub async fn on_member(
+    event: OriginalSyncRoomMemberEvent,
+    client: Client,
+    room: Room,
+    ctx: Ctx<SharedState>,
+) {
+    if !matches!(
+        event.content.membership,
+        MembershipState::Leave | MembershipState::Ban
+    ) || Some(event.state_key.as_ref()) == client.user_id()
+    {
+        return;
+    }
+    if let Err(e) = leave_if_empty(&client, &ctx, &room).await {
+        eprintln!("Failed to leave the empty room {}: {e:?}", room.room_id());
+    }
+}
+
+/// Leaves every joined room and empties the watch list. Returns how many rooms we left.
+pub async fn leave_all(client: &Client, ctx: &SharedState) -> anyhow::Result<usize> {
+    ctx.rooms.lock().unwrap().clear();
+    watch_list::store(client, ctx).await?;
+    let mut count = 0;
+    for room in client.joined_rooms() {
+        match room.leave().await {
+            Ok(()) => count += 1,
+            Err(e) => {
+                eprintln!("Failed to leave {}: {e:?}", room.room_id());
+                continue;
+            }
+        }
+        if let Err(e) = forget(client, ctx, &room).await {
+            eprintln!("Failed to clean up after leaving {}: {e:?}", room.room_id());
+        }
+    }
+    Ok(count)
+}
+
+/// Leaves the rooms that emptied while the bot wasn't running
+pub async fn leave_all_empty(client: &Client, ctx: &SharedState) {
+    if !ctx.cfg.leave_empty_rooms {
+        return;
+    }
+    for room in client.joined_rooms() {
+        if let Err(e) = leave_if_empty(client, ctx, &room).await {
+            eprintln!("Failed to leave the empty room {}: {e:?}", room.room_id());
+        }
+    }
+}
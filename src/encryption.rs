@@ -0,0 +1,147 @@
+use super::{LoginData, SharedState};
+use anyhow::Context;
+use matrix_sdk::{
+    crypto::store::CrossSigningKeyExport,
+    ruma::api::client::uiaa::{AuthData, Password, UserIdentifier},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+/// Name under which the exported cross-signing keys are kept in the session storage
+pub const CROSS_SIGNING_SECRET: &str = "cross_signing_keys";
+/// Name under which the recovery key of the server-side key backup is kept
+pub const BACKUP_RECOVERY_SECRET: &str = "backup_recovery_key";
+
+/// Serializable copy of the private cross-signing keys. Whoever has these can
+/// verify new devices of the bot, so this is our recovery key.
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredCrossSigningKeys {
+    master_key: Option<String>,
+    self_signing_key: Option<String>,
+    user_signing_key: Option<String>,
+}
+
+impl From<CrossSigningKeyExport> for StoredCrossSigningKeys {
+    fn from(export: CrossSigningKeyExport) -> Self {
+        Self {
+            master_key: export.master_key.clone(),
+            self_signing_key: export.self_signing_key.clone(),
+            user_signing_key: export.user_signing_key.clone(),
+        }
+    }
+}
+
+impl From<StoredCrossSigningKeys> for CrossSigningKeyExport {
+    fn from(keys: StoredCrossSigningKeys) -> Self {
+        Self {
+            master_key: keys.master_key,
+            self_signing_key: keys.self_signing_key,
+            user_signing_key: keys.user_signing_key,
+        }
+    }
+}
+
+async fn bootstrap(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
+    let encryption = client.encryption();
+    if let Err(e) = encryption.bootstrap_cross_signing(None).await {
+        let response = e
+            .as_uiaa_response()
+            .context("Bootstrapping cross-signing failed")?;
+        // Uploading the keys needs interactive auth, which we can only do with a password
+        let password = match &aio.cfg.login_data {
+            LoginData::UsernamePassword(username, password) if !password.is_empty() => {
+                let mut password = Password::new(
+                    UserIdentifier::UserIdOrLocalpart(username.clone()),
+                    password.clone(),
+                );
+                password.session = response.session.clone();
+                password
+            }
+            _ => anyhow::bail!(
+                "Cross-signing needs the account password, please provide login.password once"
+            ),
+        };
+        encryption
+            .bootstrap_cross_signing(Some(AuthData::Password(password)))
+            .await?;
+    }
+    println!("Bootstrapped cross-signing");
+    Ok(())
+}
+
+/// Makes sure our device is cross-signed. Restores previously stored cross-signing
+/// keys if we have them, otherwise bootstraps cross-signing if the account has none yet.
+/// The private keys get stored in the session storage, to be able to recover later on.
+pub async fn setup_cross_signing(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
+    let encryption = client.encryption();
+    if let Some(status) = encryption.cross_signing_status().await {
+        if status.is_complete() {
+            return Ok(());
+        }
+    }
+
+    if let Some(stored) = aio
+        .cfg
+        .session_storage
+        .restore_secret(CROSS_SIGNING_SECRET)
+        .await?
+    {
+        let keys: StoredCrossSigningKeys = serde_json::from_str(&stored)?;
+        encryption
+            .import_cross_signing_keys(keys.into())
+            .await
+            .context("Failed to import stored cross-signing keys")?;
+        if let Some(device) = encryption.get_own_device().await? {
+            device.verify().await?;
+        }
+        println!("Restored cross-signing keys from session storage");
+        return Ok(());
+    }
+
+    let user_id = client.user_id().context("Client is not logged in")?;
+    if encryption.get_user_identity(user_id).await?.is_some() {
+        // Bootstrapping again would reset the existing identity
+        println!("Cross-signing was set up by another device, but we don't have the keys. Verify this device from another session.");
+        return Ok(());
+    }
+
+    bootstrap(client, aio).await?;
+    if let Some(export) = encryption.export_cross_signing_keys().await {
+        let keys = StoredCrossSigningKeys::from(export);
+        aio.cfg
+            .session_storage
+            .store_secret(CROSS_SIGNING_SECRET, &serde_json::to_string(&keys)?)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Creates or joins the server-side room-key backup, so that room keys survive a
+/// device reset. The recovery key is kept in the session storage.
+pub async fn setup_key_backup(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
+    let encryption = client.encryption();
+    let recovery = encryption.recovery();
+    if let Some(recovery_key) = aio
+        .cfg
+        .session_storage
+        .restore_secret(BACKUP_RECOVERY_SECRET)
+        .await?
+    {
+        recovery
+            .recover(recovery_key.trim())
+            .await
+            .context("Failed to join the key backup with the stored recovery key")?;
+        println!("Joined the server-side key backup");
+    } else if encryption.backups().exists_on_server().await? {
+        // Creating a new one would make the existing backup useless for other devices
+        println!("A server-side key backup exists, but we don't have its recovery key. Not backing up room keys.");
+    } else {
+        let recovery_key = recovery.enable().await?;
+        aio.cfg
+            .session_storage
+            .store_secret(BACKUP_RECOVERY_SECRET, &recovery_key)
+            .await?;
+        println!("Created a server-side key backup");
+    }
+    Ok(())
+}
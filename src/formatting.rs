@@ -0,0 +1,323 @@
+//! Builds the plain and the HTML body of outgoing messages side by side. Everything
+//! interpolated goes through `escape`, so entry names with `&`, `<` or quotes can't
+//! break the markup.
+use super::mozilla::MozData;
+use matrix_sdk::ruma::{events::Mentions, OwnedUserId, UserId};
+use std::{collections::BTreeSet, fmt::Write};
+
+/// Limit for the plain and the HTML body of one message together. Events may be 64 KiB
+/// at most, including JSON escaping, signatures and custom fields.
+pub const MAX_BODY_LEN: usize = 32 * 1024;
+
+/// Escapes text for HTML content as well as attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A message with a plain and an HTML body, and the users it mentions
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    plain: String,
+    html: String,
+    mentioned: BTreeSet<OwnedUserId>,
+}
+
+impl Message {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(mut self, text: &str) -> Self {
+        self.plain += text;
+        self.html += &escape(text);
+        self
+    }
+
+    /// In the plain body only the text shows up, like in most clients
+    pub fn link(mut self, url: &str, text: &str) -> Self {
+        self.plain += text;
+        let _ = write!(
+            self.html,
+            "<a href=\"{}\">{}</a>",
+            escape(url),
+            escape(text)
+        );
+        self
+    }
+
+    /// A pill of `user`, who gets notified if the m.mentions of the message are sent along
+    pub fn mention(mut self, user: &UserId) -> Self {
+        self = self.link(&user.matrix_to_uri().to_string(), user.as_str());
+        self.mentioned.insert(user.to_owned());
+        self
+    }
+
+    pub fn bold(mut self, text: &str) -> Self {
+        self.plain += text;
+        let _ = write!(self.html, "<b>{}</b>", escape(text));
+        self
+    }
+
+    pub fn code(mut self, text: &str) -> Self {
+        self.plain += text;
+        let _ = write!(self.html, "<code>{}</code>", escape(text));
+        self
+    }
+
+    pub fn line_break(mut self) -> Self {
+        self.plain += "\n";
+        self.html += "<br>";
+        self
+    }
+
+    /// Appends `items` as list, one line each in the plain body
+    pub fn list<I: IntoIterator<Item = Message>>(mut self, items: I) -> Self {
+        self.html += "<ul>";
+        for item in items {
+            self.plain += "\n";
+            self.plain += &item.plain;
+            let _ = write!(self.html, "<li>{}</li>", item.html);
+            self.mentioned.extend(item.mentioned);
+        }
+        self.html += "</ul>";
+        self
+    }
+
+    /// Builds a message from a (translated) pattern like "{source} got new uploads",
+    /// with the text around the placeholders escaped
+    pub fn fill(pattern: &str, parts: &[(&str, Message)]) -> Self {
+        let mut message = Self::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 1..start + len];
+            match parts.iter().find(|(x, _)| *x == name) {
+                Some((_, part)) => {
+                    message = message.text(&rest[..start]).append(part.clone());
+                }
+                None => message = message.text(&rest[..start + len + 1]),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        message.text(rest)
+    }
+
+    /// Appends another message
+    pub fn append(mut self, other: Message) -> Self {
+        self.plain += &other.plain;
+        self.html += &other.html;
+        self.mentioned.extend(other.mentioned);
+        self
+    }
+
+    pub fn plain(&self) -> &str {
+        &self.plain
+    }
+
+    pub fn html(&self) -> &str {
+        &self.html
+    }
+
+    /// The m.mentions of the message (MSC3952). Empty, if it mentions nobody, which keeps
+    /// clients from notifying users whose names merely show up in the body.
+    pub fn mentions(&self) -> Mentions {
+        Mentions::with_user_ids(self.mentioned.iter().cloned())
+    }
+
+    pub fn into_parts(self) -> (String, String) {
+        (self.plain, self.html)
+    }
+}
+
+/// Adds `m.mentions` to custom event fields, unless they already have them
+pub fn add_mentions(
+    fields: &mut serde_json::Map<String, serde_json::Value>,
+    mentions: Mentions,
+) -> serde_json::Result<()> {
+    if !fields.contains_key("m.mentions") {
+        fields.insert(String::from("m.mentions"), serde_json::to_value(mentions)?);
+    }
+    Ok(())
+}
+
+/// Custom fields for one message merged from several notifications. Only the mentions
+/// survive the merge, the other fields belong to one notification each.
+pub fn merge_mentions<'a>(
+    fields: impl IntoIterator<Item = &'a serde_json::Map<String, serde_json::Value>>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let user_ids: BTreeSet<OwnedUserId> = fields
+        .into_iter()
+        .filter_map(|x| x.get("m.mentions")?.get("user_ids").cloned())
+        .filter_map(|x| serde_json::from_value::<Vec<OwnedUserId>>(x).ok())
+        .flatten()
+        .collect();
+    let mut merged = serde_json::Map::new();
+    merged.insert(
+        String::from("m.mentions"),
+        serde_json::json!({ "user_ids": user_ids }),
+    );
+    merged
+}
+
+/// The name of a subscription, linked to its directory
+pub fn source_link(source: &MozData) -> Message {
+    let url = format!("{}/{}/", source.base_url, source.url_part);
+    Message::new().link(&url, &source.url_part)
+}
+
+fn html_len(text: &str) -> usize {
+    text.chars()
+        .map(|c| match c {
+            '&' => 5,
+            '<' | '>' => 4,
+            '"' => 6,
+            '\'' => 5,
+            '\n' => 4,
+            c => c.len_utf8(),
+        })
+        .sum()
+}
+
+/// Splits bodies too long for one event into several messages. The parts are cut at
+/// whitespace, where possible, and lose their HTML formatting, as the HTML can't be cut
+/// safely in between tags. `reserved` is what the rest of the first event takes up, e.g.
+/// its custom fields.
+pub fn split(plain: &str, html: &str, reserved: usize) -> Vec<(String, String)> {
+    let mut limit = MAX_BODY_LEN.saturating_sub(reserved);
+    if plain.len() + html.len() <= limit {
+        return vec![(plain.to_string(), html.to_string())];
+    }
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut len = 0;
+    for word in plain.split_inclusive(char::is_whitespace) {
+        let word_len = word.len() + html_len(word);
+        if len + word_len > limit && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            len = 0;
+            limit = MAX_BODY_LEN;
+        }
+        if word_len <= limit {
+            chunk += word;
+            len += word_len;
+            continue;
+        }
+        // A single word without any whitespace to cut at
+        for c in word.chars() {
+            let c_len = c.len_utf8() + html_len(c.encode_utf8(&mut [0; 4]));
+            if len + c_len > limit {
+                chunks.push(std::mem::take(&mut chunk));
+                len = 0;
+                limit = MAX_BODY_LEN;
+            }
+            chunk.push(c);
+            len += c_len;
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+        .into_iter()
+        .filter(|chunk| !chunk.trim().is_empty())
+        .map(|chunk| {
+            let chunk = chunk.trim_end().to_string();
+            let html = escape(&chunk).replace('\n', "<br>");
+            (chunk, html)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn keeps_short_bodies() {
+        assert_eq!(
+            split("hello", "<b>hello</b>", 0),
+            vec![(String::from("hello"), String::from("<b>hello</b>"))]
+        );
+    }
+
+    #[test]
+    fn splits_long_bodies_at_whitespace() {
+        let plain = "word ".repeat(20_000);
+        let chunks = split(&plain, &plain, 0);
+        assert!(chunks.len() > 1);
+        for (plain, html) in &chunks {
+            assert!(plain.len() + html.len() <= MAX_BODY_LEN);
+            assert!(plain.split_whitespace().all(|x| x == "word"));
+        }
+        let words: usize = chunks
+            .iter()
+            .map(|(plain, _)| plain.split_whitespace().count())
+            .sum();
+        assert_eq!(words, 20_000);
+    }
+
+    #[test]
+    fn splits_long_words() {
+        let plain = "<".repeat(MAX_BODY_LEN);
+        let chunks = split(&plain, &escape(&plain), 0);
+        assert!(chunks.len() > 1);
+        for (plain, html) in &chunks {
+            assert!(plain.len() + html.len() <= MAX_BODY_LEN);
+            assert_eq!(*html, escape(plain));
+        }
+        let joined: String = chunks.into_iter().map(|(plain, _)| plain).collect();
+        assert_eq!(joined, plain);
+    }
+
+    #[test]
+    fn leaves_room_for_reserved() {
+        assert_eq!(
+            split("hello", "<b>hello</b>", MAX_BODY_LEN),
+            vec![(String::from("hello"), String::from("hello"))]
+        );
+    }
+
+    #[test]
+    fn fills_patterns() {
+        let message = Message::fill(
+            "{source} got {count} & more",
+            &[
+                ("source", Message::new().link("https://x/?a=1&b=2", "a<b")),
+                ("count", Message::new().bold("3")),
+            ],
+        );
+        assert_eq!(message.plain(), "a<b got 3 & more");
+        assert_eq!(
+            message.html(),
+            "<a href=\"https://x/?a=1&amp;b=2\">a&lt;b</a> got <b>3</b> &amp; more"
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        let message = Message::fill("{unknown} <x> {oops", &[]);
+        assert_eq!(message.plain(), "{unknown} <x> {oops");
+        assert_eq!(message.html(), "{unknown} &lt;x&gt; {oops");
+    }
+}
@@ -0,0 +1,143 @@
+//! Translations of everything the bot writes itself. The catalogs in `locales/` map the
+//! English text (gettext-style) to the translation, with `{name}` placeholders filled in
+//! by `tr!`. Missing translations fall back to English.
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync::OnceLock};
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Language {
+    #[default]
+    En,
+    De,
+    Fr,
+}
+
+impl Language {
+    pub fn parse(code: &str) -> anyhow::Result<Self> {
+        match code.to_lowercase().as_str() {
+            "en" | "english" => Ok(Self::En),
+            "de" | "german" | "deutsch" => Ok(Self::De),
+            "fr" | "french" | "français" => Ok(Self::Fr),
+            _ => anyhow::bail!("Unknown language {code}, expected en, de or fr"),
+        }
+    }
+
+    fn catalog_source(self) -> Option<&'static str> {
+        match self {
+            Self::En => None,
+            Self::De => Some(include_str!("../locales/de.json")),
+            Self::Fr => Some(include_str!("../locales/fr.json")),
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::En => "en",
+            Self::De => "de",
+            Self::Fr => "fr",
+        })
+    }
+}
+
+type Catalog = HashMap<String, String>;
+
+fn catalog(lang: Language) -> Option<&'static Catalog> {
+    static CATALOGS: OnceLock<HashMap<Language, Catalog>> = OnceLock::new();
+    CATALOGS
+        .get_or_init(|| {
+            [Language::De, Language::Fr]
+                .into_iter()
+                .filter_map(|lang| {
+                    let source = lang.catalog_source()?;
+                    match serde_json::from_str(source) {
+                        Ok(catalog) => Some((lang, catalog)),
+                        Err(e) => {
+                            eprintln!("Ignoring broken catalog of language {lang}: {e}");
+                            None
+                        }
+                    }
+                })
+                .collect()
+        })
+        .get(&lang)
+}
+
+/// The translation of `msgid`, or `msgid` itself
+pub fn translate(lang: Language, msgid: &'static str) -> &'static str {
+    catalog(lang)
+        .and_then(|x| x.get(msgid))
+        .map(String::as_str)
+        .unwrap_or(msgid)
+}
+
+/// Translates `msgid` and fills in its placeholders. Use `tr!` instead.
+pub fn translate_with(lang: Language, msgid: &'static str, args: &[(&str, String)]) -> String {
+    let mut text = translate(lang, msgid).to_string();
+    for (name, value) in args {
+        text = text.replace(&format!("{{{name}}}"), value);
+    }
+    text
+}
+
+/// `tr!(lang, "Unknown subscription {name}", name)` or with `name = expr`
+macro_rules! tr {
+    (@value $key:ident $value:expr) => {
+        $value
+    };
+    (@value $key:ident) => {
+        $key
+    };
+    ($lang:expr, $msgid:literal $(, $key:ident $(= $value:expr)?)* $(,)?) => {
+        $crate::i18n::translate_with(
+            $lang,
+            $msgid,
+            &[$((stringify!($key), $crate::i18n::tr!(@value $key $($value)?).to_string())),*],
+        )
+    };
+}
+pub(crate) use tr;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholders(text: &str) -> Vec<&str> {
+        let mut names: Vec<_> = text
+            .split('{')
+            .skip(1)
+            .filter_map(|x| x.split_once('}'))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn translations_keep_the_placeholders() {
+        for lang in [Language::De, Language::Fr] {
+            let catalog = catalog(lang).expect("catalog parses");
+            for (msgid, translation) in catalog {
+                assert_eq!(
+                    placeholders(msgid),
+                    placeholders(translation),
+                    "{lang}: {msgid}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        let name = "nightly";
+        assert_eq!(
+            tr!(Language::En, "Unknown subscription {name}", name),
+            "Unknown subscription nightly"
+        );
+        assert_eq!(translate(Language::De, "no such msgid"), "no such msgid");
+    }
+}
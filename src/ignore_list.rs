@@ -0,0 +1,46 @@
+//! Users whose messages and invites the bot doesn't react to at all. Besides the patterns
+//! from the config, users ignored with !ignore end up in the standard `m.ignored_user_list`
+//! of the bot account, so the homeserver stops sending us their events, too.
+use super::SharedState;
+use matrix_sdk::{
+    ruma::{
+        events::{ignored_user_list::IgnoredUserListEventContent, GlobalAccountDataEventType},
+        UserId,
+    },
+    Client,
+};
+
+/// Loads the ignored users of the bot account
+pub async fn restore(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let Some(raw) = client
+        .account()
+        .fetch_account_data(GlobalAccountDataEventType::IgnoredUserList)
+        .await?
+    else {
+        return Ok(());
+    };
+    let content = raw.deserialize_as::<IgnoredUserListEventContent>()?;
+    ctx.ignored
+        .lock()
+        .unwrap()
+        .extend(content.ignored_users.into_keys());
+    Ok(())
+}
+
+/// Returns false, if the user was already ignored
+pub async fn ignore(client: &Client, ctx: &SharedState, user: &UserId) -> anyhow::Result<bool> {
+    if !ctx.ignored.lock().unwrap().insert(user.to_owned()) {
+        return Ok(false);
+    }
+    client.account().ignore_user(user).await?;
+    Ok(true)
+}
+
+/// Returns false, if the user wasn't ignored with !ignore
+pub async fn unignore(client: &Client, ctx: &SharedState, user: &UserId) -> anyhow::Result<bool> {
+    if !ctx.ignored.lock().unwrap().remove(user) {
+        return Ok(false);
+    }
+    client.account().unignore_user(user).await?;
+    Ok(true)
+}
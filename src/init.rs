@@ -0,0 +1,178 @@
+//! The `init` subcommand: asks for the essentials, writes a config file with them and
+//! logs in once, so the bot can be started right away. `generate-config` shows all the
+//! other options.
+use super::{extract_instance, instance_lock, matrix, private_files};
+use config::Config;
+use matrix_sdk::ruma::UserId;
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+use tokio::sync::mpsc;
+
+fn read_answer(prompt: &str) -> anyhow::Result<String> {
+    print!("{prompt}: ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        anyhow::bail!("Aborted");
+    }
+    Ok(answer.trim().to_string())
+}
+
+fn ask(question: &str, default: Option<&str>) -> anyhow::Result<String> {
+    loop {
+        let answer = match default {
+            Some(default) => read_answer(&format!("{question} [{default}]"))?,
+            None => read_answer(question)?,
+        };
+        match (answer.is_empty(), default) {
+            (true, Some(default)) => return Ok(default.to_string()),
+            (true, None) => continue,
+            (false, _) => return Ok(answer),
+        }
+    }
+}
+
+/// Empty answers are None
+fn ask_optional(question: &str) -> anyhow::Result<Option<String>> {
+    Ok(Some(read_answer(question)?).filter(|x| !x.is_empty()))
+}
+
+pub fn ask_yes_no(question: &str, default: bool) -> anyhow::Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        match read_answer(&format!("{question} [{hint}]"))?
+            .to_lowercase()
+            .as_str()
+        {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer yes or no"),
+        }
+    }
+}
+
+/// A TOML string. JSON escapes are valid in TOML basic strings.
+fn quote(value: &str) -> String {
+    serde_json::to_string(value).expect("Strings always serialize")
+}
+
+pub async fn run(path: &Path, profile: Option<&str>, force: bool) -> anyhow::Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!(
+            "{} already exists, pass --force to replace it",
+            path.display()
+        );
+    }
+    let homeserver_url = loop {
+        let url = ask("Homeserver URL", Some("https://matrix.org"))?;
+        match reqwest::Url::parse(&url) {
+            Ok(_) => break url,
+            Err(e) => println!("Invalid URL: {e}"),
+        }
+    };
+    let username = ask("Username of the bot account", None)?;
+    let password = rpassword::prompt_password_stderr(&format!("Password for {username}: "))?;
+    let store_password = ask_yes_no(
+        "Store the password in the config? Needed to delete old devices, and to log in again if the session gets lost",
+        false,
+    )?;
+
+    let mut login = vec![
+        format!("homeserver_url = {}", quote(&homeserver_url)),
+        format!("username = {}", quote(&username)),
+    ];
+    if store_password {
+        login.push(format!("password = {}", quote(&password)));
+    }
+    if ask_yes_no(
+        "Keep the session in the Secret Service (the desktop keyring)? Otherwise it's a plain file",
+        false,
+    )? {
+        login.push(String::from("use_secret_service = true"));
+    } else {
+        login.push(String::from("use_secret_service = false"));
+    }
+    if let Some(db_path) =
+        ask_optional("Directory of the session database (empty for the default)")?
+    {
+        login.push(format!("db_path = {}", quote(&db_path)));
+    }
+    let db_pw =
+        rpassword::prompt_password_stderr("Password to encrypt the session database with: ")?;
+    // Otherwise the bot asks for it on every start, which services can't answer
+    login.push(format!("db_pw = {}", quote(&db_pw)));
+
+    // An empty list would let everybody command the bot
+    let user = loop {
+        let user = ask("Your Matrix ID, to allow you to use commands", None)?;
+        match UserId::parse(&user) {
+            Ok(_) => break user,
+            Err(e) => println!("Invalid Matrix ID, like @you:example.org: {e}"),
+        }
+    };
+    let mut config = vec![format!("accept_commands_from = [{}]", quote(&user))];
+    if let Some(room) =
+        ask_optional("Room (ID or alias) to announce to (empty to use !watch later)")?
+    {
+        config.push(format!("rooms = [{}]", quote(&room)));
+    }
+
+    println!("The first subscription, a directory below https://ftp.mozilla.org/pub");
+    let name = loop {
+        let name = ask("Name", Some("ff_rel"))?;
+        if name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+        {
+            break name;
+        }
+        println!("Only letters, digits, _ and - please");
+    };
+    let url_part = ask("Directory", Some("firefox/releases"))?;
+    let query_subdirs = ask_yes_no("Also watch the directories in there?", false)?;
+    let mut subscription = vec![
+        format!("url_part = {}", quote(&url_part)),
+        format!("query_subdirs = {query_subdirs}"),
+    ];
+    if let Some(filter) = ask_optional("Regex the entries have to match (empty for all)")? {
+        regex::Regex::new(&filter)?;
+        subscription.push(format!("filter = {}", quote(&filter)));
+    }
+
+    let content = format!(
+        "# Written by `matrix_mozilla_bot init`. See `matrix_mozilla_bot generate-config` for\n# all options.\n\n[login]\n{}\n\n[config]\n{}\n\n[subscription.{name}]\n{}\n",
+        login.join("\n"),
+        config.join("\n"),
+        subscription.join("\n"),
+    );
+    // It holds the db_pw, and maybe the password
+    private_files::write(path, content)?;
+    println!("Wrote {}", path.display());
+
+    println!("Logging in");
+    let mut settings = Config::builder()
+        .add_source(config::File::from(path))
+        .set_override("login.password", password)?;
+    if let Some(profile) = profile {
+        settings = settings.set_override("profile", profile)?;
+    }
+    let settings = settings.build()?;
+    let (poller, _) = mpsc::unbounded_channel();
+    let (instance, _) = extract_instance(&settings, None, None, poller, true).await?;
+    let _lock = match instance.shared_state.cfg.session_storage.get_session_db() {
+        Some(db) if !force => Some(instance_lock::acquire(&db.db_path)?),
+        _ => None,
+    };
+    matrix::login_only(&instance.shared_state).await?;
+    println!(
+        "Done. Start the bot with `matrix_mozilla_bot{} --config {}`",
+        profile
+            .map(|x| format!(" --profile {x}"))
+            .unwrap_or_default(),
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! Keeps a second copy of the bot off a db_path that is in use. Two clients on the same
+//! SQLite stores corrupt them sooner or later, and both would announce every upload.
+//! Only `run` and the subcommands writing the state or the session take it, the one-off
+//! ones like `send` and `rooms` work next to a running bot.
+//!
+//! The lock is an flock on `bot.lock`, which the OS releases when the process ends, so a
+//! crash leaves nothing stale behind. `--force` skips it, for file systems where it fails.
+use fs2::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, Write},
+    path::Path,
+};
+
+const LOCK_FILE: &str = "bot.lock";
+
+/// Held until dropped
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+pub fn acquire(db_path: &Path) -> anyhow::Result<InstanceLock> {
+    std::fs::create_dir_all(db_path)?;
+    let path = db_path.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    if file.try_lock_exclusive().is_err() {
+        let mut pid = String::new();
+        file.read_to_string(&mut pid)?;
+        anyhow::bail!(
+            "{} is in use by another copy of the bot (PID {}). Stop it, or pass --force if \
+             it really isn't running",
+            db_path.display(),
+            pid.trim()
+        );
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    Ok(InstanceLock { _file: file })
+}
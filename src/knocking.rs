@@ -0,0 +1,113 @@
+//! Knocking on invite-only rooms, for homeservers that support it. Once a room admin
+//! approves the knock, we get invited, accept right away (whoever sent the invite) and
+//! watch the room. Pending knocks are persisted like the watch list, so approvals while
+//! the bot was restarting still count.
+use super::{matrix::join_with_retry, watch_list, SharedState};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::{
+        api::client::knock::knock_room,
+        events::{macros::EventContent, room::member::StrippedRoomMemberEvent},
+        OwnedRoomId, RoomOrAliasId,
+    },
+    Client, RoomState,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use tokio::time::{sleep, Duration};
+
+const KNOCKED_DOCUMENT: &str = "knocked";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.knocked", kind = GlobalAccountData)]
+pub struct KnockedEventContent {
+    pub rooms: BTreeSet<OwnedRoomId>,
+}
+
+pub async fn store(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content = KnockedEventContent {
+        rooms: ctx.knocked.lock().unwrap().clone(),
+    };
+    watch_list::store_document(client, ctx, KNOCKED_DOCUMENT, content).await
+}
+
+/// Loads the knocks that weren't approved before the last shutdown
+pub async fn restore(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content: KnockedEventContent =
+        watch_list::restore_document(client, ctx, KNOCKED_DOCUMENT).await?;
+    ctx.knocked.lock().unwrap().extend(content.rooms);
+    // Approved while we weren't running, the invite came with the initial sync
+    for room in client.invited_rooms() {
+        if ctx.knocked.lock().unwrap().contains(room.room_id()) {
+            tokio::spawn(join_approved(client.clone(), ctx.clone(), room));
+        }
+    }
+    Ok(())
+}
+
+/// Knocks on a room, retrying like autojoin does while the homeserver can't be reached.
+/// Errors of the homeserver itself (e.g. the room doesn't allow knocking) end it right away.
+pub async fn knock(
+    client: &Client,
+    ctx: &SharedState,
+    room: &RoomOrAliasId,
+) -> anyhow::Result<OwnedRoomId> {
+    let mut delay = 2;
+    loop {
+        let request = knock_room::v3::Request::new(room.to_owned());
+        match client.send(request, None).await {
+            Ok(response) => {
+                println!("Knocked on {room}, it gets watched once we are let in");
+                ctx.knocked.lock().unwrap().insert(response.room_id.clone());
+                if let Err(e) = store(client, ctx).await {
+                    eprintln!("Failed to persist the knocked rooms: {e:?}");
+                }
+                return Ok(response.room_id);
+            }
+            Err(err) if err.client_api_error_kind().is_some() || delay > 3600 => {
+                return Err(err.into())
+            }
+            Err(err) => {
+                eprintln!("Failed to knock on {room} ({err:?}), retrying in {delay}s");
+                sleep(Duration::from_secs(delay)).await;
+                delay *= 2;
+            }
+        }
+    }
+}
+
+/// Completes the join of rooms whose knock got approved
+pub async fn on_invite(
+    room_member: StrippedRoomMemberEvent,
+    client: Client,
+    room: Room,
+    ctx: Ctx<SharedState>,
+) {
+    if Some(room_member.state_key.as_ref()) != client.user_id()
+        || room.state() != RoomState::Invited
+        || !ctx.knocked.lock().unwrap().contains(room.room_id())
+    {
+        return;
+    }
+    tokio::spawn(join_approved(client, ctx.0, room));
+}
+
+async fn join_approved(client: Client, ctx: SharedState, room: Room) {
+    println!("Our knock on {} was approved, joining", room.room_id());
+    if !join_with_retry(&room).await {
+        return;
+    }
+    ctx.knocked.lock().unwrap().remove(room.room_id());
+    if let Err(e) = store(&client, &ctx).await {
+        eprintln!("Failed to persist the knocked rooms: {e:?}");
+    }
+    ctx.rooms
+        .lock()
+        .unwrap()
+        .entry(room.room_id().to_owned())
+        .or_default();
+    if let Err(e) = watch_list::store(&client, &ctx).await {
+        eprintln!("Failed to store the watched rooms: {e:?}");
+    }
+}
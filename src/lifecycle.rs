@@ -0,0 +1,100 @@
+//! Optional announcements when the bot starts and when it shuts down cleanly, so operators
+//! see right away whether a restart or deploy worked.
+use super::{
+    formatting::Message,
+    i18n::{self, Language},
+    room_settings::{self, NotificationType},
+    send_to_room, SharedState,
+};
+use matrix_sdk::{ruma::OwnedRoomId, Client};
+
+/// Where to announce starts and shutdowns, see `config.announce_lifecycle`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LifecycleAnnouncements {
+    #[default]
+    Off,
+    AdminRoom,
+    /// The admin room and all watched rooms
+    AllRooms,
+}
+
+impl LifecycleAnnouncements {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "off" => Ok(Self::Off),
+            "admin_room" => Ok(Self::AdminRoom),
+            "all_rooms" => Ok(Self::AllRooms),
+            _ => anyhow::bail!(
+                "Unknown value {value} for announce_lifecycle, expected off, admin_room or all_rooms"
+            ),
+        }
+    }
+}
+
+fn rooms(ctx: &SharedState) -> Vec<OwnedRoomId> {
+    let mut rooms: Vec<_> = ctx.cfg.admin_room.iter().cloned().collect();
+    if ctx.cfg.announce_lifecycle == LifecycleAnnouncements::AllRooms {
+        rooms.extend(ctx.rooms.lock().unwrap().keys().cloned());
+        rooms.sort();
+        rooms.dedup();
+    }
+    rooms
+}
+
+async fn announce(client: &Client, ctx: &SharedState, message: impl Fn(Language) -> Message) {
+    if ctx.cfg.announce_lifecycle == LifecycleAnnouncements::Off {
+        return;
+    }
+    for room_id in rooms(ctx) {
+        let lang = room_settings::get(client, &room_id)
+            .await
+            .language
+            .unwrap_or(ctx.cfg.language);
+        let message = message(lang);
+        if let Err(e) = send_to_room(
+            client,
+            &room_id,
+            NotificationType::Notice,
+            message.plain(),
+            message.html(),
+        )
+        .await
+        {
+            eprintln!("Failed to announce to {room_id}: {e:?}");
+        }
+    }
+}
+
+/// Announces the start, with the version and a summary of the config
+pub async fn announce_startup(client: &Client, ctx: &SharedState) {
+    let subscriptions = ctx.sources.lock().unwrap().len();
+    let rooms = ctx.rooms.lock().unwrap().len();
+    let interval = ctx.default_interval().as_secs() / 60;
+    let message = |lang| {
+        Message::fill(
+            i18n::translate(
+                lang,
+                "Started {version}: {subscriptions} subscriptions, {rooms} watched rooms, polling every {interval}m by default",
+            ),
+            &[
+                (
+                    "version",
+                    Message::new().code(concat!(
+                        env!("CARGO_PKG_NAME"),
+                        " ",
+                        env!("CARGO_PKG_VERSION")
+                    )),
+                ),
+                ("subscriptions", Message::new().text(&subscriptions.to_string())),
+                ("rooms", Message::new().text(&rooms.to_string())),
+                ("interval", Message::new().text(&interval.to_string())),
+            ],
+        )
+    };
+    announce(client, ctx, message).await;
+}
+
+pub async fn announce_shutdown(client: &Client, ctx: &SharedState) {
+    let message = |lang| Message::new().text(i18n::translate(lang, "Shutting down"));
+    announce(client, ctx, message).await;
+}
@@ -1,24 +1,120 @@
-use config::{Config, ConfigError, Value};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use config::{Config, ConfigError};
 use matrix_sdk::{
-    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId, UserId},
-    RoomState,
+    encryption::verification::SasVerification,
+    room::Room,
+    ruma::{
+        EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedRoomOrAliasId, OwnedUserId, RoomId,
+        RoomOrAliasId, UserId,
+    },
+    Client,
 };
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 use tokio::{
-    fs,
+    sync::{mpsc, oneshot},
     time::{sleep, Duration},
 };
 
 mod matrix;
-use matrix::login_and_sync;
+use matrix::{edit_with_fields, login_and_sync, send_to_room, send_to_room_with_fields};
 
 mod mozilla;
-use mozilla::MozData;
+use mozilla::{compare_versions, HttpCache, MozData};
+mod outbox;
+use outbox::PendingNotification;
+
+mod retention;
+use retention::SentNotifications;
+mod room_settings;
+use room_settings::{MessageFormat, NotificationType};
+mod rooms;
+
+mod resources;
+use resources::{ResourceLimits, ResourceTracker};
+
+mod quiet_hours;
+mod reload;
+use quiet_hours::QuietHours;
+
+mod scheduler;
+mod send_queue;
+use scheduler::{Schedule, Scheduler};
+
+mod signing;
+use signing::{Announcement, AnnouncementSigner};
+mod spaces;
+
+mod templates;
+use templates::{NotificationTemplate, TemplateVars};
+
+mod admin;
+use admin::ReportThrottle;
+mod alerts;
+mod aliases;
+use alerts::RoomAlerts;
+#[cfg(feature = "appservice")]
+mod appservice;
+mod attachments;
+mod bot_api;
+mod check_config;
+mod cli;
+use cli::{Cli, DevicesAction, RoomsAction, StateAction, Subcommand};
+mod config_file;
+use config_file::{
+    optional_section, section, AppServiceSection, ConfigSection, LimitsSection, LoginSection,
+    RoomSection, SessionBackend, SubscriptionSection,
+};
+mod commands;
+mod devices;
+mod empty_rooms;
+mod encryption;
+mod formatting;
+use formatting::Message;
+mod i18n;
+use i18n::Language;
+mod lifecycle;
+use lifecycle::LifecycleAnnouncements;
+mod ignore_list;
+mod init;
+mod instance_lock;
+mod knocking;
+mod oidc;
+mod personal;
+mod pins;
+mod private_files;
+use personal::UserSubscriptions;
+mod migrate_session;
+mod reactions;
+mod sealed;
+mod session_store;
+mod state_db;
+use session_store::{
+    Ephemeral, KeyringStorage, PlainSessionStorage, SecretServiceStorage, SessionDB, SessionStore,
+};
+use state_db::StateDb;
+mod state;
+mod subscriptions;
+mod threads;
+use subscriptions::{RuntimeSubscription, SourceOverrides};
+mod upgrades;
+mod user_pattern;
+use user_pattern::UserPattern;
+mod verification;
+mod watch_list;
+use watch_list::{WatchListStorage, WatchedRoom};
+
+/// Notifications we remember the subscription of, for reactions on them
+const MAX_TRACKED_NOTIFICATIONS: usize = 500;
+/// Name of the state DB document with the held-back notifications
+const QUEUED_DOCUMENT: &str = "quiet_hours_queue";
 
 #[allow(unused)]
 #[derive(Debug, Clone)]
@@ -26,63 +122,151 @@ enum LoginData {
     UsernamePassword(String, String),
     #[cfg(feature = "sso-login")]
     Sso,
+    /// Device authorization grant against the homeserver's OIDC provider
+    Oidc {
+        client_id: Option<String>,
+    },
+    /// Run as application service instead of logging in
+    #[cfg(feature = "appservice")]
+    AppService(AppServiceConfig),
 }
 
+#[cfg(feature = "appservice")]
 #[derive(Debug, Clone)]
-pub struct SessionDB {
-    db_path: PathBuf, // TODO: Make this an enum and add more storage-backends
-    db_pw: String,
+pub struct AppServiceConfig {
+    /// Registration YAML, generated on first start if missing
+    registration: PathBuf,
+    server_name: String,
+    sender_localpart: String,
+    /// Where the homeserver reaches us
+    url: String,
+    listen_host: String,
+    listen_port: u16,
 }
 
-#[derive(Debug, Clone)]
-pub struct PlainSessionStorage {
-    session_path: PathBuf,
-}
+/// End of a pause, None if it lasts until resumed
+type PausedUntil = Option<DateTime<Utc>>;
 
-#[derive(Debug, Clone)]
-pub enum SessionStorage {
-    Ephemeral,
-    Plain(SessionDB, PlainSessionStorage),
-    SecretService(SessionDB),
+/// Announcements are held back while paused. Polling continues, so nothing piles up.
+#[derive(Debug, Default)]
+struct Pauses {
+    all: Option<PausedUntil>,
+    sources: HashMap<String, PausedUntil>,
 }
 
-impl SessionStorage {
-    fn session_store_exists(&self) -> bool {
-        match self {
-            SessionStorage::Ephemeral => false,
-            SessionStorage::Plain(db, session) => {
-                db.db_path.exists() && session.session_path.exists()
-            }
-            SessionStorage::SecretService(db) => db.db_path.exists(),
+impl Pauses {
+    fn drop_expired(&mut self) {
+        let now = Utc::now();
+        let expired = |until: &PausedUntil| until.map(|x| x <= now).unwrap_or(false);
+        if self.all.as_ref().map(expired).unwrap_or(false) {
+            self.all = None;
         }
+        self.sources.retain(|_, until| !expired(until));
     }
+}
 
-    fn get_session_db(&self) -> Option<SessionDB> {
-        match self {
-            SessionStorage::Ephemeral => None,
-            SessionStorage::Plain(db, _) | SessionStorage::SecretService(db) => Some(db.clone()),
-        }
-    }
+/// Settings that only apply to a single room
+#[derive(Debug, Clone, Default)]
+struct RoomConfig {
+    quiet_hours: Option<QuietHours>,
+    /// Subscriptions routed to this room. The room gets them without !watch, plus the
+    /// ones listing it in their own `rooms`, and no others even if it is watched.
+    /// Only names of the config's subscriptions are accepted.
+    sources: Option<Vec<String>>,
+    /// Replaces the global accept_commands_from in this room
+    accept_commands_from: Option<Vec<UserPattern>>,
 }
 
 #[derive(Debug, Clone)]
 struct BotConfig {
     login_data: LoginData,
     homeserver_url: String,
-    session_storage: SessionStorage,
+    session_storage: Arc<dyn SessionStore>,
     ignore_own_messages: bool,
     autojoin: bool,
-    accept_commands_from: Vec<OwnedUserId>,
+    /// Shared by all clones, so reloading the config reaches everyone holding one
+    reloadable: Arc<Mutex<ReloadableConfig>>,
+    room_configs: HashMap<OwnedRoomId, RoomConfig>,
+    /// Rooms joined and watched at startup, by ID or alias
+    rooms: Vec<OwnedRoomOrAliasId>,
+    /// Spaces whose rooms are all joined and watched
+    spaces: Vec<OwnedRoomOrAliasId>,
+    admin_room: Option<OwnedRoomId>,
+    /// Minimum time between two reports of the same kind in the admin room
+    admin_report_interval: Duration,
+    announce_lifecycle: LifecycleAnnouncements,
+    /// Undelivered notifications older than this are dropped
+    outbox_ttl: Duration,
+    max_consecutive_failures: usize,
+    startup_quiet_period: Duration,
+    bootstrap_cross_signing: bool,
+    key_backup: bool,
+    limits: ResourceLimits,
+    watch_list_storage: WatchListStorage,
+    command_prefix: String,
+    /// Anyone may DM the bot and follow subscriptions there
+    personal_subscriptions: bool,
+    /// Of rooms without their own language setting
+    language: Language,
+    /// Leave rooms once no people are left in them
+    leave_empty_rooms: bool,
+    /// Display name of the devices created by logging in
+    device_name: String,
+}
+
+/// The parts of the config besides the subscriptions that a reload on SIGHUP applies
+#[derive(Debug, Clone)]
+struct ReloadableConfig {
+    accept_commands_from: Vec<UserPattern>,
+    default_interval: Duration,
+    /// Users whose messages and invites are ignored, in addition to the ones ignored at runtime
+    ignore_users: Vec<UserPattern>,
+    /// Other bots, which don't count as people for leave_empty_rooms
+    bot_users: Vec<UserPattern>,
+    /// Who may use admin commands in the admin room. Trusted users there, if empty.
+    admins: Vec<UserPattern>,
+}
+
+impl ReloadableConfig {
+    /// Other bots may add subscriptions and trigger fetches, so unlike commands an empty
+    /// accept_commands_from trusts nobody with the bot API
+    fn trusts_bot_api(&self, user: &UserId) -> bool {
+        self.admins.iter().any(|x| x.matches(user))
+            || self.accept_commands_from.iter().any(|x| x.matches(user))
+    }
 }
 
 impl BotConfig {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         login_data: LoginData,
         homeserver_url: String,
-        session_storage: SessionStorage,
+        session_storage: Arc<dyn SessionStore>,
         ignore_own_messages: bool,
         autojoin: bool,
-        accept_commands_from: Vec<OwnedUserId>,
+        accept_commands_from: Vec<UserPattern>,
+        room_configs: HashMap<OwnedRoomId, RoomConfig>,
+        rooms: Vec<OwnedRoomOrAliasId>,
+        spaces: Vec<OwnedRoomOrAliasId>,
+        admin_room: Option<OwnedRoomId>,
+        admin_report_interval: Duration,
+        announce_lifecycle: LifecycleAnnouncements,
+        outbox_ttl: Duration,
+        max_consecutive_failures: usize,
+        startup_quiet_period: Duration,
+        bootstrap_cross_signing: bool,
+        key_backup: bool,
+        default_interval: Duration,
+        limits: ResourceLimits,
+        watch_list_storage: WatchListStorage,
+        command_prefix: String,
+        personal_subscriptions: bool,
+        ignore_users: Vec<UserPattern>,
+        language: Language,
+        leave_empty_rooms: bool,
+        bot_users: Vec<UserPattern>,
+        device_name: String,
+        admins: Vec<UserPattern>,
     ) -> Self {
         Self {
             login_data,
@@ -90,7 +274,88 @@ impl BotConfig {
             session_storage,
             ignore_own_messages,
             autojoin,
-            accept_commands_from,
+            reloadable: Arc::new(Mutex::new(ReloadableConfig {
+                accept_commands_from,
+                default_interval,
+                ignore_users,
+                bot_users,
+                admins,
+            })),
+            room_configs,
+            rooms,
+            spaces,
+            admin_room,
+            admin_report_interval,
+            announce_lifecycle,
+            outbox_ttl,
+            max_consecutive_failures,
+            startup_quiet_period,
+            bootstrap_cross_signing,
+            key_backup,
+            limits,
+            watch_list_storage,
+            command_prefix,
+            personal_subscriptions,
+            language,
+            leave_empty_rooms,
+            device_name,
+        }
+    }
+}
+
+/// A notification that is held back until the quiet hours of its room are over, or until
+/// the next digest of the room is due
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct QueuedNotification {
+    msgtype: NotificationType,
+    plain: String,
+    html: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+    attachments: Vec<attachments::Attachment>,
+    /// Unix timestamp
+    queued_at: i64,
+}
+
+/// Held-back notifications per room, in the order they were queued
+type QueuedNotifications = HashMap<OwnedRoomId, Vec<QueuedNotification>>;
+
+/// Health and settings of a single subscription, as seen by the polling loop
+#[derive(Debug, Clone)]
+struct SourceStatus {
+    url_part: String,
+    filter: Option<String>,
+    schedule: String,
+    /// Whether the subscription follows changes of the default interval
+    uses_default_interval: bool,
+    rooms: Option<Vec<OwnedRoomId>>,
+    consecutive_failures: usize,
+    recent_errors: Vec<(DateTime<Utc>, String)>,
+    disabled: bool,
+    last_success: Option<DateTime<Utc>>,
+    /// Unlike recent_errors, this is kept after the next successful poll
+    last_error: Option<(DateTime<Utc>, String)>,
+    last_change: Option<DateTime<Utc>>,
+    entries: usize,
+    latest: Option<String>,
+}
+
+impl SourceStatus {
+    fn new(source: &MozData, schedule: &Schedule) -> Self {
+        Self {
+            url_part: source.url_part.clone(),
+            filter: source.filter.as_ref().map(|x| x.as_str().to_string()),
+            schedule: schedule.to_string(),
+            // The config only allows cron expressions as schedule of a single subscription
+            uses_default_interval: matches!(schedule, Schedule::Interval(_)),
+            rooms: source.rooms.clone(),
+            consecutive_failures: 0,
+            recent_errors: Vec::new(),
+            disabled: false,
+            last_success: None,
+            last_error: None,
+            last_change: None,
+            entries: 0,
+            latest: None,
         }
     }
 }
@@ -98,187 +363,2173 @@ impl BotConfig {
 #[derive(Clone)]
 pub struct SharedState {
     cfg: BotConfig,
-    rooms: Arc<Mutex<HashSet<OwnedRoomId>>>,
+    rooms: Arc<Mutex<HashMap<OwnedRoomId, WatchedRoom>>>,
+    /// Mutes of rooms that get subscriptions without being watched
+    routed_mutes: Arc<Mutex<BTreeMap<OwnedRoomId, BTreeSet<String>>>>,
+    queued: Arc<Mutex<QueuedNotifications>>,
+    sources: Arc<Mutex<HashMap<String, SourceStatus>>>,
+    started: DateTime<Utc>,
+    poller: mpsc::UnboundedSender<PollerCommand>,
+    resources: Arc<ResourceTracker>,
+    signer: Option<Arc<AnnouncementSigner>>,
+    runtime_subscriptions: Arc<Mutex<BTreeMap<String, RuntimeSubscription>>>,
+    paused: Arc<Mutex<Pauses>>,
+    overrides: Arc<Mutex<SourceOverrides>>,
+    personal: Arc<Mutex<BTreeMap<OwnedUserId, UserSubscriptions>>>,
+    alerts: Arc<Mutex<BTreeMap<OwnedRoomId, RoomAlerts>>>,
+    /// Users ignored with !ignore
+    ignored: Arc<Mutex<BTreeSet<OwnedUserId>>>,
+    /// Our latest notifications and the subscription each one was about
+    notifications: Arc<Mutex<VecDeque<(OwnedEventId, String)>>>,
+    /// Thread roots per room and subscription, see the threads setting
+    thread_roots: Arc<Mutex<HashMap<(OwnedRoomId, String), OwnedEventId>>>,
+    /// Notifications that get edited by the next one, for update_in_place subscriptions
+    editable: Arc<Mutex<HashMap<(OwnedRoomId, String), OwnedEventId>>>,
+    /// Pinned notifications per room and subscription, for subscriptions with pin
+    pinned: Arc<Mutex<HashMap<(OwnedRoomId, String), OwnedEventId>>>,
+    /// Throttling of the reports in the admin room, by kind of report
+    admin_reports: Arc<Mutex<HashMap<String, ReportThrottle>>>,
+    /// Notifications waiting for another delivery attempt
+    outbox: Arc<Mutex<Vec<PendingNotification>>>,
+    /// Held while the outbox gets delivered, whose notifications are out of `outbox` then
+    outbox_flush: Arc<tokio::sync::Mutex<()>>,
+    /// Set while deliveries fail, see outbox::suspended
+    delivery_failing_since: Arc<Mutex<Option<DateTime<Utc>>>>,
+    /// Joined spaces from config.spaces and their subspaces
+    spaces: Arc<Mutex<BTreeSet<OwnedRoomId>>>,
+    /// Joined rooms from config.rooms and config.spaces
+    configured_rooms: Arc<Mutex<BTreeSet<OwnedRoomId>>>,
+    /// Rooms we knocked on, which get joined and watched once the knock is approved
+    knocked: Arc<Mutex<BTreeSet<OwnedRoomId>>>,
+    /// Our notifications, for redacting them after the retention of their room
+    sent: Arc<Mutex<SentNotifications>>,
+    /// SAS verifications waiting for an admin to compare the emoji, by the other device
+    verifications: Arc<Mutex<HashMap<OwnedDeviceId, SasVerification>>>,
 }
 
 impl SharedState {
-    fn new(cfg: BotConfig) -> Self {
+    fn new(cfg: BotConfig, poller: mpsc::UnboundedSender<PollerCommand>) -> Self {
+        let resources = Arc::new(ResourceTracker::new(cfg.limits));
         Self {
             cfg,
-            rooms: Arc::new(Mutex::new(HashSet::new())),
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            queued: Arc::new(Mutex::new(HashMap::new())),
+            sources: Arc::new(Mutex::new(HashMap::new())),
+            started: Utc::now(),
+            poller,
+            resources,
+            signer: None,
+            runtime_subscriptions: Arc::new(Mutex::new(BTreeMap::new())),
+            paused: Arc::new(Mutex::new(Pauses::default())),
+            overrides: Arc::new(Mutex::new(SourceOverrides::default())),
+            personal: Arc::new(Mutex::new(BTreeMap::new())),
+            alerts: Arc::new(Mutex::new(BTreeMap::new())),
+            routed_mutes: Arc::new(Mutex::new(BTreeMap::new())),
+            ignored: Arc::new(Mutex::new(BTreeSet::new())),
+            notifications: Arc::new(Mutex::new(VecDeque::new())),
+            thread_roots: Arc::new(Mutex::new(HashMap::new())),
+            editable: Arc::new(Mutex::new(HashMap::new())),
+            pinned: Arc::new(Mutex::new(HashMap::new())),
+            admin_reports: Arc::new(Mutex::new(HashMap::new())),
+            outbox: Arc::new(Mutex::new(Vec::new())),
+            outbox_flush: Arc::new(tokio::sync::Mutex::new(())),
+            delivery_failing_since: Arc::new(Mutex::new(None)),
+            spaces: Arc::new(Mutex::new(BTreeSet::new())),
+            configured_rooms: Arc::new(Mutex::new(BTreeSet::new())),
+            knocked: Arc::new(Mutex::new(BTreeSet::new())),
+            sent: Arc::new(Mutex::new(BTreeMap::new())),
+            verifications: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn accepts_commands_from(&self, user: &UserId) -> bool {
+        let accept_commands_from = &self.cfg.reloadable.lock().unwrap().accept_commands_from;
+        accept_commands_from.is_empty() || accept_commands_from.iter().any(|x| x.matches(user))
+    }
+
+    fn accepts_bot_api_from(&self, user: &UserId) -> bool {
+        self.cfg.reloadable.lock().unwrap().trusts_bot_api(user)
+    }
+
+    fn is_ignored(&self, user: &UserId) -> bool {
+        let ignore_users = &self.cfg.reloadable.lock().unwrap().ignore_users;
+        ignore_users.iter().any(|x| x.matches(user)) || self.ignored.lock().unwrap().contains(user)
+    }
+
+    /// Whether the config wants us in the room, through `rooms`, `spaces` or `[[room]]`
+    fn is_configured_room(&self, room_id: &RoomId) -> bool {
+        self.cfg.room_configs.contains_key(room_id)
+            || self.configured_rooms.lock().unwrap().contains(room_id)
+            || self.spaces.lock().unwrap().contains(room_id)
+    }
+
+    fn is_bot_user(&self, user: &UserId) -> bool {
+        let bot_users = &self.cfg.reloadable.lock().unwrap().bot_users;
+        bot_users.iter().any(|x| x.matches(user))
+    }
+
+    /// Like `accepts_commands_from`, but with the list of the `[[room]]` config, if it has
+    /// one. Unlike the global list, an empty one trusts nobody.
+    fn trusted_by_config(&self, room_id: &RoomId, user: &UserId) -> bool {
+        let room_config = self.cfg.room_configs.get(room_id);
+        match room_config.and_then(|x| x.accept_commands_from.as_deref()) {
+            Some(list) => list.iter().any(|x| x.matches(user)),
+            None => self.accepts_commands_from(user),
+        }
+    }
+
+    /// Like `trusted_by_config`, but the list from the settings of the room wins, if it was
+    /// set by someone trusted by the config
+    async fn accepts_commands_in(&self, room: &Room, user: &UserId) -> bool {
+        let room_id = room.room_id();
+        let may_set = |user: &UserId| self.trusted_by_config(room_id, user);
+        match room_settings::accept_commands_from(room, &may_set).await {
+            Some(list) => list.iter().any(|x| x.matches(user)),
+            None => self.trusted_by_config(room_id, user),
         }
     }
+
+    /// Whether `user` may use admin commands in this room: the configured admins in the
+    /// admin room and in DMs with the bot. Without config.admins nobody may.
+    fn accepts_admin_commands_in(&self, room_id: &RoomId, user: &UserId, is_dm: bool) -> bool {
+        let configured_admin = self
+            .cfg
+            .reloadable
+            .lock()
+            .unwrap()
+            .admins
+            .iter()
+            .any(|x| x.matches(user));
+        configured_admin && (is_dm || self.cfg.admin_room.as_deref() == Some(room_id))
+    }
+
+    /// Asks the polling loop to shut the bot down, and start it again if `restart`
+    fn shutdown(&self, restart: bool) -> anyhow::Result<()> {
+        self.poller
+            .send(PollerCommand::Shutdown { restart })
+            .map_err(|_| anyhow::anyhow!("polling loop is not running"))
+    }
+
+    /// Right after startup, announcements can be suppressed while the state catches up
+    fn in_startup_quiet_period(&self) -> bool {
+        chrono::Duration::from_std(self.cfg.startup_quiet_period)
+            .map(|x| Utc::now() < self.started + x)
+            .unwrap_or(false)
+    }
+
+    fn record_notification(&self, event_id: OwnedEventId, source: &str) {
+        let mut notifications = self.notifications.lock().unwrap();
+        if notifications.len() >= MAX_TRACKED_NOTIFICATIONS {
+            notifications.pop_front();
+        }
+        notifications.push_back((event_id, source.to_string()));
+    }
+
+    /// The subscription a notification of ours was about, if it is recent enough
+    fn notification_source(&self, event_id: &EventId) -> Option<String> {
+        self.notifications
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(x, _)| x == event_id)
+            .map(|(_, source)| source.clone())
+    }
+
+    /// Finds a subscription either by its name in the config or by its url_part
+    fn find_source_name(&self, name_or_url_part: &str) -> Option<String> {
+        self.sources
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, status)| *name == name_or_url_part || status.url_part == name_or_url_part)
+            .map(|(name, _)| name.clone())
+    }
+
+    fn is_disabled(&self, name: &str) -> bool {
+        self.sources
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|x| x.disabled)
+            .unwrap_or(false)
+    }
+
+    /// Pauses announcements of one subscription, or all of them if `source` is None
+    fn pause(&self, source: Option<&str>, until: PausedUntil) {
+        let mut paused = self.paused.lock().unwrap();
+        match source {
+            Some(source) => {
+                paused.sources.insert(source.to_string(), until);
+            }
+            None => paused.all = Some(until),
+        }
+    }
+
+    /// Returns false, if there was no such pause
+    fn resume(&self, source: Option<&str>) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        paused.drop_expired();
+        match source {
+            Some(source) => paused.sources.remove(source).is_some(),
+            None => paused.all.take().is_some(),
+        }
+    }
+
+    fn is_paused(&self, source: &str) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        paused.drop_expired();
+        paused.all.is_some() || paused.sources.contains_key(source)
+    }
+
+    /// Whether a subscription announces to the given room, ignoring mutes and the
+    /// room's settings. `source_rooms` are the rooms of the subscription itself.
+    fn announces_to(
+        &self,
+        source: &str,
+        source_rooms: Option<&[OwnedRoomId]>,
+        room_id: &RoomId,
+    ) -> bool {
+        let routed = self
+            .cfg
+            .room_configs
+            .get(room_id)
+            .and_then(|x| x.sources.as_ref());
+        let listed = source_rooms.is_some_and(|x| x.iter().any(|x| x == room_id));
+        match (routed, source_rooms) {
+            (Some(routed), _) => listed || routed.iter().any(|x| x == source),
+            (None, Some(_)) => listed,
+            (None, None) => self.rooms.lock().unwrap().contains_key(room_id),
+        }
+    }
+
+    /// All rooms a subscription announces to
+    fn target_rooms(&self, source: &MozData) -> Vec<OwnedRoomId> {
+        let mut candidates: Vec<_> = match &source.rooms {
+            Some(rooms) => rooms.clone(),
+            None => self.rooms.lock().unwrap().keys().cloned().collect(),
+        };
+        for room_id in self.cfg.room_configs.keys() {
+            if !candidates.contains(room_id) {
+                candidates.push(room_id.clone());
+            }
+        }
+        candidates
+            .into_iter()
+            .filter(|x| self.announces_to(&source.name, source.rooms.as_deref(), x))
+            .collect()
+    }
+
+    fn is_muted(&self, room_id: &RoomId, source: &str) -> bool {
+        let watched = self
+            .rooms
+            .lock()
+            .unwrap()
+            .get(room_id)
+            .map(|x| x.muted.contains(source))
+            .unwrap_or(false);
+        watched
+            || self
+                .routed_mutes
+                .lock()
+                .unwrap()
+                .get(room_id)
+                .is_some_and(|x| x.contains(source))
+    }
+
+    fn record_change(&self, name: &str) {
+        if let Some(status) = self.sources.lock().unwrap().get_mut(name) {
+            status.last_change = Some(Utc::now());
+        }
+    }
+
+    fn record_success(&self, name: &str, entries: usize, latest: Option<&str>) {
+        if let Some(status) = self.sources.lock().unwrap().get_mut(name) {
+            status.consecutive_failures = 0;
+            status.recent_errors.clear();
+            status.last_success = Some(Utc::now());
+            status.entries = entries;
+            status.latest = latest.map(String::from);
+        }
+    }
+
+    /// Returns the error history, if the failure used up the error budget of the
+    /// subscription and it got disabled because of it.
+    fn record_failure(&self, name: &str, error: String) -> Option<Vec<(DateTime<Utc>, String)>> {
+        let mut sources = self.sources.lock().unwrap();
+        let status = sources.get_mut(name)?;
+        status.consecutive_failures += 1;
+        status.last_error = Some((Utc::now(), error.clone()));
+        status.recent_errors.push((Utc::now(), error));
+        if status.consecutive_failures >= self.cfg.max_consecutive_failures {
+            status.disabled = true;
+            Some(status.recent_errors.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Re-enables a subscription that got disabled. Returns false, if there is no such subscription.
+    fn enable_source(&self, name: &str) -> bool {
+        if let Some(status) = self.sources.lock().unwrap().get_mut(name) {
+            status.disabled = false;
+            status.consecutive_failures = 0;
+            status.recent_errors.clear();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Asks the polling loop for the currently known entries
+    async fn entries(&self, source: Option<String>) -> anyhow::Result<Vec<SourceEntries>> {
+        let (reply, response) = oneshot::channel();
+        self.poller
+            .send(PollerCommand::Entries { source, reply })
+            .map_err(|_| anyhow::anyhow!("polling loop is not running"))?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("polling loop stopped"))
+    }
+
+    /// The polling interval of subscriptions without their own schedule
+    fn default_interval(&self) -> Duration {
+        self.overrides
+            .lock()
+            .unwrap()
+            .default_interval
+            .map(Duration::from_secs)
+            .unwrap_or(self.cfg.reloadable.lock().unwrap().default_interval)
+    }
+
+    /// Asks the polling loop to poll the given subscription (or all of them) right away
+    /// and waits for the outcome
+    async fn check_now(
+        &self,
+        source: Option<String>,
+    ) -> anyhow::Result<Vec<(String, PollOutcome)>> {
+        let (reply, response) = oneshot::channel();
+        self.poller
+            .send(PollerCommand::CheckNow { source, reply })
+            .map_err(|_| anyhow::anyhow!("polling loop is not running"))?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("polling loop stopped"))
+    }
+
+    fn in_quiet_hours(&self, room_id: &RoomId) -> bool {
+        self.cfg
+            .room_configs
+            .get(room_id)
+            .and_then(|x| x.quiet_hours.as_ref())
+            .map(|x| x.contains(Utc::now()))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(feature = "appservice")]
+fn appservice_login_data(appservice: AppServiceSection) -> anyhow::Result<LoginData> {
+    Ok(LoginData::AppService(AppServiceConfig {
+        registration: appservice.registration,
+        server_name: appservice.server_name,
+        sender_localpart: appservice.sender_localpart,
+        url: appservice
+            .url
+            .unwrap_or_else(|| format!("http://localhost:{}", appservice.listen_port)),
+        listen_host: appservice.listen_host,
+        listen_port: appservice.listen_port,
+    }))
 }
 
-fn extract_session_storage(settings: &Config) -> anyhow::Result<SessionStorage> {
-    if !settings.get_bool("login.persist_session").unwrap_or(true) {
-        return Ok(SessionStorage::Ephemeral);
+#[cfg(not(feature = "appservice"))]
+fn appservice_login_data(appservice: AppServiceSection) -> anyhow::Result<LoginData> {
+    anyhow::bail!(
+        "Configured to run as application service with registration {}, but built without the appservice feature",
+        appservice.registration.display()
+    )
+}
+
+async fn extract_room_configs(
+    settings: &Config,
+    prefix: &str,
+    homeserver_url: &str,
+    session_storage: &dyn SessionStore,
+) -> anyhow::Result<HashMap<OwnedRoomId, RoomConfig>> {
+    let mut room_configs = HashMap::new();
+    let rooms: Vec<RoomSection> =
+        optional_section(settings, &format!("{prefix}room"))?.unwrap_or_default();
+    for room in rooms {
+        let room_id = aliases::resolve(homeserver_url, session_storage, &room.id).await?;
+        room_configs.insert(room_id, parse_room_config(room)?);
     }
+    Ok(room_configs)
+}
 
-    let db_path = if let Ok(db_storage) = settings.get_string("login.db_path") {
-        PathBuf::from(db_storage)
+fn user_patterns(patterns: &[String]) -> anyhow::Result<Vec<UserPattern>> {
+    patterns.iter().map(|x| UserPattern::parse(x)).collect()
+}
+
+/// The settings of a `[[room]]` section, apart from its ID
+fn parse_room_config(room: RoomSection) -> anyhow::Result<RoomConfig> {
+    let quiet_hours = room
+        .quiet_hours
+        .map(|x| QuietHours::parse(&x, &room.timezone))
+        .transpose()
+        .context("quiet_hours")?;
+    let accept_commands_from = room
+        .accept_commands_from
+        .as_deref()
+        .map(user_patterns)
+        .transpose()
+        .context("accept_commands_from")?;
+    Ok(RoomConfig {
+        quiet_hours,
+        sources: room.sources,
+        accept_commands_from,
+    })
+}
+
+/// Fails if a room routes a subscription that isn't in the config
+fn check_routed_sources(routed: Option<&[String]>, known: &[&str]) -> anyhow::Result<()> {
+    let unknown: Vec<_> = routed
+        .unwrap_or_default()
+        .iter()
+        .filter(|x| !known.contains(&x.as_str()))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        anyhow::bail!("sources: unknown subscriptions {}", unknown.join(", "));
+    }
+    Ok(())
+}
+
+/// The subscription `name` of the config, without its rooms, which need resolving
+fn parse_subscription(name: &str, sub: &SubscriptionSection) -> anyhow::Result<MozData> {
+    let filter = sub
+        .filter
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("filter")?;
+    let mut mozdata = MozData::new(name, &sub.url_part, filter, sub.query_subdirs);
+    mozdata.update_in_place = sub.update_in_place;
+    mozdata.template = sub.template.build().context("template")?;
+    for (language, templates) in &sub.translations {
+        let context = || format!("translations.{language}");
+        let language = Language::parse(language).with_context(context)?;
+        if let Some(template) = templates.build().with_context(context)? {
+            mozdata.translated_templates.insert(language, template);
+        }
+    }
+    if let Some(msgtype) = &sub.msgtype {
+        mozdata.msgtype = NotificationType::parse(msgtype).context("msgtype")?;
+    }
+    mozdata.pin = sub.pin;
+    mozdata.attach = sub
+        .attach
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("attach")?;
+    Ok(mozdata)
+}
+
+/// When to poll a subscription of the config
+fn parse_schedule(
+    sub: &SubscriptionSection,
+    default_interval: Duration,
+) -> anyhow::Result<Schedule> {
+    Ok(sub
+        .schedule
+        .as_deref()
+        .map(Schedule::parse_cron)
+        .transpose()
+        .context("schedule")?
+        .unwrap_or(Schedule::Interval(default_interval)))
+}
+
+/// Delivers notifications that were queued during quiet hours as one batch per room,
+/// once the quiet hours of that room are over. Rooms with digests get their batch once
+/// the oldest notification in it waited for the digest interval.
+async fn flush_quiet_hours_queues(client: Client, shared_state: SharedState) {
+    loop {
+        sleep(Duration::from_secs(60)).await;
+        let waiting: Vec<_> = {
+            let queued = shared_state.queued.lock().unwrap();
+            queued
+                .iter()
+                .filter(|(x, _)| !shared_state.in_quiet_hours(x))
+                .filter_map(|(x, notifications)| {
+                    Some((x.clone(), notifications.first()?.queued_at))
+                })
+                .collect()
+        };
+        let mut ready_rooms = Vec::new();
+        for (room_id, oldest) in waiting {
+            let digest_minutes = room_settings::get(&client, &room_id).await.digest_minutes;
+            let due = digest_minutes
+                .and_then(|x| chrono::Duration::try_minutes(x as i64))
+                .map(|x| Utc::now().timestamp() - oldest >= x.num_seconds())
+                .unwrap_or(true);
+            if due {
+                ready_rooms.push(room_id);
+            }
+        }
+        let ready: Vec<_> = {
+            let mut queued = shared_state.queued.lock().unwrap();
+            ready_rooms
+                .into_iter()
+                .filter_map(|x| queued.remove_entry(&x))
+                .collect()
+        };
+        if !ready.is_empty() {
+            store_queued(&shared_state).await;
+        }
+        for (room_id, notifications) in ready {
+            let pending = match notifications.as_slice() {
+                [notification] => PendingNotification::new(
+                    room_id.clone(),
+                    None,
+                    notification.msgtype,
+                    notification.plain.clone(),
+                    notification.html.clone(),
+                    notification.fields.clone(),
+                )
+                .with_attachments(notification.attachments.clone()),
+                _ => {
+                    let plain: Vec<_> = notifications.iter().map(|x| x.plain.as_str()).collect();
+                    let html: Vec<_> = notifications.iter().map(|x| x.html.as_str()).collect();
+                    // Sources might have their own msgtype, the room's wins for a mix
+                    let msgtype = match notifications[0].msgtype {
+                        msgtype if notifications.iter().all(|x| x.msgtype == msgtype) => msgtype,
+                        _ => room_settings::get(&client, &room_id)
+                            .await
+                            .msgtype
+                            .unwrap_or_default(),
+                    };
+                    PendingNotification::new(
+                        room_id.clone(),
+                        None,
+                        msgtype,
+                        plain.join("\n"),
+                        html.join("<br>"),
+                        formatting::merge_mentions(notifications.iter().map(|x| &x.fields)),
+                    )
+                    .with_attachments(
+                        notifications
+                            .iter()
+                            .flat_map(|x| x.attachments.clone())
+                            .collect(),
+                    )
+                }
+            };
+            if outbox::suspended(&shared_state) {
+                outbox::add(&shared_state, pending).await;
+                continue;
+            }
+            let sent = send_to_room_with_fields(
+                &client,
+                &room_id,
+                pending.msgtype,
+                &pending.plain,
+                &pending.html,
+                &pending.fields,
+            )
+            .await;
+            match sent {
+                Ok(event_ids) => {
+                    retention::track(&shared_state, &room_id, &event_ids).await;
+                    if let Err(e) =
+                        attachments::send(&client, &shared_state, &room_id, &pending.attachments)
+                            .await
+                    {
+                        eprintln!("Failed to attach the files in {room_id}: {e:?}");
+                    }
+                }
+                Err(e) => {
+                    report_send_failure(&client, &shared_state, &room_id, &e).await;
+                    let event_ids = outbox::add_failed(&shared_state, pending, e).await;
+                    retention::track(&shared_state, &room_id, &event_ids).await;
+                }
+            }
+        }
+    }
+}
+
+/// Persists the held-back notifications. Without a session DB, they only live in memory.
+async fn store_queued(shared_state: &SharedState) {
+    let Some(db) = shared_state.cfg.session_storage.get_session_db() else {
+        return;
+    };
+    let queued = shared_state.queued.lock().unwrap().clone();
+    if let Err(e) = db.state.set_document(QUEUED_DOCUMENT, &queued).await {
+        eprintln!("Failed to persist the held-back notifications: {e:?}");
+    }
+}
+
+/// Loads the notifications held back at the last shutdown
+async fn restore_queued(shared_state: &SharedState) -> anyhow::Result<()> {
+    let Some(db) = shared_state.cfg.session_storage.get_session_db() else {
+        return Ok(());
+    };
+    let Some(stored) = db
+        .state
+        .document::<QueuedNotifications>(QUEUED_DOCUMENT)
+        .await?
+    else {
+        return Ok(());
+    };
+    // Ones queued since the start come after the stored ones
+    let mut queued = shared_state.queued.lock().unwrap();
+    for (room_id, mut notifications) in stored {
+        notifications.extend(queued.remove(&room_id).unwrap_or_default());
+        queued.insert(room_id, notifications);
+    }
+    Ok(())
+}
+
+/// Asks for a secret that isn't in the config, unless that is forbidden
+fn prompt_secret(prompt: &str, key: &str, interactive: bool) -> anyhow::Result<String> {
+    if !interactive {
+        anyhow::bail!("{key} is not configured, and asking for it is not possible");
+    }
+    Ok(rpassword::prompt_password_stderr(prompt)?)
+}
+
+fn extract_session_storage(
+    login: &LoginSection,
+    prefix: &str,
+    profile: Option<&str>,
+    instance: Option<&str>,
+    interactive: bool,
+) -> anyhow::Result<Arc<dyn SessionStore>> {
+    if !login.persist_session {
+        return Ok(Arc::new(Ephemeral));
+    }
+
+    let db_path = if let Some(db_storage) = &login.db_path {
+        db_storage.clone()
     } else {
-        dirs::data_dir()
+        let mut data_dir = dirs::data_dir()
             .unwrap_or(PathBuf::from("./"))
-            .join("matrix_mozilla_bot")
-            .join("session")
+            .join("matrix_mozilla_bot");
+        if let Some(profile) = profile {
+            data_dir = data_dir.join(profile);
+        }
+        if let Some(instance) = instance {
+            data_dir = data_dir.join(instance);
+        }
+        data_dir.join("session")
     };
-    let db_pw = if let Ok(db_pw) = settings.get_string("login.db_pw") {
+    let db_pw = if let Some(db_pw) = login.load_db_pw(instance)? {
         db_pw
     } else {
-        rpassword::prompt_password_stderr(&format!(
-            "Enter Session storage ({}) password: ",
-            db_path.to_string_lossy()
-        ))?
+        prompt_secret(
+            &format!(
+                "Enter Session storage ({}) password: ",
+                db_path.to_string_lossy()
+            ),
+            &format!("{prefix}login.db_pw"),
+            interactive,
+        )?
+    };
+    let db = SessionDB {
+        state: StateDb::new(&db_path, login.backups),
+        db_path,
+        db_pw,
+    };
+    Ok(build_session_store(
+        login.backend(),
+        db,
+        login,
+        profile,
+        instance,
+    ))
+}
+
+/// The `backend` keeping the session next to `db`
+fn build_session_store(
+    backend: SessionBackend,
+    db: SessionDB,
+    login: &LoginSection,
+    profile: Option<&str>,
+    instance: Option<&str>,
+) -> Arc<dyn SessionStore> {
+    // The attribute of the SecretService items, or the service of the keyring entries
+    let name = ["matrix_mozilla_bot"]
+        .into_iter()
+        .chain(profile)
+        .chain(instance)
+        .collect::<Vec<_>>()
+        .join(".");
+    match backend {
+        SessionBackend::Plain => {
+            let session_path = if let Some(session_path) = &login.session_path {
+                session_path.clone()
+            } else {
+                db.db_path.join("session.dump")
+            };
+            Arc::new(PlainSessionStorage { db, session_path })
+        }
+        SessionBackend::SecretService => Arc::new(SecretServiceStorage {
+            db,
+            attribute: name,
+        }),
+        SessionBackend::Keyring => Arc::new(KeyringStorage { db, service: name }),
+    }
+}
+
+async fn alert_disabled_source(
+    client: &Client,
+    shared_state: &SharedState,
+    source: &MozData,
+    history: &[(DateTime<Utc>, String)],
+) {
+    let message = |lang| {
+        let failures = history.iter().map(|(time, err)| {
+            Message::new()
+                .text(&format!("{}: ", time.format("%Y-%m-%d %H:%M:%S UTC")))
+                .code(err)
+        });
+        Message::fill(
+            i18n::translate(
+                lang,
+                "Subscription {name} ({url_part}) got disabled after {count} consecutive failures. Use {command} to resume.",
+            ),
+            &[
+                ("name", Message::new().bold(&source.name)),
+                ("url_part", Message::new().text(&source.url_part)),
+                ("count", Message::new().text(&history.len().to_string())),
+                (
+                    "command",
+                    Message::new().code(&format!(
+                        "{}enable {}",
+                        shared_state.cfg.command_prefix, source.name
+                    )),
+                ),
+            ],
+        )
+        .list(failures)
+    };
+    let key = format!("disabled:{}", source.name);
+    admin::report(client, shared_state, &key, message).await;
+}
+
+async fn report_fetch_failure(
+    client: &Client,
+    shared_state: &SharedState,
+    source: &MozData,
+    error: &str,
+) {
+    let message = |lang| {
+        Message::fill(
+            i18n::translate(lang, "Failed to fetch {url_part}: {error}"),
+            &[
+                ("url_part", Message::new().text(&source.url_part)),
+                ("error", Message::new().code(error)),
+            ],
+        )
+    };
+    let key = format!("fetch:{}", source.name);
+    admin::report(client, shared_state, &key, message).await;
+}
+
+async fn report_send_failure(
+    client: &Client,
+    shared_state: &SharedState,
+    room_id: &RoomId,
+    error: &anyhow::Error,
+) {
+    let message = |lang| {
+        Message::fill(
+            i18n::translate(lang, "Failed to send a notification to {room}: {error}"),
+            &[
+                ("room", Message::new().text(room_id.as_str())),
+                ("error", Message::new().code(&error.to_string())),
+            ],
+        )
+    };
+    let key = format!("send:{room_id}");
+    admin::report(client, shared_state, &key, message).await;
+}
+
+/// What a single poll of a subscription found
+#[derive(Debug, Clone)]
+pub enum PollOutcome {
+    Unchanged,
+    Changed(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for PollOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PollOutcome::Unchanged => write!(f, "no changes"),
+            PollOutcome::Changed(entries) => write!(f, "new uploads: {entries}"),
+            PollOutcome::Failed(error) => write!(f, "failed: {error}"),
+        }
+    }
+}
+
+/// Snapshot of the seen-set of a subscription
+#[derive(Debug, Clone)]
+pub struct SourceEntries {
+    name: String,
+    url: String,
+    /// Sorted by version
+    entries: Vec<String>,
+}
+
+/// Requests from the Matrix side to the polling loop
+#[derive(Debug)]
+pub enum PollerCommand {
+    /// Poll the given subscription (or all, if None) right away and report back
+    CheckNow {
+        source: Option<String>,
+        reply: oneshot::Sender<Vec<(String, PollOutcome)>>,
+    },
+    /// Report the currently known entries of the given subscription (or all, if None)
+    Entries {
+        source: Option<String>,
+        reply: oneshot::Sender<Vec<SourceEntries>>,
+    },
+    /// Add a subscription, or replace the one with the same name
+    Subscribe(MozData),
+    Unsubscribe(String),
+    /// Replace the filter of a subscription and take a new baseline
+    SetFilter {
+        source: String,
+        filter: Option<Regex>,
+    },
+    /// Announce a subscription in other rooms, keeping what it has seen
+    SetRooms {
+        source: String,
+        rooms: Option<Vec<OwnedRoomId>>,
+    },
+    /// Change the polling interval of a subscription, or the default one if None
+    SetInterval {
+        source: Option<String>,
+        interval: Duration,
+    },
+    /// Shut down all instances like on SIGTERM, and start again if `restart`
+    Shutdown {
+        restart: bool,
+    },
+}
+
+/// Loads the seen-set a subscription had at the last shutdown, so uploads in between are
+/// compared against it instead of becoming part of a fresh baseline
+async fn restore_seen_entries(shared_state: &SharedState, source: &mut MozData) {
+    let Some(db) = shared_state.cfg.session_storage.get_session_db() else {
+        return;
     };
-    if !settings
-        .get_bool("login.use_secret_service")
-        .unwrap_or(true)
+    match db.state.seen_entries(&source.name).await {
+        Ok(entries) => source.data = entries,
+        Err(e) => eprintln!(
+            "Failed to restore the seen entries of {}: {e:?}",
+            source.name
+        ),
+    }
+}
+
+/// Persists the seen-set of a subscription, an empty one is deleted
+async fn store_seen_entries(shared_state: &SharedState, name: &str, entries: &HashSet<String>) {
+    let Some(db) = shared_state.cfg.session_storage.get_session_db() else {
+        return;
+    };
+    if let Err(e) = db.state.set_seen_entries(name, entries).await {
+        eprintln!("Failed to persist the seen entries of {name}: {e:?}");
+    }
+}
+
+/// Polls a subscription and announces what is new. Errors count as failures of the
+/// subscription, instead of stopping the bot.
+async fn poll_source(
+    client: &Client,
+    shared_state: &SharedState,
+    source: &mut MozData,
+    http: &Arc<HttpCache>,
+) -> PollOutcome {
+    match poll_and_announce(client, shared_state, source, http).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("Polling {} failed: {e:?}", source.name);
+            if let Some(history) = shared_state.record_failure(&source.name, e.to_string()) {
+                alert_disabled_source(client, shared_state, source, &history).await;
+            }
+            PollOutcome::Failed(e.to_string())
+        }
+    }
+}
+
+async fn poll_and_announce(
+    client: &Client,
+    shared_state: &SharedState,
+    source: &mut MozData,
+    http: &Arc<HttpCache>,
+) -> anyhow::Result<PollOutcome> {
+    let baseline = source.data.is_empty();
+    let answer = match source
+        .fetch_upstream_and_compare(http, &shared_state.resources)
+        .await
     {
-        let session_path = if let Ok(session_path) = settings.get_string("login.session_path") {
-            PathBuf::from(session_path)
+        Ok(answer) => {
+            if shared_state
+                .resources
+                .record_seen_entries(&source.name, source.data.len())
+            {
+                shared_state.record_success(&source.name, source.data.len(), source.latest_entry());
+            } else {
+                // The seen-set is kept, clearing it would turn the next poll into a new
+                // baseline and swallow the uploads in between. Staying over the limit
+                // disables the subscription like failing fetches do.
+                let e = format!(
+                    "Seen-set of {} is over the limit of {} entries",
+                    source.url_part, shared_state.cfg.limits.max_seen_entries
+                );
+                report_fetch_failure(client, shared_state, source, &e).await;
+                if let Some(history) = shared_state.record_failure(&source.name, e) {
+                    alert_disabled_source(client, shared_state, source, &history).await;
+                }
+            }
+            if baseline || !answer.is_empty() {
+                store_seen_entries(shared_state, &source.name, &source.data).await;
+            }
+            answer
+        }
+        Err(e) => {
+            report_fetch_failure(client, shared_state, source, &e.to_string()).await;
+            if let Some(history) = shared_state.record_failure(&source.name, e.to_string()) {
+                eprintln!(
+                    "Disabling {} after {} consecutive failures",
+                    source.name,
+                    history.len()
+                );
+                alert_disabled_source(client, shared_state, source, &history).await;
+            }
+            return Ok(PollOutcome::Failed(e.to_string()));
+        }
+    };
+    if answer.is_empty() {
+        return Ok(PollOutcome::Unchanged);
+    }
+    shared_state.record_change(&source.name);
+    let answer_str = source.format_entries(&answer);
+    println!("{} differ: {:?}", source.url_part, answer_str);
+    if shared_state.in_startup_quiet_period() {
+        println!(
+            "Not announcing changes of {} during startup",
+            source.url_part
+        );
+        return Ok(PollOutcome::Changed(answer_str));
+    }
+    if shared_state.is_paused(&source.name) {
+        println!(
+            "Not announcing changes of {}, as it is paused",
+            source.url_part
+        );
+        return Ok(PollOutcome::Changed(answer_str));
+    }
+    let roomids = shared_state.target_rooms(source);
+
+    let mut entries: Vec<_> = answer.iter().cloned().collect();
+    entries.sort();
+    let default_message = |lang| {
+        Message::fill(
+            i18n::translate(lang, "{source} got new uploads: {entries}"),
+            &[
+                ("source", formatting::source_link(source)),
+                ("entries", Message::new().text(&answer_str)),
+            ],
+        )
+        .into_parts()
+    };
+    let full_message = |lang| match source.template_for(lang) {
+        Some(template) => {
+            let vars = TemplateVars {
+                source: &source.name,
+                url_part: &source.url_part,
+                base_url: &source.base_url,
+                url: format!("{}/{}/", source.base_url, source.url_part),
+                entries: &answer_str,
+                entry_list: entries.iter().map(String::as_str).collect(),
+                count: entries.len(),
+            };
+            template.render(&vars).unwrap_or_else(|e| {
+                eprintln!("Failed to render the template of {}: {e}", source.name);
+                default_message(lang)
+            })
+        }
+        None => default_message(lang),
+    };
+    let summary_message = |lang| {
+        Message::fill(
+            i18n::translate(lang, "{source} got {count} new uploads"),
+            &[
+                ("source", formatting::source_link(source)),
+                ("count", Message::new().text(&answer.len().to_string())),
+            ],
+        )
+        .into_parts()
+    };
+    let announcement = Announcement {
+        source: source.name.clone(),
+        url_part: source.url_part.clone(),
+        url: format!("{}/{}/", source.base_url, source.url_part),
+        entries,
+        timestamp: Utc::now().timestamp(),
+    };
+    let mut fields = serde_json::Map::new();
+    if let Some(signer) = &shared_state.signer {
+        fields.insert(
+            String::from("org.mozillabot.signature"),
+            signer.sign(&announcement)?,
+        );
+    }
+    fields.insert(
+        String::from("org.mozillabot.announcement"),
+        serde_json::to_value(&announcement)?,
+    );
+    let attachments = attachments::fetch(http, &shared_state.resources, source, &answer).await;
+    for roomid in roomids {
+        if shared_state.is_muted(&roomid, &source.name) {
+            continue;
+        }
+        let settings = room_settings::get(client, &roomid).await;
+        if !settings.wants(&source.name) {
+            continue;
+        }
+        let lang = settings.language.unwrap_or(shared_state.cfg.language);
+        let (mut plain, mut html) = match settings.format {
+            MessageFormat::Full => full_message(lang),
+            MessageFormat::Summary => summary_message(lang),
+        };
+        let mut fields = fields.clone();
+        let mentioned = alerts::matching_users(shared_state, &roomid, &answer);
+        if !mentioned.is_empty() {
+            let mut users = Message::new();
+            for (i, user) in mentioned.iter().enumerate() {
+                if i > 0 {
+                    users = users.text(", ");
+                }
+                users = users.mention(user);
+            }
+            let alerting = Message::new().line_break().append(Message::fill(
+                i18n::translate(lang, "Alerting {users}"),
+                &[("users", users)],
+            ));
+            plain += alerting.plain();
+            html += alerting.html();
+            formatting::add_mentions(&mut fields, alerting.mentions())?;
+        }
+        let held_back = shared_state.in_quiet_hours(&roomid) || settings.digest_minutes.is_some();
+        // Held back ones go out merged, without a thread, so no root is posted for them
+        if settings.threads && !held_back {
+            match threads::root(client, shared_state, &roomid, source, lang).await {
+                Ok(Some(root)) => {
+                    fields.insert(
+                        String::from("m.relates_to"),
+                        serde_json::json!({
+                            "rel_type": "m.thread",
+                            "event_id": root,
+                            "is_falling_back": true,
+                            "m.in_reply_to": { "event_id": root },
+                        }),
+                    );
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!(
+                    "Posting without a thread, the root of {} in {roomid} failed: {e:?}",
+                    source.name
+                ),
+            }
+        }
+        let msgtype = settings.msgtype.unwrap_or(source.msgtype);
+        if held_back {
+            shared_state
+                .queued
+                .lock()
+                .unwrap()
+                .entry(roomid)
+                .or_default()
+                .push(QueuedNotification {
+                    msgtype,
+                    plain,
+                    html,
+                    fields,
+                    attachments: attachments.clone(),
+                    queued_at: Utc::now().timestamp(),
+                });
+            store_queued(shared_state).await;
+            continue;
+        }
+        let key = (roomid.clone(), source.name.clone());
+        let previous = if source.update_in_place {
+            shared_state.editable.lock().unwrap().get(&key).cloned()
         } else {
-            db_path.join("session.dump")
+            None
         };
-        Ok(SessionStorage::Plain(
-            SessionDB { db_path, db_pw },
-            PlainSessionStorage { session_path },
-        ))
-    } else {
-        Ok(SessionStorage::SecretService(SessionDB { db_path, db_pw }))
+        // Keep detecting while the homeserver is unreachable, the outbox catches up later
+        if outbox::suspended(shared_state) {
+            let mut pending = PendingNotification::new(
+                roomid,
+                Some(source.name.clone()),
+                msgtype,
+                plain,
+                html,
+                fields,
+            );
+            if let Some(previous) = previous {
+                pending = pending.replacing(previous);
+            }
+            outbox::add(shared_state, pending.with_attachments(attachments.clone())).await;
+            continue;
+        }
+        shared_state.resources.wait_for_send_slot().await;
+        if let Some(previous) = previous {
+            let result =
+                edit_with_fields(client, &roomid, &previous, msgtype, &plain, &html, &fields).await;
+            match result {
+                Ok(_) => {
+                    if let Err(e) =
+                        attachments::send(client, shared_state, &roomid, &attachments).await
+                    {
+                        eprintln!(
+                            "Failed to attach the files of {} in {roomid}: {e:?}",
+                            source.name
+                        );
+                    }
+                }
+                Err(e) => {
+                    report_send_failure(client, shared_state, &roomid, &e).await;
+                    let pending = PendingNotification::new(
+                        roomid,
+                        Some(source.name.clone()),
+                        msgtype,
+                        plain,
+                        html,
+                        fields,
+                    )
+                    .replacing(previous)
+                    .with_attachments(attachments.clone());
+                    outbox::add_failed(shared_state, pending, e).await;
+                }
+            }
+            continue;
+        }
+        let sent = send_to_room_with_fields(client, &roomid, msgtype, &plain, &html, &fields).await;
+        // Unless the outbox took them, along with the rest of the message
+        let mut attach = true;
+        let event_ids = match sent {
+            Ok(event_ids) => event_ids,
+            Err(e) => {
+                report_send_failure(client, shared_state, &roomid, &e).await;
+                let pending = PendingNotification::new(
+                    roomid.clone(),
+                    Some(source.name.clone()),
+                    msgtype,
+                    plain,
+                    html,
+                    fields,
+                )
+                .with_attachments(attachments.clone());
+                attach = false;
+                // Some parts of a split message might have gone out
+                outbox::add_failed(shared_state, pending, e).await
+            }
+        };
+        retention::track(shared_state, &roomid, &event_ids).await;
+        if let Some(event_id) = event_ids.into_iter().next() {
+            shared_state.record_notification(event_id.clone(), &source.name);
+            if source.pin {
+                if let Err(e) = pins::pin(
+                    client,
+                    shared_state,
+                    &roomid,
+                    &source.name,
+                    event_id.clone(),
+                )
+                .await
+                {
+                    eprintln!(
+                        "Failed to pin the notification of {} in {roomid}: {e:?}",
+                        source.name
+                    );
+                }
+            }
+            if source.update_in_place {
+                shared_state.editable.lock().unwrap().insert(key, event_id);
+            }
+        }
+        if !attach {
+            continue;
+        }
+        if let Err(e) = attachments::send(client, shared_state, &roomid, &attachments).await {
+            eprintln!(
+                "Failed to attach the files of {} in {roomid}: {e:?}",
+                source.name
+            );
+        }
     }
+    personal::notify(client, shared_state, source, &answer).await;
+    Ok(PollOutcome::Changed(answer_str))
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // ------- Getting the login-credentials from file ------
-    // You can get them however you like: hard-code them here, env-variabl,
-    // tcp-connection, read from file, etc. Here, we use the config-crate to
-    // load from botconfig.toml.
-    // Change this file to your needs, if you want to use this example binary.
-    let settings = Config::builder()
-        .add_source(config::File::with_name("botconfig"))
-        // Add in settings from the environment (with a prefix of BOT)
-        // Eg.. `BOT_DEBUG=1 ./target/app` would set the `debug` key
-        .add_source(config::Environment::with_prefix("BOT"))
-        .build()?;
-
-    let homeserver_url = settings.get_string("login.homeserver_url")?;
-    let session_storage = extract_session_storage(&settings)?;
-    #[cfg(feature = "sso-login")]
-    let login_data = LoginData::Sso;
-    #[cfg(not(feature = "sso-login"))]
-    let login_data = {
-        let username = settings.get_string("login.username")?;
-        let password = match settings.get_string("login.password") {
-            Ok(pw) => pw,
-            Err(..) => {
-                // We don't need a login-password, if we can restore the session from disk
-                if session_storage.session_store_exists() {
-                    String::new()
-                } else {
-                    rpassword::prompt_password_stderr("Enter Password: ")
-                        .expect("Failed to read password")
+enum PollEvent {
+    Due(Vec<(usize, String)>),
+    Command(usize, PollerCommand),
+    /// SIGINT or SIGTERM. Shaped like `PollerCommand::Shutdown`, which is handled the same.
+    Shutdown {
+        restart: bool,
+    },
+    /// SIGHUP
+    Reload,
+}
+
+/// Replaces this process with a fresh start of the same binary and arguments. Only
+/// returns if that failed.
+#[cfg(unix)]
+fn exec(mut command: std::process::Command) -> anyhow::Result<()> {
+    use std::os::unix::process::CommandExt;
+    Err(command.exec().into())
+}
+
+/// Starts the same binary again, for this process to exit right after
+#[cfg(not(unix))]
+fn exec(mut command: std::process::Command) -> anyhow::Result<()> {
+    command.spawn()?;
+    Ok(())
+}
+
+fn restart() -> anyhow::Result<()> {
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command.args(std::env::args_os().skip(1));
+    exec(command)
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM from service managers
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
                 }
             }
+            Err(e) => {
+                eprintln!("Failed to listen for SIGTERM: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// One logical bot: a Matrix account with its own rooms and subscriptions.
+/// In fleet mode several of them share one process, HTTP cache and scheduler.
+struct Instance {
+    shared_state: SharedState,
+    sources: Vec<MozData>,
+    poll_before_sync: bool,
+}
+
+/// Reads the config of a single instance. `instance` is None, if the top-level sections
+/// describe the only bot, otherwise the `[instance.<name>]` section is used. With an
+/// `account`, the login comes from the `[account.<name>]` section instead.
+/// Secrets missing in the config are asked for, if `interactive`.
+fn instance_prefix(instance: Option<&str>) -> String {
+    instance
+        .map(|x| format!("instance.{x}."))
+        .unwrap_or_default()
+}
+
+fn extract_limits(settings: &Config, prefix: &str) -> anyhow::Result<ResourceLimits> {
+    let limits: LimitsSection = section(settings, &format!("{prefix}limits"))?;
+    Ok(ResourceLimits {
+        max_concurrent_requests: limits.max_concurrent_requests,
+        max_seen_entries: limits.max_seen_entries,
+        max_sends_per_minute: limits.max_sends_per_minute,
+        max_commands_per_minute: limits.max_commands_per_minute,
+    })
+}
+
+/// Instances without their own subscriptions watch the top-level ones
+fn subscription_sections(
+    settings: &Config,
+    instance: Option<&str>,
+) -> anyhow::Result<BTreeMap<String, SubscriptionSection>> {
+    let prefix = instance_prefix(instance);
+    match optional_section(settings, &format!("{prefix}subscription"))? {
+        Some(subscriptions) => Ok(subscriptions),
+        None if instance.is_some() => Ok(settings.get("subscription")?),
+        None => Err(ConfigError::NotFound(String::from("subscription")).into()),
+    }
+}
+
+/// The `fetch` subcommand: polls each subscription once and prints what it lists. It only
+/// reads the subscriptions, so it needs neither a login nor the homeserver.
+async fn fetch(
+    settings: &Config,
+    instance_names: &[(Option<String>, Option<String>)],
+) -> anyhow::Result<()> {
+    let http = Arc::new(HttpCache::new());
+    // The accounts all share the top-level subscriptions
+    let mut instances: Vec<Option<&str>> = Vec::new();
+    for (instance, _) in instance_names {
+        if !instances.contains(&instance.as_deref()) {
+            instances.push(instance.as_deref());
+        }
+    }
+    for instance in instances {
+        let limits = extract_limits(settings, &instance_prefix(instance))?;
+        let resources = Arc::new(ResourceTracker::new(limits));
+        for (name, sub) in subscription_sections(settings, instance)? {
+            let mut source =
+                parse_subscription(&name, &sub).with_context(|| format!("subscription {name}"))?;
+            match source.fetch_upstream_and_compare(&http, &resources).await {
+                Ok(_) => println!(
+                    "{} ({}): {} entries, latest {}",
+                    source.name,
+                    source.url_part,
+                    source.data.len(),
+                    source.latest_entry().unwrap_or("-")
+                ),
+                Err(e) => println!("{} ({}): failed: {e:?}", source.name, source.url_part),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn extract_instance(
+    settings: &Config,
+    instance: Option<&str>,
+    account: Option<&str>,
+    poller: mpsc::UnboundedSender<PollerCommand>,
+    interactive: bool,
+) -> anyhow::Result<(Instance, Vec<(String, Schedule)>)> {
+    let prefix = instance_prefix(instance);
+    let login_prefix = account
+        .map(|x| format!("account.{x}."))
+        .unwrap_or_else(|| prefix.clone());
+    let login: LoginSection = settings.get(&format!("{login_prefix}login"))?;
+    let homeserver_url = login.homeserver_url.clone();
+    // Also settable in the config file or with BOT_PROFILE, not just on the command line
+    let profile: Option<String> = optional_section(settings, "profile")?;
+    if let Some(profile) = &profile {
+        check_profile(profile)?;
+    }
+    let session_storage = extract_session_storage(
+        &login,
+        &login_prefix,
+        profile.as_deref(),
+        instance.or(account),
+        interactive,
+    )?;
+    let bootstrap_cross_signing = login.bootstrap_cross_signing;
+    let key_backup = login.key_backup;
+    let device_name = login.device_name.clone();
+    let appservice: Option<AppServiceSection> =
+        optional_section(settings, &format!("{login_prefix}appservice"))?;
+    let login_data = if let Some(appservice) = appservice {
+        appservice_login_data(appservice)?
+    } else if login.oidc {
+        login.check_oidc()?;
+        LoginData::Oidc {
+            client_id: login.oidc_client_id.clone(),
+        }
+    } else {
+        #[cfg(feature = "sso-login")]
+        let login_data = LoginData::Sso;
+        #[cfg(not(feature = "sso-login"))]
+        let login_data = {
+            let Some(username) = login.username.clone() else {
+                return Err(ConfigError::NotFound(format!("{login_prefix}login.username")).into());
+            };
+            let password = match login.load_password(instance.or(account))? {
+                Some(pw) => pw,
+                None => {
+                    // We don't need a login-password, if we can restore the session from disk
+                    if session_storage.session_store_exists() {
+                        String::new()
+                    } else {
+                        prompt_secret(
+                            &format!("Enter Password for {username}: "),
+                            &format!("{login_prefix}login.password"),
+                            interactive,
+                        )?
+                    }
+                }
+            };
+            LoginData::UsernamePassword(username, password)
         };
-        LoginData::UsernamePassword(username, password)
+        login_data
     };
+    let config: ConfigSection = section(settings, &format!("{prefix}config"))?;
     // Currently not really used, but I leave it here in case we need it at some point
-    let ignore_own_messages = settings
-        .get_bool("config.ignore_own_messages")
-        .unwrap_or(true);
-    let autojoin = settings.get_bool("config.autojoin").unwrap_or(true);
-    let sleep_time_in_minutes = settings
-        .get_int("config.sleep_time_in_minutes")
-        .unwrap_or(60) as u64;
-    let accept_commands_from_str: Vec<String> = settings
-        .get_array("config.accept_commands_from")
-        .unwrap_or_default()
-        .into_iter()
-        .map(|x| x.into_string())
-        .collect::<Result<Vec<_>, _>>()?;
-    let accept_commands_from = accept_commands_from_str
-        .into_iter()
-        .map(UserId::parse)
-        .collect::<Result<Vec<_>, _>>()?;
+    let ignore_own_messages = config.ignore_own_messages;
+    let accept_commands_from = user_patterns(&config.accept_commands_from)?;
+    let ignore_users = user_patterns(&config.ignore_users)?;
+    if let Err(e) = aliases::restore(&session_storage).await {
+        eprintln!("Ignoring the unreadable cache of room aliases: {e:?}");
+    }
+    let room_configs =
+        extract_room_configs(settings, &prefix, &homeserver_url, &session_storage).await?;
+    let rooms = config
+        .rooms
+        .iter()
+        .map(|x| Ok(RoomOrAliasId::parse(x)?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let spaces = config
+        .spaces
+        .iter()
+        .map(|x| Ok(RoomOrAliasId::parse(x)?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let admin_room = match &config.admin_room {
+        Some(room) => Some(aliases::resolve(&homeserver_url, &session_storage, room).await?),
+        None => None,
+    };
+    let announce_lifecycle = config
+        .announce_lifecycle
+        .as_deref()
+        .map(LifecycleAnnouncements::parse)
+        .unwrap_or(Ok(LifecycleAnnouncements::Off))?;
+    let poll_before_sync = config.poll_before_sync;
+    let default_interval = Duration::from_secs(config.sleep_time_in_minutes * 60);
+    let watch_list_storage =
+        WatchListStorage::parse(config.watch_list_storage.as_deref().unwrap_or("file"))?;
+    let language = config
+        .language
+        .as_deref()
+        .map(Language::parse)
+        .unwrap_or(Ok(Language::En))?;
+    let bot_users = user_patterns(&config.bot_users)?;
+    let admins = user_patterns(&config.admins)?;
+    let limits = extract_limits(settings, &prefix)?;
 
+    let subscriptions = subscription_sections(settings, instance)?;
     let mut sources = Vec::new();
-    for (_name, val) in settings.get_table("subscription")? {
-        let sub = val.into_table()?;
-        let url_part = sub
-            .get("url_part")
-            .ok_or(ConfigError::NotFound(String::from("url_part")))?
-            .clone()
-            .into_string()?;
-        let query_subdirs = sub
-            .get("query_subdirs")
-            .ok_or(ConfigError::NotFound(String::from("query_subdirs")))?
-            .clone()
-            .into_bool()?;
-        let filter = sub
-            .get("filter")
-            .map(Clone::clone)
-            .map(Value::into_string)
-            .transpose()?
-            .map(|x| Regex::new(&x))
-            .transpose()?;
-        sources.push(MozData::new(&url_part, filter, query_subdirs));
+    let mut schedules = Vec::new();
+    for (name, sub) in subscriptions {
+        let mut mozdata =
+            parse_subscription(&name, &sub).with_context(|| format!("subscription {name}"))?;
+        let schedule = parse_schedule(&sub, default_interval)
+            .with_context(|| format!("subscription {name}"))?;
+        mozdata.rooms = match &sub.rooms {
+            Some(rooms) => {
+                let mut resolved = Vec::new();
+                for room in rooms {
+                    resolved.push(aliases::resolve(&homeserver_url, &session_storage, room).await?);
+                }
+                Some(resolved)
+            }
+            None => None,
+        };
+        schedules.push((name, schedule));
+        sources.push(mozdata);
     }
-    // -------------------------------------------------------
+    for (room_id, room) in &room_configs {
+        let names: Vec<_> = sources.iter().map(|x| x.name.as_str()).collect();
+        check_routed_sources(room.sources.as_deref(), &names)
+            .with_context(|| format!("room {room_id}"))?;
+    }
+
     let botconfig = BotConfig::new(
         login_data,
         homeserver_url,
         session_storage,
         ignore_own_messages,
-        autojoin,
+        config.autojoin,
         accept_commands_from,
+        room_configs,
+        rooms,
+        spaces,
+        admin_room,
+        Duration::from_secs(config.admin_report_interval_minutes * 60),
+        announce_lifecycle,
+        Duration::from_secs(config.outbox_ttl_hours * 60 * 60),
+        config.max_consecutive_failures,
+        Duration::from_secs(config.startup_quiet_minutes * 60),
+        bootstrap_cross_signing,
+        key_backup,
+        default_interval,
+        limits,
+        watch_list_storage,
+        config.command_prefix.clone(),
+        config.personal_subscriptions,
+        ignore_users,
+        language,
+        config.leave_empty_rooms,
+        bot_users,
+        device_name,
+        admins,
     );
-    let mut shared_state = SharedState::new(botconfig);
+    let mut shared_state = SharedState::new(botconfig, poller);
+    for (source, (_, schedule)) in sources.iter().zip(&schedules) {
+        shared_state
+            .sources
+            .lock()
+            .unwrap()
+            .insert(source.name.clone(), SourceStatus::new(source, schedule));
+    }
+
+    if config.sign_announcements {
+        let signer = AnnouncementSigner::load_or_create(&shared_state.cfg.session_storage).await?;
+        println!(
+            "Signing announcements with Ed25519 key {}",
+            signer.public_key()
+        );
+        shared_state.signer = Some(Arc::new(signer));
+    }
+
+    watch_list::restore_from_file(&shared_state).await?;
+
+    Ok((
+        Instance {
+            shared_state,
+            sources,
+            poll_before_sync,
+        },
+        schedules,
+    ))
+}
 
-    if let Some(db) = &shared_state.cfg.session_storage.get_session_db() {
-        let watched_files = db.db_path.join("watched_rooms");
-        if watched_files.exists() {
-            let serialized_session = fs::read_to_string(&watched_files).await?;
-            shared_state.rooms = serde_json::from_str(&serialized_session)?;
+/// Extensions of the formats the config crate reads
+const CONFIG_EXTENSIONS: &[&str] = &["toml", "json", "yaml", "yml", "ini", "ron", "json5"];
+
+/// Where to look for botconfig.<extension>, in this order
+fn config_dirs() -> Vec<PathBuf> {
+    let mut config_dirs = vec![PathBuf::from(".")];
+    if let Some(config_dir) = dirs::config_dir() {
+        config_dirs.push(config_dir.join("matrix_mozilla_bot"));
+    }
+    #[cfg(unix)]
+    config_dirs.push(PathBuf::from("/etc/matrix_mozilla_bot"));
+    config_dirs
+}
+
+/// Profile names end up in file names and the data directory
+fn check_profile(profile: &str) -> anyhow::Result<()> {
+    if profile.is_empty()
+        || !profile
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+    {
+        anyhow::bail!("Profile names may only contain letters, digits, - and _");
+    }
+    Ok(())
+}
+
+/// botconfig, or botconfig.<profile> for a profile
+fn config_name(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("botconfig.{profile}"),
+        None => String::from("botconfig"),
+    }
+}
+
+/// The first botconfig.<extension> (botconfig.<profile>.<extension> for a profile) in the
+/// `config_dirs`
+fn find_config(profile: Option<&str>) -> anyhow::Result<PathBuf> {
+    let name = config_name(profile);
+    let config_dirs = config_dirs();
+    for dir in &config_dirs {
+        for extension in CONFIG_EXTENSIONS {
+            let path = dir.join(format!("{name}.{extension}"));
+            if path.is_file() {
+                return Ok(path);
+            }
         }
     }
+    let tried: Vec<_> = config_dirs
+        .iter()
+        .map(|x| {
+            format!(
+                "  {}{}{name}.{{{}}}",
+                x.display(),
+                std::path::MAIN_SEPARATOR,
+                CONFIG_EXTENSIONS.join(",")
+            )
+        })
+        .collect();
+    anyhow::bail!(
+        "No config found, tried:\n{}\nCreate one with `matrix_mozilla_bot init` or pass --config",
+        tried.join("\n")
+    )
+}
+
+/// Options that take a comma-separated list when set via the environment
+const ENVIRONMENT_LISTS: &[&str] = &[
+    "config.accept_commands_from",
+    "config.ignore_users",
+    "config.rooms",
+    "config.spaces",
+    "config.bot_users",
+    "config.admins",
+];
 
-    let client = login_and_sync(shared_state.clone()).await?;
+/// Sections that don't fit into variable names, as JSON in these variables: tables by
+/// name, or the list of rooms. Per-instance lists are only settable that way, too.
+const ENVIRONMENT_JSON: &[(&str, &str)] = &[
+    ("BOT_SUBSCRIPTIONS", "subscription"),
+    ("BOT_ROOMS", "room"),
+    ("BOT_ACCOUNTS", "account"),
+    ("BOT_INSTANCES", "instance"),
+];
 
+/// Options from environment variables like BOT_CONFIG__SLEEP_TIME_IN_MINUTES, which
+/// stands for `sleep_time_in_minutes` in `[config]`
+fn environment() -> config::Environment {
+    let mut environment = config::Environment::with_prefix("BOT")
+        .prefix_separator("_")
+        .separator("__")
+        .list_separator(",");
+    for key in ENVIRONMENT_LISTS {
+        environment = environment.with_list_parse_key(key);
+    }
+    environment
+}
+
+/// Reads botconfig.toml (or the file given with --config), overlaid by the environment.
+/// Without a config file, the environment has to provide everything. Which of them is
+/// used only gets printed at `startup`, not on every reload.
+fn load_settings(
+    config: Option<&Path>,
+    profile: Option<&str>,
+    startup: bool,
+) -> anyhow::Result<Config> {
+    // ------- Getting the login-credentials from file ------
+    // You can get them however you like: hard-code them here, env-variabl,
+    // tcp-connection, read from file, etc. Here, we use the config-crate to
+    // load from botconfig.toml, or the file given with --config.
+    // Change this file to your needs, if you want to use this example binary.
+    let mut builder = Config::builder();
+    match config {
+        Some(path) => builder = builder.add_source(config::File::from(path)),
+        None => match find_config(profile) {
+            Ok(path) => {
+                if startup {
+                    println!("Using the config {}", path.display());
+                }
+                builder = builder.add_source(config::File::from(path));
+            }
+            Err(_) if std::env::vars().any(|(x, _)| x.starts_with("BOT_")) => {
+                if startup {
+                    println!("No config file, using only the environment");
+                }
+            }
+            Err(e) => return Err(e),
+        },
+    }
+    for (variable, key) in ENVIRONMENT_JSON {
+        let Ok(value) = std::env::var(variable) else {
+            continue;
+        };
+        let value: serde_json::Value = serde_json::from_str(&value)
+            .map_err(|e| anyhow::anyhow!("{variable} is not valid JSON: {e}"))?;
+        let section = serde_json::json!({ *key: value });
+        builder = builder.add_source(config::File::from_str(
+            &section.to_string(),
+            config::FileFormat::Json,
+        ));
+    }
+    builder = builder.add_source(environment());
+    if let Some(profile) = profile {
+        builder = builder.set_override("profile", profile)?;
+    }
+    Ok(builder.build()?)
+}
+
+/// The instance and account name of each bot of the config.
+/// In fleet mode, every [instance.<name>] section is a bot of its own. Otherwise every
+/// [account.<name>] section is another account announcing the top-level subscriptions.
+fn instance_names(settings: &Config) -> anyhow::Result<Vec<(Option<String>, Option<String>)>> {
+    match settings.get_table("instance") {
+        Ok(instances) => Ok(instances.into_keys().map(|x| (Some(x), None)).collect()),
+        Err(..) => {
+            let mut accounts = Vec::new();
+            if settings.get_string("login.homeserver_url").is_ok() {
+                accounts.push((None, None));
+            }
+            let names = settings.get_table("account").unwrap_or_default();
+            accounts.extend(names.into_keys().map(|x| (None, Some(x))));
+            if accounts.is_empty() {
+                anyhow::bail!("No login configured, neither [login] nor [account.<name>]");
+            }
+            Ok(accounts)
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let subcommand = cli.command.clone().unwrap_or(Subcommand::Run);
+    let profile = cli.profile.as_deref();
+    if let Some(profile) = profile {
+        check_profile(profile)?;
+    }
+    if cli.verbose {
+        tracing_subscriber::fmt()
+            .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
+            .init();
+    }
+    // These need no config, they write one
+    match &subcommand {
+        Subcommand::GenerateConfig { output } => {
+            return cli::generate_config(output.as_deref(), cli.force);
+        }
+        Subcommand::Init if cli.non_interactive => {
+            anyhow::bail!("init asks questions, it can't run with --non-interactive");
+        }
+        Subcommand::Init => {
+            let path = cli
+                .config
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(format!("{}.toml", config_name(profile))));
+            return init::run(&path, profile, cli.force).await;
+        }
+        _ => {}
+    }
+    let settings = load_settings(cli.config.as_deref(), profile, true)?;
+    let instance_names = instance_names(&settings)?;
+
+    if subcommand == Subcommand::CheckConfig {
+        return check_config::run(&settings, &instance_names).await;
+    }
+    // Without the login, which would need the secrets and the homeserver
+    if subcommand == Subcommand::Fetch {
+        return fetch(&settings, &instance_names).await;
+    }
+
+    let http = Arc::new(HttpCache::new());
+    let mut scheduler = Scheduler::new();
+    let (poller_tx, mut poller_rx) = mpsc::unbounded_channel();
+    let mut instances = Vec::new();
+    // Held as long as the bot runs
+    let mut locks = Vec::new();
+    for (idx, (instance_name, account)) in instance_names.iter().enumerate() {
+        // All instances talk to the same polling loop, tagged with their index
+        let (instance_tx, mut instance_rx) = mpsc::unbounded_channel();
+        let merged_tx = poller_tx.clone();
+        tokio::spawn(async move {
+            while let Some(cmd) = instance_rx.recv().await {
+                if merged_tx.send((idx, cmd)).is_err() {
+                    break;
+                }
+            }
+        });
+        let (mut instance, schedules) = extract_instance(
+            &settings,
+            instance_name.as_deref(),
+            account.as_deref(),
+            instance_tx,
+            !cli.non_interactive,
+        )
+        .await?;
+        for (name, schedule) in schedules {
+            scheduler.add((idx, name), schedule);
+        }
+        // Only where the state or the session gets written, the one-off commands like send
+        // and rooms work next to a running bot
+        let writes_state = matches!(
+            subcommand,
+            Subcommand::Run
+                | Subcommand::Login
+                | Subcommand::MigrateSession { .. }
+                | Subcommand::RestoreBackup { .. }
+                | Subcommand::State {
+                    action: StateAction::Import { .. }
+                }
+        );
+        if writes_state && !cli.force {
+            if let Some(db) = instance.shared_state.cfg.session_storage.get_session_db() {
+                locks.push(instance_lock::acquire(&db.db_path)?);
+            }
+        }
+
+        if subcommand == Subcommand::Run {
+            for source in &mut instance.sources {
+                restore_seen_entries(&instance.shared_state, source).await;
+            }
+        }
+        if instance.poll_before_sync && subcommand == Subcommand::Run {
+            // Get the baseline of the sources that have none yet before we start listening
+            // to commands
+            for source in instance.sources.iter_mut().filter(|x| x.data.is_empty()) {
+                if let Err(e) = source
+                    .fetch_upstream_and_compare(&http, &instance.shared_state.resources)
+                    .await
+                {
+                    eprintln!("Failed to fetch {}: {e:?}", source.url_part);
+                    instance
+                        .shared_state
+                        .record_failure(&source.name, e.to_string());
+                }
+                scheduler.mark_polled(&(idx, source.name.clone()));
+            }
+        }
+        instances.push(instance);
+    }
+    // -------------------------------------------------------
+
+    match &subcommand {
+        // The others without any instances were handled before setting them up
+        Subcommand::Run
+        | Subcommand::CheckConfig
+        | Subcommand::Fetch
+        | Subcommand::GenerateConfig { .. }
+        | Subcommand::Init => {}
+        Subcommand::Login => {
+            for instance in &instances {
+                matrix::login_only(&instance.shared_state).await?;
+            }
+            return Ok(());
+        }
+        Subcommand::Devices { action } => {
+            let action = action.clone().unwrap_or(DevicesAction::List);
+            for instance in &instances {
+                devices::run_subcommand(&instance.shared_state, &action).await?;
+            }
+            return Ok(());
+        }
+        Subcommand::Rooms { action } => {
+            let action = action.clone().unwrap_or(RoomsAction::List);
+            for instance in &instances {
+                rooms::run_subcommand(&instance.shared_state, &action).await?;
+            }
+            return Ok(());
+        }
+        Subcommand::Send { room, message } => {
+            let message = match message {
+                Some(message) => message.clone(),
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let message = message.trim();
+            if message.is_empty() {
+                anyhow::bail!("Nothing to send");
+            }
+            // From the first account that is in the room, the others would repeat it
+            let mut sent = false;
+            for instance in &instances {
+                if rooms::send(&instance.shared_state, room, message).await? {
+                    sent = true;
+                    break;
+                }
+            }
+            if !sent {
+                anyhow::bail!("No bot account is in {room}");
+            }
+            return Ok(());
+        }
+        Subcommand::MigrateSession { to } => {
+            return migrate_session::run(
+                cli.config.as_deref(),
+                profile,
+                &settings,
+                &instance_names,
+                &instances,
+                *to,
+            )
+            .await;
+        }
+        Subcommand::State { action } => {
+            let aios: Vec<_> = instances.iter().map(|x| &x.shared_state).collect();
+            return state::run_subcommand(&aios, action).await;
+        }
+        Subcommand::RestoreBackup { backup } => {
+            let aios: Vec<_> = instances.iter().map(|x| &x.shared_state).collect();
+            return state_db::run_subcommand(&aios, backup.as_deref());
+        }
+        Subcommand::LeaveAll { yes } => {
+            if !yes {
+                if cli.non_interactive {
+                    anyhow::bail!("leave-all needs --yes to run with --non-interactive");
+                }
+                if !init::ask_yes_no("Leave every room of every account of the config?", false)? {
+                    println!("Aborted");
+                    return Ok(());
+                }
+            }
+            for instance in &instances {
+                let aio = &instance.shared_state;
+                let client = matrix::restore_client(aio).await?;
+                // So that the cleanup after leaving persists the rest of the state correctly
+                subscriptions::restore(&client, aio).await?;
+                alerts::restore(&client, aio).await?;
+                outbox::restore(&client, aio).await?;
+                let count = empty_rooms::leave_all(&client, aio).await?;
+                println!("Left {count} rooms");
+            }
+            return Ok(());
+        }
+    }
+
+    let mut clients = Vec::with_capacity(instances.len());
+    for instance in &instances {
+        let client = login_and_sync(instance.shared_state.clone()).await?;
+        tokio::spawn(flush_quiet_hours_queues(
+            client.clone(),
+            instance.shared_state.clone(),
+        ));
+        if let Err(e) = subscriptions::restore(&client, &instance.shared_state).await {
+            eprintln!("Failed to restore the runtime subscriptions: {e:?}");
+        }
+        if let Err(e) = personal::restore(&client, &instance.shared_state).await {
+            eprintln!("Failed to restore the personal subscriptions: {e:?}");
+        }
+        if let Err(e) = alerts::restore(&client, &instance.shared_state).await {
+            eprintln!("Failed to restore the keyword alerts: {e:?}");
+        }
+        if let Err(e) = ignore_list::restore(&client, &instance.shared_state).await {
+            eprintln!("Failed to restore the ignored users: {e:?}");
+        }
+        if let Err(e) = outbox::restore(&client, &instance.shared_state).await {
+            eprintln!("Failed to restore the undelivered notifications: {e:?}");
+        }
+        if let Err(e) = restore_queued(&instance.shared_state).await {
+            eprintln!("Failed to restore the held-back notifications: {e:?}");
+        }
+        if let Err(e) = pins::restore(&instance.shared_state).await {
+            eprintln!("Failed to restore the pinned notifications: {e:?}");
+        }
+        tokio::spawn(outbox::run(client.clone(), instance.shared_state.clone()));
+        tokio::spawn(admin::run(client.clone(), instance.shared_state.clone()));
+        if let Err(e) = retention::restore(&client, &instance.shared_state).await {
+            eprintln!("Failed to restore the sent notifications: {e:?}");
+        }
+        tokio::spawn(retention::run(
+            client.clone(),
+            instance.shared_state.clone(),
+        ));
+        lifecycle::announce_startup(&client, &instance.shared_state).await;
+        clients.push(client);
+    }
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut hangups = reload::Hangups::listen();
     loop {
-        for source in &mut sources {
-            let answer = source.fetch_upstream_and_compare().await?;
-            if !answer.is_empty() {
-                let mut formatted_answer: Vec<_> = answer.iter().map(|x| x.to_string()).collect();
-                formatted_answer.sort();
-                let answer_str = formatted_answer.join(", ");
-                println!("{} differ: {:?}", source.url_part, answer_str);
-                let roomids: Vec<_> = shared_state
-                    .rooms
-                    .lock()
-                    .unwrap()
+        let event = tokio::select! {
+            due = scheduler.wait_for_due() => PollEvent::Due(due),
+            Some((idx, cmd)) = poller_rx.recv() => PollEvent::Command(idx, cmd),
+            _ = &mut shutdown => PollEvent::Shutdown { restart: false },
+            _ = hangups.recv() => PollEvent::Reload,
+        };
+        match event {
+            PollEvent::Reload => {
+                println!("Reloading the config");
+                if let Err(e) = reload::reload(
+                    cli.config.as_deref(),
+                    profile,
+                    &instance_names,
+                    &mut instances,
+                    &mut scheduler,
+                )
+                .await
+                {
+                    eprintln!("Not reloading the config: {e:?}");
+                }
+            }
+            PollEvent::Shutdown { restart: again }
+            | PollEvent::Command(_, PollerCommand::Shutdown { restart: again }) => {
+                println!("Shutting down");
+                for (instance, client) in instances.iter().zip(&clients) {
+                    lifecycle::announce_shutdown(client, &instance.shared_state).await;
+                    outbox::finish(client, &instance.shared_state).await;
+                    if let Err(e) = retention::store(&instance.shared_state).await {
+                        eprintln!("Failed to persist the sent notifications: {e:?}");
+                    }
+                }
+                if again {
+                    println!("Restarting");
+                    // On unix the new process replaces this one, and the lock files go
+                    // away with the exec, as std opens all files with O_CLOEXEC.
+                    // Elsewhere it starts while this one still runs, and must get the
+                    // locks.
+                    #[cfg(not(unix))]
+                    drop(locks);
+                    restart()?;
+                }
+                return Ok(());
+            }
+            PollEvent::Due(due) => {
+                for (idx, name) in due {
+                    let instance = &mut instances[idx];
+                    if instance.shared_state.is_disabled(&name) {
+                        continue;
+                    }
+                    if let Some(source) = instance.sources.iter_mut().find(|x| x.name == name) {
+                        poll_source(&clients[idx], &instance.shared_state, source, &http).await;
+                    }
+                }
+            }
+            PollEvent::Command(idx, PollerCommand::CheckNow { source, reply }) => {
+                let instance = &mut instances[idx];
+                let mut results = Vec::new();
+                for mozdata in instance
+                    .sources
+                    .iter_mut()
+                    .filter(|x| source.is_none() || source.as_ref() == Some(&x.name))
+                {
+                    let outcome =
+                        poll_source(&clients[idx], &instance.shared_state, mozdata, &http).await;
+                    results.push((mozdata.url_part.clone(), outcome));
+                }
+                // The requester might have given up waiting, which is fine
+                let _ = reply.send(results);
+            }
+            PollEvent::Command(idx, PollerCommand::Entries { source, reply }) => {
+                let results = instances[idx]
+                    .sources
                     .iter()
-                    .map(|x| x.to_owned())
-                    .collect();
-
-                for roomid in roomids {
-                    if let Some(room) = client.get_room(&roomid) {
-                        if room.state() != RoomState::Joined {
-                            continue;
+                    .filter(|x| source.is_none() || source.as_ref() == Some(&x.name))
+                    .map(|x| {
+                        let mut entries: Vec<_> = x.data.iter().cloned().collect();
+                        entries.sort_by(|a, b| compare_versions(a, b));
+                        SourceEntries {
+                            name: x.name.clone(),
+                            url: format!("{}/{}", x.base_url, x.url_part),
+                            entries,
                         }
-                        let content = RoomMessageEventContent::text_html(
-                            &format!("{} got new uploads: {}", source.url_part, answer_str),
-                            &format!(
-                                "<a href=\"{}/{}/\">{}</a> got new uploads: {}",
-                                source.base_url, source.url_part, source.url_part, answer_str
-                            ),
-                        );
-                        room.send(content).await?;
+                    })
+                    .collect();
+                let _ = reply.send(results);
+            }
+            PollEvent::Command(idx, PollerCommand::Subscribe(mozdata)) => {
+                let instance = &mut instances[idx];
+                println!("Subscribing to {} ({})", mozdata.name, mozdata.url_part);
+                let mut mozdata = mozdata;
+                if instance.sources.iter().any(|x| x.name == mozdata.name) {
+                    // What the replaced one has seen doesn't apply to the new one
+                    store_seen_entries(&instance.shared_state, &mozdata.name, &HashSet::new())
+                        .await;
+                } else {
+                    // Runtime subscriptions come back this way after a restart
+                    restore_seen_entries(&instance.shared_state, &mut mozdata).await;
+                }
+                instance.sources.retain(|x| x.name != mozdata.name);
+                instance
+                    .shared_state
+                    .resources
+                    .forget_seen_entries(&mozdata.name);
+                let schedule = Schedule::Interval(instance.shared_state.default_interval());
+                instance
+                    .shared_state
+                    .sources
+                    .lock()
+                    .unwrap()
+                    .insert(mozdata.name.clone(), SourceStatus::new(&mozdata, &schedule));
+                scheduler.add((idx, mozdata.name.clone()), schedule);
+                instance.sources.push(mozdata);
+            }
+            PollEvent::Command(idx, PollerCommand::SetFilter { source, filter }) => {
+                let instance = &mut instances[idx];
+                let Some(mozdata) = instance.sources.iter_mut().find(|x| x.name == source) else {
+                    continue;
+                };
+                println!("Changing the filter of {source} to {filter:?}");
+                if let Some(status) = instance
+                    .shared_state
+                    .sources
+                    .lock()
+                    .unwrap()
+                    .get_mut(&source)
+                {
+                    status.filter = filter.as_ref().map(|x| x.as_str().to_string());
+                }
+                mozdata.filter = filter;
+                // Entries matching the new filter but not the old one aren't new uploads,
+                // so we take a fresh baseline right away instead of announcing them.
+                mozdata.data.clear();
+                instance.shared_state.resources.forget_seen_entries(&source);
+                poll_source(&clients[idx], &instance.shared_state, mozdata, &http).await;
+                scheduler.mark_polled(&(idx, source));
+            }
+            PollEvent::Command(idx, PollerCommand::SetRooms { source, rooms }) => {
+                let instance = &mut instances[idx];
+                let Some(mozdata) = instance.sources.iter_mut().find(|x| x.name == source) else {
+                    continue;
+                };
+                println!("Announcing {source} in {rooms:?}");
+                if let Some(status) = instance
+                    .shared_state
+                    .sources
+                    .lock()
+                    .unwrap()
+                    .get_mut(&source)
+                {
+                    status.rooms = rooms.clone();
+                }
+                mozdata.rooms = rooms;
+            }
+            PollEvent::Command(idx, PollerCommand::SetInterval { source, interval }) => {
+                let instance = &mut instances[idx];
+                let schedule = Schedule::Interval(interval);
+                let mut sources = instance.shared_state.sources.lock().unwrap();
+                for (name, status) in sources.iter_mut() {
+                    let affected = match &source {
+                        Some(source) => source == name,
+                        None => status.uses_default_interval,
+                    };
+                    if affected {
+                        println!("Polling {name} {schedule}");
+                        status.schedule = schedule.to_string();
+                        // Explicitly set intervals stick, even if the default changes later
+                        status.uses_default_interval &= source.is_none();
+                        scheduler.set_schedule(&(idx, name.clone()), schedule.clone());
                     }
                 }
             }
+            PollEvent::Command(idx, PollerCommand::Unsubscribe(name)) => {
+                let instance = &mut instances[idx];
+                println!("Unsubscribing from {name}");
+                instance.sources.retain(|x| x.name != name);
+                instance.shared_state.resources.forget_seen_entries(&name);
+                store_seen_entries(&instance.shared_state, &name, &HashSet::new()).await;
+                instance.shared_state.sources.lock().unwrap().remove(&name);
+                scheduler.remove(&(idx, name));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reloadable(accept_commands_from: &[&str], admins: &[&str]) -> ReloadableConfig {
+        let patterns = |x: &[&str]| x.iter().map(|x| UserPattern::parse(x).unwrap()).collect();
+        ReloadableConfig {
+            accept_commands_from: patterns(accept_commands_from),
+            default_interval: Duration::from_secs(60 * 60),
+            ignore_users: Vec::new(),
+            bot_users: Vec::new(),
+            admins: patterns(admins),
         }
-        sleep(Duration::from_secs(sleep_time_in_minutes * 60)).await;
+    }
+
+    fn user(id: &str) -> &UserId {
+        <&UserId>::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn bot_api_rejects_unlisted_senders() {
+        let config = reloadable(&["@alice:example.org"], &["@admin:example.org"]);
+        assert!(config.trusts_bot_api(user("@alice:example.org")));
+        assert!(config.trusts_bot_api(user("@admin:example.org")));
+        assert!(!config.trusts_bot_api(user("@mallory:evil.org")));
+    }
+
+    #[test]
+    fn bot_api_trusts_nobody_by_default() {
+        let config = reloadable(&[], &[]);
+        assert!(!config.trusts_bot_api(user("@mallory:evil.org")));
+        let config = reloadable(&[], &["@admin:example.org"]);
+        assert!(config.trusts_bot_api(user("@admin:example.org")));
+        assert!(!config.trusts_bot_api(user("@mallory:evil.org")));
     }
 }
@@ -1,84 +1,51 @@
-use super::{LoginData, SharedState};
+#[cfg(feature = "appservice")]
+use super::appservice;
+use super::{
+    admin, bot_api, commands, empty_rooms, encryption,
+    formatting::{self, Message},
+    i18n, knocking, oidc, personal, reactions,
+    room_settings::NotificationType,
+    send_queue, spaces, upgrades, verification, watch_list, LoginData, SharedState,
+};
 use matrix_sdk::{
     config::SyncSettings,
     event_handler::Ctx,
-    matrix_auth::{MatrixSession, MatrixSessionTokens},
     room::Room,
     ruma::{
         api::client::{error::ErrorKind, filter::FilterDefinition},
+        events::relation::Thread,
+        events::room::encrypted::OriginalSyncRoomEncryptedEvent,
         events::room::member::StrippedRoomMemberEvent,
         events::room::message::{
-            MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+            MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
             TextMessageEventContent,
         },
-        OwnedDeviceId, OwnedUserId,
+        events::Mentions,
+        EventId, OwnedEventId, RoomId,
     },
-    Client, RoomState, SessionMeta,
+    Client, LoopCtrl, RoomState,
 };
-use secret_service::{EncryptionType, SecretService};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
-use tokio::fs;
+use std::{
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 use tokio::time::{sleep, Duration};
 
-macro_rules! store_to_secret_service {
-    ($collection:expr, $name:expr, $data:expr) => {
-        $collection
-            .create_item(
-                "matrix_mozilla_bot",
-                HashMap::from([("matrix_mozilla_bot", $name)]),
-                $data,
-                true, // replace item with same attributes
-                "text/plain",
-            )
-            .await?;
-    };
-}
-
-macro_rules! get_from_secret_service {
-    ($collection:expr, $name:expr) => {
-        String::from_utf8(
-            $collection
-                .search_items(HashMap::from([("matrix_mozilla_bot", $name)]))
-                .await?
-                .get(0)
-                .ok_or(secret_service::Error::NoResult)?
-                .get_secret()
-                .await?,
-        )?
-    };
-}
+const MIN_SYNC_RESTART_DELAY: Duration = Duration::from_secs(2);
+const MAX_SYNC_RESTART_DELAY: Duration = Duration::from_secs(5 * 60);
+const SYNC_TOKEN_STORE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-macro_rules! get_optional_from_secret_service {
-    ($collection:expr, $name:expr) => {
-        if let Ok(tokens) = $collection
-            .search_items(HashMap::from([("name", $name)]))
-            .await
-        {
-            // Can't use .map() here, because of async-weirdness
-            if let Some(t) = tokens.get(0) {
-                t.get_secret()
-                    .await
-                    .map(|x| String::from_utf8(x).ok())
-                    .ok()
-                    .flatten()
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    };
-}
-
-async fn update_room_cache(ctx: &Ctx<SharedState>) -> anyhow::Result<()> {
-    if let Some(db) = ctx.cfg.session_storage.get_session_db() {
-        if db.db_path.exists() {
-            let serialized_rooms = serde_json::to_string(&*ctx.rooms.lock().unwrap())?;
-            fs::write(&db.db_path.join("watched_rooms"), serialized_rooms).await?;
-        }
+/// Rich replies quote the original message at the start of the body, like
+/// "> <@alice:example.org> original\n\n!status". Returns the body without that quote.
+fn strip_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = rest.split_once('\n').map(|(_, x)| x).unwrap_or_default();
     }
-    Ok(())
+    rest.trim_start_matches('\n')
 }
 
 async fn on_room_message(
@@ -93,33 +60,313 @@ async fn on_room_message(
             println!("Skipping message from ourselves.");
             return Ok(());
         }
-        if ctx.cfg.accept_commands_from.is_empty()
-            || ctx.cfg.accept_commands_from.contains(&event.sender)
-        {
-            if let MessageType::Text(TextMessageEventContent { body, .. }) = event.content.msgtype {
-                if body == "!ping" {
-                    let content = RoomMessageEventContent::text_plain("pong");
-                    room.send(content).await?;
-                }
-                if body == "!leave" {
-                    let content = RoomMessageEventContent::text_plain("Bye");
-                    room.send(content).await?;
-                    room.leave().await?;
-                    ctx.rooms.lock().unwrap().remove(room.room_id());
-                    update_room_cache(&ctx).await?;
-                }
-                if body == "!watch" {
-                    let content = RoomMessageEventContent::text_plain("Watching...");
-                    room.send(content).await?;
-                    ctx.rooms.lock().unwrap().insert(room.room_id().to_owned());
-                    update_room_cache(&ctx).await?;
+        if ctx.is_ignored(&event.sender) {
+            return Ok(());
+        }
+        let thread = match &event.content.relates_to {
+            Some(Relation::Thread(thread)) => Some(thread.event_id.clone()),
+            _ => None,
+        };
+        let is_reply = matches!(event.content.relates_to, Some(Relation::Reply { .. }));
+        if let MessageType::Text(TextMessageEventContent { body, .. }) = event.content.msgtype {
+            // Other bots talk to us with JSON in DMs
+            if ctx.accepts_bot_api_from(&event.sender)
+                && body.trim_start().starts_with('{')
+                && room.is_direct().await.unwrap_or(false)
+            {
+                if let Ok(command) = serde_json::from_str::<bot_api::BotApiCommand>(&body) {
+                    let response = bot_api::execute(&client, &ctx, command).await;
+                    let content = RoomMessageEventContent::text_plain(response.to_string());
+                    send_queue::send(&room, content).await?;
+                    return Ok(());
                 }
             }
+            let body = if is_reply {
+                strip_reply_fallback(&body)
+            } else {
+                &body
+            };
+            commands::registry()
+                .dispatch(
+                    body,
+                    room,
+                    client,
+                    ctx.0.clone(),
+                    event.sender,
+                    event.event_id,
+                    thread,
+                )
+                .await?;
         }
     }
     Ok(())
 }
 
+/// Successfully decrypted events end up in the regular handlers, so everything ending up
+/// here is an event we couldn't decrypt (yet), e.g. because the sender didn't share the
+/// room key with our device.
+async fn on_undecryptable_message(event: OriginalSyncRoomEncryptedEvent, room: Room) {
+    eprintln!(
+        "Unable to decrypt event {} from {} in room {}. Commands in it are ignored.",
+        event.event_id,
+        event.sender,
+        room.room_id()
+    );
+}
+
+/// Sends a notification to a joined room. For encrypted rooms the SDK takes care of
+/// loading all members and sharing the room key with their devices before sending.
+pub async fn send_to_room(
+    client: &Client,
+    room_id: &RoomId,
+    msgtype: NotificationType,
+    plain: &str,
+    html: &str,
+) -> anyhow::Result<()> {
+    send_to_room_with_fields(
+        client,
+        room_id,
+        msgtype,
+        plain,
+        html,
+        &serde_json::Map::new(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Where the rest of a split message goes: the thread it is in, after its last part
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Continuation {
+    pub root: OwnedEventId,
+    pub previous: OwnedEventId,
+}
+
+/// A split message of which some parts went out, but not all of them
+#[derive(Debug)]
+pub struct PartiallySent {
+    /// The parts that went out
+    pub sent: Vec<OwnedEventId>,
+    pub continuation: Continuation,
+    /// The parts that didn't go out
+    pub rest: Vec<(String, String)>,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for PartiallySent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} parts of the message weren't sent: {}",
+            self.rest.len(),
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for PartiallySent {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Sends `parts` one after the other into the thread of `continuation`, adding the IDs of
+/// the sent ones to `sent`
+async fn send_parts(
+    room: &Room,
+    msgtype: NotificationType,
+    mut continuation: Continuation,
+    parts: Vec<(String, String)>,
+    sent: &mut Vec<OwnedEventId>,
+) -> Result<(), (Continuation, Vec<(String, String)>, anyhow::Error)> {
+    let mut parts = parts.into_iter();
+    while let Some((plain, html)) = parts.next() {
+        let mut content = msgtype.content(plain.clone(), html.clone());
+        content.mentions = Some(Mentions::new());
+        content.relates_to = Some(Relation::Thread(Thread::plain(
+            continuation.root.clone(),
+            continuation.previous.clone(),
+        )));
+        match send_queue::send(room, content).await {
+            Ok(event_id) => {
+                sent.push(event_id.clone());
+                continuation.previous = event_id;
+            }
+            Err(e) => {
+                let rest = std::iter::once((plain, html)).chain(parts).collect();
+                return Err((continuation, rest, e));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Like `send_to_room`, but adds custom fields (e.g. machine-readable payloads) to the
+/// event content. Returns the IDs of the events it went out as, the first part first, or
+/// none if we can't post to the room.
+///
+/// Bodies too long for one event get split: the first part carries the fields, the rest
+/// follows in a thread on it (or in the thread the first part went to). If only some of
+/// the parts went out, the error is a `PartiallySent`.
+pub async fn send_to_room_with_fields(
+    client: &Client,
+    room_id: &RoomId,
+    msgtype: NotificationType,
+    plain: &str,
+    html: &str,
+    fields: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<Vec<OwnedEventId>> {
+    let Some(room) = client.get_room(room_id) else {
+        return Ok(Vec::new());
+    };
+    if room.state() != RoomState::Joined {
+        return Ok(Vec::new());
+    }
+    let mut fields = fields.clone();
+    formatting::add_mentions(&mut fields, Mentions::new())?;
+    let fields_len = serde_json::to_string(&fields)?.len();
+    let mut chunks = formatting::split(plain, html, fields_len).into_iter();
+    let Some((plain, html)) = chunks.next() else {
+        return Ok(Vec::new());
+    };
+    let mut content = serde_json::to_value(msgtype.content(plain, html))?;
+    if let Some(content) = content.as_object_mut() {
+        content.extend(fields.clone());
+    }
+    let first = send_queue::send_raw(&room, "m.room.message", content).await?;
+    let root = fields
+        .get("m.relates_to")
+        .filter(|x| x["rel_type"] == "m.thread")
+        .and_then(|x| x["event_id"].as_str())
+        .and_then(|x| EventId::parse(x).ok())
+        .unwrap_or_else(|| first.clone());
+    let continuation = Continuation {
+        root,
+        previous: first.clone(),
+    };
+    let mut sent = vec![first];
+    if let Err((continuation, rest, error)) =
+        send_parts(&room, msgtype, continuation, chunks.collect(), &mut sent).await
+    {
+        return Err(PartiallySent {
+            sent,
+            continuation,
+            rest,
+            error,
+        }
+        .into());
+    }
+    Ok(sent)
+}
+
+/// Sends the parts of a split message that didn't go out before. Returns the IDs of the
+/// sent parts, none if we can't post to the room. Fails with a `PartiallySent` like
+/// `send_to_room_with_fields`.
+pub async fn send_continuation(
+    client: &Client,
+    room_id: &RoomId,
+    msgtype: NotificationType,
+    continuation: &Continuation,
+    parts: Vec<(String, String)>,
+) -> anyhow::Result<Vec<OwnedEventId>> {
+    let Some(room) = client.get_room(room_id) else {
+        return Ok(Vec::new());
+    };
+    if room.state() != RoomState::Joined {
+        return Ok(Vec::new());
+    }
+    let mut sent = Vec::new();
+    if let Err((continuation, rest, error)) =
+        send_parts(&room, msgtype, continuation.clone(), parts, &mut sent).await
+    {
+        if sent.is_empty() {
+            return Err(error);
+        }
+        return Err(PartiallySent {
+            sent,
+            continuation,
+            rest,
+            error,
+        }
+        .into());
+    }
+    Ok(sent)
+}
+
+/// Replaces an earlier notification of ours with new content (m.replace), which clients
+/// show in place of the original. Returns false, if we can't post to the room.
+pub async fn edit_with_fields(
+    client: &Client,
+    room_id: &RoomId,
+    original: &EventId,
+    msgtype: NotificationType,
+    plain: &str,
+    html: &str,
+    fields: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<bool> {
+    let Some(room) = client.get_room(room_id) else {
+        return Ok(false);
+    };
+    if room.state() != RoomState::Joined {
+        return Ok(false);
+    }
+    // There is no continuing an edit in further messages, so overlong ones get cut. The
+    // body is in there twice, as the new content and as the fallback.
+    let fields_len = serde_json::to_string(fields)?.len();
+    let reserved =
+        formatting::MAX_BODY_LEN - formatting::MAX_BODY_LEN.saturating_sub(fields_len) / 2;
+    let (plain, html) = formatting::split(plain, html, reserved)
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    let (plain, html) = (plain.as_str(), html.as_str());
+    let mut new_content = serde_json::to_value(msgtype.content(plain, html))?;
+    if let Some(new_content) = new_content.as_object_mut() {
+        // Edits keep the relation of the original, e.g. its thread
+        new_content.extend(
+            fields
+                .iter()
+                .filter(|(key, _)| *key != "m.relates_to")
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+    }
+    // The fallback for clients without support for edits
+    let mut content =
+        serde_json::to_value(msgtype.content(format!("* {plain}"), format!("* {html}")))?;
+    if let Some(content) = content.as_object_mut() {
+        content.insert(String::from("m.new_content"), new_content);
+        content.insert(
+            String::from("m.relates_to"),
+            serde_json::json!({ "rel_type": "m.replace", "event_id": original }),
+        );
+    }
+    send_queue::send_raw(&room, "m.room.message", content).await?;
+    Ok(true)
+}
+
+/// Accepts an invite, retrying for up to an hour. Returns whether we got in.
+pub async fn join_with_retry(room: &Room) -> bool {
+    let mut delay = 2;
+    while let Err(err) = room.join().await {
+        // retry autojoin due to synapse sending invites, before the
+        // invited user can join for more information see
+        // https://github.com/matrix-org/synapse/issues/4345
+        eprintln!(
+            "Failed to join room {} ({err:?}), retrying in {delay}s",
+            room.room_id()
+        );
+
+        sleep(Duration::from_secs(delay)).await;
+        delay *= 2;
+
+        if delay > 3600 {
+            eprintln!("Can't join room {} ({err:?})", room.room_id());
+            return false;
+        }
+    }
+    true
+}
+
 async fn on_stripped_state_member(
     room_member: StrippedRoomMemberEvent,
     client: Client,
@@ -129,33 +376,30 @@ async fn on_stripped_state_member(
     if room_member.state_key != client.user_id().unwrap() {
         return;
     }
+    // Approved knocks are handled by knocking::on_invite
+    if ctx.knocked.lock().unwrap().contains(room.room_id()) {
+        return;
+    }
+    // Not even rejecting, so ignored users don't learn about it
+    if ctx.is_ignored(&room_member.sender) {
+        println!(
+            "Ignoring invite to room {} from ignored user {}",
+            room.room_id(),
+            room_member.sender
+        );
+        return;
+    }
 
     if room.state() == RoomState::Invited {
         tokio::spawn(async move {
-            if ctx.cfg.accept_commands_from.is_empty()
-                || ctx.cfg.accept_commands_from.contains(&room_member.sender)
-            {
+            // With personal subscriptions, anyone may start a DM with us
+            let personal_dm =
+                ctx.cfg.personal_subscriptions && room_member.content.is_direct == Some(true);
+            if ctx.accepts_commands_from(&room_member.sender) || personal_dm {
                 println!("Autojoining room {}", room.room_id());
-                let mut delay = 2;
-
-                while let Err(err) = room.join().await {
-                    // retry autojoin due to synapse sending invites, before the
-                    // invited user can join for more information see
-                    // https://github.com/matrix-org/synapse/issues/4345
-                    eprintln!(
-                        "Failed to join room {} ({err:?}), retrying in {delay}s",
-                        room.room_id()
-                    );
-
-                    sleep(Duration::from_secs(delay)).await;
-                    delay *= 2;
-
-                    if delay > 3600 {
-                        eprintln!("Can't join room {} ({err:?})", room.room_id());
-                        break;
-                    }
+                if join_with_retry(&room).await {
+                    println!("Successfully joined room {}", room.room_id());
                 }
-                println!("Successfully joined room {}", room.room_id());
             } else {
                 println!("Rejecting invite to room {}", room.room_id());
                 let mut delay = 2;
@@ -185,120 +429,13 @@ async fn on_stripped_state_member(
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct PlainMatrixSession {
-    user_session: MatrixSession,
-    sync_token: Option<String>,
-}
-
-/// Restore a previous session from plain storage.
-pub async fn restore_plain_session(
-    client: &Client,
-    session_file: &Path,
-) -> anyhow::Result<Option<String>> {
-    // The session was serialized as JSON in a file.
-    let serialized_session = fs::read_to_string(session_file).await?;
-    let session: PlainMatrixSession = serde_json::from_str(&serialized_session)?;
-
-    println!(
-        "Restoring session for {}…",
-        session.user_session.meta.user_id
-    );
-
-    // Restore the Matrix user session.
-    client.restore_session(session.user_session).await?;
-
-    Ok(session.sync_token)
-}
-
-/// Restore a previous session via SecretService.
-pub async fn restore_ss_session(client: &Client) -> anyhow::Result<Option<String>> {
-    let ss = SecretService::connect(EncryptionType::Dh).await?;
-    let collection = ss.get_default_collection().await?;
-    let access_token = get_from_secret_service!(collection, "access_token");
-    let device_id = get_from_secret_service!(collection, "device_id");
-    let user_id = get_from_secret_service!(collection, "user_id");
-    let refresh_token = get_optional_from_secret_service!(collection, "refresh_token");
-    let sync_token = get_optional_from_secret_service!(collection, "sync_token");
-
-    let user_session = MatrixSession {
-        meta: SessionMeta {
-            user_id: OwnedUserId::try_from(user_id)?,
-            device_id: OwnedDeviceId::try_from(device_id)?,
-        },
-        tokens: MatrixSessionTokens {
-            access_token,
-            refresh_token,
-        },
-    };
-    println!("Restoring session for {}…", user_session.meta.user_id);
-
-    // Restore the Matrix user session.
-    client.restore_session(user_session).await?;
-
-    Ok(sync_token)
-}
-
-pub async fn store_plain_session(
-    client: &Client,
-    session_path: &Path,
-    sync_token: &str,
-) -> anyhow::Result<()> {
-    let user_session = client
-        .matrix_auth()
-        .session()
-        .expect("A logged-in client should have a session");
-    let data = PlainMatrixSession {
-        user_session,
-        sync_token: Some(sync_token.to_string()),
-    };
-    let serialized_session = serde_json::to_string(&data)?;
-    fs::write(&session_path, serialized_session).await?;
-    Ok(())
-}
-
-pub async fn store_ss_session(client: &Client, sync_token: &str) -> anyhow::Result<()> {
-    let user_session = client
-        .matrix_auth()
-        .session()
-        .expect("A logged-in client should have a session");
-    let ss = SecretService::connect(EncryptionType::Dh).await?;
-    let collection = match ss.get_default_collection().await {
-        Ok(c) => c,
-        Err(secret_service::Error::NoResult) => {
-            ss.create_collection("matrix_mozilla_bot", "default")
-                .await?
-        }
-        Err(x) => {
-            return Err(x.into());
-        }
-    };
-
-    if let Some(refresh_token) = user_session.tokens.refresh_token {
-        store_to_secret_service!(collection, "refresh_token", refresh_token.as_bytes());
-    }
-    store_to_secret_service!(collection, "sync_token", sync_token.as_bytes());
-    store_to_secret_service!(
-        collection,
-        "access_token",
-        user_session.tokens.access_token.as_bytes()
-    );
-    store_to_secret_service!(collection, "user_id", user_session.meta.user_id.as_bytes());
-    store_to_secret_service!(
-        collection,
-        "device_id",
-        user_session.meta.device_id.as_bytes()
-    );
-    Ok(())
-}
-
 pub async fn login(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
     match &aio.cfg.login_data {
         LoginData::UsernamePassword(username, password) => {
             client
                 .matrix_auth()
                 .login_username(username, password)
-                .initial_device_display_name("Mozilla FTP watcher")
+                .initial_device_display_name(&aio.cfg.device_name)
                 .send()
                 .await?;
             println!("logged in as {}", username);
@@ -312,7 +449,7 @@ pub async fn login(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
                     println!("{sso_url}");
                     Ok(())
                 })
-                .initial_device_display_name("Mozilla FTP watcher")
+                .initial_device_display_name(&aio.cfg.device_name)
                 .send()
                 .await
                 .unwrap();
@@ -322,34 +459,139 @@ pub async fn login(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
                 response.user_id, response.device_id, response.access_token
             );
         }
+        LoginData::Oidc { client_id } => {
+            oidc::login(client, aio, client_id).await?;
+        }
+        #[cfg(feature = "appservice")]
+        LoginData::AppService(..) => anyhow::bail!("Application services don't log in"),
     }
     Ok(())
 }
 
-pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
+pub fn register_event_handlers(client: &Client, aio: &SharedState) {
+    client.add_event_handler_context(aio.clone());
+    if aio.cfg.autojoin {
+        client.add_event_handler(on_stripped_state_member);
+    }
+    client.add_event_handler(on_room_message);
+    client.add_event_handler(reactions::on_reaction);
+    client.add_event_handler(upgrades::on_tombstone);
+    client.add_event_handler(spaces::on_space_child);
+    client.add_event_handler(knocking::on_invite);
+    if aio.cfg.leave_empty_rooms {
+        client.add_event_handler(empty_rooms::on_member);
+    }
+    if aio.cfg.personal_subscriptions {
+        client.add_event_handler(personal::on_member);
+    }
+    client.add_event_handler(on_undecryptable_message);
+    client.add_event_handler(verification::on_to_device_verification_request);
+    client.add_event_handler(verification::on_room_verification_request);
+    client.add_event_handler(bot_api::on_to_device_command);
+}
+
+/// Removes the stores of the Matrix SDK, but not the bot's own files next to them (the
+/// state DB, its backups and the lock)
+async fn clear_sdk_stores(db_path: &Path) -> anyhow::Result<()> {
+    let mut entries = tokio::fs::read_dir(db_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with("matrix-sdk-") {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the client and restores the persisted session, if there is one. Returns whether
+/// a session was restored, and its sync token.
+async fn build_client(aio: &SharedState) -> anyhow::Result<(Client, bool, Option<String>)> {
     let mut client_builder = Client::builder().homeserver_url(aio.cfg.homeserver_url.clone());
+    // The sqlite store holds the state- as well as the crypto-store
     if let Some(db) = &aio.cfg.session_storage.get_session_db() {
         client_builder = client_builder.sqlite_store(&db.db_path, Some(&db.db_pw));
+    } else {
+        println!("Session is not persisted. Encryption keys only live in memory and messages in encrypted rooms can't be decrypted after a restart.");
     }
 
-    let mut client = client_builder.build().await?;
-    let (mut logged_in, sync_token) = match &aio.cfg.session_storage {
-        crate::SessionStorage::Ephemeral => (false, None), // Nothing to restore
-        crate::SessionStorage::Plain(_, session) => {
-            if let Ok(sync_token) = restore_plain_session(&client, &session.session_path).await {
-                (true, sync_token)
-            } else {
-                (false, None)
-            }
-        }
-        crate::SessionStorage::SecretService(_) => {
-            if let Ok(sync_token) = restore_ss_session(&client).await {
-                (true, sync_token)
-            } else {
-                (false, None)
-            }
-        }
+    let client = client_builder.build().await?;
+    let (logged_in, sync_token) = match aio.cfg.session_storage.restore_session(&client).await {
+        Ok(sync_token) => (true, sync_token),
+        Err(_) => (false, None),
     };
+    Ok((client, logged_in, sync_token))
+}
+
+/// A client with the persisted session, for one-off tasks that neither log in (which
+/// would leave yet another device behind) nor sync
+pub async fn restore_client(aio: &SharedState) -> anyhow::Result<Client> {
+    Ok(restore_client_with_sync_token(aio).await?.0)
+}
+
+/// Like `restore_client`, also returning the sync token stored with the session
+pub async fn restore_client_with_sync_token(
+    aio: &SharedState,
+) -> anyhow::Result<(Client, Option<String>)> {
+    #[cfg(feature = "appservice")]
+    if matches!(aio.cfg.login_data, LoginData::AppService(..)) {
+        anyhow::bail!("Not available for application services");
+    }
+    let (client, logged_in, sync_token) = build_client(aio).await?;
+    if !logged_in {
+        anyhow::bail!("No stored session to restore, run the bot once to log in");
+    }
+    if matches!(aio.cfg.login_data, LoginData::Oidc { .. }) {
+        oidc::refresh_restored_session(&client, aio).await?;
+    }
+    Ok((client, sync_token))
+}
+
+/// Logs in and persists the session, syncing only once for a sync token to store with it.
+/// For setting up the session where there is a TTY, for a service to restore later.
+pub async fn login_only(aio: &SharedState) -> anyhow::Result<()> {
+    #[cfg(feature = "appservice")]
+    if matches!(aio.cfg.login_data, LoginData::AppService(..)) {
+        anyhow::bail!("Application services don't log in");
+    }
+    if aio.cfg.session_storage.get_session_db().is_none() {
+        anyhow::bail!(
+            "The session isn't persisted (login.persist_session), logging in now is pointless"
+        );
+    }
+    let (client, logged_in, _) = build_client(aio).await?;
+    if logged_in {
+        let user_id = client.user_id().map(|x| x.to_string()).unwrap_or_default();
+        println!("There already is a stored session of {user_id}");
+        return Ok(());
+    }
+    login(&client, aio).await?;
+    let filter = FilterDefinition::with_lazy_loading();
+    let response = client
+        .sync_once(SyncSettings::default().filter(filter.into()))
+        .await?;
+    store_session(&client, aio, &response.next_batch).await?;
+    println!("Session stored");
+    Ok(())
+}
+
+pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
+    // Events are pushed to us by the homeserver, so there is no login and no sync
+    #[cfg(feature = "appservice")]
+    if let LoginData::AppService(appservice_cfg) = &aio.cfg.login_data {
+        return appservice::start(aio.clone(), appservice_cfg).await;
+    }
+    let (mut client, mut logged_in, sync_token) = build_client(&aio).await?;
+
+    // Reported to the admin room once we are able to send
+    let mut login_problems = Vec::new();
+
+    // OIDC access tokens are short-lived, the restored one has most likely expired
+    if logged_in && matches!(aio.cfg.login_data, LoginData::Oidc { .. }) {
+        if let Err(e) = oidc::refresh_restored_session(&client, &aio).await {
+            eprintln!("Failed to refresh the OIDC access token: {e:?}");
+            login_problems.push(format!("Failed to refresh the OIDC access token: {e}"));
+        }
+    }
 
     let filter = FilterDefinition::with_lazy_loading();
     let mut sync_settings = SyncSettings::default().filter(filter.clone().into());
@@ -375,16 +617,7 @@ pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
                 // This is the last time we need to provide this token, the sync method after
                 // will handle it on its own.
                 sync_settings = sync_settings.token(response.next_batch.clone());
-                match &aio.cfg.session_storage {
-                    crate::SessionStorage::Ephemeral => (),
-                    crate::SessionStorage::Plain(_, session) => {
-                        store_plain_session(&client, &session.session_path, &response.next_batch)
-                            .await?;
-                    }
-                    crate::SessionStorage::SecretService(..) => {
-                        store_ss_session(&client, &response.next_batch).await?;
-                    }
-                }
+                store_session(&client, &aio, &response.next_batch).await?;
                 // persist_sync_token(session_file, response.next_batch).await?;
                 break;
             }
@@ -392,6 +625,10 @@ pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
                 None => {
                     println!("An error occurred during initial sync: {error}");
                     println!("Trying again…");
+                    let problem = format!("Initial sync failed: {error}");
+                    if !login_problems.contains(&problem) {
+                        login_problems.push(problem);
+                    }
                 }
                 Some(ErrorKind::LimitExceeded { retry_after_ms }) => {
                     sleep(retry_after_ms.unwrap_or(Duration::from_secs(5))).await;
@@ -400,6 +637,11 @@ pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
                 Some(ErrorKind::ConnectionTimeout) => {}
                 Some(ErrorKind::UnknownToken { .. }) | Some(ErrorKind::MissingToken) => {
                     println!("The login data we sent didn't work (probably from restoring the session). Trying fresh login.");
+                    let problem =
+                        String::from("The restored session was rejected, logged in again");
+                    if !login_problems.contains(&problem) {
+                        login_problems.push(problem);
+                    }
                     logged_in = false;
                     // Unsetting sync_token isn't possible, so we recreate a new sync_setting-object
                     sync_settings = SyncSettings::default().filter(filter.clone().into());
@@ -408,7 +650,7 @@ pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
                     if let Some(db) = &aio.cfg.session_storage.get_session_db() {
                         println!("Removing storage DB");
                         // We need to clear the database, too
-                        tokio::fs::remove_dir_all(&db.db_path).await?;
+                        clear_sdk_stores(&db.db_path).await?;
                         client_builder = client_builder.sqlite_store(&db.db_path, Some(&db.db_pw));
                     }
                     client = client_builder.build().await?;
@@ -416,6 +658,10 @@ pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
                 }
                 Some(ErrorKind::Forbidden) => {
                     println!("Wrong password or username. Trying again.");
+                    let problem = String::from("Wrong password or username");
+                    if !login_problems.contains(&problem) {
+                        login_problems.push(problem);
+                    }
                     logged_in = false;
                     continue;
                 }
@@ -426,16 +672,182 @@ pub async fn login_and_sync(aio: SharedState) -> anyhow::Result<Client> {
         }
     }
 
+    if let Err(e) = watch_list::restore_from_account_data(&client, &aio).await {
+        login_problems.push(format!(
+            "Failed to restore the watched rooms from the account data: {e}"
+        ));
+    }
+    if let Err(e) = watch_list::restore_routed_mutes(&client, &aio).await {
+        login_problems.push(format!("Failed to restore the mutes of routed rooms: {e}"));
+    }
+    if let Err(e) = knocking::restore(&client, &aio).await {
+        login_problems.push(format!("Failed to restore the knocked rooms: {e}"));
+    }
+    if let Err(e) = watch_list::validate(&client, &aio).await {
+        login_problems.push(format!("Failed to validate the watched rooms: {e}"));
+    }
+    login_problems.extend(watch_list::join_configured(&client, &aio).await);
+    login_problems.extend(spaces::join_configured(&client, &aio).await);
+    empty_rooms::leave_all_empty(&client, &aio).await;
+    println!("Watching {} rooms", aio.rooms.lock().unwrap().len());
+
+    if aio.cfg.bootstrap_cross_signing {
+        if let Err(e) = encryption::setup_cross_signing(&client, &aio).await {
+            login_problems.push(format!("Failed to set up cross-signing: {e}"));
+        }
+    }
+    if aio.cfg.key_backup {
+        if let Err(e) = encryption::setup_key_backup(&client, &aio).await {
+            login_problems.push(format!("Failed to set up the key backup: {e}"));
+        }
+    }
+    if !login_problems.is_empty() {
+        let message = |lang| {
+            Message::new()
+                .text(i18n::translate(lang, "Problems while logging in:"))
+                .list(login_problems.iter().map(|x| Message::new().text(x)))
+        };
+        admin::report(&client, &aio, "login", message).await;
+    }
+
     // add our CommandBot to be notified of incoming messages, we do this after the
     // initial sync to avoid responding to messages before the bot was running.
-    client.add_event_handler_context(aio.clone());
-    if aio.cfg.autojoin {
-        client.add_event_handler(on_stripped_state_member);
-    }
-    client.add_event_handler(on_room_message);
+    register_event_handlers(&client, &aio);
 
     let client_cc = client.clone();
-    tokio::spawn(async move { client.sync(sync_settings).await });
+    tokio::spawn(supervise_sync(client, aio, sync_settings, filter));
 
     Ok(client_cc)
 }
+
+/// Persists the session with the given sync token to the configured storage
+pub async fn store_session(
+    client: &Client,
+    aio: &SharedState,
+    sync_token: &str,
+) -> anyhow::Result<()> {
+    aio.cfg
+        .session_storage
+        .store_session(client, sync_token)
+        .await
+}
+
+/// Runs the sync, and restarts it with backoff whenever it stops. Without it, the bot
+/// would silently stop receiving events while the polling goes on. If the homeserver
+/// invalidated our access token, we log in again first.
+async fn supervise_sync(
+    client: Client,
+    aio: SharedState,
+    initial_settings: SyncSettings,
+    filter: FilterDefinition,
+) {
+    let mut sync_settings = initial_settings;
+    let mut delay = MIN_SYNC_RESTART_DELAY;
+    let last_stored = Arc::new(Mutex::new(Instant::now()));
+    loop {
+        let started = Instant::now();
+        let synced = client
+            .sync_with_callback(sync_settings, |response| {
+                let (client, aio, last_stored) = (client.clone(), aio.clone(), last_stored.clone());
+                async move {
+                    persist_sync_token(&client, &aio, &last_stored, &response.next_batch).await;
+                    LoopCtrl::Continue
+                }
+            })
+            .await;
+        let error = match synced {
+            Ok(()) => anyhow::anyhow!("The sync ended"),
+            Err(e) => {
+                if matches!(
+                    e.client_api_error_kind(),
+                    Some(ErrorKind::UnknownToken { .. }) | Some(ErrorKind::MissingToken)
+                ) {
+                    relogin(&client, &aio).await;
+                }
+                e.into()
+            }
+        };
+        // A sync that ran for a while before failing doesn't need a long pause
+        if started.elapsed() > MAX_SYNC_RESTART_DELAY {
+            delay = MIN_SYNC_RESTART_DELAY;
+        }
+        let message = |lang| {
+            Message::fill(
+                i18n::translate(
+                    lang,
+                    "The sync with the homeserver stopped ({error}), restarting it in {delay}s",
+                ),
+                &[
+                    ("error", Message::new().text(&error.to_string())),
+                    ("delay", Message::new().text(&delay.as_secs().to_string())),
+                ],
+            )
+        };
+        admin::report(&client, &aio, "sync", message).await;
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_SYNC_RESTART_DELAY);
+        // Continue from the token in the store, not the one of the initial sync
+        sync_settings = SyncSettings::default().filter(filter.clone().into());
+    }
+}
+
+/// Saves the sync token every few minutes, so after a crash the next start neither
+/// replays nor skips much
+async fn persist_sync_token(
+    client: &Client,
+    aio: &SharedState,
+    last_stored: &Mutex<Instant>,
+    next_batch: &str,
+) {
+    {
+        let mut last_stored = last_stored.lock().unwrap();
+        if last_stored.elapsed() < SYNC_TOKEN_STORE_INTERVAL {
+            return;
+        }
+        *last_stored = Instant::now();
+    }
+    if let Err(e) = aio
+        .cfg
+        .session_storage
+        .store_sync_token(client, next_batch)
+        .await
+    {
+        eprintln!("Failed to store the sync token: {e:?}");
+    }
+}
+
+async fn relogin(client: &Client, aio: &SharedState) {
+    println!("The access token was invalidated, logging in again");
+    if let Err(e) = login(client, aio).await {
+        eprintln!("Failed to log in again: {e:?}");
+        return;
+    }
+    if let Some(sync_token) = client.sync_token().await {
+        if let Err(e) = store_session(client, aio, &sync_token).await {
+            eprintln!("Failed to store the new session: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_reply_fallbacks() {
+        assert_eq!(
+            strip_reply_fallback("> <@alice:example.org> original\n> more\n\n!status"),
+            "!status"
+        );
+        assert_eq!(strip_reply_fallback("!status"), "!status");
+        assert_eq!(strip_reply_fallback("> only a quote"), "");
+    }
+
+    #[test]
+    fn keeps_quotes_after_the_command() {
+        assert_eq!(
+            strip_reply_fallback("!status\n> quoted"),
+            "!status\n> quoted"
+        );
+    }
+}
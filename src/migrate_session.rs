@@ -0,0 +1,126 @@
+//! The `migrate-session` subcommand: copies the stored session, and the secrets kept with
+//! it, from the configured storage to another one. The session DB stays where it is, so
+//! the bot keeps its device and the keys of encrypted rooms, which a fresh login with the
+//! new storage would lose.
+use super::{
+    build_session_store,
+    config_file::{optional_section, LoginSection, SessionBackend},
+    encryption::{BACKUP_RECOVERY_SECRET, CROSS_SIGNING_SECRET},
+    find_config,
+    matrix::restore_client_with_sync_token,
+    oidc::OIDC_SESSION_SECRET,
+    signing::SIGNING_KEY_SECRET,
+    Instance,
+};
+use anyhow::Context;
+use config::Config;
+use std::path::{Path, PathBuf};
+
+/// Everything stored with `store_secret` besides the session
+const SECRETS: &[&str] = &[
+    CROSS_SIGNING_SECRET,
+    BACKUP_RECOVERY_SECRET,
+    OIDC_SESSION_SECRET,
+    SIGNING_KEY_SECRET,
+];
+
+async fn migrate(
+    settings: &Config,
+    login_prefix: &str,
+    name: Option<&str>,
+    instance: &Instance,
+    to: SessionBackend,
+) -> anyhow::Result<bool> {
+    let aio = &instance.shared_state;
+    let login: LoginSection = settings.get(&format!("{login_prefix}login"))?;
+    if login.backend() == to {
+        println!("The session already is in {}", to.name());
+        return Ok(false);
+    }
+    let Some(db) = aio.cfg.session_storage.get_session_db() else {
+        anyhow::bail!(
+            "The session isn't persisted (login.persist_session), there is nothing to migrate"
+        );
+    };
+    let (client, sync_token) = restore_client_with_sync_token(aio).await?;
+    let sync_token =
+        sync_token.context("No sync token stored with the session, run the bot once")?;
+    let profile: Option<String> = optional_section(settings, "profile")?;
+    let target = build_session_store(to, db, &login, profile.as_deref(), name);
+    target.store_session(&client, &sync_token).await?;
+    for secret_name in SECRETS {
+        if let Some(secret) = aio.cfg.session_storage.restore_secret(secret_name).await? {
+            target.store_secret(secret_name, &secret).await?;
+        }
+    }
+    let user_id = client.user_id().map(|x| x.to_string()).unwrap_or_default();
+    println!(
+        "Copied the session of {user_id} from {} to {}",
+        login.backend().name(),
+        to.name()
+    );
+    Ok(true)
+}
+
+/// Sets `<login_prefix>login.storage`, keeping the comments and layout of the file
+fn update_toml(path: &Path, login_prefixes: &[String], to: SessionBackend) -> anyhow::Result<()> {
+    let mut doc: toml_edit::Document = std::fs::read_to_string(path)?.parse()?;
+    for login_prefix in login_prefixes {
+        let mut item = doc.as_item_mut();
+        for key in login_prefix.split('.').filter(|x| !x.is_empty()) {
+            item = &mut item[key];
+        }
+        item["login"]["storage"] = toml_edit::value(to.name());
+    }
+    std::fs::write(path, doc.to_string())?;
+    println!(
+        "Set login.storage = \"{}\" in {}",
+        to.name(),
+        path.display()
+    );
+    Ok(())
+}
+
+pub async fn run(
+    config: Option<&Path>,
+    profile: Option<&str>,
+    settings: &Config,
+    names: &[(Option<String>, Option<String>)],
+    instances: &[Instance],
+    to: SessionBackend,
+) -> anyhow::Result<()> {
+    let mut migrated = Vec::new();
+    for ((instance_name, account), instance) in names.iter().zip(instances) {
+        let login_prefix = match (account, instance_name) {
+            (Some(account), _) => format!("account.{account}."),
+            (None, Some(instance)) => format!("instance.{instance}."),
+            (None, None) => String::new(),
+        };
+        let name = instance_name.as_deref().or(account.as_deref());
+        if migrate(settings, &login_prefix, name, instance, to).await? {
+            migrated.push(login_prefix);
+        }
+    }
+    if migrated.is_empty() {
+        return Ok(());
+    }
+    let path = match config {
+        Some(path) => Some(PathBuf::from(path)),
+        None => find_config(profile).ok(),
+    };
+    match path {
+        Some(path) if path.extension().is_some_and(|x| x == "toml") => {
+            update_toml(&path, &migrated, to)?;
+        }
+        _ => {
+            for login_prefix in &migrated {
+                println!(
+                    "Set {login_prefix}login.storage = \"{}\" in the config, to use it",
+                    to.name()
+                );
+            }
+        }
+    }
+    println!("The old storage still holds the session, delete it once the bot runs fine");
+    Ok(())
+}
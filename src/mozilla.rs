@@ -1,29 +1,188 @@
+use super::{
+    i18n::Language, resources::ResourceTracker, room_settings::NotificationType,
+    templates::NotificationTemplate,
+};
+use matrix_sdk::ruma::OwnedRoomId;
 use regex::Regex;
 use scraper::{Html, Selector};
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use tokio::time::{Duration, Instant};
+
+/// Listings fetched less than this ago get reused, so that several bot instances
+/// watching the same path only cause one request upstream.
+const HTTP_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Where the subscriptions' `url_part`s are
+pub const BASE_URL: &str = "https://ftp.mozilla.org/pub";
+
+/// Directories with more new entries than this only get their entry count announced
+const MAX_LISTED_PER_DIRECTORY: usize = 5;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum VersionToken<'a> {
+    // Order matters: Pre-release suffixes ("b9", "rc1") sort before the end of the
+    // version, which sorts before further components (".1")
+    Text(&'a str),
+    End,
+    Separator(char),
+    Number(u64),
+}
+
+fn version_tokens(version: &str) -> Vec<VersionToken<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = version;
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            rest.find(|x: char| !x.is_ascii_digit())
+                .unwrap_or(rest.len())
+        } else if c.is_alphabetic() {
+            rest.find(|x: char| !x.is_alphabetic())
+                .unwrap_or(rest.len())
+        } else {
+            c.len_utf8()
+        };
+        let (token, remainder) = rest.split_at(len);
+        tokens.push(if c.is_ascii_digit() {
+            // Absurdly long numbers are compared as text
+            token
+                .parse()
+                .map(VersionToken::Number)
+                .unwrap_or(VersionToken::Text(token))
+        } else if c.is_alphabetic() {
+            VersionToken::Text(token)
+        } else {
+            VersionToken::Separator(c)
+        });
+        rest = remainder;
+    }
+    tokens.push(VersionToken::End);
+    tokens
+}
+
+/// Orders entries like "140.0b9" < "140.0" < "140.0.1" < "141.0"
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    version_tokens(a).cmp(&version_tokens(b))
+}
+
+/// HTTP client with a short-lived cache of directory listings, shared by all bot instances
+#[derive(Debug, Default)]
+pub struct HttpCache {
+    client: reqwest::Client,
+    entries: Mutex<HashMap<String, (Instant, String)>>,
+}
+
+impl HttpCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cache hits don't count against the request limit of the instance
+    pub async fn get(&self, url: &str, resources: &ResourceTracker) -> anyhow::Result<String> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, (fetched, _)| fetched.elapsed() < HTTP_CACHE_TTL);
+            if let Some((_, body)) = entries.get(url) {
+                return Ok(body.clone());
+            }
+        }
+        let _permit = resources.acquire_request().await;
+        let body = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (Instant::now(), body.clone()));
+        Ok(body)
+    }
+
+    /// Downloads a file of at most `max_len` bytes. Files aren't cached.
+    pub async fn download(
+        &self,
+        url: &str,
+        max_len: usize,
+        resources: &ResourceTracker,
+    ) -> anyhow::Result<Vec<u8>> {
+        let _permit = resources.acquire_request().await;
+        let mut response = self.client.get(url).send().await?.error_for_status()?;
+        if response.content_length().unwrap_or(0) > max_len as u64 {
+            anyhow::bail!("larger than {max_len} bytes");
+        }
+        // The length might be missing or wrong, so we stop reading once over the limit
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if data.len() + chunk.len() > max_len {
+                anyhow::bail!("larger than {max_len} bytes");
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+}
 
 #[derive(Debug)]
 pub struct MozData {
+    pub name: String,
     pub url_part: String,
     pub query_subdirs: bool,
     pub filter: Option<Regex>,
     pub data: HashSet<String>,
     pub base_url: String,
+    /// Rooms to announce to. All watched rooms, if unset.
+    pub rooms: Option<Vec<OwnedRoomId>>,
+    /// Edit the previous notification in a room instead of posting a new one
+    pub update_in_place: bool,
+    /// Keep the newest notification pinned in each room
+    pub pin: bool,
+    /// Unless the room has its own
+    pub msgtype: NotificationType,
+    /// Wording of the full notifications, instead of the built-in one
+    pub template: Option<NotificationTemplate>,
+    /// Replace `template` in rooms with these languages
+    pub translated_templates: BTreeMap<Language, NotificationTemplate>,
+    /// New files with matching names get posted along with the notification
+    pub attach: Option<Regex>,
 }
 
 impl MozData {
-    pub fn new(url_part: &str, filter: Option<Regex>, query_subdirs: bool) -> Self {
+    pub fn new(name: &str, url_part: &str, filter: Option<Regex>, query_subdirs: bool) -> Self {
         Self {
+            name: name.to_string(),
             url_part: url_part.to_string(),
             query_subdirs,
             filter,
             data: HashSet::new(),
-            base_url: "https://ftp.mozilla.org/pub".to_string(),
+            base_url: BASE_URL.to_string(),
+            rooms: None,
+            update_in_place: false,
+            pin: false,
+            msgtype: NotificationType::default(),
+            template: None,
+            translated_templates: BTreeMap::new(),
+            attach: None,
         }
     }
 
-    pub async fn fetch_upstream_and_compare(&mut self) -> anyhow::Result<HashSet<String>> {
-        let answer = self.query_url().await?;
+    pub fn template_for(&self, lang: Language) -> Option<&NotificationTemplate> {
+        self.translated_templates
+            .get(&lang)
+            .or(self.template.as_ref())
+    }
+
+    pub async fn fetch_upstream_and_compare(
+        &mut self,
+        http: &Arc<HttpCache>,
+        resources: &Arc<ResourceTracker>,
+    ) -> anyhow::Result<HashSet<String>> {
+        let answer = self.query_url(http, resources).await?;
         // Ignore the first iteration, where we haven't had any data yet
         let res = if self.data.is_empty() {
             HashSet::new()
@@ -34,14 +193,54 @@ impl MozData {
         Ok(res)
     }
 
+    /// The newest entry we currently know, by version ordering
+    pub fn latest_entry(&self) -> Option<&str> {
+        self.data
+            .iter()
+            .map(String::as_str)
+            .max_by(|a, b| compare_versions(a, b))
+    }
+
+    /// Formats new entries for an announcement. When querying subdirs, entries are
+    /// grouped by their top-level directory instead of being listed flat.
+    pub fn format_entries(&self, entries: &HashSet<String>) -> String {
+        if !self.query_subdirs {
+            let mut sorted: Vec<_> = entries.iter().map(String::as_str).collect();
+            sorted.sort();
+            return sorted.join(", ");
+        }
+
+        let mut groups: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for entry in entries {
+            let (dir, file) = entry.split_once('/').unwrap_or((entry, ""));
+            groups
+                .entry(dir)
+                .or_default()
+                .push(file.trim_end_matches('/'));
+        }
+        groups
+            .into_iter()
+            .map(|(dir, mut files)| {
+                if files.len() > MAX_LISTED_PER_DIRECTORY {
+                    format!("{}: {} files", dir, files.len())
+                } else {
+                    files.sort();
+                    format!("{}: {}", dir, files.join(", "))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
     async fn query_subdir(
+        http: Arc<HttpCache>,
+        resources: Arc<ResourceTracker>,
         base_url: String,
         url_part: String,
         cand: String,
     ) -> anyhow::Result<HashSet<String>> {
-        let html = reqwest::get(&format!("{}/{}/{}/", base_url, url_part, cand))
-            .await?
-            .text()
+        let html = http
+            .get(&format!("{}/{}/{}/", base_url, url_part, cand), &resources)
             .await?;
         let document = Html::parse_document(&html);
         let selector = Selector::parse("a").unwrap();
@@ -54,9 +253,13 @@ impl MozData {
         Ok(candidates)
     }
 
-    async fn query_url(&self) -> anyhow::Result<HashSet<String>> {
+    async fn query_url(
+        &self,
+        http: &Arc<HttpCache>,
+        resources: &Arc<ResourceTracker>,
+    ) -> anyhow::Result<HashSet<String>> {
         let url = format!("{}/{}/", self.base_url, self.url_part);
-        let html = reqwest::get(&url).await?.text().await?;
+        let html = http.get(&url, resources).await?;
         let document = Html::parse_document(&html);
         let selector = Selector::parse("a").unwrap();
         let candidates: HashSet<_> = document
@@ -76,6 +279,8 @@ impl MozData {
             let mut tasks = Vec::with_capacity(candidates.len());
             for cand in candidates {
                 tasks.push(tokio::spawn(Self::query_subdir(
+                    http.clone(),
+                    resources.clone(),
                     self.base_url.clone(),
                     self.url_part.clone(),
                     cand.clone(),
@@ -95,3 +300,34 @@ impl MozData {
         Ok(outputs)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn orders_versions() {
+        let versions = ["140.0b9", "140.0", "140.0.1", "141.0"];
+        for pair in versions.windows(2) {
+            assert_eq!(
+                compare_versions(pair[0], pair[1]),
+                Ordering::Less,
+                "{pair:?}"
+            );
+            assert_eq!(
+                compare_versions(pair[1], pair[0]),
+                Ordering::Greater,
+                "{pair:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn compares_numbers_numerically() {
+        assert_eq!(compare_versions("9.0", "10.0"), Ordering::Less);
+        assert_eq!(compare_versions("140.0b9", "140.0b10"), Ordering::Less);
+        assert_eq!(compare_versions("140.0rc1", "140.0"), Ordering::Less);
+        assert_eq!(compare_versions("140.0", "140.0"), Ordering::Equal);
+    }
+}
@@ -0,0 +1,325 @@
+//! Login via OIDC (MSC3861) using the OAuth 2.0 device authorization grant, which works
+//! for a headless bot: the operator opens a URL on any device and enters a code.
+use super::SharedState;
+use anyhow::{bail, Context};
+use matrix_sdk::{
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::{OwnedDeviceId, OwnedUserId},
+    Client, SessionMeta,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::time::{sleep, Duration};
+
+/// Name under which the OIDC client and refresh token are kept in the session storage
+pub const OIDC_SESSION_SECRET: &str = "oidc_session";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OidcSession {
+    client_id: String,
+    token_endpoint: String,
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IssuerResponse {
+    issuer: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    device_authorization_endpoint: String,
+    token_endpoint: String,
+    registration_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistrationResponse {
+    client_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    #[serde(default = "default_poll_interval")]
+    interval: u64,
+    expires_in: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WhoamiResponse {
+    user_id: OwnedUserId,
+    device_id: Option<OwnedDeviceId>,
+}
+
+async fn discover(homeserver_url: &str) -> anyhow::Result<ProviderMetadata> {
+    let http = reqwest::Client::new();
+    let issuer: IssuerResponse = http
+        .get(format!(
+            "{}/_matrix/client/unstable/org.matrix.msc2965/auth_issuer",
+            homeserver_url.trim_end_matches('/')
+        ))
+        .send()
+        .await?
+        .error_for_status()
+        .context("Homeserver doesn't support OIDC login")?
+        .json()
+        .await?;
+    let metadata = http
+        .get(format!(
+            "{}/.well-known/openid-configuration",
+            issuer.issuer.trim_end_matches('/')
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(metadata)
+}
+
+/// Registers us as OIDC client (MSC2966), unless a client_id was configured
+async fn register_client(
+    metadata: &ProviderMetadata,
+    client_id: &Option<String>,
+) -> anyhow::Result<String> {
+    if let Some(client_id) = client_id {
+        return Ok(client_id.clone());
+    }
+    let registration_endpoint = metadata
+        .registration_endpoint
+        .as_ref()
+        .context("Provider doesn't support dynamic client registration, please configure login.oidc_client_id")?;
+    let response: RegistrationResponse = reqwest::Client::new()
+        .post(registration_endpoint)
+        .json(&json!({
+            "client_name": "Mozilla FTP watcher",
+            "client_uri": "https://github.com/msirringhaus/matrix_mozilla_bot",
+            "application_type": "native",
+            "grant_types": [DEVICE_CODE_GRANT, "refresh_token"],
+            "token_endpoint_auth_method": "none",
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response.client_id)
+}
+
+async fn request_token(
+    token_endpoint: &str,
+    params: &[(&str, &str)],
+) -> anyhow::Result<Result<TokenResponse, TokenErrorResponse>> {
+    let response = reqwest::Client::new()
+        .post(token_endpoint)
+        .form(params)
+        .send()
+        .await?;
+    if response.status().is_success() {
+        Ok(Ok(response.json().await?))
+    } else {
+        Ok(Err(response.json().await?))
+    }
+}
+
+async fn whoami(homeserver_url: &str, access_token: &str) -> anyhow::Result<WhoamiResponse> {
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/_matrix/client/v3/account/whoami",
+            homeserver_url.trim_end_matches('/')
+        ))
+        .bearer_auth(access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response)
+}
+
+fn generate_device_id() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// Performs the device authorization grant and restores the resulting session in the client
+pub async fn login(
+    client: &Client,
+    aio: &SharedState,
+    client_id: &Option<String>,
+) -> anyhow::Result<()> {
+    let metadata = discover(&aio.cfg.homeserver_url).await?;
+    let client_id = register_client(&metadata, client_id).await?;
+    let device_id = generate_device_id();
+    let scope = format!(
+        "openid urn:matrix:org.matrix.msc2967.client:api:* urn:matrix:org.matrix.msc2967.client:device:{device_id}"
+    );
+
+    let authorization: DeviceAuthorizationResponse = reqwest::Client::new()
+        .post(&metadata.device_authorization_endpoint)
+        .form(&[("client_id", client_id.as_str()), ("scope", scope.as_str())])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    match &authorization.verification_uri_complete {
+        Some(uri) => println!("To log in, open {uri}"),
+        None => println!(
+            "To log in, open {} and enter the code {}",
+            authorization.verification_uri, authorization.user_code
+        ),
+    }
+
+    let mut interval = authorization.interval;
+    let mut waited = 0;
+    let tokens = loop {
+        if waited > authorization.expires_in {
+            bail!("OIDC login timed out");
+        }
+        sleep(Duration::from_secs(interval)).await;
+        waited += interval;
+        match request_token(
+            &metadata.token_endpoint,
+            &[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("device_code", authorization.device_code.as_str()),
+                ("client_id", client_id.as_str()),
+            ],
+        )
+        .await?
+        {
+            Ok(tokens) => break tokens,
+            Err(e) if e.error == "authorization_pending" => {}
+            Err(e) if e.error == "slow_down" => interval += 5,
+            Err(e) => bail!("OIDC login failed: {}", e.error),
+        }
+    };
+
+    let whoami = whoami(&aio.cfg.homeserver_url, &tokens.access_token).await?;
+    let device_id = whoami.device_id.unwrap_or_else(|| device_id.into());
+    client
+        .restore_session(MatrixSession {
+            meta: SessionMeta {
+                user_id: whoami.user_id.clone(),
+                device_id,
+            },
+            tokens: MatrixSessionTokens {
+                access_token: tokens.access_token,
+                refresh_token: tokens.refresh_token.clone(),
+            },
+        })
+        .await?;
+    println!("Logged in as {} via OIDC", whoami.user_id);
+
+    let session = OidcSession {
+        client_id,
+        token_endpoint: metadata.token_endpoint,
+        refresh_token: tokens.refresh_token,
+    };
+    aio.cfg
+        .session_storage
+        .store_secret(OIDC_SESSION_SECRET, &serde_json::to_string(&session)?)
+        .await?;
+    if let Some(expires_in) = tokens.expires_in {
+        spawn_token_refresh(client.clone(), aio.clone(), Duration::from_secs(expires_in));
+    }
+    Ok(())
+}
+
+/// Uses the stored refresh token to get a fresh access token. Returns the lifetime of
+/// the new access token.
+async fn refresh(client: &Client, aio: &SharedState) -> anyhow::Result<Option<Duration>> {
+    let stored = aio
+        .cfg
+        .session_storage
+        .restore_secret(OIDC_SESSION_SECRET)
+        .await?
+        .context("No OIDC session stored")?;
+    let mut session: OidcSession = serde_json::from_str(&stored)?;
+    let refresh_token = session
+        .refresh_token
+        .clone()
+        .context("OIDC session has no refresh token")?;
+    let tokens = match request_token(
+        &session.token_endpoint,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("client_id", session.client_id.as_str()),
+        ],
+    )
+    .await?
+    {
+        Ok(tokens) => tokens,
+        Err(e) => bail!("Refreshing the OIDC token failed: {}", e.error),
+    };
+
+    // Refresh tokens may be rotated
+    if tokens.refresh_token.is_some() {
+        session.refresh_token = tokens.refresh_token.clone();
+    }
+    client
+        .matrix_auth()
+        .set_session_tokens(MatrixSessionTokens {
+            access_token: tokens.access_token,
+            refresh_token: session.refresh_token.clone(),
+        });
+    aio.cfg
+        .session_storage
+        .store_secret(OIDC_SESSION_SECRET, &serde_json::to_string(&session)?)
+        .await?;
+    Ok(tokens.expires_in.map(Duration::from_secs))
+}
+
+/// A restored access token has likely expired while we were not running
+pub async fn refresh_restored_session(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
+    let expires_in = refresh(client, aio).await?;
+    if let Some(expires_in) = expires_in {
+        spawn_token_refresh(client.clone(), aio.clone(), expires_in);
+    }
+    Ok(())
+}
+
+/// Keeps refreshing the access token shortly before it expires
+fn spawn_token_refresh(client: Client, aio: SharedState, mut expires_in: Duration) {
+    tokio::spawn(async move {
+        loop {
+            sleep(expires_in.mul_f64(0.8)).await;
+            match refresh(&client, &aio).await {
+                Ok(Some(new_expiry)) => expires_in = new_expiry,
+                Ok(None) => break, // Token doesn't expire anymore
+                Err(e) => {
+                    eprintln!("{e:?}, retrying in 30s");
+                    expires_in = Duration::from_secs(30).div_f64(0.8);
+                }
+            }
+        }
+    });
+}
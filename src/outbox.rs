@@ -0,0 +1,542 @@
+//! Notifications that couldn't be delivered, even after the retries of the send queue.
+//! They are kept in the state DB, so they survive restarts, and get
+//! delivered once the homeserver takes them again. Notifications older than
+//! `config.outbox_ttl_hours` are dropped instead of replaying stale news.
+//!
+//! The outbox doubles as a circuit breaker: once a delivery failed because the homeserver
+//! is unreachable or overloaded, polling goes on, but new notifications go straight to the
+//! outbox instead of each waiting for its own retries to run out. Failures specific to a
+//! room don't trip it. When the homeserver is back, the notifications that piled up for a
+//! room are sent as one consolidated message.
+//!
+//! Notifications the homeserver rejected (e.g. because we may no longer post to the room)
+//! are dropped instead, as they would fail the same way again. Of split messages only the
+//! parts that didn't go out are kept, and failed edits are kept as edits.
+use super::{
+    attachments::{self, Attachment},
+    edit_with_fields,
+    formatting::{escape, merge_mentions},
+    i18n,
+    matrix::{send_continuation, Continuation, PartiallySent},
+    retention, room_settings,
+    room_settings::NotificationType,
+    send_queue::{self, Failure},
+    send_to_room_with_fields, SharedState,
+};
+use chrono::Utc;
+use matrix_sdk::{
+    ruma::{OwnedEventId, OwnedRoomId},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, timeout, Duration};
+
+const OUTBOX_KEY: &[u8] = b"org.mozillabot.outbox";
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long shutting down waits for a delivery in progress
+const FINISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PendingNotification {
+    pub room_id: OwnedRoomId,
+    /// Name of the subscription, None for digests of several ones
+    pub source: Option<String>,
+    pub msgtype: NotificationType,
+    pub plain: String,
+    pub html: String,
+    pub fields: serde_json::Map<String, serde_json::Value>,
+    /// Unix timestamp of the first attempt
+    pub queued_at: i64,
+    /// Set for the parts of a split message that didn't go out, which follow the others
+    /// in their thread
+    #[serde(default)]
+    pub continues: Option<Continuation>,
+    /// The parts still to send, if `continues` is set
+    #[serde(default)]
+    pub rest: Vec<(String, String)>,
+    /// Set for an edit of this earlier notification
+    #[serde(default)]
+    pub replaces: Option<OwnedEventId>,
+    /// Files to post after it
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+impl PendingNotification {
+    pub fn new(
+        room_id: OwnedRoomId,
+        source: Option<String>,
+        msgtype: NotificationType,
+        plain: String,
+        html: String,
+        fields: serde_json::Map<String, serde_json::Value>,
+    ) -> Self {
+        Self {
+            room_id,
+            source,
+            msgtype,
+            plain,
+            html,
+            fields,
+            queued_at: Utc::now().timestamp(),
+            continues: None,
+            rest: Vec::new(),
+            replaces: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    pub fn with_attachments(mut self, attachments: Vec<Attachment>) -> Self {
+        self.attachments = attachments;
+        self
+    }
+
+    /// Makes this an edit of `original` instead of a new message
+    pub fn replacing(mut self, original: OwnedEventId) -> Self {
+        self.replaces = Some(original);
+        self
+    }
+
+    /// Whether this can be merged with others into one message
+    fn mergeable(&self) -> bool {
+        self.continues.is_none() && self.replaces.is_none()
+    }
+}
+
+/// Without a persisted session, the outbox only lives in memory
+pub async fn store(ctx: &SharedState) -> anyhow::Result<()> {
+    if let Some(db) = ctx.cfg.session_storage.get_session_db() {
+        let pending = ctx.outbox.lock().unwrap().clone();
+        db.state.set_outbox(&pending).await?;
+    }
+    Ok(())
+}
+
+/// Loads the notifications that were still undelivered at the last shutdown
+pub async fn restore(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let Some(db) = ctx.cfg.session_storage.get_session_db() else {
+        return Ok(());
+    };
+    let mut pending = db.state.outbox().await?;
+    // Older versions kept them in the state store of the client
+    if let Some(serialized) = client.store().get_custom_value(OUTBOX_KEY).await? {
+        let legacy: Vec<PendingNotification> = serde_json::from_slice(&serialized)?;
+        pending.splice(0..0, legacy);
+        db.state.set_outbox(&pending).await?;
+        client.store().remove_custom_value(OUTBOX_KEY).await?;
+    }
+    if pending.is_empty() {
+        return Ok(());
+    }
+    println!("{} undelivered notifications restored", pending.len());
+    *ctx.outbox.lock().unwrap() = pending;
+    Ok(())
+}
+
+/// Whether deliveries are failing, so new notifications should go to the outbox right away
+pub fn suspended(ctx: &SharedState) -> bool {
+    ctx.delivery_failing_since.lock().unwrap().is_some()
+}
+
+/// Keeps a notification for another delivery attempt later
+pub async fn add(ctx: &SharedState, pending: PendingNotification) {
+    ctx.outbox.lock().unwrap().push(pending);
+    if let Err(e) = store(ctx).await {
+        eprintln!("Failed to persist the undelivered notifications: {e:?}");
+    }
+}
+
+/// What is left of `notifications` after sending them failed with `error`: only the
+/// missing parts, if some of them went out. Returns the IDs of the parts that went out,
+/// and the error the rest failed with.
+fn unsent(
+    notifications: Vec<PendingNotification>,
+    error: anyhow::Error,
+) -> (Vec<OwnedEventId>, Vec<PendingNotification>, anyhow::Error) {
+    match error.downcast::<PartiallySent>() {
+        Ok(partial) => {
+            let mut sources = notifications.iter().map(|x| x.source.clone());
+            let source = sources.next().flatten();
+            let rest = PendingNotification {
+                source: source.filter(|x| sources.all(|y| y.as_ref() == Some(x))),
+                plain: String::new(),
+                html: String::new(),
+                fields: serde_json::Map::new(),
+                continues: Some(partial.continuation),
+                rest: partial.rest,
+                replaces: None,
+                attachments: notifications
+                    .iter()
+                    .flat_map(|x| x.attachments.clone())
+                    .collect(),
+                ..notifications[0].clone()
+            };
+            (partial.sent, vec![rest], partial.error)
+        }
+        Err(error) => (Vec::new(), notifications, error),
+    }
+}
+
+/// Sorts out what to retry after sending `notifications` failed: nothing if the homeserver
+/// rejected them, only the missing parts if some of them went out. Returns the IDs of the
+/// parts that went out as well.
+fn retry_after(
+    room_id: &OwnedRoomId,
+    notifications: Vec<PendingNotification>,
+    error: anyhow::Error,
+) -> (Vec<OwnedEventId>, Vec<PendingNotification>) {
+    let (sent, retry, error) = unsent(notifications, error);
+    match send_queue::classify(&error) {
+        Failure::Unavailable => (sent, retry),
+        Failure::Rejected => {
+            eprintln!(
+                "Dropping {} notifications for {room_id}, the homeserver rejected them: {error:?}",
+                retry.len()
+            );
+            (sent, Vec::new())
+        }
+    }
+}
+
+/// Keeps what is worth retrying of a notification that failed to send with `error`.
+/// Returns the IDs of the parts that went out, if some did. If the homeserver is
+/// unavailable, further notifications are buffered without trying to send them until the
+/// outbox could be delivered.
+pub async fn add_failed(
+    ctx: &SharedState,
+    pending: PendingNotification,
+    error: anyhow::Error,
+) -> Vec<OwnedEventId> {
+    let room_id = pending.room_id.clone();
+    let (sent, retry) = retry_after(&room_id, vec![pending], error);
+    if !retry.is_empty() {
+        ctx.delivery_failing_since
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Utc::now);
+    }
+    for pending in retry {
+        add(ctx, pending).await;
+    }
+    sent
+}
+
+/// Sends the notifications buffered for one room, as one message if there are several.
+/// Returns the IDs of the new events, the first part of a message first.
+async fn deliver(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &OwnedRoomId,
+    notifications: &[PendingNotification],
+) -> anyhow::Result<Vec<OwnedEventId>> {
+    match notifications {
+        [PendingNotification {
+            continues: Some(continuation),
+            rest,
+            msgtype,
+            ..
+        }] => send_continuation(client, room_id, *msgtype, continuation, rest.clone()).await,
+        [PendingNotification {
+            replaces: Some(original),
+            msgtype,
+            plain,
+            html,
+            fields,
+            ..
+        }] => {
+            edit_with_fields(client, room_id, original, *msgtype, plain, html, fields).await?;
+            Ok(Vec::new())
+        }
+        [notification] => {
+            send_to_room_with_fields(
+                client,
+                room_id,
+                notification.msgtype,
+                &notification.plain,
+                &notification.html,
+                &notification.fields,
+            )
+            .await
+        }
+        _ => {
+            let lang = room_settings::get(client, room_id)
+                .await
+                .language
+                .unwrap_or(ctx.cfg.language);
+            let header = i18n::translate(lang, "Missed while the homeserver was unreachable:");
+            let plain: Vec<_> = notifications.iter().map(|x| x.plain.as_str()).collect();
+            let html: Vec<_> = notifications.iter().map(|x| x.html.as_str()).collect();
+            let fields = merge_mentions(notifications.iter().map(|x| &x.fields));
+            send_to_room_with_fields(
+                client,
+                room_id,
+                notifications[0].msgtype,
+                &format!("{header}\n{}", plain.join("\n")),
+                &format!("{}<br>{}", escape(header), html.join("<br>")),
+                &fields,
+            )
+            .await
+        }
+    }
+}
+
+/// Keeps track of the events a message went out as
+async fn delivered(
+    ctx: &SharedState,
+    room_id: &OwnedRoomId,
+    notifications: &[PendingNotification],
+    sent: &[OwnedEventId],
+) {
+    retention::track(ctx, room_id, sent).await;
+    // Continuations don't start a message
+    if notifications.iter().any(|x| x.continues.is_some()) {
+        return;
+    }
+    let Some(first) = sent.first() else {
+        return;
+    };
+    let mut sources = notifications.iter().map(|x| x.source.as_deref());
+    if let Some(Some(source)) = sources.next() {
+        if sources.all(|x| x == Some(source)) {
+            ctx.record_notification(first.clone(), source);
+        }
+    }
+}
+
+/// Splits the notifications of a room into messages. Continuations and edits go alone, the
+/// others in one message per run.
+fn batches(notifications: Vec<PendingNotification>) -> Vec<Vec<PendingNotification>> {
+    let mut batches: Vec<Vec<PendingNotification>> = Vec::new();
+    for notification in notifications {
+        match batches.last_mut() {
+            Some(batch) if batch[0].mergeable() && notification.mergeable() => {
+                batch.push(notification)
+            }
+            _ => batches.push(vec![notification]),
+        }
+    }
+    batches
+}
+
+/// Tries to deliver all pending notifications, room by room in the order they were
+/// detected. Notifications the homeserver rejected are dropped, the other rooms still get
+/// theirs. Stops once the homeserver turns out to be still unavailable.
+pub async fn flush(client: &Client, ctx: &SharedState) {
+    let _flushing = ctx.outbox_flush.lock().await;
+    let pending = std::mem::take(&mut *ctx.outbox.lock().unwrap());
+    if pending.is_empty() {
+        return;
+    }
+    let expired_before = Utc::now().timestamp() - ctx.cfg.outbox_ttl.as_secs() as i64;
+    let (pending, expired): (Vec<_>, Vec<_>) = pending
+        .into_iter()
+        .partition(|x| x.queued_at >= expired_before);
+    if !expired.is_empty() {
+        println!(
+            "Dropping {} expired undelivered notifications",
+            expired.len()
+        );
+    }
+    let mut by_room: Vec<(OwnedRoomId, Vec<PendingNotification>)> = Vec::new();
+    for notification in pending {
+        match by_room.iter_mut().find(|(x, _)| *x == notification.room_id) {
+            Some((_, notifications)) => notifications.push(notification),
+            None => by_room.push((notification.room_id.clone(), vec![notification])),
+        }
+    }
+    let mut by_room = by_room.into_iter();
+    let mut undelivered = Vec::new();
+    let mut sent = 0;
+    let mut unavailable = false;
+    for (room_id, notifications) in by_room.by_ref() {
+        let mut batches = batches(notifications).into_iter();
+        for batch in batches.by_ref() {
+            match deliver(client, ctx, &room_id, &batch).await {
+                Ok(event_ids) => {
+                    sent += batch.len();
+                    delivered(ctx, &room_id, &batch, &event_ids).await;
+                    let files: Vec<_> = batch.iter().flat_map(|x| x.attachments.clone()).collect();
+                    if let Err(e) = attachments::send(client, ctx, &room_id, &files).await {
+                        eprintln!("Failed to attach the files in {room_id}: {e:?}");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Still unable to deliver to {room_id}: {e:?}");
+                    let (event_ids, retry) = retry_after(&room_id, batch.clone(), e);
+                    delivered(ctx, &room_id, &batch, &event_ids).await;
+                    // Only what failed for lack of the homeserver is kept
+                    if !retry.is_empty() {
+                        undelivered.extend(retry);
+                        unavailable = true;
+                        break;
+                    }
+                }
+            }
+        }
+        undelivered.extend(batches.flatten());
+        if unavailable {
+            break;
+        }
+    }
+    undelivered.extend(by_room.flat_map(|(_, notifications)| notifications));
+    if undelivered.is_empty() {
+        if let Some(since) = ctx.delivery_failing_since.lock().unwrap().take() {
+            println!(
+                "Delivering works again after failing since {since}, sent {sent} buffered notifications"
+            );
+        }
+    }
+    // Notifications might have been added while we were sending
+    ctx.outbox.lock().unwrap().splice(0..0, undelivered);
+    if let Err(e) = store(ctx).await {
+        eprintln!("Failed to persist the undelivered notifications: {e:?}");
+    }
+}
+
+/// Persists the outbox before shutting down, once a delivery in progress is done. If it
+/// takes too long, what was persisted before that delivery stays, which at worst sends
+/// some notifications twice.
+pub async fn finish(client: &Client, ctx: &SharedState) {
+    let Ok(_flushing) = timeout(FINISH_TIMEOUT, ctx.outbox_flush.lock()).await else {
+        eprintln!("Not persisting the undelivered notifications, a delivery is still running");
+        return;
+    };
+    if let Err(e) = store(ctx).await {
+        eprintln!("Failed to persist the undelivered notifications: {e:?}");
+    }
+}
+
+/// Retries the delivery of pending notifications every minute
+pub async fn run(client: Client, ctx: SharedState) {
+    loop {
+        flush(&client, &ctx).await;
+        sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(source: &str, plain: &str) -> PendingNotification {
+        PendingNotification::new(
+            OwnedRoomId::try_from("!room:example.org").unwrap(),
+            Some(source.to_string()),
+            NotificationType::Notice,
+            plain.to_string(),
+            escape(plain),
+            serde_json::Map::new(),
+        )
+    }
+
+    fn event_id(id: &str) -> OwnedEventId {
+        OwnedEventId::try_from(id).unwrap()
+    }
+
+    fn partially_sent(rest: &[&str]) -> anyhow::Error {
+        anyhow::Error::new(PartiallySent {
+            sent: vec![event_id("$first:example.org")],
+            continuation: Continuation {
+                root: event_id("$first:example.org"),
+                previous: event_id("$first:example.org"),
+            },
+            rest: rest.iter().map(|x| (x.to_string(), escape(x))).collect(),
+            error: anyhow::anyhow!("connection reset"),
+        })
+    }
+
+    fn sizes(batches: &[Vec<PendingNotification>]) -> Vec<usize> {
+        batches.iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn merges_runs_of_plain_notifications() {
+        let edit = notification("nightly", "edited").replacing(event_id("$old:example.org"));
+        let mut continuation = notification("nightly", "");
+        continuation.continues = Some(Continuation {
+            root: event_id("$root:example.org"),
+            previous: event_id("$previous:example.org"),
+        });
+        let batched = batches(vec![
+            notification("nightly", "a"),
+            notification("beta", "b"),
+            edit,
+            notification("nightly", "c"),
+            continuation,
+            notification("nightly", "d"),
+            notification("nightly", "e"),
+        ]);
+        assert_eq!(sizes(&batched), [2, 1, 1, 1, 2]);
+        assert_eq!(batched[0][1].plain, "b");
+        assert!(batched[1][0].replaces.is_some());
+        assert!(batched[3][0].continues.is_some());
+    }
+
+    #[test]
+    fn keeps_everything_if_nothing_went_out() {
+        let (sent, retry, error) = unsent(
+            vec![notification("nightly", "a"), notification("beta", "b")],
+            anyhow::anyhow!("connection reset"),
+        );
+        assert!(sent.is_empty());
+        assert_eq!(retry.len(), 2);
+        assert_eq!(error.to_string(), "connection reset");
+    }
+
+    #[test]
+    fn keeps_only_the_unsent_parts() {
+        let mut first = notification("nightly", "a");
+        first.attachments = vec![Attachment {
+            name: String::from("a.txt"),
+            data: b"a".to_vec(),
+        }];
+        let (sent, retry, error) = unsent(
+            vec![first, notification("nightly", "b")],
+            partially_sent(&["part 2", "part 3"]),
+        );
+        assert_eq!(sent, [event_id("$first:example.org")]);
+        assert_eq!(error.to_string(), "connection reset");
+        let [rest] = retry.as_slice() else {
+            panic!("expected one notification, got {retry:?}");
+        };
+        assert_eq!(rest.source.as_deref(), Some("nightly"));
+        assert!(rest.plain.is_empty());
+        assert_eq!(
+            rest.continues.as_ref().map(|x| x.previous.clone()),
+            Some(event_id("$first:example.org"))
+        );
+        let parts: Vec<_> = rest.rest.iter().map(|(plain, _)| plain.as_str()).collect();
+        assert_eq!(parts, ["part 2", "part 3"]);
+        assert_eq!(rest.attachments.len(), 1);
+    }
+
+    #[test]
+    fn digests_of_several_sources_have_none() {
+        let (_, retry, _) = unsent(
+            vec![notification("nightly", "a"), notification("beta", "b")],
+            partially_sent(&["part 2"]),
+        );
+        assert_eq!(retry.len(), 1);
+        assert_eq!(retry[0].source, None);
+    }
+
+    #[test]
+    fn drops_what_the_homeserver_rejected() {
+        let room_id = OwnedRoomId::try_from("!room:example.org").unwrap();
+        // Errors that didn't come from the homeserver can't be helped by retrying either
+        let (sent, retry) = retry_after(
+            &room_id,
+            vec![notification("nightly", "a")],
+            anyhow::anyhow!("invalid event"),
+        );
+        assert!(sent.is_empty());
+        assert!(retry.is_empty());
+        let (sent, retry) = retry_after(
+            &room_id,
+            vec![notification("nightly", "a")],
+            partially_sent(&["part 2"]),
+        );
+        assert_eq!(sent, [event_id("$first:example.org")]);
+        assert!(retry.is_empty());
+    }
+}
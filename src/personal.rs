@@ -0,0 +1,245 @@
+//! Personal subscriptions: users follow existing subscriptions in a DM with the bot and get
+//! their own notifications there, optionally only for entries matching their filter.
+//! They are persisted separately from the subscriptions of rooms. Leaving the DM (or
+//! removing the bot from it) ends all personal subscriptions of that user.
+use super::{
+    formatting::{self, Message},
+    i18n,
+    mozilla::MozData,
+    send_to_room, watch_list, SharedState,
+};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::{
+        events::{
+            macros::EventContent,
+            room::member::{MembershipState, OriginalSyncRoomMemberEvent},
+        },
+        OwnedRoomId, OwnedUserId, UserId,
+    },
+    Client, RoomState,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+const PERSONAL_SUBSCRIPTIONS_DOCUMENT: &str = "personal_subscriptions";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserSubscriptions {
+    /// DM we notify the user in. A new one gets created, if we are no longer in it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dm_room: Option<OwnedRoomId>,
+    /// Names of the followed subscriptions and their optional filter
+    #[serde(default)]
+    pub sources: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.personal_subscriptions", kind = GlobalAccountData)]
+pub struct PersonalSubscriptionsEventContent {
+    pub users: BTreeMap<OwnedUserId, UserSubscriptions>,
+}
+
+pub async fn store(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content = PersonalSubscriptionsEventContent {
+        users: ctx.personal.lock().unwrap().clone(),
+    };
+    watch_list::store_document(client, ctx, PERSONAL_SUBSCRIPTIONS_DOCUMENT, content).await
+}
+
+/// Loads the persisted personal subscriptions
+pub async fn restore(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content: PersonalSubscriptionsEventContent =
+        watch_list::restore_document(client, ctx, PERSONAL_SUBSCRIPTIONS_DOCUMENT).await?;
+    *ctx.personal.lock().unwrap() = content.users;
+    Ok(())
+}
+
+/// Lets `user` follow the subscription `source`, replacing an earlier filter
+pub async fn follow(
+    client: &Client,
+    ctx: &SharedState,
+    user: &UserId,
+    dm_room: OwnedRoomId,
+    source: &str,
+    filter: Option<String>,
+) -> anyhow::Result<()> {
+    if let Some(filter) = &filter {
+        Regex::new(filter)?;
+    }
+    {
+        let mut personal = ctx.personal.lock().unwrap();
+        let subscriptions = personal.entry(user.to_owned()).or_default();
+        subscriptions.dm_room = Some(dm_room);
+        subscriptions.sources.insert(source.to_string(), filter);
+    }
+    store(client, ctx).await
+}
+
+/// Returns false, if `user` didn't follow `source`
+pub async fn unfollow(
+    client: &Client,
+    ctx: &SharedState,
+    user: &UserId,
+    source: &str,
+) -> anyhow::Result<bool> {
+    let removed = {
+        let mut personal = ctx.personal.lock().unwrap();
+        let removed = personal
+            .get_mut(user)
+            .map(|x| x.sources.remove(source).is_some())
+            .unwrap_or(false);
+        if personal.get(user).is_some_and(|x| x.sources.is_empty()) {
+            personal.remove(user);
+        }
+        removed
+    };
+    if removed {
+        store(client, ctx).await?;
+    }
+    Ok(removed)
+}
+
+/// The subscriptions `user` follows, with their filters
+pub fn followed_by(ctx: &SharedState, user: &UserId) -> BTreeMap<String, Option<String>> {
+    ctx.personal
+        .lock()
+        .unwrap()
+        .get(user)
+        .map(|x| x.sources.clone())
+        .unwrap_or_default()
+}
+
+/// Drops all personal subscriptions of `user`, who left the DM with us
+async fn forget_user(client: &Client, ctx: &SharedState, user: &UserId) {
+    if ctx.personal.lock().unwrap().remove(user).is_none() {
+        return;
+    }
+    println!("{user} left the DM, dropping their personal subscriptions");
+    if let Err(e) = store(client, ctx).await {
+        eprintln!("Failed to persist the personal subscriptions: {e:?}");
+    }
+}
+
+/// Our DM with `user`, creating one if there is none yet. Fails if the user left it.
+async fn dm_room(client: &Client, ctx: &SharedState, user: &UserId) -> anyhow::Result<OwnedRoomId> {
+    let known = ctx
+        .personal
+        .lock()
+        .unwrap()
+        .get(user)
+        .and_then(|x| x.dm_room.clone());
+    if let Some(room_id) = known {
+        if client
+            .get_room(&room_id)
+            .is_some_and(|x| x.state() == RoomState::Joined)
+        {
+            return Ok(room_id);
+        }
+        // Left while we weren't running
+        forget_user(client, ctx, user).await;
+        anyhow::bail!("{user} left the DM {room_id}");
+    }
+    let room_id = client.create_dm(user).await?.room_id().to_owned();
+    if let Some(subscriptions) = ctx.personal.lock().unwrap().get_mut(user) {
+        subscriptions.dm_room = Some(room_id.clone());
+    }
+    store(client, ctx).await?;
+    Ok(room_id)
+}
+
+/// Either side leaving a DM ends the personal subscriptions of the user, instead of the
+/// next notification opening a new DM
+pub async fn on_member(
+    event: OriginalSyncRoomMemberEvent,
+    client: Client,
+    room: Room,
+    ctx: Ctx<SharedState>,
+) {
+    if !matches!(
+        event.content.membership,
+        MembershipState::Leave | MembershipState::Ban
+    ) {
+        return;
+    }
+    let user = ctx
+        .personal
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, x)| x.dm_room.as_deref() == Some(room.room_id()))
+        .map(|(user, _)| user.clone());
+    let Some(user) = user else {
+        return;
+    };
+    let by_us = client.user_id() == Some(&*event.state_key);
+    if event.state_key != user && !by_us {
+        return;
+    }
+    forget_user(&client, &ctx, &user).await;
+    if !by_us && room.state() == RoomState::Joined {
+        if let Err(e) = room.leave().await {
+            eprintln!("Failed to leave the DM {}: {e:?}", room.room_id());
+        }
+    }
+}
+
+/// Sends the new entries of `source` to everyone following it, filtered by their patterns
+pub async fn notify(
+    client: &Client,
+    ctx: &SharedState,
+    source: &MozData,
+    entries: &HashSet<String>,
+) {
+    if !ctx.cfg.personal_subscriptions {
+        return;
+    }
+    let followers: Vec<_> = ctx
+        .personal
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(user, x)| {
+            x.sources
+                .get(&source.name)
+                .map(|filter| (user.clone(), filter.clone()))
+        })
+        .collect();
+    for (user, filter) in followers {
+        let matching: HashSet<String> = match filter.as_deref().map(Regex::new).transpose() {
+            Ok(Some(filter)) => entries
+                .iter()
+                .filter(|x| filter.is_match(x))
+                .cloned()
+                .collect(),
+            Ok(None) => entries.clone(),
+            Err(e) => {
+                eprintln!("Ignoring broken filter of {user} for {}: {e}", source.name);
+                continue;
+            }
+        };
+        if matching.is_empty() {
+            continue;
+        }
+        let answer_str = source.format_entries(&matching);
+        let (plain, html) = Message::fill(
+            i18n::translate(ctx.cfg.language, "{source} got new uploads: {entries}"),
+            &[
+                ("source", formatting::source_link(source)),
+                ("entries", Message::new().text(&answer_str)),
+            ],
+        )
+        .into_parts();
+        let result = async {
+            let room_id = dm_room(client, ctx, &user).await?;
+            ctx.resources.wait_for_send_slot().await;
+            send_to_room(client, &room_id, source.msgtype, &plain, &html).await
+        }
+        .await;
+        if let Err(e) = result {
+            eprintln!("Failed to notify {user} about {}: {e:?}", source.name);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Keeps the newest notification of subscriptions with `pin = true` pinned in the room,
+//! replacing the pin of the one before. The pins are kept in the session DB, so a restart
+//! still replaces the pin from before it.
+use super::SharedState;
+use matrix_sdk::{
+    deserialized_responses::RawSyncOrStrippedState,
+    room::Room,
+    ruma::{
+        events::{
+            room::pinned_events::RoomPinnedEventsEventContent, StateEventType, SyncStateEvent,
+        },
+        OwnedEventId, OwnedRoomId, RoomId,
+    },
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+const PINS_DOCUMENT: &str = "pins";
+
+/// The notification we pinned last for a source in a room
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Pin {
+    pub room_id: OwnedRoomId,
+    pub source: String,
+    pub event_id: OwnedEventId,
+}
+
+pub async fn store(ctx: &SharedState) -> anyhow::Result<()> {
+    let Some(db) = ctx.cfg.session_storage.get_session_db() else {
+        return Ok(());
+    };
+    db.state.set_document(PINS_DOCUMENT, &stored(ctx)).await
+}
+
+/// The pins in the form they are persisted in
+pub fn stored(ctx: &SharedState) -> Vec<Pin> {
+    ctx.pinned
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((room_id, source), event_id)| Pin {
+            room_id: room_id.clone(),
+            source: source.clone(),
+            event_id: event_id.clone(),
+        })
+        .collect()
+}
+
+pub async fn restore(ctx: &SharedState) -> anyhow::Result<()> {
+    let Some(db) = ctx.cfg.session_storage.get_session_db() else {
+        return Ok(());
+    };
+    let Some(pins) = db.state.document::<Vec<Pin>>(PINS_DOCUMENT).await? else {
+        return Ok(());
+    };
+    set(ctx, &pins);
+    Ok(())
+}
+
+/// Replaces the known pins, e.g. by imported ones
+pub fn set(ctx: &SharedState, pins: &[Pin]) {
+    *ctx.pinned.lock().unwrap() = pins
+        .iter()
+        .map(|x| ((x.room_id.clone(), x.source.clone()), x.event_id.clone()))
+        .collect();
+}
+
+async fn pinned_events(room: &Room) -> anyhow::Result<Vec<OwnedEventId>> {
+    let pinned = match room
+        .get_state_event_static::<RoomPinnedEventsEventContent>()
+        .await?
+    {
+        Some(RawSyncOrStrippedState::Sync(raw)) => match raw.deserialize()? {
+            SyncStateEvent::Original(event) => event.content.pinned,
+            SyncStateEvent::Redacted(_) => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+    Ok(pinned)
+}
+
+/// Pins `event_id` as the newest notification of `source` in `room_id`. Rooms where we
+/// lack the power level to pin just keep the notifications unpinned.
+pub async fn pin(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &RoomId,
+    source: &str,
+    event_id: OwnedEventId,
+) -> anyhow::Result<()> {
+    let (Some(room), Some(own_id)) = (client.get_room(room_id), client.user_id()) else {
+        return Ok(());
+    };
+    let may_pin = room
+        .get_member_no_sync(own_id)
+        .await?
+        .map(|x| x.can_send_state(StateEventType::RoomPinnedEvents))
+        .unwrap_or(false);
+    if !may_pin {
+        println!(
+            "Not pinning the notification of {source} in {room_id}, as we lack the power level"
+        );
+        return Ok(());
+    }
+    let key = (room_id.to_owned(), source.to_string());
+    let previous = ctx.pinned.lock().unwrap().get(&key).cloned();
+    let mut pinned = pinned_events(&room).await?;
+    pinned.retain(|x| Some(x) != previous.as_ref());
+    pinned.push(event_id.clone());
+    room.send_state_event(RoomPinnedEventsEventContent::new(pinned))
+        .await?;
+    ctx.pinned.lock().unwrap().insert(key, event_id);
+    store(ctx).await
+}
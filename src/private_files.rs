@@ -0,0 +1,39 @@
+//! Files and directories only the bot's user may access, for everything holding keys,
+//! tokens or passwords. Elsewhere than on unix, they get the default permissions.
+use std::{
+    fs::{DirBuilder, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+
+/// Like `create_dir_all`, the directories created on the way are only accessible by us
+pub fn create_dir_all(path: &Path) -> std::io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(path)
+}
+
+/// Like `fs::write`, but for a file only we can read. Existing files get restricted, too.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    restrict(path)?;
+    file.write_all(contents.as_ref())
+}
+
+/// Takes the access of everybody else away from an existing file
+pub fn restrict(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+
+/// A daily window (in a given timezone) during which a room doesn't want to be notified.
+/// Windows may wrap around midnight, e.g. 22:00-07:00.
+#[derive(Debug, Clone)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    timezone: Tz,
+}
+
+impl QuietHours {
+    /// Parses a window like "22:00-07:00" in the given timezone (e.g. "Europe/Berlin")
+    pub fn parse(window: &str, timezone: &str) -> anyhow::Result<Self> {
+        let (start, end) = window.split_once('-').ok_or_else(|| {
+            anyhow!("Quiet hours need to look like '22:00-07:00', got '{window}'")
+        })?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M")
+            .with_context(|| format!("Invalid start of quiet hours '{start}'"))?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M")
+            .with_context(|| format!("Invalid end of quiet hours '{end}'"))?;
+        let timezone = timezone
+            .parse::<Tz>()
+            .map_err(|e| anyhow!("Invalid timezone '{timezone}': {e}"))?;
+        Ok(Self {
+            start,
+            end,
+            timezone,
+        })
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone).time();
+        if self.start <= self.end {
+            self.start <= local && local < self.end
+        } else {
+            // Wraps around midnight
+            local >= self.start || local < self.end
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Reactions on our notifications: 🔁 polls the subscription of the notification again
+//! and answers in a thread on it.
+use super::{i18n::tr, resources::CommandAllowance, room_settings, send_queue, SharedState};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::events::{
+        reaction::OriginalSyncReactionEvent,
+        relation::Thread,
+        room::message::{Relation, RoomMessageEventContent},
+        Mentions,
+    },
+    Client, RoomState,
+};
+
+const RECHECK_KEY: &str = "🔁";
+
+pub async fn on_reaction(
+    event: OriginalSyncReactionEvent,
+    room: Room,
+    client: Client,
+    ctx: Ctx<SharedState>,
+) -> anyhow::Result<()> {
+    if room.state() != RoomState::Joined || ctx.is_ignored(&event.sender) {
+        return Ok(());
+    }
+    let annotation = event.content.relates_to;
+    // Clients differ in whether they add a variation selector to the emoji
+    if !annotation.key.starts_with(RECHECK_KEY) {
+        return Ok(());
+    }
+    let Some(source) = ctx.notification_source(&annotation.event_id) else {
+        return Ok(());
+    };
+    let settings = room_settings::get(&client, room.room_id()).await;
+    if !ctx.accepts_commands_in(&room, &event.sender).await {
+        println!(
+            "Ignoring re-check of {source} by untrusted user {}",
+            event.sender
+        );
+        return Ok(());
+    }
+    if ctx.resources.check_command(&event.sender, room.room_id()) != CommandAllowance::Allowed {
+        return Ok(());
+    }
+    let lang = settings.language.unwrap_or(ctx.cfg.language);
+    let reply = match ctx.check_now(Some(source)).await {
+        Ok(results) => results
+            .into_iter()
+            .map(|(url_part, outcome)| format!("{url_part}: {outcome}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(e) => tr!(lang, "Check failed: {e}", e),
+    };
+    let mut content = RoomMessageEventContent::text_plain(reply);
+    content.mentions = Some(Mentions::new());
+    content.relates_to = Some(Relation::Thread(Thread::plain(
+        annotation.event_id.clone(),
+        annotation.event_id,
+    )));
+    send_queue::send(&room, content).await?;
+    Ok(())
+}
@@ -0,0 +1,255 @@
+//! Reloading the config file on SIGHUP, without restarting. The subscriptions get compared
+//! by name: new ones are added, removed ones dropped, and changed ones replaced. The seen
+//! entries of a subscription are kept as long as it still lists the same directory with
+//! the same filter, so there is no new baseline for it. Changes made at runtime (filters
+//! and intervals set with commands, runtime subscriptions) win over the config, like at
+//! startup. Of the rest of the config, only `ReloadableConfig` is applied, everything else
+//! needs a restart.
+use super::{
+    extract_instance, instance_names, load_settings, mozilla::MozData, scheduler::Scheduler,
+    Instance, LoginData, Schedule, SourceStatus,
+};
+use config::Config;
+use regex::Regex;
+use std::{collections::BTreeSet, path::Path};
+use tokio::{sync::mpsc, time::Duration};
+
+/// Resolves on every SIGHUP. Never, where there are no signals.
+pub struct Hangups {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangups {
+    pub fn listen() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| eprintln!("Failed to listen for SIGHUP: {e}"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+/// Reads the config again and applies it to the running `instances`. Nothing gets
+/// applied, if the config is broken.
+pub async fn reload(
+    config: Option<&Path>,
+    profile: Option<&str>,
+    names: &[(Option<String>, Option<String>)],
+    instances: &mut [Instance],
+    scheduler: &mut Scheduler<(usize, String)>,
+) -> anyhow::Result<()> {
+    let settings = load_settings(config, profile, false)?;
+    if instance_names(&settings)? != names {
+        anyhow::bail!("The instances or accounts changed, which needs a restart");
+    }
+    let mut reloaded = Vec::with_capacity(names.len());
+    for ((instance_name, account), instance) in names.iter().zip(instances.iter()) {
+        let settings = with_running_secrets(&settings, instance_name, account, instance)?;
+        // The reloaded instance only lends us its config, it never polls anything
+        let (poller, _) = mpsc::unbounded_channel();
+        reloaded.push(
+            extract_instance(
+                &settings,
+                instance_name.as_deref(),
+                account.as_deref(),
+                poller,
+                false,
+            )
+            .await?,
+        );
+    }
+    for (idx, (instance, (new, schedules))) in instances.iter_mut().zip(reloaded).enumerate() {
+        apply(idx, instance, new, schedules, scheduler);
+    }
+    println!("Reloaded the config");
+    Ok(())
+}
+
+/// The secrets that were asked for at startup aren't in the config, and nobody is there to
+/// ask again. Neither can change without a restart, so the running ones are just reused.
+fn with_running_secrets(
+    settings: &Config,
+    instance_name: &Option<String>,
+    account: &Option<String>,
+    instance: &Instance,
+) -> anyhow::Result<Config> {
+    let login_prefix = match (account, instance_name) {
+        (Some(account), _) => format!("account.{account}."),
+        (None, Some(instance)) => format!("instance.{instance}."),
+        (None, None) => String::new(),
+    };
+    let cfg = &instance.shared_state.cfg;
+    let mut builder = Config::builder().add_source(settings.clone());
+    if let Some(db) = cfg.session_storage.get_session_db() {
+        builder = builder.set_override(format!("{login_prefix}login.db_pw"), db.db_pw)?;
+    }
+    if let LoginData::UsernamePassword(_, password) = &cfg.login_data {
+        builder =
+            builder.set_override(format!("{login_prefix}login.password"), password.clone())?;
+    }
+    Ok(builder.build()?)
+}
+
+fn same_listing(a: &MozData, b: &MozData) -> bool {
+    a.base_url == b.base_url
+        && a.url_part == b.url_part
+        && a.query_subdirs == b.query_subdirs
+        && a.filter.as_ref().map(Regex::as_str) == b.filter.as_ref().map(Regex::as_str)
+}
+
+fn apply(
+    idx: usize,
+    instance: &mut Instance,
+    new: Instance,
+    schedules: Vec<(String, Schedule)>,
+    scheduler: &mut Scheduler<(usize, String)>,
+) {
+    let ctx = instance.shared_state.clone();
+    let reloadable = new.shared_state.cfg.reloadable.lock().unwrap().clone();
+    *ctx.cfg.reloadable.lock().unwrap() = reloadable;
+    let runtime: BTreeSet<String> = ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    let overrides = ctx.overrides.lock().unwrap().clone();
+
+    let configured: BTreeSet<&str> = new.sources.iter().map(|x| x.name.as_str()).collect();
+    let removed: Vec<String> = instance
+        .sources
+        .iter()
+        .map(|x| x.name.clone())
+        .filter(|x| !runtime.contains(x) && !configured.contains(x.as_str()))
+        .collect();
+    for name in removed {
+        println!("Unsubscribing from {name}, it is no longer in the config");
+        instance.sources.retain(|x| x.name != name);
+        ctx.resources.forget_seen_entries(&name);
+        ctx.sources.lock().unwrap().remove(&name);
+        scheduler.remove(&(idx, name));
+    }
+
+    for (mut source, (name, schedule)) in new.sources.into_iter().zip(schedules) {
+        // A runtime subscription replaced the one of the config at startup already
+        if runtime.contains(&name) {
+            continue;
+        }
+        if let Some(filter) = overrides.filters.get(&name) {
+            match filter.as_deref().map(Regex::new).transpose() {
+                Ok(filter) => source.filter = filter,
+                Err(e) => eprintln!("Ignoring the broken filter override of {name}: {e}"),
+            }
+        }
+        let uses_default_interval =
+            matches!(schedule, Schedule::Interval(_)) && !overrides.intervals.contains_key(&name);
+        let schedule = match overrides.intervals.get(&name) {
+            Some(seconds) => Schedule::Interval(Duration::from_secs(*seconds)),
+            None if uses_default_interval => Schedule::Interval(ctx.default_interval()),
+            None => schedule,
+        };
+        let mut statuses = ctx.sources.lock().unwrap();
+        match instance.sources.iter_mut().find(|x| x.name == name) {
+            Some(old) => {
+                if same_listing(old, &source) {
+                    source.data = std::mem::take(&mut old.data);
+                } else {
+                    println!("Taking a new baseline of {name}, its listing changed");
+                    ctx.resources.forget_seen_entries(&name);
+                }
+                *old = source;
+                if let Some(status) = statuses.get_mut(&name) {
+                    status.url_part = old.url_part.clone();
+                    status.filter = old.filter.as_ref().map(|x| x.as_str().to_string());
+                    status.rooms = old.rooms.clone();
+                    status.uses_default_interval = uses_default_interval;
+                    if status.schedule != schedule.to_string() {
+                        println!("Polling {name} {schedule}");
+                        status.schedule = schedule.to_string();
+                        scheduler.set_schedule(&(idx, name.clone()), schedule);
+                    }
+                }
+            }
+            None => {
+                println!(
+                    "Subscribing to {name} ({}) from the config",
+                    source.url_part
+                );
+                let mut status = SourceStatus::new(&source, &schedule);
+                status.uses_default_interval = uses_default_interval;
+                statuses.insert(name.clone(), status);
+                scheduler.add((idx, name), schedule);
+                instance.sources.push(source);
+            }
+        }
+    }
+
+    // The default interval might have changed, which also affects runtime subscriptions
+    let default = Schedule::Interval(ctx.default_interval());
+    for (name, status) in ctx.sources.lock().unwrap().iter_mut() {
+        if status.uses_default_interval && status.schedule != default.to_string() {
+            println!("Polling {name} {default}");
+            status.schedule = default.to_string();
+            scheduler.set_schedule(&(idx, name.clone()), default.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(url_part: &str, filter: Option<&str>) -> MozData {
+        MozData::new(
+            "nightly",
+            url_part,
+            filter.map(|x| Regex::new(x).unwrap()),
+            false,
+        )
+    }
+
+    #[test]
+    fn keeps_the_baseline_of_the_same_listing() {
+        let old = source("firefox/nightly", Some(r"\.zip$"));
+        let mut new = source("firefox/nightly", Some(r"\.zip$"));
+        new.pin = true;
+        new.rooms = Some(Vec::new());
+        assert!(same_listing(&old, &new));
+    }
+
+    #[test]
+    fn takes_a_new_baseline_of_changed_listings() {
+        let old = source("firefox/nightly", Some(r"\.zip$"));
+        assert!(!same_listing(
+            &old,
+            &source("firefox/releases", Some(r"\.zip$"))
+        ));
+        assert!(!same_listing(
+            &old,
+            &source("firefox/nightly", Some(r"\.exe$"))
+        ));
+        assert!(!same_listing(&old, &source("firefox/nightly", None)));
+        let mut subdirs = source("firefox/nightly", Some(r"\.zip$"));
+        subdirs.query_subdirs = true;
+        assert!(!same_listing(&old, &subdirs));
+        let mut mirror = source("firefox/nightly", Some(r"\.zip$"));
+        mirror.base_url = String::from("https://mirror.example.org/pub");
+        assert!(!same_listing(&old, &mirror));
+    }
+}
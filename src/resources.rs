@@ -0,0 +1,172 @@
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    time::{sleep, Duration, Instant},
+};
+
+const SEND_WINDOW: Duration = Duration::from_secs(60);
+/// Command buckets are only pruned beyond this many, to keep the common case cheap
+const MAX_IDLE_COMMAND_BUCKETS: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceLimits {
+    pub max_concurrent_requests: usize,
+    /// Summed up over the seen-sets of all subscriptions of an instance
+    pub max_seen_entries: usize,
+    pub max_sends_per_minute: usize,
+    /// Per user and room, with bursts up to the same number
+    pub max_commands_per_minute: usize,
+}
+
+/// Whether a command may run, see `ResourceTracker::check_command`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandAllowance {
+    Allowed,
+    /// Over the limit for the first time since the last allowed command
+    SlowDown,
+    Dropped,
+}
+
+/// Token bucket of one user in one room
+#[derive(Debug)]
+struct CommandBucket {
+    tokens: f64,
+    updated: Instant,
+    warned: bool,
+}
+
+/// Enforces the resource limits of one bot instance and keeps track of its usage
+#[derive(Debug)]
+pub struct ResourceTracker {
+    limits: ResourceLimits,
+    requests: Semaphore,
+    total_requests: AtomicU64,
+    seen_entries: Mutex<HashMap<String, usize>>,
+    recent_sends: Mutex<VecDeque<Instant>>,
+    total_sends: AtomicU64,
+    command_buckets: Mutex<HashMap<(OwnedUserId, OwnedRoomId), CommandBucket>>,
+}
+
+impl ResourceTracker {
+    pub fn new(limits: ResourceLimits) -> Self {
+        Self {
+            limits,
+            requests: Semaphore::new(limits.max_concurrent_requests.max(1)),
+            total_requests: AtomicU64::new(0),
+            seen_entries: Mutex::new(HashMap::new()),
+            recent_sends: Mutex::new(VecDeque::new()),
+            total_sends: AtomicU64::new(0),
+            command_buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until we are allowed to make another HTTP request. The request counts as
+    /// in flight as long as the returned permit is alive.
+    pub async fn acquire_request(&self) -> SemaphorePermit<'_> {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        self.requests
+            .acquire()
+            .await
+            .expect("Request semaphore is never closed")
+    }
+
+    fn requests_in_flight(&self) -> usize {
+        self.limits.max_concurrent_requests.max(1) - self.requests.available_permits()
+    }
+
+    /// Records the size of the seen-set of a subscription. Returns false, if the
+    /// instance is over its limit because of it.
+    pub fn record_seen_entries(&self, source: &str, count: usize) -> bool {
+        let mut seen_entries = self.seen_entries.lock().unwrap();
+        seen_entries.insert(source.to_string(), count);
+        seen_entries.values().sum::<usize>() <= self.limits.max_seen_entries
+    }
+
+    pub fn forget_seen_entries(&self, source: &str) {
+        self.seen_entries.lock().unwrap().remove(source);
+    }
+
+    /// Waits until sending another message keeps us within the send rate
+    pub async fn wait_for_send_slot(&self) {
+        loop {
+            let wait = {
+                let mut recent_sends = self.recent_sends.lock().unwrap();
+                while recent_sends
+                    .front()
+                    .map(|x| x.elapsed() >= SEND_WINDOW)
+                    .unwrap_or(false)
+                {
+                    recent_sends.pop_front();
+                }
+                if recent_sends.len() < self.limits.max_sends_per_minute.max(1) {
+                    recent_sends.push_back(Instant::now());
+                    self.total_sends.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                SEND_WINDOW.saturating_sub(recent_sends.front().unwrap().elapsed())
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// Takes a token from the bucket of `user` in `room`. Only the first command over the
+    /// limit gets a SlowDown, further ones are dropped silently until tokens are back.
+    pub fn check_command(&self, user: &UserId, room: &RoomId) -> CommandAllowance {
+        let capacity = self.limits.max_commands_per_minute.max(1) as f64;
+        let mut buckets = self.command_buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_COMMAND_BUCKETS {
+            // Buckets that had a minute to refill are full anyways
+            buckets.retain(|_, x| x.updated.elapsed() < SEND_WINDOW);
+        }
+        let bucket = buckets
+            .entry((user.to_owned(), room.to_owned()))
+            .or_insert(CommandBucket {
+                tokens: capacity,
+                updated: Instant::now(),
+                warned: false,
+            });
+        let refill = bucket.updated.elapsed().as_secs_f64() / SEND_WINDOW.as_secs_f64();
+        bucket.tokens = (bucket.tokens + refill * capacity).min(capacity);
+        bucket.updated = Instant::now();
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.warned = false;
+            CommandAllowance::Allowed
+        } else if !bucket.warned {
+            bucket.warned = true;
+            CommandAllowance::SlowDown
+        } else {
+            CommandAllowance::Dropped
+        }
+    }
+
+    /// Human readable usage against limits, e.g. for the !resources command
+    pub fn report(&self) -> String {
+        let seen_entries: usize = self.seen_entries.lock().unwrap().values().sum();
+        let recent_sends = {
+            let recent_sends = self.recent_sends.lock().unwrap();
+            recent_sends
+                .iter()
+                .filter(|x| x.elapsed() < SEND_WINDOW)
+                .count()
+        };
+        format!(
+            "HTTP requests in flight: {}/{} ({} total)\nSeen entries: {}/{}\nMessages sent in the last minute: {}/{} ({} total)",
+            self.requests_in_flight(),
+            self.limits.max_concurrent_requests,
+            self.total_requests.load(Ordering::Relaxed),
+            seen_entries,
+            self.limits.max_seen_entries,
+            recent_sends,
+            self.limits.max_sends_per_minute,
+            self.total_sends.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,156 @@
+//! Rooms can set a retention (`!settings retention <days>`), after which our notifications
+//! get redacted, so long-lived rooms don't fill up with obsolete nightly announcements.
+//! The IDs of sent notifications, including the further parts of split ones and their
+//! attachments, go into the state DB for that as soon as they are sent. Without a
+//! persisted session, they only live in memory.
+use super::{
+    room_settings,
+    send_queue::{self, Failure},
+    SharedState,
+};
+use chrono::Utc;
+use matrix_sdk::{
+    ruma::{OwnedEventId, OwnedRoomId, RoomId},
+    Client,
+};
+use std::collections::BTreeMap;
+use tokio::time::{sleep, Duration};
+
+const SENT_KEY: &[u8] = b"org.mozillabot.sent_notifications";
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Per room, the oldest ones are forgotten beyond that
+const MAX_TRACKED_PER_ROOM: usize = 10_000;
+
+/// Our notifications per room, with the Unix timestamp they were sent at
+pub type SentNotifications = BTreeMap<OwnedRoomId, Vec<(OwnedEventId, i64)>>;
+
+/// Remembers the events of a notification, for redacting them once the room's retention
+/// is over
+pub async fn track(ctx: &SharedState, room_id: &RoomId, event_ids: &[OwnedEventId]) {
+    if event_ids.is_empty() {
+        return;
+    }
+    let sent_at = Utc::now().timestamp();
+    let tracked: Vec<_> = event_ids.iter().map(|x| (x.clone(), sent_at)).collect();
+    {
+        let mut sent = ctx.sent.lock().unwrap();
+        let notifications = sent.entry(room_id.to_owned()).or_default();
+        notifications.extend(tracked.iter().cloned());
+        let excess = notifications.len().saturating_sub(MAX_TRACKED_PER_ROOM);
+        notifications.drain(..excess);
+    }
+    // Right away, a crash would leave them unredacted forever otherwise
+    if let Some(db) = ctx.cfg.session_storage.get_session_db() {
+        if let Err(e) = db
+            .state
+            .add_sent(room_id, &tracked, MAX_TRACKED_PER_ROOM)
+            .await
+        {
+            eprintln!("Failed to persist the sent notifications: {e:?}");
+        }
+    }
+}
+
+/// Persists the tracked notifications, for after a restart
+pub async fn store(ctx: &SharedState) -> anyhow::Result<()> {
+    if let Some(db) = ctx.cfg.session_storage.get_session_db() {
+        let sent = ctx.sent.lock().unwrap().clone();
+        db.state.set_sent(&sent).await?;
+    }
+    Ok(())
+}
+
+/// Loads the notifications tracked before the last shutdown
+pub async fn restore(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let Some(db) = ctx.cfg.session_storage.get_session_db() else {
+        return Ok(());
+    };
+    let mut stored = db.state.sent().await?;
+    // Older versions kept them in the state store of the client
+    if let Some(serialized) = client.store().get_custom_value(SENT_KEY).await? {
+        let legacy: SentNotifications = serde_json::from_slice(&serialized)?;
+        for (room_id, mut notifications) in legacy {
+            notifications.extend(stored.remove(&room_id).unwrap_or_default());
+            stored.insert(room_id, notifications);
+        }
+        db.state.set_sent(&stored).await?;
+        client.store().remove_custom_value(SENT_KEY).await?;
+    }
+    // Ones sent since the start come after the stored ones
+    let mut sent = ctx.sent.lock().unwrap();
+    for (room_id, mut notifications) in stored {
+        let newer = sent.remove(&room_id).unwrap_or_default();
+        notifications.extend(newer);
+        sent.insert(room_id, notifications);
+    }
+    Ok(())
+}
+
+/// Redacts the notifications older than the retention of their room
+async fn redact_expired(client: &Client, ctx: &SharedState) {
+    let rooms: Vec<_> = ctx.sent.lock().unwrap().keys().cloned().collect();
+    for room_id in rooms {
+        let Some(room) = client.get_room(&room_id) else {
+            continue;
+        };
+        let Some(days) = room_settings::get(client, &room_id).await.retention_days else {
+            continue;
+        };
+        let expired_before = Utc::now().timestamp() - i64::from(days) * 24 * 60 * 60;
+        let expired: Vec<_> = ctx
+            .sent
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .map(|x| {
+                x.iter()
+                    .take_while(|(_, sent_at)| *sent_at < expired_before)
+                    .map(|(event_id, _)| event_id.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut redacted = 0;
+        let mut done = Vec::new();
+        for event_id in expired {
+            match room
+                .redact(&event_id, Some("Retention period is over"), None)
+                .await
+            {
+                Ok(_) => redacted += 1,
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    eprintln!("Failed to redact {event_id} in {room_id}: {e:?}");
+                    match send_queue::classify(&e) {
+                        // E.g. already gone, trying again wouldn't help
+                        Failure::Rejected => {}
+                        // The next run tries again
+                        Failure::Unavailable => break,
+                    }
+                }
+            }
+            done.push(event_id);
+        }
+        if redacted > 0 {
+            println!("Redacted {redacted} expired notifications in {room_id}");
+        }
+        if done.is_empty() {
+            continue;
+        }
+        if let Some(notifications) = ctx.sent.lock().unwrap().get_mut(&room_id) {
+            notifications.drain(..done.len());
+        }
+        if let Some(db) = ctx.cfg.session_storage.get_session_db() {
+            if let Err(e) = db.state.remove_sent(&room_id, &done).await {
+                eprintln!("Failed to persist the sent notifications: {e:?}");
+            }
+        }
+    }
+}
+
+/// Checks for expired notifications every hour
+pub async fn run(client: Client, ctx: SharedState) {
+    loop {
+        redact_expired(&client, &ctx).await;
+        sleep(CHECK_INTERVAL).await;
+    }
+}
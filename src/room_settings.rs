@@ -0,0 +1,360 @@
+//! Per-room settings, kept in a `org.mozillabot.settings` state event in the room itself.
+//! That way room admins can read (and audit) them with any client, and they move along
+//! with the room instead of living in the bot's config.
+use super::{
+    i18n::{self, tr, Language},
+    scheduler::parse_duration,
+    UserPattern,
+};
+use matrix_sdk::{
+    deserialized_responses::RawSyncOrStrippedState,
+    room::Room,
+    ruma::{
+        events::{
+            macros::EventContent, room::message::RoomMessageEventContent, EmptyStateKey,
+            SyncStateEvent,
+        },
+        OwnedUserId, RoomId, UserId,
+    },
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageFormat {
+    /// Lists the new entries
+    #[default]
+    Full,
+    /// Only the number of new entries and a link
+    Summary,
+}
+
+/// msgtype of notifications. Clients show notices less prominently, and other bots
+/// ignore them, which prevents loops between bots.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationType {
+    #[default]
+    Notice,
+    Text,
+}
+
+impl NotificationType {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "notice" | "m.notice" => Ok(Self::Notice),
+            "text" | "m.text" => Ok(Self::Text),
+            _ => anyhow::bail!("Unknown message type {name}, expected notice or text"),
+        }
+    }
+
+    pub fn content(
+        self,
+        plain: impl Into<String>,
+        html: impl Into<String>,
+    ) -> RoomMessageEventContent {
+        match self {
+            Self::Notice => RoomMessageEventContent::notice_html(plain, html),
+            Self::Text => RoomMessageEventContent::text_html(plain, html),
+        }
+    }
+}
+
+/// How successful commands like !watch or !mute get confirmed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Acknowledgement {
+    #[default]
+    Message,
+    /// ✅ or ❌ on the command, to keep busy rooms readable. Errors are explained in a reply.
+    Reaction,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.settings", kind = State, state_key_type = EmptyStateKey)]
+pub struct RoomSettingsEventContent {
+    /// Names of the subscriptions announced in this room. All of them, if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources: Option<Vec<String>>,
+    #[serde(default)]
+    pub format: MessageFormat,
+    #[serde(default)]
+    pub muted: bool,
+    /// Users trusted with commands in this room, instead of the ones from the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_commands_from: Option<Vec<UserPattern>>,
+    #[serde(default)]
+    pub acknowledge: Acknowledgement,
+    /// Post the notifications of each subscription in a thread of their own
+    #[serde(default)]
+    pub threads: bool,
+    /// Overrides the msgtype of the subscriptions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msgtype: Option<NotificationType>,
+    /// Language of replies and notifications, instead of the one from the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<Language>,
+    /// Our notifications older than this many days get redacted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u32>,
+    /// Collect the notifications and post them as one message, at most every this many
+    /// minutes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest_minutes: Option<u64>,
+}
+
+impl RoomSettingsEventContent {
+    pub fn wants(&self, source: &str) -> bool {
+        !self.muted
+            && self
+                .sources
+                .as_ref()
+                .map(|x| x.iter().any(|s| s == source))
+                .unwrap_or(true)
+    }
+
+    /// One `key: value` line per setting. The keys stay untranslated, `get` looks them up.
+    fn describe(&self, lang: Language) -> String {
+        format!(
+            "sources: {}\nformat: {}\nmuted: {}\ntrusted: {}\nack: {}\nthreads: {}\nmsgtype: {}\nlanguage: {}\nretention: {}\ndigest: {}",
+            self.sources
+                .as_ref()
+                .map(|x| x.join(", "))
+                .unwrap_or_else(|| i18n::translate(lang, "all").to_string()),
+            match self.format {
+                MessageFormat::Full => "full",
+                MessageFormat::Summary => "summary",
+            },
+            i18n::translate(lang, if self.muted { "yes" } else { "no" }),
+            self.accept_commands_from
+                .as_ref()
+                .map(|x| x
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "))
+                .unwrap_or_else(|| i18n::translate(lang, "from the config").to_string()),
+            match self.acknowledge {
+                Acknowledgement::Message => "message",
+                Acknowledgement::Reaction => "reaction",
+            },
+            if self.threads { "on" } else { "off" },
+            match self.msgtype {
+                Some(NotificationType::Notice) => "notice",
+                Some(NotificationType::Text) => "text",
+                None => i18n::translate(lang, "per subscription"),
+            },
+            self.language
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| i18n::translate(lang, "from the config").to_string()),
+            self.retention_days
+                .map(|days| tr!(lang, "{days} days", days))
+                .unwrap_or_else(|| i18n::translate(lang, "forever").to_string()),
+            self.digest_minutes
+                .map(|minutes| tr!(lang, "every {minutes} minutes", minutes))
+                .unwrap_or_else(|| String::from("off"))
+        )
+    }
+}
+
+/// The settings, and who sent them
+async fn get_with_sender(
+    room: &Room,
+) -> anyhow::Result<(RoomSettingsEventContent, Option<OwnedUserId>)> {
+    let settings = match room
+        .get_state_event_static::<RoomSettingsEventContent>()
+        .await?
+    {
+        Some(RawSyncOrStrippedState::Sync(raw)) => match raw.deserialize()? {
+            SyncStateEvent::Original(event) => (event.content, Some(event.sender)),
+            SyncStateEvent::Redacted(_) => (RoomSettingsEventContent::default(), None),
+        },
+        _ => (RoomSettingsEventContent::default(), None),
+    };
+    Ok(settings)
+}
+
+async fn get_for_room(room: &Room) -> anyhow::Result<RoomSettingsEventContent> {
+    Ok(get_with_sender(room).await?.0)
+}
+
+/// Like `get_for_room`, but without `accept_commands_from` unless the bot itself set it
+/// (with `!settings`, which only trusted users get to use) or a user `may_set` accepts.
+/// Any room admin can send the state event without asking the bot, and trusted commands
+/// reach beyond the room.
+async fn get_checked(
+    room: &Room,
+    may_set: &dyn Fn(&UserId) -> bool,
+) -> anyhow::Result<RoomSettingsEventContent> {
+    let (mut settings, sender) = get_with_sender(room).await?;
+    if let Some(sender) = sender {
+        if settings.accept_commands_from.is_some()
+            && sender != room.own_user_id()
+            && !may_set(&sender)
+        {
+            println!(
+                "Ignoring the trusted users of {}, set by untrusted user {sender}",
+                room.room_id()
+            );
+            settings.accept_commands_from = None;
+        }
+    }
+    Ok(settings)
+}
+
+/// The users trusted in the room by its settings, if someone allowed to set them did
+pub async fn accept_commands_from(
+    room: &Room,
+    may_set: &dyn Fn(&UserId) -> bool,
+) -> Option<Vec<UserPattern>> {
+    match get_checked(room, may_set).await {
+        Ok(settings) => settings.accept_commands_from,
+        Err(e) => {
+            eprintln!("Ignoring unreadable settings of {}: {e:?}", room.room_id());
+            None
+        }
+    }
+}
+
+/// Carries the settings over to the replacement of an upgraded room. Needs the power level
+/// for the settings event in the new room.
+pub async fn copy(from: &Room, to: &Room, may_set: &dyn Fn(&UserId) -> bool) -> anyhow::Result<()> {
+    if from
+        .get_state_event_static::<RoomSettingsEventContent>()
+        .await?
+        .is_none()
+    {
+        return Ok(());
+    }
+    to.send_state_event(get_checked(from, may_set).await?)
+        .await?;
+    Ok(())
+}
+
+/// Settings of the room as of the last sync. Unreadable settings fall back to the
+/// defaults, so a broken state event doesn't silence the room.
+pub async fn get(client: &Client, room_id: &RoomId) -> RoomSettingsEventContent {
+    let Some(room) = client.get_room(room_id) else {
+        return RoomSettingsEventContent::default();
+    };
+    get_for_room(&room).await.unwrap_or_else(|e| {
+        eprintln!("Ignoring unreadable settings of {room_id}: {e:?}");
+        RoomSettingsEventContent::default()
+    })
+}
+
+const SETTINGS_USAGE: &str = "Usage: !settings [sources <name>...|all] [format full|summary] [mute on|off] [trusted <user>...|default] [ack message|reaction] [threads on|off] [msgtype notice|text|default] [language en|de|fr|default] [retention <days>|off] [digest <duration>|off], or !settings get [key] | !settings set key=value...";
+
+/// Digests have to go out at least once a week
+const MAX_DIGEST_MINUTES: u64 = 7 * 24 * 60;
+
+/// Changes one setting. Returns false for unknown keys or values.
+fn apply(
+    lang: Language,
+    settings: &mut RoomSettingsEventContent,
+    key: &str,
+    values: &[&str],
+) -> anyhow::Result<bool> {
+    match (key, values) {
+        ("sources", ["all"]) => settings.sources = None,
+        ("sources", names) if !names.is_empty() => {
+            settings.sources = Some(names.iter().map(|x| x.to_string()).collect())
+        }
+        ("format", ["full"]) => settings.format = MessageFormat::Full,
+        ("format", ["summary"]) => settings.format = MessageFormat::Summary,
+        ("mute", ["on"]) => settings.muted = true,
+        ("mute", ["off"]) => settings.muted = false,
+        ("trusted", ["default"]) => settings.accept_commands_from = None,
+        ("trusted", users) if !users.is_empty() => {
+            settings.accept_commands_from = Some(
+                users
+                    .iter()
+                    .map(|x| UserPattern::parse(x))
+                    .collect::<anyhow::Result<_>>()?,
+            )
+        }
+        ("ack", ["message"]) => settings.acknowledge = Acknowledgement::Message,
+        ("ack", ["reaction"]) => settings.acknowledge = Acknowledgement::Reaction,
+        ("threads", ["on"]) => settings.threads = true,
+        ("threads", ["off"]) => settings.threads = false,
+        ("msgtype", ["default"]) => settings.msgtype = None,
+        ("msgtype", [msgtype]) => settings.msgtype = Some(NotificationType::parse(msgtype)?),
+        ("language", ["default"]) => settings.language = None,
+        ("language", [language]) => settings.language = Some(Language::parse(language)?),
+        ("retention", ["off"]) => settings.retention_days = None,
+        ("retention", [days]) => match days.parse() {
+            Ok(days) if days > 0 => settings.retention_days = Some(days),
+            _ => anyhow::bail!(tr!(lang, "Invalid number of days {days}", days)),
+        },
+        ("digest", ["off"]) => settings.digest_minutes = None,
+        ("digest", [interval]) => match parse_duration(interval)?.as_secs() / 60 {
+            0 => anyhow::bail!(i18n::translate(
+                lang,
+                "Digests need an interval of at least a minute"
+            )),
+            minutes if minutes > MAX_DIGEST_MINUTES => {
+                anyhow::bail!(i18n::translate(
+                    lang,
+                    "Digests need an interval of at most 7 days"
+                ))
+            }
+            minutes => settings.digest_minutes = Some(minutes),
+        },
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Handles `!settings [sources <name>...|all] [format full|summary] [mute on|off]
+/// [trusted <user>...|default] [ack message|reaction] [threads on|off]
+/// [msgtype notice|text|default] [language en|de|fr|default] [retention <days>|off]
+/// [digest <duration>|off]` and returns the reply. `get [key]` shows the settings, and
+/// `set key=value...` changes several at once, with lists separated by commas like
+/// `sources=a,b`. Replies and errors are in `lang`.
+pub async fn settings_command(
+    lang: Language,
+    room: &Room,
+    args: &[String],
+    may_set: &dyn Fn(&UserId) -> bool,
+) -> anyhow::Result<String> {
+    // What the bot writes counts as set by a trusted user, so nothing else may go along
+    let mut settings = get_checked(room, may_set).await?;
+    let args: Vec<_> = args.iter().map(String::as_str).collect();
+    let changes = match args.as_slice() {
+        [] | ["get"] => return Ok(settings.describe(lang)),
+        ["get", key] => {
+            return Ok(settings
+                .describe(lang)
+                .lines()
+                .find(|x| x.split(':').next() == Some(*key))
+                .map(String::from)
+                .unwrap_or_else(|| tr!(lang, "Unknown setting {key}", key)))
+        }
+        ["set", pairs @ ..] if !pairs.is_empty() => pairs
+            .iter()
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) => {
+                    Ok((key, value.split(',').filter(|x| !x.is_empty()).collect()))
+                }
+                None => Err(anyhow::anyhow!(tr!(
+                    lang,
+                    "Expected key=value, got {pair}",
+                    pair
+                ))),
+            })
+            .collect::<anyhow::Result<Vec<(&str, Vec<&str>)>>>()?,
+        [key, values @ ..] => vec![(*key, values.to_vec())],
+    };
+    for (key, values) in changes {
+        if !apply(lang, &mut settings, key, &values)? {
+            return Ok(i18n::translate(lang, SETTINGS_USAGE).to_string());
+        }
+    }
+    room.send_state_event(settings.clone()).await?;
+    Ok(tr!(
+        lang,
+        "Updated settings:\n{settings}",
+        settings = settings.describe(lang)
+    ))
+}
@@ -0,0 +1,105 @@
+//! The `rooms` and `send` subcommands: the rooms of the bot account, joining or leaving
+//! them and posting to them, without starting the bot.
+use super::{
+    alerts, aliases, cli::RoomsAction, empty_rooms, formatting::escape, matrix::restore_client,
+    outbox, room_settings, send_to_room_with_fields, subscriptions, watch_list, SharedState,
+};
+use matrix_sdk::{ruma::events::room::message::FormattedBody, Client, RoomState};
+
+/// The state the cleanup after leaving a room updates, so it doesn't persist half of it
+async fn restore_state(client: &Client, aio: &SharedState) -> anyhow::Result<()> {
+    watch_list::restore_from_account_data(client, aio).await?;
+    subscriptions::restore(client, aio).await?;
+    alerts::restore(client, aio).await?;
+    outbox::restore(client, aio).await?;
+    Ok(())
+}
+
+/// The rooms as of the last sync of the bot, this doesn't sync
+async fn list(client: &Client, aio: &SharedState) {
+    eprintln!("As of the last sync, rooms the bot was invited to since are missing");
+    let mut rooms: Vec<_> = client
+        .rooms()
+        .into_iter()
+        .filter(|x| matches!(x.state(), RoomState::Joined | RoomState::Invited))
+        .collect();
+    rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+    for room in rooms {
+        let name = room
+            .display_name()
+            .await
+            .map(|x| x.to_string())
+            .unwrap_or_default();
+        let state = match room.state() {
+            RoomState::Invited => "invited",
+            _ => "joined",
+        };
+        let watched = if aio.rooms.lock().unwrap().contains_key(room.room_id()) {
+            "watched"
+        } else {
+            "-"
+        };
+        println!("{}\t{state}\t{watched}\t{name}", room.room_id());
+    }
+}
+
+pub async fn run_subcommand(aio: &SharedState, action: &RoomsAction) -> anyhow::Result<()> {
+    let client = restore_client(aio).await?;
+    restore_state(&client, aio).await?;
+    match action {
+        RoomsAction::List => list(&client, aio).await,
+        RoomsAction::Join { room, watch } => {
+            let room_id =
+                aliases::resolve(&aio.cfg.homeserver_url, &aio.cfg.session_storage, room).await?;
+            let room = client.join_room_by_id(&room_id).await?;
+            println!("Joined {}", room.room_id());
+            if *watch {
+                aio.rooms
+                    .lock()
+                    .unwrap()
+                    .entry(room.room_id().to_owned())
+                    .or_default();
+                watch_list::store(&client, aio).await?;
+                println!("Watching {}", room.room_id());
+            }
+        }
+        RoomsAction::Leave { room } => {
+            let room_id =
+                aliases::resolve(&aio.cfg.homeserver_url, &aio.cfg.session_storage, room).await?;
+            let Some(room) = client.get_room(&room_id) else {
+                anyhow::bail!("Not in {room_id}");
+            };
+            room.leave().await?;
+            empty_rooms::forget(&client, aio, &room).await?;
+            println!("Left {room_id}");
+        }
+    }
+    Ok(())
+}
+
+/// Sends `markdown` to `room` and returns whether the bot account is in it
+pub async fn send(aio: &SharedState, room: &str, markdown: &str) -> anyhow::Result<bool> {
+    let client = restore_client(aio).await?;
+    let room_id = aliases::resolve(&aio.cfg.homeserver_url, &aio.cfg.session_storage, room).await?;
+    let html = FormattedBody::markdown(markdown)
+        .map(|x| x.body)
+        .unwrap_or_else(|| escape(markdown));
+    let msgtype = room_settings::get(&client, &room_id)
+        .await
+        .msgtype
+        .unwrap_or_default();
+    let sent = send_to_room_with_fields(
+        &client,
+        &room_id,
+        msgtype,
+        markdown,
+        &html,
+        &serde_json::Map::new(),
+    )
+    .await?;
+    match sent.first() {
+        Some(event_id) => println!("Sent {event_id} to {room_id}"),
+        None => eprintln!("Not in {room_id}"),
+    }
+    Ok(!sent.is_empty())
+}
@@ -0,0 +1,235 @@
+use anyhow::Context;
+use chrono::{DateTime, Local, Utc};
+use std::{collections::HashMap, hash::Hash, str::FromStr};
+use tokio::time::{sleep, Duration, Instant};
+
+/// We never sleep longer than this in one go, so that we notice wall-clock jumps
+/// (e.g. the host was suspended) in a timely manner.
+const MAX_SLEEP: Duration = Duration::from_secs(30);
+/// Differences between monotonic and wall-clock time below this are considered noise
+const CLOCK_JUMP_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// When a subscription should be polled
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Parses a cron expression. Classic 5-field expressions ("0 */2 * * MON-FRI")
+    /// are accepted as well and get a seconds-field prepended.
+    pub fn parse_cron(expression: &str) -> anyhow::Result<Self> {
+        let expression = if expression.split_whitespace().count() == 5 {
+            format!("0 {expression}")
+        } else {
+            expression.to_string()
+        };
+        let schedule = cron::Schedule::from_str(&expression)
+            .with_context(|| format!("Invalid cron expression '{expression}'"))?;
+        Ok(Schedule::Cron(Box::new(schedule)))
+    }
+
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(interval) => {
+                after.checked_add_signed(chrono::Duration::from_std(*interval).ok()?)
+            }
+            // Cron expressions are meant in the local time of the host
+            Schedule::Cron(schedule) => schedule
+                .after(&after.with_timezone(&Local))
+                .next()
+                .map(|x| x.with_timezone(&Utc)),
+        }
+    }
+}
+
+/// Parses durations like "90s", "15m", "4h" or "2d"
+pub fn parse_duration(text: &str) -> anyhow::Result<Duration> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .context("Duration needs a unit (s, m, h or d)")?;
+    let (amount, unit) = text.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .with_context(|| format!("Invalid duration '{text}'"))?;
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => anyhow::bail!("Unknown unit '{unit}' in duration '{text}'"),
+    };
+    let seconds = amount
+        .checked_mul(factor)
+        .with_context(|| format!("Duration '{text}' is too long"))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Interval(interval) if interval.as_secs() % 60 == 0 => {
+                write!(f, "every {}m", interval.as_secs() / 60)
+            }
+            Schedule::Interval(interval) => write!(f, "every {}s", interval.as_secs()),
+            Schedule::Cron(schedule) => write!(f, "cron '{schedule}'"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ScheduleEntry {
+    schedule: Schedule,
+    next_run: Option<DateTime<Utc>>,
+}
+
+/// Keeps track of when each subscription is due next.
+/// Every newly added subscription is due immediately, to get an initial baseline.
+///
+/// Due times are anchored to the wall-clock, but waiting is done with monotonic timers
+/// in short steps. If the host was suspended, all overdue subscriptions get polled once
+/// after resuming (missed cycles are not replayed). If the wall-clock jumps backwards,
+/// the due times get shifted along, so intervals don't get stretched.
+///
+/// Subscriptions are identified by a key, which allows several bot instances to share
+/// one scheduler.
+#[derive(Debug)]
+pub struct Scheduler<K> {
+    entries: HashMap<K, ScheduleEntry>,
+    last_check: Option<(Instant, DateTime<Utc>)>,
+}
+
+impl<K: Clone + Eq + Hash> Scheduler<K> {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            last_check: None,
+        }
+    }
+
+    pub fn add(&mut self, key: K, schedule: Schedule) {
+        self.entries.insert(
+            key,
+            ScheduleEntry {
+                schedule,
+                next_run: Some(Utc::now()),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+
+    /// Replaces the schedule of a subscription. The next poll is one new interval from now.
+    pub fn set_schedule(&mut self, key: &K, schedule: Schedule) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.next_run = schedule.next_after(Utc::now());
+            entry.schedule = schedule;
+        }
+    }
+
+    /// Reschedules a subscription that was polled outside of the scheduler
+    pub fn mark_polled(&mut self, key: &K) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.next_run = entry.schedule.next_after(Utc::now());
+        }
+    }
+
+    fn detect_clock_jump(&mut self) {
+        let mono_now = Instant::now();
+        let wall_now = Utc::now();
+        if let Some((last_mono, last_wall)) = self.last_check {
+            let mono_elapsed = chrono::Duration::from_std(mono_now - last_mono)
+                .unwrap_or_else(|_| chrono::Duration::zero());
+            let skew = (wall_now - last_wall) - mono_elapsed;
+            let tolerance = chrono::Duration::from_std(CLOCK_JUMP_TOLERANCE).unwrap();
+            if skew > tolerance {
+                println!(
+                    "Wall-clock advanced {}s more than expected (host suspended?). Catching up on overdue polls.",
+                    skew.num_seconds()
+                );
+            } else if skew < -tolerance {
+                println!(
+                    "Wall-clock jumped back by {}s. Shifting schedule.",
+                    -skew.num_seconds()
+                );
+                for entry in self.entries.values_mut() {
+                    if let (Schedule::Interval(_), Some(next_run)) =
+                        (&entry.schedule, entry.next_run)
+                    {
+                        entry.next_run = Some(next_run + skew);
+                    }
+                }
+            }
+        }
+        self.last_check = Some((mono_now, wall_now));
+    }
+
+    fn next_run(&self) -> Option<DateTime<Utc>> {
+        self.entries.values().filter_map(|x| x.next_run).min()
+    }
+
+    /// Sleeps until at least one subscription is due and returns the keys of all due ones.
+    /// They get rescheduled according to their schedule.
+    pub async fn wait_for_due(&mut self) -> Vec<K> {
+        loop {
+            self.detect_clock_jump();
+            let now = Utc::now();
+            let due: Vec<_> = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.next_run.map(|x| x <= now).unwrap_or(false))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if !due.is_empty() {
+                for key in &due {
+                    let entry = self.entries.get_mut(key).unwrap();
+                    entry.next_run = entry.schedule.next_after(now);
+                }
+                return due;
+            }
+
+            let wait = self
+                .next_run()
+                .and_then(|x| (x - now).to_std().ok())
+                // Nothing scheduled at all. Check back later, as subscriptions may get added.
+                .unwrap_or(MAX_SLEEP);
+            sleep(wait.min(MAX_SLEEP)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(
+            parse_duration("4h").unwrap(),
+            Duration::from_secs(4 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("2d").unwrap(),
+            Duration::from_secs(2 * 24 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("15").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("-5m").is_err());
+    }
+
+    #[test]
+    fn rejects_overlong_durations() {
+        let error = parse_duration(&format!("{}d", u64::MAX)).unwrap_err();
+        assert!(error.to_string().contains("too long"));
+    }
+}
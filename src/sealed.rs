@@ -0,0 +1,65 @@
+//! Passphrase-based encryption of small values, for the session of `storage = "plain"`.
+//! The key is derived from the db_pw with Argon2id, with a fresh salt for every value, and
+//! encrypts with XChaCha20-Poly1305, whose random nonces can't collide in practice.
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// An encrypted value, everything base64
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sealed {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive the key: {e}"))?;
+    Ok(key)
+}
+
+impl Sealed {
+    /// Encrypts `value` as JSON
+    pub fn seal<T: Serialize + ?Sized>(passphrase: &str, value: &T) -> anyhow::Result<Self> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 24];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                serde_json::to_vec(value)?.as_slice(),
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+        Ok(Self {
+            salt: STANDARD.encode(salt),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        })
+    }
+
+    pub fn open<T: DeserializeOwned>(&self, passphrase: &str) -> anyhow::Result<T> {
+        let salt = STANDARD.decode(&self.salt)?;
+        let nonce = STANDARD.decode(&self.nonce)?;
+        if nonce.len() != 24 {
+            anyhow::bail!("The nonce has the wrong length");
+        }
+        let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+        let plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                STANDARD.decode(&self.ciphertext)?.as_slice(),
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt, the db_pw is probably wrong"))?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
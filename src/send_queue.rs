@@ -0,0 +1,154 @@
+//! All outgoing events go through a queue per account and room, which sends them one
+//! after the other, so they keep their order without one room holding up the others.
+//! Sends rejected with M_LIMIT_EXCEEDED wait as long as the homeserver asks for, other
+//! transient failures (e.g. the connection dropped) get retried with exponential backoff.
+//! All attempts use the same transaction ID, so the homeserver drops the duplicate if an
+//! attempt went through after all. Errors the homeserver means (e.g. M_FORBIDDEN) are
+//! returned right away.
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::client::error::ErrorKind, events::MessageLikeEventContent, OwnedEventId, OwnedRoomId,
+        OwnedUserId, TransactionId,
+    },
+};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{sleep, Duration},
+};
+
+const MAX_ATTEMPTS: usize = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+struct Job {
+    room: Room,
+    event_type: String,
+    content: serde_json::Value,
+    reply: oneshot::Sender<anyhow::Result<OwnedEventId>>,
+}
+
+type Queues = HashMap<(OwnedUserId, OwnedRoomId), mpsc::UnboundedSender<Job>>;
+
+/// The queue of `room`, started on first use
+fn queue(room: &Room) -> mpsc::UnboundedSender<Job> {
+    static QUEUES: OnceLock<Mutex<Queues>> = OnceLock::new();
+    let key = (room.own_user_id().to_owned(), room.room_id().to_owned());
+    QUEUES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| {
+            let (jobs, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(run(receiver));
+            jobs
+        })
+        .clone()
+}
+
+async fn run(mut jobs: mpsc::UnboundedReceiver<Job>) {
+    while let Some(job) = jobs.recv().await {
+        let result = send_with_retry(&job.room, &job.event_type, &job.content).await;
+        // The sender might have given up waiting, which is fine
+        let _ = job.reply.send(result);
+    }
+}
+
+async fn send_with_retry(
+    room: &Room,
+    event_type: &str,
+    content: &serde_json::Value,
+) -> anyhow::Result<OwnedEventId> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    let txn_id = TransactionId::new();
+    loop {
+        let error = match room
+            .send_raw(event_type, content.clone())
+            .with_transaction_id(&txn_id)
+            .await
+        {
+            Ok(response) => return Ok(response.event_id),
+            Err(e) => e,
+        };
+        let wait = match error.client_api_error_kind() {
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => retry_after_ms.unwrap_or(backoff),
+            Some(_) => return Err(error.into()),
+            None => backoff,
+        };
+        if attempt >= MAX_ATTEMPTS {
+            return Err(error.into());
+        }
+        eprintln!(
+            "Sending to {} failed (attempt {attempt}/{MAX_ATTEMPTS}), retrying in {}s: {error}",
+            room.room_id(),
+            wait.as_secs()
+        );
+        sleep(wait).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        attempt += 1;
+    }
+}
+
+/// Why sending failed, as far as retrying later is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The homeserver is unreachable or overloaded, it might take the event later
+    Unavailable,
+    /// The homeserver refused the event, e.g. because we may not post to the room.
+    /// Sending it again would fail the same way.
+    Rejected,
+}
+
+/// Tells failures worth retrying later from permanent ones
+pub fn classify(error: &anyhow::Error) -> Failure {
+    let Some(error) = error
+        .chain()
+        .find_map(|x| x.downcast_ref::<matrix_sdk::Error>())
+    else {
+        return Failure::Rejected;
+    };
+    match error.as_client_api_error() {
+        // 429 is M_LIMIT_EXCEEDED, which outlasted our retries
+        Some(e) if e.status_code.is_server_error() || e.status_code.as_u16() == 429 => {
+            Failure::Unavailable
+        }
+        Some(_) => Failure::Rejected,
+        // No response at all, e.g. the connection failed
+        None if matches!(error, matrix_sdk::Error::Http(_)) => Failure::Unavailable,
+        None => Failure::Rejected,
+    }
+}
+
+/// Queues an event given as JSON and waits until it was sent
+pub async fn send_raw(
+    room: &Room,
+    event_type: &str,
+    content: serde_json::Value,
+) -> anyhow::Result<OwnedEventId> {
+    let (reply, response) = oneshot::channel();
+    let job = Job {
+        room: room.clone(),
+        event_type: event_type.to_string(),
+        content,
+        reply,
+    };
+    queue(room)
+        .send(job)
+        .map_err(|_| anyhow::anyhow!("The send queue has stopped"))?;
+    response.await?
+}
+
+/// Like `send_raw`, for typed event content
+pub async fn send(
+    room: &Room,
+    content: impl MessageLikeEventContent,
+) -> anyhow::Result<OwnedEventId> {
+    let event_type = content.event_type().to_string();
+    send_raw(room, &event_type, serde_json::to_value(content)?).await
+}
@@ -0,0 +1,483 @@
+//! Where the session (access token, device ID and sync token) is kept between runs, next
+//! to the session DB holding the state- and crypto-store. Each backend implements
+//! `SessionStore`, so `matrix` doesn't need to know which one is configured.
+use super::{sealed::Sealed, state_db::StateDb, watch_list::StoredRooms};
+use async_trait::async_trait;
+use matrix_sdk::{
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::{OwnedDeviceId, OwnedUserId},
+    Client, SessionMeta,
+};
+use secret_service::{EncryptionType, SecretService};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+const SESSION_DOCUMENT: &str = "session";
+
+macro_rules! store_to_secret_service {
+    ($collection:expr, $attribute:expr, $name:expr, $data:expr) => {
+        $collection
+            .create_item(
+                $attribute,
+                HashMap::from([($attribute, $name)]),
+                $data,
+                true, // replace item with same attributes
+                "text/plain",
+            )
+            .await?;
+    };
+}
+
+macro_rules! get_from_secret_service {
+    ($collection:expr, $attribute:expr, $name:expr) => {
+        String::from_utf8(
+            $collection
+                .search_items(HashMap::from([($attribute, $name)]))
+                .await?
+                .get(0)
+                .ok_or(secret_service::Error::NoResult)?
+                .get_secret()
+                .await?,
+        )?
+    };
+}
+
+macro_rules! get_optional_from_secret_service {
+    ($collection:expr, $attribute:expr, $name:expr) => {
+        if let Ok(tokens) = $collection
+            .search_items(HashMap::from([($attribute, $name)]))
+            .await
+        {
+            // Can't use .map() here, because of async-weirdness
+            if let Some(t) = tokens.get(0) {
+                t.get_secret()
+                    .await
+                    .map(|x| String::from_utf8(x).ok())
+                    .ok()
+                    .flatten()
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionDB {
+    pub db_path: PathBuf,
+    pub db_pw: String,
+    /// The bot's own data, in the db_path
+    pub state: StateDb,
+}
+
+#[async_trait]
+pub trait SessionStore: std::fmt::Debug + Send + Sync {
+    /// None, if nothing is persisted
+    fn get_session_db(&self) -> Option<SessionDB>;
+
+    /// Whether there (probably) is a session to restore, without trying to
+    fn session_store_exists(&self) -> bool {
+        self.get_session_db()
+            .map(|db| db.db_path.exists())
+            .unwrap_or(false)
+    }
+
+    /// Restores the session into `client`. Returns the sync token stored with it.
+    async fn restore_session(&self, client: &Client) -> anyhow::Result<Option<String>>;
+
+    async fn store_session(&self, client: &Client, sync_token: &str) -> anyhow::Result<()>;
+
+    /// Called every few minutes while syncing. Backends that keep the sync token apart
+    /// from the rest of the session only need to replace that.
+    async fn store_sync_token(&self, client: &Client, sync_token: &str) -> anyhow::Result<()> {
+        self.store_session(client, sync_token).await
+    }
+
+    /// Stores an additional secret (e.g. exported keys) next to the session
+    async fn store_secret(&self, name: &str, secret: &str) -> anyhow::Result<()>;
+
+    /// Restores a secret previously saved with `store_secret`
+    async fn restore_secret(&self, name: &str) -> anyhow::Result<Option<String>>;
+
+    /// Persists the watch list of `config.watch_list_storage = "file"`, in the state DB
+    async fn store_rooms(&self, rooms: &StoredRooms) -> anyhow::Result<()> {
+        if let Some(db) = self.get_session_db() {
+            db.state
+                .set_watched_rooms(&rooms.clone().into_map().into_iter().collect())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The watch list stored with `store_rooms`
+    async fn restore_rooms(&self) -> anyhow::Result<Option<StoredRooms>> {
+        let Some(db) = self.get_session_db() else {
+            return Ok(None);
+        };
+        Ok(Some(StoredRooms::Current(db.state.watched_rooms().await?)))
+    }
+}
+
+fn current_session(client: &Client) -> MatrixSession {
+    client
+        .matrix_auth()
+        .session()
+        .expect("A logged-in client should have a session")
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PlainMatrixSession {
+    user_session: MatrixSession,
+    sync_token: Option<String>,
+}
+
+/// Nothing survives a restart
+#[derive(Debug, Clone)]
+pub struct Ephemeral;
+
+#[async_trait]
+impl SessionStore for Ephemeral {
+    fn get_session_db(&self) -> Option<SessionDB> {
+        None
+    }
+
+    async fn restore_session(&self, _client: &Client) -> anyhow::Result<Option<String>> {
+        anyhow::bail!("The session isn't persisted")
+    }
+
+    async fn store_session(&self, _client: &Client, _sync_token: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn store_secret(&self, _name: &str, _secret: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn restore_secret(&self, _name: &str) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// A document of `PlainSessionStorage`, which might still be unencrypted
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredDocument<T> {
+    Sealed(Sealed),
+    Unencrypted(T),
+}
+
+/// The session and secrets in the state DB, encrypted with the db_pw. Older versions wrote
+/// them into unencrypted files, which get imported once.
+#[derive(Debug, Clone)]
+pub struct PlainSessionStorage {
+    pub db: SessionDB,
+    /// Where older versions kept the session
+    pub session_path: PathBuf,
+}
+
+impl PlainSessionStorage {
+    fn secret_document(name: &str) -> String {
+        format!("secret.{name}")
+    }
+
+    /// Argon2 is slow on purpose, so sealing and opening run apart from the runtime
+    async fn store_sealed<T: Serialize + ?Sized>(
+        &self,
+        document: &str,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value)?;
+        let passphrase = self.db.db_pw.clone();
+        let sealed =
+            tokio::task::spawn_blocking(move || Sealed::seal(&passphrase, &value)).await??;
+        self.db.state.set_document(document, &sealed).await
+    }
+
+    /// Documents stored unencrypted get encrypted on the way
+    async fn restore_sealed<T: Serialize + DeserializeOwned>(
+        &self,
+        document: &str,
+    ) -> anyhow::Result<Option<T>> {
+        match self
+            .db
+            .state
+            .document::<StoredDocument<T>>(document)
+            .await?
+        {
+            None => Ok(None),
+            Some(StoredDocument::Sealed(sealed)) => {
+                let passphrase = self.db.db_pw.clone();
+                let value = tokio::task::spawn_blocking(move || {
+                    sealed.open::<serde_json::Value>(&passphrase)
+                })
+                .await??;
+                Ok(Some(serde_json::from_value(value)?))
+            }
+            Some(StoredDocument::Unencrypted(value)) => {
+                self.store_sealed(document, &value).await?;
+                // Don't leave the cleartext in the free pages of the DB
+                self.db.state.vacuum().await?;
+                Ok(Some(value))
+            }
+        }
+    }
+
+    /// Moves a file of an older version into the state DB, and deletes it
+    async fn import_legacy_file(&self, path: &Path, document: &str) -> anyhow::Result<bool> {
+        // Earlier versions of the import kept the file under this name
+        let mut imported = path.to_path_buf().into_os_string();
+        imported.push(".imported");
+        if fs::try_exists(&imported).await? {
+            fs::remove_file(&imported).await?;
+        }
+        if !path.exists() {
+            return Ok(false);
+        }
+        let content = fs::read_to_string(path).await?;
+        if document == SESSION_DOCUMENT {
+            let session: PlainMatrixSession = serde_json::from_str(&content)?;
+            self.store_sealed(document, &session).await?;
+        } else {
+            self.store_sealed(document, &content).await?;
+        }
+        fs::remove_file(path).await?;
+        println!("Imported {} into the state DB", path.display());
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl SessionStore for PlainSessionStorage {
+    fn get_session_db(&self) -> Option<SessionDB> {
+        Some(self.db.clone())
+    }
+
+    fn session_store_exists(&self) -> bool {
+        self.db.db_path.exists()
+            && (self.session_path.exists()
+                || matches!(self.db.state.has_document(SESSION_DOCUMENT), Ok(true)))
+    }
+
+    async fn restore_session(&self, client: &Client) -> anyhow::Result<Option<String>> {
+        self.import_legacy_file(&self.session_path, SESSION_DOCUMENT)
+            .await?;
+        let Some(session) = self
+            .restore_sealed::<PlainMatrixSession>(SESSION_DOCUMENT)
+            .await?
+        else {
+            anyhow::bail!("No session stored");
+        };
+
+        println!(
+            "Restoring session for {}…",
+            session.user_session.meta.user_id
+        );
+
+        // Restore the Matrix user session.
+        client.restore_session(session.user_session).await?;
+
+        Ok(session.sync_token)
+    }
+
+    async fn store_session(&self, client: &Client, sync_token: &str) -> anyhow::Result<()> {
+        let data = PlainMatrixSession {
+            user_session: current_session(client),
+            sync_token: Some(sync_token.to_string()),
+        };
+        self.store_sealed(SESSION_DOCUMENT, &data).await
+    }
+
+    async fn store_secret(&self, name: &str, secret: &str) -> anyhow::Result<()> {
+        self.store_sealed(&Self::secret_document(name), secret)
+            .await
+    }
+
+    async fn restore_secret(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let document = Self::secret_document(name);
+        self.import_legacy_file(&self.session_path.with_file_name(name), &document)
+            .await?;
+        self.restore_sealed(&document).await
+    }
+}
+
+/// One item per value in the default collection of the SecretService
+#[derive(Debug, Clone)]
+pub struct SecretServiceStorage {
+    pub db: SessionDB,
+    /// Attribute all our items are stored under, so several instances don't collide
+    pub attribute: String,
+}
+
+#[async_trait]
+impl SessionStore for SecretServiceStorage {
+    fn get_session_db(&self) -> Option<SessionDB> {
+        Some(self.db.clone())
+    }
+
+    async fn restore_session(&self, client: &Client) -> anyhow::Result<Option<String>> {
+        let attribute = self.attribute.as_str();
+        let ss = SecretService::connect(EncryptionType::Dh).await?;
+        let collection = ss.get_default_collection().await?;
+        let access_token = get_from_secret_service!(collection, attribute, "access_token");
+        let device_id = get_from_secret_service!(collection, attribute, "device_id");
+        let user_id = get_from_secret_service!(collection, attribute, "user_id");
+        let refresh_token =
+            get_optional_from_secret_service!(collection, attribute, "refresh_token");
+        let sync_token = get_optional_from_secret_service!(collection, attribute, "sync_token");
+
+        let user_session = MatrixSession {
+            meta: SessionMeta {
+                user_id: OwnedUserId::try_from(user_id)?,
+                device_id: OwnedDeviceId::try_from(device_id)?,
+            },
+            tokens: MatrixSessionTokens {
+                access_token,
+                refresh_token,
+            },
+        };
+        println!("Restoring session for {}…", user_session.meta.user_id);
+
+        // Restore the Matrix user session.
+        client.restore_session(user_session).await?;
+
+        Ok(sync_token)
+    }
+
+    async fn store_session(&self, client: &Client, sync_token: &str) -> anyhow::Result<()> {
+        let user_session = current_session(client);
+        let attribute = self.attribute.as_str();
+        let ss = SecretService::connect(EncryptionType::Dh).await?;
+        let collection = match ss.get_default_collection().await {
+            Ok(c) => c,
+            Err(secret_service::Error::NoResult) => {
+                ss.create_collection(attribute, "default").await?
+            }
+            Err(x) => {
+                return Err(x.into());
+            }
+        };
+
+        if let Some(refresh_token) = user_session.tokens.refresh_token {
+            store_to_secret_service!(
+                collection,
+                attribute,
+                "refresh_token",
+                refresh_token.as_bytes()
+            );
+        }
+        store_to_secret_service!(collection, attribute, "sync_token", sync_token.as_bytes());
+        store_to_secret_service!(
+            collection,
+            attribute,
+            "access_token",
+            user_session.tokens.access_token.as_bytes()
+        );
+        store_to_secret_service!(
+            collection,
+            attribute,
+            "user_id",
+            user_session.meta.user_id.as_bytes()
+        );
+        store_to_secret_service!(
+            collection,
+            attribute,
+            "device_id",
+            user_session.meta.device_id.as_bytes()
+        );
+        Ok(())
+    }
+
+    async fn store_sync_token(&self, _client: &Client, sync_token: &str) -> anyhow::Result<()> {
+        self.store_secret("sync_token", sync_token).await
+    }
+
+    async fn store_secret(&self, name: &str, secret: &str) -> anyhow::Result<()> {
+        let attribute = self.attribute.as_str();
+        let ss = SecretService::connect(EncryptionType::Dh).await?;
+        let collection = match ss.get_default_collection().await {
+            Ok(c) => c,
+            Err(secret_service::Error::NoResult) => {
+                ss.create_collection(attribute, "default").await?
+            }
+            Err(x) => {
+                return Err(x.into());
+            }
+        };
+        store_to_secret_service!(collection, attribute, name, secret.as_bytes());
+        Ok(())
+    }
+
+    async fn restore_secret(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let ss = SecretService::connect(EncryptionType::Dh).await?;
+        let collection = ss.get_default_collection().await?;
+        let items = collection
+            .search_items(HashMap::from([(self.attribute.as_str(), name)]))
+            .await?;
+        match items.get(0) {
+            Some(item) => Ok(Some(String::from_utf8(item.get_secret().await?)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The platform's credential store: the Keychain on macOS, the Credential Manager on
+/// Windows, the kernel keyutils or the SecretService on Linux
+#[derive(Debug, Clone)]
+pub struct KeyringStorage {
+    pub db: SessionDB,
+    /// Service name of our entries, so several instances don't collide
+    pub service: String,
+}
+
+#[async_trait]
+impl SessionStore for KeyringStorage {
+    fn get_session_db(&self) -> Option<SessionDB> {
+        Some(self.db.clone())
+    }
+
+    async fn restore_session(&self, client: &Client) -> anyhow::Result<Option<String>> {
+        let entry = keyring::Entry::new(&self.service, "session")?;
+        let session: PlainMatrixSession = serde_json::from_str(&entry.get_password()?)?;
+
+        println!(
+            "Restoring session for {}…",
+            session.user_session.meta.user_id
+        );
+
+        // Restore the Matrix user session.
+        client.restore_session(session.user_session).await?;
+
+        Ok(session.sync_token)
+    }
+
+    /// The whole session goes into a single entry, as the Windows Credential Manager has
+    /// no way to search entries like the SecretService
+    async fn store_session(&self, client: &Client, sync_token: &str) -> anyhow::Result<()> {
+        let data = PlainMatrixSession {
+            user_session: current_session(client),
+            sync_token: Some(sync_token.to_string()),
+        };
+        keyring::Entry::new(&self.service, "session")?
+            .set_password(&serde_json::to_string(&data)?)?;
+        Ok(())
+    }
+
+    async fn store_secret(&self, name: &str, secret: &str) -> anyhow::Result<()> {
+        keyring::Entry::new(&self.service, name)?.set_password(secret)?;
+        Ok(())
+    }
+
+    async fn restore_secret(&self, name: &str) -> anyhow::Result<Option<String>> {
+        match keyring::Entry::new(&self.service, name)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
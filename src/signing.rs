@@ -0,0 +1,65 @@
+use super::SessionStore;
+use matrix_sdk::{crypto::vodozemac::Ed25519SecretKey, ruma::canonical_json::to_canonical_value};
+use serde::Serialize;
+use serde_json::json;
+
+/// Name under which the signing key is kept in the session storage
+pub const SIGNING_KEY_SECRET: &str = "announcement_signing_key";
+
+/// Machine-readable part of an announcement, sent along in the event content
+#[derive(Debug, Clone, Serialize)]
+pub struct Announcement {
+    pub source: String,
+    pub url_part: String,
+    pub url: String,
+    pub entries: Vec<String>,
+    pub timestamp: i64,
+}
+
+/// Signs announcements with a bot-held Ed25519 key, so consumers can verify them
+pub struct AnnouncementSigner {
+    key: Ed25519SecretKey,
+}
+
+impl std::fmt::Debug for AnnouncementSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnnouncementSigner")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+impl AnnouncementSigner {
+    /// Loads the signing key from the session storage, or creates and stores a new one
+    pub async fn load_or_create(storage: &dyn SessionStore) -> anyhow::Result<Self> {
+        if let Some(stored) = storage.restore_secret(SIGNING_KEY_SECRET).await? {
+            let key = Ed25519SecretKey::from_base64(stored.trim())?;
+            return Ok(Self { key });
+        }
+        if storage.get_session_db().is_none() {
+            println!(
+                "Session is not persisted. The announcement signing key changes on every restart."
+            );
+        }
+        let key = Ed25519SecretKey::new();
+        storage
+            .store_secret(SIGNING_KEY_SECRET, &key.to_base64())
+            .await?;
+        Ok(Self { key })
+    }
+
+    pub fn public_key(&self) -> String {
+        self.key.public_key().to_base64()
+    }
+
+    /// Returns the detached signature over the canonical JSON of the announcement
+    pub fn sign(&self, announcement: &Announcement) -> anyhow::Result<serde_json::Value> {
+        let canonical = to_canonical_value(announcement)?.to_string();
+        let signature = self.key.sign(canonical.as_bytes());
+        Ok(json!({
+            "algorithm": "ed25519",
+            "public_key": self.public_key(),
+            "signature": signature.to_base64(),
+        }))
+    }
+}
@@ -0,0 +1,164 @@
+//! Spaces from `config.spaces`: the bot joins them and watches all rooms in them, including
+//! rooms added to the space later. Subspaces are followed as well.
+use super::{watch_list, SharedState};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::{
+        api::client::space::get_hierarchy,
+        events::space::child::{HierarchySpaceChildEvent, OriginalSyncSpaceChildEvent},
+        room::RoomType,
+        OwnedRoomId, OwnedServerName, RoomId, RoomOrAliasId,
+    },
+    Client, RoomState,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Joins a room and watches it, or only joins it, if it is a space itself
+async fn join_and_watch(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &RoomId,
+    via: &[OwnedServerName],
+    is_space: bool,
+) -> anyhow::Result<()> {
+    match client.get_room(room_id) {
+        Some(room) if room.state() == RoomState::Joined => {}
+        _ => {
+            client
+                .join_room_by_id_or_alias(<&RoomOrAliasId>::from(room_id), via)
+                .await?;
+        }
+    }
+    // Right after joining, the room's create event might not be synced yet, so this
+    // comes from the hierarchy
+    if is_space {
+        ctx.spaces.lock().unwrap().insert(room_id.to_owned());
+        return Ok(());
+    }
+    ctx.configured_rooms
+        .lock()
+        .unwrap()
+        .insert(room_id.to_owned());
+    let added = ctx
+        .rooms
+        .lock()
+        .unwrap()
+        .insert(room_id.to_owned(), Default::default())
+        .is_none();
+    if added {
+        println!("Watching {room_id} from a configured space");
+        watch_list::store(client, ctx).await?;
+    }
+    Ok(())
+}
+
+/// The rooms in a space and its subspaces
+struct Hierarchy {
+    /// With the servers to join them via
+    children: BTreeMap<OwnedRoomId, Vec<OwnedServerName>>,
+    /// Which of them (and the space itself) are spaces
+    spaces: BTreeSet<OwnedRoomId>,
+}
+
+impl Hierarchy {
+    async fn of(client: &Client, space_id: &RoomId) -> anyhow::Result<Self> {
+        let mut children = BTreeMap::new();
+        let mut spaces = BTreeSet::new();
+        let mut from = None;
+        loop {
+            let mut request = get_hierarchy::v1::Request::new(space_id.to_owned());
+            request.from = from;
+            let response = client.send(request, None).await?;
+            for chunk in response.rooms {
+                if chunk.room_type == Some(RoomType::Space) {
+                    spaces.insert(chunk.room_id);
+                }
+                for child in chunk.children_state {
+                    let child: HierarchySpaceChildEvent = child.deserialize()?;
+                    children.insert(child.state_key, child.content.via);
+                }
+            }
+            match response.next_batch {
+                Some(next_batch) => from = Some(next_batch),
+                None => break,
+            }
+        }
+        children.remove(space_id);
+        Ok(Self { children, spaces })
+    }
+
+    /// Joins all the rooms, and watches the ones that aren't spaces. Returns the problems.
+    async fn join(self, client: &Client, ctx: &SharedState) -> Vec<(OwnedRoomId, anyhow::Error)> {
+        let mut problems = Vec::new();
+        for (room_id, via) in self.children {
+            let is_space = self.spaces.contains(&room_id);
+            if let Err(e) = join_and_watch(client, ctx, &room_id, &via, is_space).await {
+                problems.push((room_id, e));
+            }
+        }
+        problems
+    }
+}
+
+/// Joins the configured spaces and all rooms in them. Returns the problems.
+pub async fn join_configured(client: &Client, ctx: &SharedState) -> Vec<String> {
+    let mut problems = Vec::new();
+    for space in &ctx.cfg.spaces {
+        let space_id = match client.join_room_by_id_or_alias(space, &[]).await {
+            Ok(joined) => joined.room_id().to_owned(),
+            Err(e) => {
+                problems.push(format!("Failed to join the space {space}: {e}"));
+                continue;
+            }
+        };
+        ctx.spaces.lock().unwrap().insert(space_id.clone());
+        let hierarchy = match Hierarchy::of(client, &space_id).await {
+            Ok(hierarchy) => hierarchy,
+            Err(e) => {
+                problems.push(format!("Failed to list the rooms of {space}: {e}"));
+                continue;
+            }
+        };
+        for (room_id, e) in hierarchy.join(client, ctx).await {
+            problems.push(format!("Failed to join {room_id} from {space}: {e}"));
+        }
+    }
+    problems
+}
+
+/// Picks up rooms added to one of our spaces
+pub async fn on_space_child(
+    event: OriginalSyncSpaceChildEvent,
+    client: Client,
+    room: Room,
+    ctx: Ctx<SharedState>,
+) {
+    // An empty via means the room got removed from the space, we keep watching it anyway
+    if event.content.via.is_empty() || !ctx.spaces.lock().unwrap().contains(room.room_id()) {
+        return;
+    }
+    let room_id = event.state_key;
+    // Tells whether it is a subspace, and lists its rooms if so
+    let hierarchy = match Hierarchy::of(&client, &room_id).await {
+        Ok(hierarchy) => hierarchy,
+        Err(e) => {
+            eprintln!("Failed to look into {room_id}: {e:?}");
+            return;
+        }
+    };
+    let is_space = hierarchy.spaces.contains(&room_id);
+    if let Err(e) = join_and_watch(&client, &ctx, &room_id, &event.content.via, is_space).await {
+        eprintln!(
+            "Failed to join {room_id}, which was added to {}: {e:?}",
+            room.room_id()
+        );
+        return;
+    }
+    // A new subspace, its rooms are ours as well
+    if is_space {
+        for (child, e) in hierarchy.join(&client, &ctx).await {
+            eprintln!("Failed to join {child} from {room_id}: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! The `state export` and `state import` subcommands: everything the bot persists besides
+//! the session itself, as one JSON file, for moving to another host or for backups that
+//! don't depend on the session DB or the SecretService. Import only while the bot is
+//! stopped, as the running bot would overwrite it with its own state.
+//!
+//! The sync token only belongs to the device it was exported from, importing it into
+//! another session would skip the events that device hasn't seen.
+use super::{
+    alerts::{self, RoomAlerts},
+    cli::StateAction,
+    ignore_list,
+    matrix::{restore_client_with_sync_token, store_session},
+    outbox::{self, PendingNotification},
+    personal::{self, UserSubscriptions},
+    pins::{self, Pin},
+    restore_queued,
+    retention::{self, SentNotifications},
+    store_queued,
+    subscriptions::{self, RuntimeSubscription, SourceOverrides},
+    watch_list::{self, WatchedRoom},
+    QueuedNotification, SharedState,
+};
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::fs;
+
+/// State of one bot account
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AccountState {
+    #[serde(default)]
+    pub watched_rooms: BTreeMap<OwnedRoomId, WatchedRoom>,
+    /// Mutes of rooms that get subscriptions without being watched
+    #[serde(default)]
+    pub routed_mutes: BTreeMap<OwnedRoomId, BTreeSet<String>>,
+    #[serde(default)]
+    pub subscriptions: Vec<RuntimeSubscription>,
+    #[serde(default)]
+    pub overrides: SourceOverrides,
+    #[serde(default)]
+    pub personal_subscriptions: BTreeMap<OwnedUserId, UserSubscriptions>,
+    #[serde(default)]
+    pub alerts: BTreeMap<OwnedRoomId, RoomAlerts>,
+    #[serde(default)]
+    pub outbox: Vec<PendingNotification>,
+    /// Held back by quiet hours or digests
+    #[serde(default)]
+    pub queued: BTreeMap<OwnedRoomId, Vec<QueuedNotification>>,
+    /// Per source, the entries it already announced
+    #[serde(default)]
+    pub seen_entries: BTreeMap<String, BTreeSet<String>>,
+    /// Ignored with !ignore
+    #[serde(default)]
+    pub ignored_users: BTreeSet<OwnedUserId>,
+    /// For the retention of the rooms
+    #[serde(default)]
+    pub sent: SentNotifications,
+    /// Pinned newest notifications
+    #[serde(default)]
+    pub pins: Vec<Pin>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_token: Option<String>,
+    /// The device the sync token belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<OwnedDeviceId>,
+}
+
+/// The exported file, with the state of each account of the config
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ExportedState {
+    pub accounts: BTreeMap<OwnedUserId, AccountState>,
+}
+
+async fn export(aio: &SharedState, state: &mut ExportedState) -> anyhow::Result<()> {
+    let (client, sync_token) = restore_client_with_sync_token(aio).await?;
+    let Some(user_id) = client.user_id().map(|x| x.to_owned()) else {
+        anyhow::bail!("The restored session has no user ID");
+    };
+    watch_list::restore_from_file(aio).await?;
+    watch_list::restore_from_account_data(&client, aio).await?;
+    watch_list::restore_routed_mutes(&client, aio).await?;
+    subscriptions::restore(&client, aio).await?;
+    personal::restore(&client, aio).await?;
+    alerts::restore(&client, aio).await?;
+    outbox::restore(&client, aio).await?;
+    restore_queued(aio).await?;
+    ignore_list::restore(&client, aio).await?;
+    retention::restore(&client, aio).await?;
+    pins::restore(aio).await?;
+    let seen_entries = match aio.cfg.session_storage.get_session_db() {
+        Some(db) => db.state.all_seen_entries().await?,
+        None => BTreeMap::new(),
+    };
+    let account = AccountState {
+        watched_rooms: aio
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, room)| (id.clone(), room.clone()))
+            .collect(),
+        routed_mutes: aio.routed_mutes.lock().unwrap().clone(),
+        subscriptions: aio
+            .runtime_subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect(),
+        overrides: aio.overrides.lock().unwrap().clone(),
+        personal_subscriptions: aio.personal.lock().unwrap().clone(),
+        alerts: aio.alerts.lock().unwrap().clone(),
+        outbox: aio.outbox.lock().unwrap().clone(),
+        queued: aio
+            .queued
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, queued)| (id.clone(), queued.clone()))
+            .collect(),
+        seen_entries,
+        ignored_users: aio.ignored.lock().unwrap().clone(),
+        sent: aio.sent.lock().unwrap().clone(),
+        pins: pins::stored(aio),
+        sync_token,
+        device_id: client.device_id().map(|x| x.to_owned()),
+    };
+    println!(
+        "Exported {user_id}: {} watched rooms, {} subscriptions",
+        account.watched_rooms.len(),
+        account.subscriptions.len()
+    );
+    state.accounts.insert(user_id, account);
+    Ok(())
+}
+
+async fn import(aio: &SharedState, state: &ExportedState) -> anyhow::Result<()> {
+    let (client, _) = restore_client_with_sync_token(aio).await?;
+    let Some(user_id) = client.user_id() else {
+        anyhow::bail!("The restored session has no user ID");
+    };
+    let Some(account) = state.accounts.get(user_id) else {
+        println!("Nothing to import for {user_id}");
+        return Ok(());
+    };
+    *aio.rooms.lock().unwrap() = account
+        .watched_rooms
+        .iter()
+        .map(|(id, room)| (id.clone(), room.clone()))
+        .collect();
+    *aio.routed_mutes.lock().unwrap() = account.routed_mutes.clone();
+    *aio.runtime_subscriptions.lock().unwrap() = account
+        .subscriptions
+        .iter()
+        .map(|x| (x.name.clone(), x.clone()))
+        .collect();
+    *aio.overrides.lock().unwrap() = account.overrides.clone();
+    *aio.personal.lock().unwrap() = account.personal_subscriptions.clone();
+    *aio.alerts.lock().unwrap() = account.alerts.clone();
+    *aio.outbox.lock().unwrap() = account.outbox.clone();
+    *aio.queued.lock().unwrap() = account
+        .queued
+        .iter()
+        .map(|(id, queued)| (id.clone(), queued.clone()))
+        .collect();
+    *aio.sent.lock().unwrap() = account.sent.clone();
+    pins::set(aio, &account.pins);
+    watch_list::store(&client, aio).await?;
+    watch_list::store_routed_mutes(&client, aio).await?;
+    subscriptions::store(&client, aio).await?;
+    personal::store(&client, aio).await?;
+    alerts::store(&client, aio).await?;
+    outbox::store(aio).await?;
+    store_queued(aio).await;
+    retention::store(aio).await?;
+    pins::store(aio).await?;
+    ignore_list::restore(&client, aio).await?;
+    for user in &account.ignored_users {
+        ignore_list::ignore(&client, aio, user).await?;
+    }
+    match aio.cfg.session_storage.get_session_db() {
+        Some(db) => {
+            for (source, entries) in &account.seen_entries {
+                db.state
+                    .set_seen_entries(source, &entries.iter().cloned().collect())
+                    .await?;
+            }
+        }
+        None if !account.seen_entries.is_empty() => {
+            println!("Not importing the seen entries of {user_id}, there is no session DB");
+        }
+        None => {}
+    }
+    if let Some(sync_token) = &account.sync_token {
+        if account.device_id.is_some() && account.device_id.as_deref() == client.device_id() {
+            store_session(&client, aio, sync_token).await?;
+        } else {
+            println!("Not importing the sync token of {user_id}, it belongs to another device");
+        }
+    }
+    println!(
+        "Imported {user_id}: {} watched rooms, {} subscriptions",
+        account.watched_rooms.len(),
+        account.subscriptions.len()
+    );
+    Ok(())
+}
+
+pub async fn run_subcommand(
+    instances: &[&SharedState],
+    action: &StateAction,
+) -> anyhow::Result<()> {
+    match action {
+        StateAction::Export { output } => {
+            let mut state = ExportedState::default();
+            for aio in instances {
+                export(aio, &mut state).await?;
+            }
+            fs::write(output, serde_json::to_string_pretty(&state)? + "\n").await?;
+        }
+        StateAction::Import { input } => {
+            let state: ExportedState = serde_json::from_str(&fs::read_to_string(input).await?)?;
+            for aio in instances {
+                import(aio, &state).await?;
+            }
+        }
+    }
+    Ok(())
+}
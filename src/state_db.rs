@@ -0,0 +1,731 @@
+//! The bot's own SQLite database, `bot_state.sqlite3` in the db_path, next to (but apart
+//! from) the stores of the Matrix SDK. It holds everything the bot persists locally: the
+//! watch list, the undelivered notifications, the sent notifications (for the retention),
+//! the seen-sets of the subscriptions, and the rest as JSON documents by name.
+//! One file is simple to back up, and can be looked into with the sqlite3 shell.
+//!
+//! The schema is versioned with `PRAGMA user_version`, each entry of `MIGRATIONS` brings
+//! it one version further. The first one imports the JSON files older versions wrote into
+//! the db_path, which get renamed to `<name>.imported` once that is committed.
+//!
+//! Before writing, at most once an hour, a copy of the DB goes to `backups/` in the
+//! db_path. The newest `login.backups` copies are kept, `restore-backup` puts one back.
+//!
+//! The connection is opened on first use and kept. Queries run on the blocking threads
+//! of tokio, so a slow disk doesn't hold up the runtime.
+use super::{
+    outbox::PendingNotification,
+    private_files,
+    retention::SentNotifications,
+    watch_list::{StoredRooms, WatchedRoom},
+    SharedState,
+};
+use chrono::Utc;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId, RoomId};
+use rusqlite::{
+    backup::Progress, params, Connection, DatabaseName, OptionalExtension, Transaction,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const STATE_DB_FILE: &str = "bot_state.sqlite3";
+const BACKUP_DIR: &str = "backups";
+const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Files of older versions, imported as documents of the same name
+const LEGACY_DOCUMENTS: &[&str] = &[
+    "subscriptions",
+    "personal_subscriptions",
+    "alerts",
+    "room_aliases",
+];
+
+/// Returns the files it imported, to be renamed once the migration is committed
+type Migration = fn(&Transaction, &Path) -> anyhow::Result<Vec<PathBuf>>;
+
+const MIGRATIONS: &[Migration] = &[create_tables, create_seen_entries, create_sent];
+
+fn create_tables(tx: &Transaction, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    tx.execute_batch(
+        "CREATE TABLE documents (
+            name TEXT PRIMARY KEY,
+            content TEXT NOT NULL,
+            updated_at INTEGER NOT NULL
+        );
+        CREATE TABLE watched_rooms (
+            room_id TEXT PRIMARY KEY,
+            muted TEXT NOT NULL
+        );
+        CREATE TABLE outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            room_id TEXT NOT NULL,
+            source TEXT,
+            queued_at INTEGER NOT NULL,
+            notification TEXT NOT NULL
+        );",
+    )?;
+    let mut imported = Vec::new();
+    for name in LEGACY_DOCUMENTS {
+        let path = dir.join(name);
+        if path.exists() {
+            set_document_in(tx, name, &std::fs::read_to_string(&path)?)?;
+            imported.push(path);
+        }
+    }
+    let path = dir.join("watched_rooms");
+    if path.exists() {
+        match serde_json::from_str::<StoredRooms>(&std::fs::read_to_string(&path)?) {
+            Ok(rooms) => set_watched_rooms_in(tx, &rooms.into_map().into_iter().collect())?,
+            Err(e) => eprintln!("Not importing the unreadable {}: {e}", path.display()),
+        }
+        imported.push(path);
+    }
+    Ok(imported)
+}
+
+fn create_seen_entries(tx: &Transaction, _dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    tx.execute_batch(
+        "CREATE TABLE seen_entries (
+            source TEXT NOT NULL,
+            entry TEXT NOT NULL,
+            PRIMARY KEY (source, entry)
+        );",
+    )?;
+    Ok(Vec::new())
+}
+
+fn create_sent(tx: &Transaction, _dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    tx.execute_batch(
+        "CREATE TABLE sent (
+            room_id TEXT NOT NULL,
+            event_id TEXT NOT NULL,
+            sent_at INTEGER NOT NULL
+        );",
+    )?;
+    Ok(Vec::new())
+}
+
+fn set_document_in(tx: &Transaction, name: &str, content: &str) -> anyhow::Result<()> {
+    tx.execute(
+        "INSERT INTO documents (name, content, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (name) DO UPDATE SET content = ?2, updated_at = ?3",
+        params![name, content, Utc::now().timestamp()],
+    )?;
+    Ok(())
+}
+
+fn set_watched_rooms_in(
+    tx: &Transaction,
+    rooms: &BTreeMap<OwnedRoomId, WatchedRoom>,
+) -> anyhow::Result<()> {
+    tx.execute("DELETE FROM watched_rooms", [])?;
+    for (room_id, room) in rooms {
+        tx.execute(
+            "INSERT INTO watched_rooms (room_id, muted) VALUES (?1, ?2)",
+            params![room_id.as_str(), serde_json::to_string(&room.muted)?],
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct StateDb {
+    dir: PathBuf,
+    /// How many backups to keep, 0 for none at all
+    keep_backups: usize,
+    last_backup: Arc<Mutex<Option<Instant>>>,
+    /// Opened on first use
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+impl StateDb {
+    pub fn new(dir: &Path, keep_backups: usize) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            keep_backups,
+            last_backup: Arc::new(Mutex::new(None)),
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Runs `f` on the connection, opening it if needed. Blocks.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+        f(connection.as_mut().expect("Opened above"))
+    }
+
+    /// Runs a query on a blocking thread
+    async fn read<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || db.with_connection(|x| f(x))).await?
+    }
+
+    /// Runs `f` in a transaction on a blocking thread, after a backup if one is due
+    async fn write(
+        &self,
+        f: impl FnOnce(&Transaction) -> anyhow::Result<()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            db.with_connection(|connection| {
+                db.backup_if_due(connection)?;
+                let tx = connection.transaction()?;
+                f(&tx)?;
+                tx.commit()?;
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Oldest first
+    pub fn backups(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let dir = self.dir.join(BACKUP_DIR);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut backups = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|x| x == "sqlite3") {
+                backups.push(path);
+            }
+        }
+        // The names start with the time, so they sort by it
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// Copies the DB into the backups and drops the ones exceeding `keep_backups`
+    fn write_backup(&self, connection: &Connection) -> anyhow::Result<()> {
+        let dir = self.dir.join(BACKUP_DIR);
+        private_files::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "{}-bot_state.sqlite3",
+            Utc::now().format("%Y%m%dT%H%M%S")
+        ));
+        if path.exists() {
+            return Ok(());
+        }
+        connection.execute("VACUUM INTO ?1", [path.to_string_lossy()])?;
+        let backups = self.backups()?;
+        let excess = backups.len().saturating_sub(self.keep_backups.max(1));
+        for old in &backups[..excess] {
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+
+    fn backup_if_due(&self, connection: &Connection) -> anyhow::Result<()> {
+        if self.keep_backups == 0 {
+            return Ok(());
+        }
+        {
+            let mut last_backup = self.last_backup.lock().unwrap();
+            if last_backup.is_some_and(|x| x.elapsed() < BACKUP_INTERVAL) {
+                return Ok(());
+            }
+            *last_backup = Some(Instant::now());
+        }
+        self.write_backup(connection)
+    }
+
+    /// Replaces the DB with a backup, after backing up the current one
+    pub fn restore_backup(&self, backup: &Path) -> anyhow::Result<()> {
+        // Check that it is a state DB at all, before replacing ours
+        Connection::open(backup)?.query_row("SELECT count(*) FROM documents", [], |row| {
+            row.get::<_, i64>(0)
+        })?;
+        self.with_connection(|connection| {
+            self.write_backup(connection)?;
+            // Through SQLite rather than copying the file, which would leave its journal
+            // behind to be applied to the restored content
+            connection.restore(DatabaseName::Main, backup, None::<fn(Progress)>)?;
+            Ok(())
+        })?;
+        // Reopened on next use, which migrates a backup of an older version
+        *self.connection.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn connect(&self) -> anyhow::Result<Connection> {
+        // Next to the sealed session and secrets, the SDK keeps its stores in there
+        private_files::create_dir_all(&self.dir)?;
+        let path = self.dir.join(STATE_DB_FILE);
+        let mut connection = Connection::open(&path)?;
+        // SQLite gives its journal the same permissions
+        private_files::restrict(&path)?;
+        // Deleted content gets overwritten, e.g. secrets replaced by sealed ones
+        connection.pragma_update(None, "secure_delete", true)?;
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = connection.transaction()?;
+            let imported = migration(&tx, &self.dir)?;
+            tx.pragma_update(None, "user_version", idx + 1)?;
+            tx.commit()?;
+            for path in imported {
+                println!("Imported {} into the state DB", path.display());
+                let mut renamed = path.clone().into_os_string();
+                renamed.push(".imported");
+                std::fs::rename(&path, renamed)?;
+            }
+        }
+        Ok(connection)
+    }
+
+    /// Whether there is a document of that name. Blocks.
+    pub fn has_document(&self, name: &str) -> anyhow::Result<bool> {
+        self.with_connection(|connection| {
+            Ok(connection
+                .query_row(
+                    "SELECT 1 FROM documents WHERE name = ?1",
+                    [name],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some())
+        })
+    }
+
+    pub async fn document<T: DeserializeOwned>(&self, name: &str) -> anyhow::Result<Option<T>> {
+        let name = name.to_string();
+        let content: Option<String> = self
+            .read(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT content FROM documents WHERE name = ?1",
+                        [name],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        Ok(content.map(|x| serde_json::from_str(&x)).transpose()?)
+    }
+
+    pub async fn set_document<T: Serialize + ?Sized>(
+        &self,
+        name: &str,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let name = name.to_string();
+        let content = serde_json::to_string(value)?;
+        self.write(move |tx| set_document_in(tx, &name, &content))
+            .await
+    }
+
+    /// Rebuilds the DB file, so nothing deleted lingers in its free pages
+    pub async fn vacuum(&self) -> anyhow::Result<()> {
+        self.read(|connection| Ok(connection.execute_batch("VACUUM")?))
+            .await
+    }
+
+    pub async fn watched_rooms(&self) -> anyhow::Result<BTreeMap<OwnedRoomId, WatchedRoom>> {
+        let rows = self
+            .read(|connection| {
+                let mut statement =
+                    connection.prepare("SELECT room_id, muted FROM watched_rooms")?;
+                let rows = statement.query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?;
+                Ok(rows.collect::<Result<Vec<_>, _>>()?)
+            })
+            .await?;
+        let mut rooms = BTreeMap::new();
+        for (room_id, muted) in rows {
+            rooms.insert(
+                OwnedRoomId::try_from(room_id)?,
+                WatchedRoom {
+                    muted: serde_json::from_str(&muted)?,
+                },
+            );
+        }
+        Ok(rooms)
+    }
+
+    pub async fn set_watched_rooms(
+        &self,
+        rooms: &BTreeMap<OwnedRoomId, WatchedRoom>,
+    ) -> anyhow::Result<()> {
+        let rooms = rooms.clone();
+        self.write(move |tx| set_watched_rooms_in(tx, &rooms)).await
+    }
+
+    /// In the order they were queued
+    pub async fn outbox(&self) -> anyhow::Result<Vec<PendingNotification>> {
+        let rows = self
+            .read(|connection| {
+                let mut statement =
+                    connection.prepare("SELECT notification FROM outbox ORDER BY id")?;
+                let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+                Ok(rows.collect::<Result<Vec<_>, _>>()?)
+            })
+            .await?;
+        let mut pending = Vec::new();
+        for row in rows {
+            pending.push(serde_json::from_str(&row)?);
+        }
+        Ok(pending)
+    }
+
+    pub async fn set_outbox(&self, pending: &[PendingNotification]) -> anyhow::Result<()> {
+        let rows = pending
+            .iter()
+            .map(|x| {
+                Ok((
+                    x.room_id.to_string(),
+                    x.source.clone(),
+                    x.queued_at,
+                    serde_json::to_string(x)?,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.write(move |tx| {
+            tx.execute("DELETE FROM outbox", [])?;
+            for (room_id, source, queued_at, notification) in rows {
+                tx.execute(
+                    "INSERT INTO outbox (room_id, source, queued_at, notification)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![room_id, source, queued_at, notification],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn seen_entries(&self, source: &str) -> anyhow::Result<HashSet<String>> {
+        let source = source.to_string();
+        self.read(move |connection| {
+            let mut statement =
+                connection.prepare("SELECT entry FROM seen_entries WHERE source = ?1")?;
+            let rows = statement.query_map([source], |row| row.get::<_, String>(0))?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    /// The seen-sets of all sources
+    pub async fn all_seen_entries(&self) -> anyhow::Result<BTreeMap<String, BTreeSet<String>>> {
+        let rows = self
+            .read(|connection| {
+                let mut statement = connection.prepare("SELECT source, entry FROM seen_entries")?;
+                let rows = statement.query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?;
+                Ok(rows.collect::<Result<Vec<_>, _>>()?)
+            })
+            .await?;
+        let mut seen = BTreeMap::<_, BTreeSet<_>>::new();
+        for (source, entry) in rows {
+            seen.entry(source).or_default().insert(entry);
+        }
+        Ok(seen)
+    }
+
+    /// Replaces the seen-set of `source`, an empty one deletes it
+    pub async fn set_seen_entries(
+        &self,
+        source: &str,
+        entries: &HashSet<String>,
+    ) -> anyhow::Result<()> {
+        let source = source.to_string();
+        let entries = entries.clone();
+        self.write(move |tx| {
+            tx.execute("DELETE FROM seen_entries WHERE source = ?1", [&source])?;
+            for entry in entries {
+                tx.execute(
+                    "INSERT INTO seen_entries (source, entry) VALUES (?1, ?2)",
+                    params![source, entry],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn sent(&self) -> anyhow::Result<SentNotifications> {
+        let rows = self
+            .read(|connection| {
+                let mut statement = connection
+                    .prepare("SELECT room_id, event_id, sent_at FROM sent ORDER BY rowid")?;
+                let rows = statement.query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                })?;
+                Ok(rows.collect::<Result<Vec<_>, _>>()?)
+            })
+            .await?;
+        let mut sent = SentNotifications::new();
+        for (room_id, event_id, sent_at) in rows {
+            sent.entry(OwnedRoomId::try_from(room_id)?)
+                .or_default()
+                .push((OwnedEventId::try_from(event_id)?, sent_at));
+        }
+        Ok(sent)
+    }
+
+    /// Adds to the sent notifications of a room, keeping only the newest `max` of it
+    pub async fn add_sent(
+        &self,
+        room_id: &RoomId,
+        sent: &[(OwnedEventId, i64)],
+        max: usize,
+    ) -> anyhow::Result<()> {
+        let room_id = room_id.to_string();
+        let sent = sent.to_vec();
+        self.write(move |tx| {
+            for (event_id, sent_at) in &sent {
+                tx.execute(
+                    "INSERT INTO sent (room_id, event_id, sent_at) VALUES (?1, ?2, ?3)",
+                    params![room_id, event_id.as_str(), sent_at],
+                )?;
+            }
+            tx.execute(
+                "DELETE FROM sent WHERE room_id = ?1 AND rowid NOT IN
+                 (SELECT rowid FROM sent WHERE room_id = ?1 ORDER BY rowid DESC LIMIT ?2)",
+                params![room_id, max],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove_sent(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> anyhow::Result<()> {
+        let room_id = room_id.to_string();
+        let event_ids = event_ids.to_vec();
+        self.write(move |tx| {
+            for event_id in &event_ids {
+                tx.execute(
+                    "DELETE FROM sent WHERE room_id = ?1 AND event_id = ?2",
+                    params![room_id, event_id.as_str()],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_sent(&self, sent: &SentNotifications) -> anyhow::Result<()> {
+        let sent = sent.clone();
+        self.write(move |tx| {
+            tx.execute("DELETE FROM sent", [])?;
+            for (room_id, notifications) in &sent {
+                for (event_id, sent_at) in notifications {
+                    tx.execute(
+                        "INSERT INTO sent (room_id, event_id, sent_at) VALUES (?1, ?2, ?3)",
+                        params![room_id.as_str(), event_id.as_str(), sent_at],
+                    )?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// The `restore-backup` subcommand: lists the backups of each instance, or restores the one
+/// named `backup` wherever it exists
+pub fn run_subcommand(instances: &[&SharedState], backup: Option<&str>) -> anyhow::Result<()> {
+    let mut restored = false;
+    for aio in instances {
+        let Some(db) = aio.cfg.session_storage.get_session_db() else {
+            continue;
+        };
+        let backups = db.state.backups()?;
+        match backup {
+            None => {
+                println!("{}:", db.db_path.display());
+                for path in &backups {
+                    if let Some(name) = path.file_name() {
+                        println!("  {}", name.to_string_lossy());
+                    }
+                }
+            }
+            Some(backup) => {
+                if let Some(path) = backups
+                    .iter()
+                    .find(|x| x.file_name().is_some_and(|x| x == backup))
+                {
+                    db.state.restore_backup(path)?;
+                    println!("Restored {}", path.display());
+                    restored = true;
+                }
+            }
+        }
+    }
+    if let (Some(backup), false) = (backup, restored) {
+        anyhow::bail!("There is no backup {backup}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh directory, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "state_db_test-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn user_version(db: &StateDb) -> usize {
+        db.with_connection(|x| Ok(x.query_row("PRAGMA user_version", [], |row| row.get(0))?))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrates_a_new_db() {
+        let dir = TempDir::new();
+        let db = StateDb::new(&dir.0, 0);
+        assert_eq!(user_version(&db), MIGRATIONS.len());
+        assert!(db.outbox().await.unwrap().is_empty());
+        assert!(db.seen_entries("nightly").await.unwrap().is_empty());
+        assert!(db.sent().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn migrates_an_older_db() {
+        let dir = TempDir::new();
+        {
+            let mut connection = Connection::open(dir.0.join(STATE_DB_FILE)).unwrap();
+            let tx = connection.transaction().unwrap();
+            create_tables(&tx, &dir.0).unwrap();
+            set_document_in(&tx, "alerts", "[\"kept\"]").unwrap();
+            tx.pragma_update(None, "user_version", 1).unwrap();
+            tx.commit().unwrap();
+        }
+        let db = StateDb::new(&dir.0, 0);
+        assert_eq!(user_version(&db), MIGRATIONS.len());
+        let alerts: Option<Vec<String>> = db.document("alerts").await.unwrap();
+        assert_eq!(alerts, Some(vec![String::from("kept")]));
+        let entries = HashSet::from([String::from("a.zip")]);
+        db.set_seen_entries("nightly", &entries).await.unwrap();
+        assert_eq!(db.seen_entries("nightly").await.unwrap(), entries);
+    }
+
+    #[tokio::test]
+    async fn imports_legacy_files() {
+        let dir = TempDir::new();
+        std::fs::write(dir.0.join("alerts"), "[\"imported\"]").unwrap();
+        let db = StateDb::new(&dir.0, 0);
+        let alerts: Option<Vec<String>> = db.document("alerts").await.unwrap();
+        assert_eq!(alerts, Some(vec![String::from("imported")]));
+        assert!(!dir.0.join("alerts").exists());
+        assert!(dir.0.join("alerts.imported").exists());
+        // Only once, a later start doesn't import it again
+        drop(db);
+        std::fs::write(dir.0.join("alerts"), "[\"again\"]").unwrap();
+        let db = StateDb::new(&dir.0, 0);
+        let alerts: Option<Vec<String>> = db.document("alerts").await.unwrap();
+        assert_eq!(alerts, Some(vec![String::from("imported")]));
+    }
+
+    #[tokio::test]
+    async fn replaces_seen_entries() {
+        let dir = TempDir::new();
+        let db = StateDb::new(&dir.0, 0);
+        let entries = HashSet::from([String::from("a.zip"), String::from("b.zip")]);
+        db.set_seen_entries("nightly", &entries).await.unwrap();
+        db.set_seen_entries("beta", &HashSet::from([String::from("c.zip")]))
+            .await
+            .unwrap();
+        assert_eq!(db.seen_entries("nightly").await.unwrap(), entries);
+        db.set_seen_entries("nightly", &HashSet::new())
+            .await
+            .unwrap();
+        let all = db.all_seen_entries().await.unwrap();
+        assert_eq!(all.keys().collect::<Vec<_>>(), ["beta"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_newest_backups() {
+        let dir = TempDir::new();
+        let db = StateDb::new(&dir.0, 2);
+        let backup_dir = dir.0.join(BACKUP_DIR);
+        std::fs::create_dir_all(&backup_dir).unwrap();
+        for name in ["20000101T000000", "20000102T000000", "20000103T000000"] {
+            std::fs::write(backup_dir.join(format!("{name}-bot_state.sqlite3")), "").unwrap();
+        }
+        db.set_document("alerts", &["first"]).await.unwrap();
+        let backups = db.backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(backups[0].ends_with("20000103T000000-bot_state.sqlite3"));
+        // Not again within the backup interval
+        db.set_document("alerts", &["second"]).await.unwrap();
+        assert_eq!(db.backups().unwrap(), backups);
+    }
+
+    #[tokio::test]
+    async fn no_backups_if_disabled() {
+        let dir = TempDir::new();
+        let db = StateDb::new(&dir.0, 0);
+        db.set_document("alerts", &["first"]).await.unwrap();
+        assert!(db.backups().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn restores_backups() {
+        let dir = TempDir::new();
+        // No backups on writes, which could take the name of the one below
+        let db = StateDb::new(&dir.0, 0);
+        db.set_document("alerts", &["before"]).await.unwrap();
+        db.with_connection(|x| db.write_backup(x)).unwrap();
+        let backup = db.backups().unwrap().pop().unwrap();
+        db.set_document("alerts", &["after"]).await.unwrap();
+        db.restore_backup(&backup).unwrap();
+        let alerts: Option<Vec<String>> = db.document("alerts").await.unwrap();
+        assert_eq!(alerts, Some(vec![String::from("before")]));
+    }
+
+    #[test]
+    fn refuses_to_restore_other_files() {
+        let dir = TempDir::new();
+        let db = StateDb::new(&dir.0, 5);
+        let other = dir.0.join("other.sqlite3");
+        Connection::open(&other)
+            .unwrap()
+            .execute_batch("CREATE TABLE unrelated (x TEXT);")
+            .unwrap();
+        assert!(db.restore_backup(&other).is_err());
+    }
+}
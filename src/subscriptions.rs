@@ -0,0 +1,275 @@
+//! Subscriptions added at runtime (via !subscribe or the bot API), as opposed to the ones
+//! in the config file. They are persisted alongside the watch list, so they survive restarts.
+use super::{
+    mozilla::MozData,
+    watch_list::{self, WatchListStorage},
+    PollerCommand, SharedState,
+};
+use matrix_sdk::{
+    ruma::{events::macros::EventContent, OwnedRoomId, RoomId},
+    Client,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::time::Duration;
+
+const SUBSCRIPTIONS_DOCUMENT: &str = "subscriptions";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RuntimeSubscription {
+    pub name: String,
+    pub url_part: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub query_subdirs: bool,
+    /// Room the subscription was created in and announces to. All watched rooms, if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<OwnedRoomId>,
+}
+
+impl RuntimeSubscription {
+    pub fn to_mozdata(&self) -> anyhow::Result<MozData> {
+        let filter = self.filter.as_deref().map(Regex::new).transpose()?;
+        let mut mozdata = MozData::new(&self.name, &self.url_part, filter, self.query_subdirs);
+        mozdata.rooms = self.room.clone().map(|x| vec![x]);
+        Ok(mozdata)
+    }
+}
+
+/// Changes made at runtime to the subscriptions from the config file
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SourceOverrides {
+    /// A None value means the filter from the config file got cleared
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub filters: BTreeMap<String, Option<String>>,
+    /// Polling intervals in seconds
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub intervals: BTreeMap<String, u64>,
+    /// Replaces config.sleep_time_in_minutes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_interval: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.subscriptions", kind = GlobalAccountData)]
+pub struct SubscriptionsEventContent {
+    pub subscriptions: Vec<RuntimeSubscription>,
+    #[serde(default)]
+    pub overrides: SourceOverrides,
+}
+
+pub async fn store(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content = SubscriptionsEventContent {
+        subscriptions: ctx
+            .runtime_subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect(),
+        overrides: ctx.overrides.lock().unwrap().clone(),
+    };
+    watch_list::store_document(client, ctx, SUBSCRIPTIONS_DOCUMENT, content).await
+}
+
+/// Whether subscriptions are persisted at all
+pub fn persisted(ctx: &SharedState) -> bool {
+    match ctx.cfg.watch_list_storage {
+        WatchListStorage::File => ctx.cfg.session_storage.get_session_db().is_some(),
+        WatchListStorage::AccountData => true,
+    }
+}
+
+/// Adds (or replaces) a subscription and hands it to the polling loop. The ones from the
+/// config file can't be replaced. If persisting it fails, nothing changes.
+pub async fn add(
+    client: &Client,
+    ctx: &SharedState,
+    subscription: RuntimeSubscription,
+) -> anyhow::Result<()> {
+    let mozdata = subscription.to_mozdata()?;
+    let name = subscription.name.clone();
+    let is_runtime = ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .contains_key(&name);
+    if !is_runtime && ctx.sources.lock().unwrap().contains_key(&name) {
+        anyhow::bail!("{name} is already defined in the config file");
+    }
+    let previous = ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .insert(name.clone(), subscription);
+    if let Err(e) = store(client, ctx).await {
+        let mut subscriptions = ctx.runtime_subscriptions.lock().unwrap();
+        match previous {
+            Some(previous) => subscriptions.insert(name, previous),
+            None => subscriptions.remove(&name),
+        };
+        return Err(e);
+    }
+    if !persisted(ctx) {
+        eprintln!("Without a session DB, the subscription {name} is gone after a restart");
+    }
+    ctx.poller
+        .send(PollerCommand::Subscribe(mozdata))
+        .map_err(|_| anyhow::anyhow!("polling loop is not running"))
+}
+
+/// Removes a runtime subscription. Returns false, if there is no such subscription.
+pub async fn remove(client: &Client, ctx: &SharedState, name: &str) -> anyhow::Result<bool> {
+    if ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .remove(name)
+        .is_none()
+    {
+        return Ok(false);
+    }
+    ctx.overrides.lock().unwrap().intervals.remove(name);
+    store(client, ctx).await?;
+    ctx.poller
+        .send(PollerCommand::Unsubscribe(name.to_string()))
+        .map_err(|_| anyhow::anyhow!("polling loop is not running"))?;
+    Ok(true)
+}
+
+/// Changes the filter of a subscription, or removes it if `filter` is None
+pub async fn set_filter(
+    client: &Client,
+    ctx: &SharedState,
+    name: &str,
+    filter: Option<String>,
+) -> anyhow::Result<()> {
+    let regex = filter.as_deref().map(Regex::new).transpose()?;
+    let is_runtime = match ctx.runtime_subscriptions.lock().unwrap().get_mut(name) {
+        Some(subscription) => {
+            subscription.filter = filter.clone();
+            true
+        }
+        None => false,
+    };
+    if !is_runtime {
+        ctx.overrides
+            .lock()
+            .unwrap()
+            .filters
+            .insert(name.to_string(), filter);
+    }
+    store(client, ctx).await?;
+    ctx.poller
+        .send(PollerCommand::SetFilter {
+            source: name.to_string(),
+            filter: regex,
+        })
+        .map_err(|_| anyhow::anyhow!("polling loop is not running"))
+}
+
+/// Changes the polling interval of a subscription, or the default one if `name` is None
+pub async fn set_interval(
+    client: &Client,
+    ctx: &SharedState,
+    name: Option<&str>,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    {
+        let mut overrides = ctx.overrides.lock().unwrap();
+        match name {
+            Some(name) => {
+                overrides
+                    .intervals
+                    .insert(name.to_string(), interval.as_secs());
+            }
+            None => overrides.default_interval = Some(interval.as_secs()),
+        }
+    }
+    store(client, ctx).await?;
+    ctx.poller
+        .send(PollerCommand::SetInterval {
+            source: name.map(String::from),
+            interval,
+        })
+        .map_err(|_| anyhow::anyhow!("polling loop is not running"))
+}
+
+/// Loads the persisted subscriptions and overrides and hands them to the polling loop
+pub async fn restore(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content: SubscriptionsEventContent =
+        watch_list::restore_document(client, ctx, SUBSCRIPTIONS_DOCUMENT).await?;
+    for subscription in content.subscriptions {
+        match subscription.to_mozdata() {
+            Ok(mozdata) => {
+                ctx.runtime_subscriptions
+                    .lock()
+                    .unwrap()
+                    .insert(subscription.name.clone(), subscription);
+                let _ = ctx.poller.send(PollerCommand::Subscribe(mozdata));
+            }
+            Err(e) => eprintln!("Dropping broken subscription {}: {e}", subscription.name),
+        }
+    }
+    for (name, filter) in &content.overrides.filters {
+        match filter.as_deref().map(Regex::new).transpose() {
+            Ok(filter) => {
+                let _ = ctx.poller.send(PollerCommand::SetFilter {
+                    source: name.clone(),
+                    filter,
+                });
+            }
+            Err(e) => eprintln!("Dropping broken filter of {name}: {e}"),
+        }
+    }
+    if let Some(interval) = content.overrides.default_interval {
+        let _ = ctx.poller.send(PollerCommand::SetInterval {
+            source: None,
+            interval: Duration::from_secs(interval),
+        });
+    }
+    for (name, interval) in &content.overrides.intervals {
+        let _ = ctx.poller.send(PollerCommand::SetInterval {
+            source: Some(name.clone()),
+            interval: Duration::from_secs(*interval),
+        });
+    }
+    *ctx.overrides.lock().unwrap() = content.overrides;
+    Ok(())
+}
+
+/// Points the subscriptions created in `from` to `to`, e.g. after a room upgrade
+pub async fn move_room(
+    client: &Client,
+    ctx: &SharedState,
+    from: &RoomId,
+    to: &RoomId,
+) -> anyhow::Result<()> {
+    let moved: Vec<_> = ctx
+        .runtime_subscriptions
+        .lock()
+        .unwrap()
+        .values_mut()
+        .filter(|x| x.room.as_deref() == Some(from))
+        .map(|x| {
+            x.room = Some(to.to_owned());
+            x.clone()
+        })
+        .collect();
+    if moved.is_empty() {
+        return Ok(());
+    }
+    store(client, ctx).await?;
+    // Not resubscribed, which would start over with an empty seen-set
+    for subscription in moved {
+        ctx.poller
+            .send(PollerCommand::SetRooms {
+                source: subscription.name,
+                rooms: Some(vec![to.to_owned()]),
+            })
+            .map_err(|_| anyhow::anyhow!("polling loop is not running"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Per-subscription templates for the wording of notifications, see `template` in the
+//! subscription config. Values in the HTML template get escaped automatically.
+use matrix_sdk::ruma::events::room::message::FormattedBody;
+use minijinja::{AutoEscape, Environment};
+use serde::Serialize;
+
+#[derive(Debug, Clone)]
+pub struct NotificationTemplate {
+    plain: String,
+    /// The plain one (escaped) is used, if unset
+    html: Option<String>,
+    /// The plain template is Markdown, the HTML body gets rendered from it
+    markdown: bool,
+}
+
+/// Variables available in templates
+#[derive(Debug, Serialize)]
+pub struct TemplateVars<'a> {
+    /// Name of the subscription
+    pub source: &'a str,
+    pub url_part: &'a str,
+    pub base_url: &'a str,
+    /// Link to the directory of the subscription
+    pub url: String,
+    /// The new entries as one string, as in the default notifications
+    pub entries: &'a str,
+    /// The new entries one by one, sorted
+    pub entry_list: Vec<&'a str>,
+    pub count: usize,
+}
+
+fn environment(html: bool) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(move |_| {
+        if html {
+            AutoEscape::Html
+        } else {
+            AutoEscape::None
+        }
+    });
+    env
+}
+
+impl NotificationTemplate {
+    /// Checks the templates for syntax errors, so they show up at startup
+    pub fn new(plain: String, html: Option<String>, markdown: bool) -> anyhow::Result<Self> {
+        environment(false).template_from_str(&plain)?;
+        if let Some(html) = &html {
+            environment(true).template_from_str(html)?;
+        }
+        if markdown && html.is_some() {
+            anyhow::bail!("Markdown templates can't have a separate HTML template");
+        }
+        Ok(Self {
+            plain,
+            html,
+            markdown,
+        })
+    }
+
+    /// Returns the plain and the HTML body
+    pub fn render(&self, vars: &TemplateVars) -> anyhow::Result<(String, String)> {
+        let plain = environment(false).render_str(&self.plain, vars)?;
+        let html = environment(true).render_str(self.html.as_ref().unwrap_or(&self.plain), vars)?;
+        // The escaped values survive the Markdown rendering as entities
+        let html = if self.markdown {
+            FormattedBody::markdown(&html)
+                .map(|x| x.body)
+                .unwrap_or(html)
+        } else {
+            html
+        };
+        Ok((plain, html))
+    }
+}
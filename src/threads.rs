@@ -0,0 +1,95 @@
+//! Notifications as per-subscription threads: every subscription gets one root message per
+//! room, and its notifications are posted as replies in that thread. The roots are kept
+//! in `org.mozillabot.thread_root` state events keyed by subscription name, so they
+//! survive restarts. Notifications held back for quiet hours or digests aren't threaded,
+//! and if posting the root fails, the notification goes out without a thread.
+use super::{
+    formatting::{self, Message},
+    i18n::{self, Language},
+    mozilla::MozData,
+    send_queue, SharedState,
+};
+use matrix_sdk::{
+    deserialized_responses::RawSyncOrStrippedState,
+    room::Room,
+    ruma::{
+        events::{
+            macros::EventContent, room::message::RoomMessageEventContent, Mentions, SyncStateEvent,
+        },
+        OwnedEventId, RoomId,
+    },
+    Client, RoomState,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.thread_root", kind = State, state_key_type = String)]
+pub struct ThreadRootEventContent {
+    pub event_id: OwnedEventId,
+}
+
+async fn stored_root(room: &Room, source: &str) -> anyhow::Result<Option<OwnedEventId>> {
+    let root = match room
+        .get_state_event_static_for_key::<ThreadRootEventContent, _>(source)
+        .await?
+    {
+        Some(RawSyncOrStrippedState::Sync(raw)) => match raw.deserialize()? {
+            SyncStateEvent::Original(event) => Some(event.content.event_id),
+            SyncStateEvent::Redacted(_) => None,
+        },
+        _ => None,
+    };
+    Ok(root)
+}
+
+/// The root of the thread of `source` in `room_id`. Posts one, if there is none yet.
+pub async fn root(
+    client: &Client,
+    ctx: &SharedState,
+    room_id: &RoomId,
+    source: &MozData,
+    lang: Language,
+) -> anyhow::Result<Option<OwnedEventId>> {
+    let key = (room_id.to_owned(), source.name.clone());
+    if let Some(root) = ctx.thread_roots.lock().unwrap().get(&key) {
+        return Ok(Some(root.clone()));
+    }
+    let Some(room) = client.get_room(room_id) else {
+        return Ok(None);
+    };
+    if room.state() != RoomState::Joined {
+        return Ok(None);
+    }
+    let root = match stored_root(&room, &source.name).await {
+        Ok(Some(root)) => root,
+        result => {
+            if let Err(e) = result {
+                eprintln!(
+                    "Ignoring unreadable thread root of {} in {room_id}: {e:?}",
+                    source.name
+                );
+            }
+            let (plain, html) = Message::fill(
+                i18n::translate(lang, "New uploads of {source}"),
+                &[("source", formatting::source_link(source))],
+            )
+            .into_parts();
+            let mut content = RoomMessageEventContent::text_html(plain, html);
+            content.mentions = Some(Mentions::new());
+            let root = send_queue::send(&room, content).await?;
+            // Without the power level for state events, the thread only lasts until a restart
+            let state = ThreadRootEventContent {
+                event_id: root.clone(),
+            };
+            if let Err(e) = room.send_state_event_for_key(&source.name, state).await {
+                eprintln!(
+                    "Failed to store the thread root of {} in {room_id}: {e:?}",
+                    source.name
+                );
+            }
+            root
+        }
+    };
+    ctx.thread_roots.lock().unwrap().insert(key, root.clone());
+    Ok(Some(root))
+}
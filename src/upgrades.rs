@@ -0,0 +1,161 @@
+//! Room upgrades: when a room we post to gets replaced (m.room.tombstone), we join the new
+//! room and move everything we keep per room over to it, instead of posting into the dead
+//! one.
+use super::{
+    admin, alerts, formatting::Message, i18n, outbox, pins, room_settings, store_queued,
+    subscriptions, watch_list, SharedState,
+};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::{
+        events::room::tombstone::OriginalSyncRoomTombstoneEvent, OwnedRoomId, RoomOrAliasId, UserId,
+    },
+    Client,
+};
+use tokio::time::{sleep, Duration};
+
+/// Whether we keep anything for the room, so it's worth following its upgrade
+fn is_relevant(ctx: &SharedState, room_id: &OwnedRoomId) -> bool {
+    ctx.rooms.lock().unwrap().contains_key(room_id)
+        || ctx.alerts.lock().unwrap().contains_key(room_id)
+        || ctx
+            .runtime_subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .any(|x| x.room.as_ref() == Some(room_id))
+        || ctx.cfg.room_configs.contains_key(room_id)
+        || ctx.cfg.admin_room.as_ref() == Some(room_id)
+}
+
+pub async fn on_tombstone(
+    event: OriginalSyncRoomTombstoneEvent,
+    client: Client,
+    room: Room,
+    ctx: Ctx<SharedState>,
+) {
+    let old = room.room_id().to_owned();
+    if !is_relevant(&ctx, &old) {
+        return;
+    }
+    let new = event.content.replacement_room;
+    let via = [event.sender.server_name().to_owned()];
+    // Joining can take a while, and should not hold up the other event handlers
+    tokio::spawn(async move {
+        println!("{old} was upgraded to {new}, joining it");
+        let mut delay = 2;
+        let new_room = loop {
+            match client
+                .join_room_by_id_or_alias(<&RoomOrAliasId>::from(&*new), &via)
+                .await
+            {
+                Ok(new_room) => break new_room,
+                Err(err) if delay > 3600 => {
+                    let message = |lang| {
+                        Message::fill(
+                            i18n::translate(
+                                lang,
+                                "{old} was upgraded, but joining its replacement {new} failed: {err}",
+                            ),
+                            &[
+                                ("old", Message::new().text(old.as_str())),
+                                ("new", Message::new().text(new.as_str())),
+                                ("err", Message::new().text(&err.to_string())),
+                            ],
+                        )
+                    };
+                    admin::report(&client, &ctx, "upgrade", message).await;
+                    return;
+                }
+                Err(err) => {
+                    eprintln!("Failed to join {new} ({err:?}), retrying in {delay}s");
+                    sleep(Duration::from_secs(delay)).await;
+                    delay *= 2;
+                }
+            }
+        };
+        migrate(&client, &ctx, &room, &new_room).await;
+    });
+}
+
+/// Moves the watch list entry, alerts, subscriptions and settings of `old` to `new`
+async fn migrate(client: &Client, ctx: &SharedState, old: &Room, new: &Room) {
+    let (old_id, new_id) = (old.room_id(), new.room_id());
+    let watched = ctx.rooms.lock().unwrap().remove(old_id);
+    if let Some(watched) = watched {
+        ctx.rooms.lock().unwrap().insert(new_id.to_owned(), watched);
+        if let Err(e) = watch_list::store(client, ctx).await {
+            eprintln!("Failed to store the watched rooms: {e:?}");
+        }
+    }
+    let muted = ctx.routed_mutes.lock().unwrap().remove(old_id);
+    if let Some(muted) = muted {
+        ctx.routed_mutes
+            .lock()
+            .unwrap()
+            .insert(new_id.to_owned(), muted);
+        if let Err(e) = watch_list::store_routed_mutes(client, ctx).await {
+            eprintln!("Failed to store the mutes of routed rooms: {e:?}");
+        }
+    }
+    if let Err(e) = alerts::move_room(client, ctx, old_id, new_id).await {
+        eprintln!("Failed to move the keyword alerts of {old_id}: {e:?}");
+    }
+    if let Err(e) = subscriptions::move_room(client, ctx, old_id, new_id).await {
+        eprintln!("Failed to move the subscriptions of {old_id}: {e:?}");
+    }
+    let may_set = |user: &UserId| ctx.trusted_by_config(old_id, user);
+    if let Err(e) = room_settings::copy(old, new, &may_set).await {
+        eprintln!("Failed to copy the settings of {old_id} to {new_id}: {e:?}");
+    }
+    let queued = ctx.queued.lock().unwrap().remove(old_id);
+    if let Some(queued) = queued {
+        ctx.queued
+            .lock()
+            .unwrap()
+            .entry(new_id.to_owned())
+            .or_default()
+            .extend(queued);
+        store_queued(ctx).await;
+    }
+    let mut moved_pending = false;
+    for pending in ctx.outbox.lock().unwrap().iter_mut() {
+        if pending.room_id == old_id {
+            pending.room_id = new_id.to_owned();
+            moved_pending = true;
+        }
+    }
+    if moved_pending {
+        if let Err(e) = outbox::store(ctx).await {
+            eprintln!("Failed to persist the undelivered notifications: {e:?}");
+        }
+    }
+    // Threads, edits and pins refer to events in the old room
+    ctx.thread_roots
+        .lock()
+        .unwrap()
+        .retain(|(x, _), _| x != old_id);
+    ctx.editable.lock().unwrap().retain(|(x, _), _| x != old_id);
+    ctx.pinned.lock().unwrap().retain(|(x, _), _| x != old_id);
+    if let Err(e) = pins::store(ctx).await {
+        eprintln!("Failed to persist the pins: {e:?}");
+    }
+    println!("Moved everything from {old_id} to {new_id}");
+    // The config file can't be changed from here
+    if ctx.cfg.room_configs.contains_key(old_id) || ctx.cfg.admin_room.as_deref() == Some(old_id) {
+        let message = |lang| {
+            Message::fill(
+                i18n::translate(
+                    lang,
+                    "{old} was upgraded to {new}. Please update the config file, it still refers to the old room.",
+                ),
+                &[
+                    ("old", Message::new().text(old_id.as_str())),
+                    ("new", Message::new().text(new_id.as_str())),
+                ],
+            )
+        };
+        admin::report(client, ctx, "upgrade", message).await;
+    }
+}
@@ -0,0 +1,96 @@
+//! Entries of accept_commands_from: either a full user ID, or a glob like
+//! `@release-*:example.org`. `*:mozilla.org` is short for `@*:mozilla.org`.
+use matrix_sdk::ruma::UserId;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UserPattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl UserPattern {
+    pub fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let pattern = if pattern.starts_with("*:") {
+            format!("@{pattern}")
+        } else {
+            pattern.to_string()
+        };
+        if !pattern.contains(['*', '?']) {
+            UserId::parse(pattern.as_str())?;
+        } else if !pattern.starts_with('@') || !pattern.contains(':') {
+            anyhow::bail!("{pattern} doesn't look like @localpart:server");
+        }
+        let regex = regex::escape(&pattern)
+            .replace(r"\*", ".*")
+            .replace(r"\?", ".");
+        Ok(Self {
+            regex: Regex::new(&format!("^{regex}$"))?,
+            pattern,
+        })
+    }
+
+    pub fn matches(&self, user: &UserId) -> bool {
+        self.regex.is_match(user.as_str())
+    }
+}
+
+impl TryFrom<String> for UserPattern {
+    type Error = anyhow::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Self::parse(&pattern)
+    }
+}
+
+impl From<UserPattern> for String {
+    fn from(pattern: UserPattern) -> Self {
+        pattern.pattern
+    }
+}
+
+impl fmt::Display for UserPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> &UserId {
+        <&UserId>::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn matches_full_user_ids() {
+        let pattern = UserPattern::parse("@alice:example.org").unwrap();
+        assert!(pattern.matches(user("@alice:example.org")));
+        assert!(!pattern.matches(user("@alice2:example.org")));
+        assert!(!pattern.matches(user("@alice:example.org.evil")));
+    }
+
+    #[test]
+    fn matches_globs() {
+        let pattern = UserPattern::parse("*:mozilla.org").unwrap();
+        assert_eq!(pattern.to_string(), "@*:mozilla.org");
+        assert!(pattern.matches(user("@bob:mozilla.org")));
+        assert!(!pattern.matches(user("@bob:evil.org")));
+        assert!(!pattern.matches(user("@bob:mozilla.org.evil.org")));
+
+        let pattern = UserPattern::parse("@release-?:example.org").unwrap();
+        assert!(pattern.matches(user("@release-1:example.org")));
+        assert!(!pattern.matches(user("@release-10:example.org")));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(UserPattern::parse("alice").is_err());
+        assert!(UserPattern::parse("release-*").is_err());
+        assert!(UserPattern::parse("@release-*").is_err());
+    }
+}
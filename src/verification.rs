@@ -0,0 +1,211 @@
+//! Interactive SAS verification of the bot's device by trusted users. The bot has nobody
+//! to compare the emoji on its side, so it reports them to the admin room and waits for
+//! an admin to compare them with the other device and confirm with `!verify`.
+use super::{admin, formatting::Message, i18n, SharedState};
+use futures_util::StreamExt;
+use matrix_sdk::{
+    encryption::verification::{
+        format_emojis, SasState, SasVerification, Verification, VerificationRequest,
+        VerificationRequestState,
+    },
+    event_handler::Ctx,
+    ruma::{
+        events::{
+            key::verification::request::ToDeviceKeyVerificationRequestEvent,
+            room::message::{MessageType, OriginalSyncRoomMessageEvent},
+        },
+        DeviceId,
+    },
+    Client,
+};
+use tokio::time::{sleep, Duration};
+
+/// Unconfirmed verifications get cancelled after this long
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+async fn sas_verification_handler(sas: SasVerification, client: Client, ctx: SharedState) {
+    let user_id = sas.other_device().user_id().to_owned();
+    let device_id = sas.other_device().device_id().to_owned();
+    println!("Starting verification with {user_id} {device_id}");
+    if let Err(e) = sas.accept().await {
+        eprintln!("Failed to accept SAS verification: {e}");
+        return;
+    }
+
+    let mut stream = sas.changes();
+    while let Some(state) = stream.next().await {
+        match state {
+            SasState::KeysExchanged { emojis, decimals } => {
+                let shown = match emojis {
+                    Some(emojis) => format_emojis(emojis.emojis),
+                    None => format!("{} {} {}", decimals.0, decimals.1, decimals.2),
+                };
+                ctx.verifications
+                    .lock()
+                    .unwrap()
+                    .insert(device_id.clone(), sas.clone());
+                let prefix = &ctx.cfg.command_prefix;
+                admin::report(
+                    &client,
+                    &ctx,
+                    &format!("verification.{}", sas.flow_id()),
+                    |lang| {
+                        Message::fill(
+                            i18n::translate(
+                                lang,
+                                "{user} wants to verify the bot from their device {device}. If it shows the following, confirm with {confirm}, otherwise cancel with {cancel}:",
+                            ),
+                            &[
+                                ("user", Message::new().text(user_id.as_str())),
+                                ("device", Message::new().code(device_id.as_str())),
+                                (
+                                    "confirm",
+                                    Message::new().code(&format!("{prefix}verify {device_id}")),
+                                ),
+                                (
+                                    "cancel",
+                                    Message::new().code(&format!(
+                                        "{prefix}verify {device_id} cancel"
+                                    )),
+                                ),
+                            ],
+                        )
+                        .line_break()
+                        .text(&shown)
+                    },
+                )
+                .await;
+                let (ctx, sas) = (ctx.clone(), sas.clone());
+                tokio::spawn(async move {
+                    sleep(CONFIRMATION_TIMEOUT).await;
+                    if forget(&ctx, &sas) {
+                        println!("Nobody confirmed the verification in time, cancelling it");
+                        if let Err(e) = sas.cancel().await {
+                            eprintln!("Failed to cancel SAS verification: {e}");
+                        }
+                    }
+                });
+            }
+            SasState::Done { .. } => {
+                println!("Successfully verified device {user_id} {device_id}");
+                break;
+            }
+            SasState::Cancelled(cancel_info) => {
+                println!(
+                    "Verification has been cancelled, reason: {}",
+                    cancel_info.reason()
+                );
+                break;
+            }
+            SasState::Started { .. } | SasState::Accepted { .. } | SasState::Confirmed => (),
+        }
+    }
+    forget(&ctx, &sas);
+}
+
+/// Stops `sas` from waiting for `!verify`. Returns whether it still was.
+fn forget(ctx: &SharedState, sas: &SasVerification) -> bool {
+    let mut verifications = ctx.verifications.lock().unwrap();
+    let device_id = sas.other_device().device_id();
+    match verifications.get(device_id) {
+        Some(waiting) if waiting.flow_id() == sas.flow_id() => {
+            verifications.remove(device_id);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Removes the verification with `device_id` that waits for `!verify`
+pub fn take_unconfirmed(ctx: &SharedState, device_id: &DeviceId) -> Option<SasVerification> {
+    ctx.verifications.lock().unwrap().remove(device_id)
+}
+
+async fn request_verification_handler(
+    request: VerificationRequest,
+    client: Client,
+    ctx: SharedState,
+) {
+    println!(
+        "Accepting verification request from {}",
+        request.other_user_id()
+    );
+    if let Err(e) = request.accept().await {
+        eprintln!("Failed to accept verification request: {e}");
+        return;
+    }
+
+    let mut stream = request.changes();
+    while let Some(state) = stream.next().await {
+        match state {
+            VerificationRequestState::Created { .. }
+            | VerificationRequestState::Requested { .. }
+            | VerificationRequestState::Ready { .. } => (),
+            VerificationRequestState::Transitioned { verification } => {
+                if let Verification::SasV1(sas) = verification {
+                    tokio::spawn(sas_verification_handler(sas, client, ctx));
+                }
+                break;
+            }
+            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => break,
+        }
+    }
+}
+
+async fn handle_request(request: Option<VerificationRequest>, client: Client, ctx: &SharedState) {
+    let Some(request) = request else {
+        eprintln!("Got a verification request we don't know about");
+        return;
+    };
+    // An empty list trusts everyone with commands, but not with verifying the bot
+    if ctx
+        .cfg
+        .reloadable
+        .lock()
+        .unwrap()
+        .accept_commands_from
+        .is_empty()
+    {
+        println!(
+            "Ignoring verification request from {}, config.accept_commands_from is empty",
+            request.other_user_id()
+        );
+        return;
+    }
+    if !ctx.accepts_commands_from(request.other_user_id()) {
+        println!(
+            "Ignoring verification request from untrusted user {}",
+            request.other_user_id()
+        );
+        return;
+    }
+    tokio::spawn(request_verification_handler(request, client, ctx.clone()));
+}
+
+/// Verification requests sent directly to our device
+pub async fn on_to_device_verification_request(
+    event: ToDeviceKeyVerificationRequestEvent,
+    client: Client,
+    ctx: Ctx<SharedState>,
+) {
+    let request = client
+        .encryption()
+        .get_verification_request(&event.sender, &event.content.transaction_id)
+        .await;
+    handle_request(request, client, &ctx).await;
+}
+
+/// Verification requests sent in a (DM) room
+pub async fn on_room_verification_request(
+    event: OriginalSyncRoomMessageEvent,
+    client: Client,
+    ctx: Ctx<SharedState>,
+) {
+    if let MessageType::VerificationRequest(_) = &event.content.msgtype {
+        let request = client
+            .encryption()
+            .get_verification_request(&event.sender, &event.event_id)
+            .await;
+        handle_request(request, client, &ctx).await;
+    }
+}
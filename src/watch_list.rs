@@ -0,0 +1,253 @@
+//! Persistence of the rooms we post notifications to, either in a local file next to the
+//! session DB or in the account data of the bot user on the homeserver.
+use super::{knocking, SharedState};
+use matrix_sdk::{
+    ruma::{
+        api::client::error::ErrorKind,
+        events::{
+            macros::EventContent, GlobalAccountDataEventContent, GlobalAccountDataEventType,
+            StaticEventContent,
+        },
+        OwnedRoomId,
+    },
+    Client, RoomState,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchListStorage {
+    /// With the session storage, see `SessionStore::store_rooms`. Not available for
+    /// ephemeral sessions.
+    File,
+    /// Survives host migrations, but is readable by the homeserver admins
+    AccountData,
+}
+
+impl WatchListStorage {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "file" => Ok(Self::File),
+            "account_data" => Ok(Self::AccountData),
+            _ => anyhow::bail!("Unknown watch list storage {name}, expected file or account_data"),
+        }
+    }
+}
+
+/// What a watched room wants to get announced
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WatchedRoom {
+    /// Subscriptions this room doesn't want to hear about
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub muted: BTreeSet<String>,
+}
+
+/// Older versions only stored the list of rooms
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum StoredRooms {
+    Legacy(Vec<OwnedRoomId>),
+    Current(BTreeMap<OwnedRoomId, WatchedRoom>),
+}
+
+impl StoredRooms {
+    pub fn into_map(self) -> HashMap<OwnedRoomId, WatchedRoom> {
+        match self {
+            StoredRooms::Legacy(rooms) => rooms
+                .into_iter()
+                .map(|x| (x, WatchedRoom::default()))
+                .collect(),
+            StoredRooms::Current(rooms) => rooms.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.watched_rooms", kind = GlobalAccountData)]
+pub struct WatchedRoomsEventContent {
+    pub rooms: StoredRooms,
+}
+
+const ROUTED_MUTES_DOCUMENT: &str = "routed_mutes";
+
+/// Mutes of rooms that get subscriptions through `[room].sources` or the subscription's
+/// `rooms`, without being on the watch list
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.mozillabot.routed_mutes", kind = GlobalAccountData)]
+pub struct RoutedMutesEventContent {
+    pub rooms: BTreeMap<OwnedRoomId, BTreeSet<String>>,
+}
+
+/// Persists the current watch list
+pub async fn store(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let rooms = StoredRooms::Current(
+        ctx.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, room)| (id.clone(), room.clone()))
+            .collect(),
+    );
+    match ctx.cfg.watch_list_storage {
+        WatchListStorage::File => ctx.cfg.session_storage.store_rooms(&rooms).await?,
+        WatchListStorage::AccountData => {
+            client
+                .account()
+                .set_account_data(WatchedRoomsEventContent { rooms })
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn store_routed_mutes(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content = RoutedMutesEventContent {
+        rooms: ctx.routed_mutes.lock().unwrap().clone(),
+    };
+    store_document(client, ctx, ROUTED_MUTES_DOCUMENT, content).await
+}
+
+pub async fn restore_routed_mutes(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let content: RoutedMutesEventContent =
+        restore_document(client, ctx, ROUTED_MUTES_DOCUMENT).await?;
+    *ctx.routed_mutes.lock().unwrap() = content.rooms;
+    Ok(())
+}
+
+/// Persists other state the way the watch list is: as the state DB document `name`, or as
+/// account data. File storage keeps nothing without a session DB.
+pub async fn store_document<C>(
+    client: &Client,
+    ctx: &SharedState,
+    name: &str,
+    content: C,
+) -> anyhow::Result<()>
+where
+    C: GlobalAccountDataEventContent + Serialize,
+{
+    match ctx.cfg.watch_list_storage {
+        WatchListStorage::File => {
+            if let Some(db) = ctx.cfg.session_storage.get_session_db() {
+                db.state.set_document(name, &content).await?;
+            }
+        }
+        WatchListStorage::AccountData => {
+            client.account().set_account_data(content).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads what `store_document` persisted, the default if there is nothing yet
+pub async fn restore_document<C>(
+    client: &Client,
+    ctx: &SharedState,
+    name: &str,
+) -> anyhow::Result<C>
+where
+    C: StaticEventContent + DeserializeOwned + Default,
+{
+    let content = match ctx.cfg.watch_list_storage {
+        WatchListStorage::File => match ctx.cfg.session_storage.get_session_db() {
+            Some(db) => db.state.document(name).await?.unwrap_or_default(),
+            None => C::default(),
+        },
+        WatchListStorage::AccountData => {
+            let event_type = GlobalAccountDataEventType::from(C::TYPE);
+            match client.account().fetch_account_data(event_type).await? {
+                Some(raw) => raw.deserialize_as::<C>()?,
+                None => C::default(),
+            }
+        }
+    };
+    Ok(content)
+}
+
+/// Reads the watch list from the local file. Done before logging in, so that polls
+/// before the first sync already know where to post.
+pub async fn restore_from_file(ctx: &SharedState) -> anyhow::Result<()> {
+    if ctx.cfg.watch_list_storage != WatchListStorage::File {
+        return Ok(());
+    }
+    if let Some(rooms) = ctx.cfg.session_storage.restore_rooms().await? {
+        *ctx.rooms.lock().unwrap() = rooms.into_map();
+    }
+    Ok(())
+}
+
+/// Reads the watch list from the account data, which needs a logged in client
+pub async fn restore_from_account_data(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    if ctx.cfg.watch_list_storage != WatchListStorage::AccountData {
+        return Ok(());
+    }
+    let event_type = GlobalAccountDataEventType::from("org.mozillabot.watched_rooms");
+    if let Some(raw) = client.account().fetch_account_data(event_type).await? {
+        let content: WatchedRoomsEventContent = raw.deserialize_as()?;
+        ctx.rooms.lock().unwrap().extend(content.rooms.into_map());
+    }
+    Ok(())
+}
+
+/// Drops rooms from the restored watch list that we are no longer in, e.g. because we
+/// got kicked while the bot wasn't running
+pub async fn validate(client: &Client, ctx: &SharedState) -> anyhow::Result<()> {
+    let removed: Vec<_> = {
+        let mut rooms = ctx.rooms.lock().unwrap();
+        let removed = rooms
+            .keys()
+            .filter(|room_id| {
+                client
+                    .get_room(room_id)
+                    .map(|room| room.state() != RoomState::Joined)
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect();
+        for room_id in &removed {
+            rooms.remove(room_id);
+        }
+        removed
+    };
+    if !removed.is_empty() {
+        for room_id in &removed {
+            println!("No longer watching {room_id}, as we are not in that room anymore");
+        }
+        store(client, ctx).await?;
+    }
+    Ok(())
+}
+
+/// Joins the rooms of `config.rooms` and watches them, so fresh deployments don't need an
+/// invite and !watch. Rooms we may not join get a knock instead. Returns the problems.
+pub async fn join_configured(client: &Client, ctx: &SharedState) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut added = false;
+    for room in &ctx.cfg.rooms {
+        let room_id = match client.join_room_by_id_or_alias(room, &[]).await {
+            Ok(joined) => joined.room_id().to_owned(),
+            Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::Forbidden)) => {
+                if let Err(e) = knocking::knock(client, ctx, room).await {
+                    problems.push(format!("Failed to join or knock on {room}: {e}"));
+                }
+                continue;
+            }
+            Err(e) => {
+                problems.push(format!("Failed to join {room}: {e}"));
+                continue;
+            }
+        };
+        ctx.configured_rooms.lock().unwrap().insert(room_id.clone());
+        let mut rooms = ctx.rooms.lock().unwrap();
+        if !rooms.contains_key(&room_id) {
+            println!("Watching configured room {room}");
+            rooms.insert(room_id, WatchedRoom::default());
+            added = true;
+        }
+    }
+    if added {
+        if let Err(e) = store(client, ctx).await {
+            problems.push(format!("Failed to store the watched rooms: {e}"));
+        }
+    }
+    problems
+}